@@ -0,0 +1,147 @@
+#ifndef YAQUE_H
+#define YAQUE_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The call succeeded.
+#define YAQUE_OK 0
+
+// A pointer was null or a path was not valid UTF-8.
+#define YAQUE_INVALID_ARGUMENT 1
+
+// An underlying IO error occurred (including the queue being in use).
+#define YAQUE_IO_ERROR 2
+
+// The queue is empty (or nothing arrived before the timeout).
+#define YAQUE_QUEUE_EMPTY 3
+
+// The queue is full.
+#define YAQUE_QUEUE_FULL 4
+
+// Sending would take the queue over its quota.
+#define YAQUE_QUOTA_EXCEEDED 5
+
+// There is no pending element to commit or roll back.
+#define YAQUE_NOT_PENDING 6
+
+// An element is still pending: commit it or roll it back first.
+#define YAQUE_STILL_PENDING 7
+
+// The library panicked. The handle should not be used anymore.
+#define YAQUE_PANIC 8
+
+// The receiving side of a queue, opened with [`yaque_receiver_open`].
+typedef struct YaqueReceiver YaqueReceiver;
+
+// The sending side of a queue, opened with [`yaque_sender_open`].
+typedef struct YaqueSender YaqueSender;
+
+// Gets the message of the last error on the calling thread, or null if there
+// was none. The message is valid until the next failed call on the thread.
+const char *yaque_last_error(void);
+
+// Opens a queue at the directory `base` for sending, writing the handle to
+// `sender`. See [`crate::Sender::open`].
+//
+// # Safety
+//
+// `base` must be a valid C string and `sender` must be valid for writes.
+int yaque_sender_open(const char *base, struct YaqueSender **sender);
+
+// Sends `len` bytes from `data` into the queue, blocking while the queue is
+// full. See [`crate::Sender::send`].
+//
+// # Safety
+//
+// `sender` must come from [`yaque_sender_open`] and `data` must be valid for
+// `len` bytes.
+int yaque_send(struct YaqueSender *sender, const uint8_t *data, size_t len);
+
+// Tries to send `len` bytes from `data` into the queue, returning
+// `YAQUE_QUEUE_FULL` instead of blocking. See [`crate::Sender::try_send`].
+//
+// # Safety
+//
+// `sender` must come from [`yaque_sender_open`] and `data` must be valid for
+// `len` bytes.
+int yaque_try_send(struct YaqueSender *sender, const uint8_t *data, size_t len);
+
+// Closes the sending side of a queue, saving its state. Closing null does
+// nothing.
+//
+// # Safety
+//
+// `sender` must come from [`yaque_sender_open`] (or be null) and must not be
+// used afterwards.
+void yaque_sender_close(struct YaqueSender *sender);
+
+// Opens a queue at the directory `base` for receiving, writing the handle to
+// `receiver`. See [`crate::Receiver::open`].
+//
+// # Safety
+//
+// `base` must be a valid C string and `receiver` must be valid for writes.
+int yaque_receiver_open(const char *base, struct YaqueReceiver **receiver);
+
+// Receives an element from the queue, blocking until there is one. Its data
+// and length are written to `data` and `len`, and stay valid until the
+// element is committed or rolled back. See [`crate::Receiver::recv`].
+//
+// # Safety
+//
+// `receiver` must come from [`yaque_receiver_open`] and `data` and `len` must
+// be valid for writes.
+int yaque_recv(struct YaqueReceiver *receiver, const uint8_t **data, size_t *len);
+
+// Tries to receive an element from the queue, returning `YAQUE_QUEUE_EMPTY`
+// instead of blocking. See [`yaque_recv`].
+//
+// # Safety
+//
+// Same as [`yaque_recv`].
+int yaque_try_recv(struct YaqueReceiver *receiver, const uint8_t **data, size_t *len);
+
+// Receives an element from the queue, blocking for at most `timeout_ms`
+// milliseconds. If nothing arrives in time, this returns
+// `YAQUE_QUEUE_EMPTY`. See [`yaque_recv`].
+//
+// # Safety
+//
+// Same as [`yaque_recv`].
+int yaque_recv_timeout(struct YaqueReceiver *receiver,
+                       uint64_t timeout_ms,
+                       const uint8_t **data,
+                       size_t *len);
+
+// Commits the pending element, removing it from the queue for good. Its
+// data is no longer valid afterwards. See [`crate::queue::RecvGuard::commit`].
+//
+// # Safety
+//
+// `receiver` must come from [`yaque_receiver_open`].
+int yaque_commit(struct YaqueReceiver *receiver);
+
+// Rolls the pending element back, so that it is received again. Its data is
+// no longer valid afterwards. See [`crate::queue::RecvGuard::rollback`].
+//
+// # Safety
+//
+// `receiver` must come from [`yaque_receiver_open`].
+int yaque_rollback(struct YaqueReceiver *receiver);
+
+// Closes the receiving side of a queue, rolling back the pending element (if
+// any) and saving its state. Closing null does nothing.
+//
+// # Safety
+//
+// `receiver` must come from [`yaque_receiver_open`] (or be null) and must not
+// be used afterwards.
+void yaque_receiver_close(struct YaqueReceiver *receiver);
+
+#endif  /* YAQUE_H */
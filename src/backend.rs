@@ -0,0 +1,187 @@
+//! Traits common to the queues on disk and to the queues in memory (see
+//! [`crate::mem`]), so that the backend can be chosen when the queue is opened.
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::io;
+
+use crate::error::{TryRecvError, TrySendError};
+use crate::{mem, queue};
+
+/// The sender part of a queue, on disk or in memory.
+/// ```rust
+/// use yaque::{mem, QueueSender, Sender};
+///
+/// fn open(in_memory: bool) -> std::io::Result<Box<dyn QueueSender>> {
+///     if in_memory {
+///         Ok(Box::new(mem::Sender::open("data/my-backend")?))
+///     } else {
+///         Ok(Box::new(Sender::open("data/my-backend")?))
+///     }
+/// }
+///
+/// futures::executor::block_on(async {
+///     open(true).unwrap().send(b"some data").await.unwrap();
+/// });
+/// ```
+pub trait QueueSender: Send {
+    /// Sends some data into the queue. See [`crate::Sender::send`].
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Tries to send some data into the queue, without waiting. See
+    /// [`crate::Sender::try_send`].
+    fn try_send<'a>(&mut self, data: &'a [u8]) -> Result<(), TrySendError<&'a [u8]>>;
+}
+
+/// The receiver part of a queue, on disk or in memory.
+pub trait QueueReceiver: Send {
+    /// Retrieves an element from the queue. See [`crate::Receiver::recv`].
+    fn recv(&mut self) -> BoxFuture<'_, io::Result<Box<dyn QueueGuard + '_>>>;
+
+    /// Tries to retrieve an element from the queue, without waiting. See
+    /// [`crate::Receiver::try_recv`].
+    fn try_recv(&mut self) -> Result<Box<dyn QueueGuard + '_>, TryRecvError>;
+}
+
+/// An element received from a queue, on disk or in memory, which is rolled
+/// back when dropped unless committed. See [`crate::queue::RecvGuard`].
+pub trait QueueGuard: Send {
+    /// The element.
+    fn data(&self) -> &[u8];
+
+    /// Commits the changes to the queue. See [`crate::queue::RecvGuard::commit`].
+    fn commit(self: Box<Self>) -> io::Result<()>;
+
+    /// Rolls the changes to the queue back. See
+    /// [`crate::queue::RecvGuard::rollback`].
+    fn rollback(self: Box<Self>) -> io::Result<()>;
+}
+
+impl QueueSender for queue::Sender {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        queue::Sender::send(self, data).boxed()
+    }
+
+    fn try_send<'a>(&mut self, data: &'a [u8]) -> Result<(), TrySendError<&'a [u8]>> {
+        queue::Sender::try_send(self, data)
+    }
+}
+
+impl QueueSender for mem::Sender {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        mem::Sender::send(self, data).boxed()
+    }
+
+    fn try_send<'a>(&mut self, data: &'a [u8]) -> Result<(), TrySendError<&'a [u8]>> {
+        mem::Sender::try_send(self, data)
+    }
+}
+
+impl<S: QueueSender + ?Sized> QueueSender for Box<S> {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        (**self).send(data)
+    }
+
+    fn try_send<'a>(&mut self, data: &'a [u8]) -> Result<(), TrySendError<&'a [u8]>> {
+        (**self).try_send(data)
+    }
+}
+
+impl QueueReceiver for queue::Receiver {
+    fn recv(&mut self) -> BoxFuture<'_, io::Result<Box<dyn QueueGuard + '_>>> {
+        async move {
+            let guard = queue::Receiver::recv(self).await?;
+            Ok(Box::new(guard) as Box<dyn QueueGuard>)
+        }
+        .boxed()
+    }
+
+    fn try_recv(&mut self) -> Result<Box<dyn QueueGuard + '_>, TryRecvError> {
+        Ok(Box::new(queue::Receiver::try_recv(self)?))
+    }
+}
+
+impl QueueReceiver for mem::Receiver {
+    fn recv(&mut self) -> BoxFuture<'_, io::Result<Box<dyn QueueGuard + '_>>> {
+        async move {
+            let guard = mem::Receiver::recv(self).await?;
+            Ok(Box::new(guard) as Box<dyn QueueGuard>)
+        }
+        .boxed()
+    }
+
+    fn try_recv(&mut self) -> Result<Box<dyn QueueGuard + '_>, TryRecvError> {
+        Ok(Box::new(mem::Receiver::try_recv(self)?))
+    }
+}
+
+impl<R: QueueReceiver + ?Sized> QueueReceiver for Box<R> {
+    fn recv(&mut self) -> BoxFuture<'_, io::Result<Box<dyn QueueGuard + '_>>> {
+        (**self).recv()
+    }
+
+    fn try_recv(&mut self) -> Result<Box<dyn QueueGuard + '_>, TryRecvError> {
+        (**self).try_recv()
+    }
+}
+
+impl<'a> QueueGuard for queue::RecvGuard<'a, Vec<u8>> {
+    fn data(&self) -> &[u8] {
+        self
+    }
+
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        queue::RecvGuard::commit(*self)
+    }
+
+    fn rollback(self: Box<Self>) -> io::Result<()> {
+        queue::RecvGuard::rollback(*self)
+    }
+}
+
+impl<'a> QueueGuard for mem::RecvGuard<'a, Vec<u8>> {
+    fn data(&self) -> &[u8] {
+        self
+    }
+
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        mem::RecvGuard::commit(*self)
+    }
+
+    fn rollback(self: Box<Self>) -> io::Result<()> {
+        mem::RecvGuard::rollback(*self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(sender: &mut dyn QueueSender, receiver: &mut dyn QueueReceiver) {
+        futures::executor::block_on(async {
+            sender.send(b"first").await.unwrap();
+            sender.try_send(b"second").unwrap();
+
+            let guard = receiver.recv().await.unwrap();
+            assert_eq!(guard.data(), b"first");
+            guard.rollback().unwrap();
+
+            for expected in [&b"first"[..], b"second"] {
+                let guard = receiver.try_recv().ok().unwrap();
+                assert_eq!(guard.data(), expected);
+                guard.commit().unwrap();
+            }
+        });
+
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+    }
+
+    #[test]
+    fn both_backends() {
+        let (mut sender, mut receiver) = queue::channel("data/backend").unwrap();
+        roundtrip(&mut sender, &mut receiver);
+
+        let (mut sender, mut receiver) = mem::channel("data/backend").unwrap();
+        roundtrip(&mut sender, &mut receiver);
+    }
+}
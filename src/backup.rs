@@ -0,0 +1,635 @@
+//! Backups of whole queues into a single archive file, e.g., for moving a
+//! queue between hosts. See [`export`] and [`import`].
+//!
+//! The archive is a plain (ustar) tar file, so it can also be inspected with
+//! `tar`. It contains the segments and the state of the receiver of the queue
+//! and of each of its priority lanes, followed by a manifest with the size and
+//! the CRC32 of every other file in the archive:
+//! ```text
+//! <crc32 (hex)> <size> <name>
+//! ```
+//! Locks and other transient files are left out.
+
+use std::fs::*;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::header::Header;
+use crate::metadata::HEADER_METADATA;
+use crate::queue::{
+    lane_priority, list_segments, recv_lock_filename, segment_filename, send_lock_filename,
+    HEADER_EOF,
+};
+use crate::sync::FileGuard;
+use crate::version::read_format_version;
+
+/// The name of the manifest in the archive.
+pub(crate) const MANIFEST_NAME: &str = "manifest";
+
+/// The files with the state of a queue that go into an archive, besides the
+/// segments. This includes the type of the messages recorded by the typed
+/// handles, such as [`crate::queue::TypedSender`], the offset replicated to a
+/// replica (see the `replication` module), the elements a sender remembers
+/// (see [`crate::SenderBuilder::dedup_window`]) and the settings of the queue
+/// (see [`crate::SenderBuilder::open`]).
+const STATE_FILES: [&str; 7] = [
+    "recv-metadata",
+    "recv-deliveries",
+    "recv-seen-ids",
+    "message-type",
+    "replica-offset",
+    "send-dedup",
+    "settings",
+];
+
+/// The size of a block in a tar file.
+const BLOCK_SIZE: usize = 512;
+
+/// Exports a snapshot of a queue (segments and state) into an archive written
+/// to `writer`. Priority lanes are exported as well, in folders named after
+/// them.
+///
+/// The receiver side of the queue is locked while exporting, so that the state
+/// of the receiver matches the exported segments. The sender side, on the other
+/// hand, may be in use: in that case, only the elements that were completely
+/// written when their segment was read go into the archive. If the sender side
+/// is free, it is locked as well.
+///
+/// # Errors
+///
+/// This function fails if the queue is in use for receiving. It also fails
+/// with an error of kind [`io::ErrorKind::InvalidData`] if a segment is
+/// corrupted. Any underlying errors encountered while reading the queue or
+/// while writing the archive are also returned.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+pub fn export<P: AsRef<Path>, W: Write>(base: P, mut writer: W) -> io::Result<()> {
+    let mut manifest = String::new();
+    export_queue(base.as_ref(), "", &mut writer, &mut manifest)?;
+
+    write_entry(&mut writer, MANIFEST_NAME, manifest.as_bytes())?;
+
+    // A tar file ends with two empty blocks:
+    writer.write_all(&[0; 2 * BLOCK_SIZE])?;
+    writer.flush()
+}
+
+/// Locks a side of a queue (`"sender"` or `"receiver"`), failing if it is in
+/// use.
+fn try_lock_side(base: &Path, side: &str) -> io::Result<FileGuard> {
+    let filename = if side == "sender" {
+        send_lock_filename(base)
+    } else {
+        recv_lock_filename(base)
+    };
+
+    FileGuard::try_lock(filename)?.ok_or_else(|| {
+        io::Error::other(format!(
+            "queue `{}` {} side already in use",
+            base.to_string_lossy(),
+            side
+        ))
+    })
+}
+
+/// Exports a queue (or a priority lane) into an archive, with the names of its
+/// files starting with `prefix`.
+fn export_queue<W: Write>(
+    base: &Path,
+    prefix: &str,
+    writer: &mut W,
+    manifest: &mut String,
+) -> io::Result<()> {
+    let _recv_lock = try_lock_side(base, "receiver")?;
+    let send_lock = FileGuard::try_lock(send_lock_filename(base))?;
+
+    if send_lock.is_none() {
+        log::debug!("exporting {:?} while a sender is running", base);
+    }
+
+    let mut add = |name: String, contents: &[u8]| -> io::Result<()> {
+        manifest.push_str(&format!(
+            "{:08x} {} {}\n",
+            crc32fast::hash(contents),
+            contents.len(),
+            name
+        ));
+        write_entry(writer, &name, contents)
+    };
+
+    let format = format!("{}\n", read_format_version(base)?);
+    add(format!("{}format", prefix), format.as_bytes())?;
+
+    for state_file in &STATE_FILES {
+        match read(base.join(state_file)) {
+            Ok(contents) => add(format!("{}{}", prefix, state_file), &contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    for segment in list_segments(base)? {
+        let contents = match read(segment_filename(base, segment)) {
+            Ok(contents) => contents,
+            // Dropped by the sender, with `RetentionPolicy::DropOldest`:
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+
+        let len = complete_len(&contents).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("segment {} of {:?} is corrupted", segment, base),
+            )
+        })?;
+
+        add(format!("{}{}.q", prefix, segment), &contents[..len])?;
+    }
+
+    // Priority lanes are queues of their own:
+    let mut lanes = vec![];
+    for maybe_entry in read_dir(base)? {
+        let path = maybe_entry?.path();
+        if let Some(priority) = lane_priority(&path) {
+            lanes.push((priority, path));
+        }
+    }
+    lanes.sort_unstable();
+
+    for (priority, path) in lanes {
+        let prefix = format!("{}priority-{}/", prefix, priority);
+        export_queue(&path, &prefix, writer, manifest)?;
+    }
+
+    Ok(())
+}
+
+/// The length of the part of a segment that was completely written, walking
+/// the headers of its elements. Returns `None` if a header is corrupted.
+fn complete_len(contents: &[u8]) -> Option<usize> {
+    let read_header = |position: usize| {
+        contents
+            .get(position..position + 4)
+            .map(|header| [header[0], header[1], header[2], header[3]])
+    };
+
+    let mut position = 0;
+    while let Some(header) = read_header(position) {
+        // Everything after the EOF (i.e., the footer) was written at once:
+        if header == HEADER_EOF {
+            return Some(contents.len());
+        }
+
+        let mut end = position + 4;
+        let mut data_header = header;
+
+        if header == HEADER_METADATA {
+            let metadata_header = match read_header(end) {
+                Some(metadata_header) => metadata_header,
+                None => break,
+            };
+            end += 4 + Header::try_decode(metadata_header)?.len() as usize;
+            data_header = match read_header(end) {
+                Some(data_header) => data_header,
+                None => break,
+            };
+            end += 4;
+        }
+
+        end += Header::try_decode(data_header)?.len() as usize;
+
+        if end > contents.len() {
+            break;
+        }
+
+        position = end;
+    }
+
+    Some(position)
+}
+
+/// Writes a file into a tar archive.
+fn write_entry<W: Write>(writer: &mut W, name: &str, contents: &[u8]) -> io::Result<()> {
+    let mut header = [0; BLOCK_SIZE];
+
+    if name.len() > 100 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("name `{}` is too long for the archive", name),
+        ));
+    } else if contents.len() as u64 >= 1 << 33 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file `{}` is too big for the archive", name),
+        ));
+    }
+
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", contents.len()).as_bytes());
+    header[136..148].copy_from_slice(format!("{:011o}\0", mtime & 0o777_7777_7777).as_bytes());
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with the checksum field filled with spaces:
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|&byte| byte as u32).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    writer.write_all(&header)?;
+    writer.write_all(contents)?;
+
+    let padding = (BLOCK_SIZE - contents.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    writer.write_all(&[0; BLOCK_SIZE][..padding])
+}
+
+/// Imports a queue from an archive written by [`export`] into the folder
+/// `base`, replacing the segments and the state of any queue already there.
+///
+/// The archive is first unpacked next to `base` (in a folder with the
+/// `.import` extension) and checked against its manifest. Only then is the
+/// queue in `base` locked for both sending and receiving and its files
+/// replaced, so that a corrupted archive never touches the existing queue.
+///
+/// # Errors
+///
+/// This function fails if the queue in `base` is in use either for sending or
+/// receiving. It also fails with an error of kind
+/// [`io::ErrorKind::InvalidData`] if the archive is corrupted or does not match
+/// its manifest. Any underlying errors encountered while reading the archive
+/// or while writing the queue are also returned.
+pub fn import<R: Read, P: AsRef<Path>>(mut reader: R, base: P) -> io::Result<()> {
+    let base = base.as_ref();
+    let staging = match base.file_name() {
+        Some(name) => base.with_file_name(format!("{}.import", name.to_string_lossy())),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot import a queue into {:?}", base),
+            ))
+        }
+    };
+
+    match remove_dir_all(&staging) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    create_dir_all(&staging)?;
+
+    let result = unpack(&mut reader, &staging).and_then(|names| install(&staging, base, &names));
+    remove_dir_all(&staging).ok();
+
+    result
+}
+
+/// An error for a corrupted archive.
+fn corrupted(message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted archive: {}", message),
+    )
+}
+
+/// Unpacks an archive into a folder, checking it against its manifest.
+/// Returns the names of the unpacked files.
+fn unpack<R: Read>(reader: &mut R, staging: &Path) -> io::Result<Vec<String>> {
+    let mut unpacked = vec![];
+    let mut manifest = None;
+
+    loop {
+        let mut header = [0; BLOCK_SIZE];
+        reader.read_exact(&mut header)?;
+
+        // The end of the archive:
+        if header == [0; BLOCK_SIZE] {
+            break;
+        }
+
+        let (name, size, is_dir) = parse_header(&header)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut contents = vec![];
+        let mut file = None;
+
+        if is_dir {
+            // Added if the archive was repacked with `tar`.
+        } else if name == MANIFEST_NAME {
+            contents.reserve(size as usize);
+        } else if is_queue_file(&name) {
+            let path = staging.join(&name);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            file = Some(File::create(path)?);
+        } else {
+            return Err(corrupted(format!("unexpected file `{}`", name)));
+        }
+
+        let mut buffer = [0; 8192];
+        let mut remaining = size.div_ceil(BLOCK_SIZE as u64) * BLOCK_SIZE as u64;
+        let mut data_left = size;
+
+        while remaining > 0 {
+            let chunk = &mut buffer[..u64::min(remaining, 8192) as usize];
+            reader.read_exact(chunk)?;
+            remaining -= chunk.len() as u64;
+
+            // Leave the padding out:
+            let data = &chunk[..u64::min(data_left, chunk.len() as u64) as usize];
+            data_left -= data.len() as u64;
+
+            hasher.update(data);
+            if let Some(file) = &mut file {
+                file.write_all(data)?;
+            } else {
+                contents.extend_from_slice(data);
+            }
+        }
+
+        if let Some(file) = file {
+            file.sync_all()?;
+            unpacked.push((name, size, hasher.finalize()));
+        } else if !is_dir {
+            manifest = Some(contents);
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| corrupted("no manifest".to_owned()))?;
+    let manifest = String::from_utf8(manifest)
+        .map_err(|_| corrupted("manifest is not valid UTF-8".to_owned()))?;
+
+    let mut expected = manifest.lines().collect::<Vec<_>>();
+    expected.sort_unstable();
+
+    let mut found = unpacked
+        .iter()
+        .map(|(name, size, checksum)| format!("{:08x} {} {}", checksum, size, name))
+        .collect::<Vec<_>>();
+    found.sort_unstable();
+
+    if expected != found {
+        return Err(corrupted("files do not match the manifest".to_owned()));
+    }
+
+    Ok(unpacked.into_iter().map(|(name, _, _)| name).collect())
+}
+
+/// Parses the header of a file in a tar archive, returning the name and the
+/// size of the file and whether it is a folder.
+fn parse_header(header: &[u8; BLOCK_SIZE]) -> io::Result<(String, u64, bool)> {
+    let octal = |field: &[u8]| {
+        std::str::from_utf8(field).ok().and_then(|field| {
+            u64::from_str_radix(field.trim_matches(|c| c == '\0' || c == ' '), 8).ok()
+        })
+    };
+
+    let checksum = header[..148]
+        .iter()
+        .chain(&[b' '; 8])
+        .chain(&header[156..])
+        .map(|&byte| byte as u64)
+        .sum::<u64>();
+
+    if octal(&header[148..156]) != Some(checksum) {
+        return Err(corrupted("bad header checksum".to_owned()));
+    }
+
+    let name_len = header[..100]
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(100);
+    let name = std::str::from_utf8(&header[..name_len])
+        .map_err(|_| corrupted("file name is not valid UTF-8".to_owned()))?;
+    let size =
+        octal(&header[124..136]).ok_or_else(|| corrupted(format!("bad size for `{}`", name)))?;
+
+    match header[156] {
+        b'0' | 0 => Ok((name.to_owned(), size, false)),
+        b'5' => Ok((name.to_owned(), size, true)),
+        _ => Err(corrupted(format!("`{}` is not a regular file", name))),
+    }
+}
+
+/// Whether a name in an archive is of a file that belongs to a queue (or to
+/// one of its priority lanes).
+fn is_queue_file(name: &str) -> bool {
+    let file = match name.split_once('/') {
+        Some((lane, file)) => {
+            let is_lane = lane
+                .strip_prefix("priority-")
+                .and_then(|priority| priority.parse::<u8>().ok())
+                .map(|priority| priority > 0)
+                .unwrap_or(false);
+
+            if !is_lane {
+                return false;
+            }
+
+            file
+        }
+        None => name,
+    };
+
+    file == "format"
+        || STATE_FILES.contains(&file)
+        || file
+            .strip_suffix(".q")
+            .map(|segment| segment.bytes().all(|byte| byte.is_ascii_digit()) && !segment.is_empty())
+            .unwrap_or(false)
+}
+
+/// Replaces the files of the queue in `base` by the unpacked ones.
+fn install(staging: &Path, base: &Path, names: &[String]) -> io::Result<()> {
+    create_dir_all(base)?;
+    let _send_lock = try_lock_side(base, "sender")?;
+    let _recv_lock = try_lock_side(base, "receiver")?;
+
+    // Remove the old queue, keeping the locks:
+    for maybe_entry in read_dir(base)? {
+        let path = maybe_entry?.path();
+        let name = path
+            .file_name()
+            .expect("entry has a name")
+            .to_string_lossy()
+            .into_owned();
+
+        if lane_priority(&path).is_some() {
+            remove_dir_all(path)?;
+        } else if is_queue_file(&name) {
+            remove_file(path)?;
+        }
+    }
+
+    for name in names {
+        let path = base.join(name);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(staging.join(name), path)?;
+    }
+
+    log::info!("imported {} files into queue {:?}", names.len(), base);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{Sender, SenderBuilder};
+
+    /// Reads the files in a tar archive.
+    fn read_archive(mut archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut files = vec![];
+
+        while archive[..BLOCK_SIZE] != [0; BLOCK_SIZE] {
+            let header = &archive[..BLOCK_SIZE];
+            let name_len = header[..100].iter().position(|&byte| byte == 0).unwrap();
+            let name = String::from_utf8(header[..name_len].to_vec()).unwrap();
+            let size = std::str::from_utf8(&header[124..135]).unwrap();
+            let size = usize::from_str_radix(size, 8).unwrap();
+
+            let checksum = std::str::from_utf8(&header[148..154]).unwrap();
+            let checksum = u32::from_str_radix(checksum, 8).unwrap();
+            let actual = header[..148].iter().map(|&byte| byte as u32).sum::<u32>()
+                + 8 * b' ' as u32
+                + header[156..].iter().map(|&byte| byte as u32).sum::<u32>();
+            assert_eq!(checksum, actual);
+
+            archive = &archive[BLOCK_SIZE..];
+            files.push((name, archive[..size].to_vec()));
+            archive = &archive[size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE..];
+        }
+
+        files
+    }
+
+    #[test]
+    fn test_export() {
+        // Two elements fill the first segment and the third goes to the next:
+        let mut sender = SenderBuilder::new()
+            .segment_size(16)
+            .open("data/export")
+            .unwrap();
+        for _ in 0..3 {
+            sender.try_send(b"0123456789").unwrap();
+        }
+        sender.try_send_with_priority(1, b"urgent").unwrap();
+
+        // A half-written element is left out while the sender is running:
+        let filename = segment_filename("data/export", 1);
+        let mut file = OpenOptions::new().append(true).open(&filename).unwrap();
+        file.write_all(&Header::new(10).encode()).unwrap();
+        file.write_all(b"01234").unwrap();
+        drop(file);
+
+        let mut archive = vec![];
+        export("data/export", &mut archive).unwrap();
+        let files = read_archive(&archive);
+
+        let names = files
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "format",
+                "settings",
+                "0.q",
+                "1.q",
+                "priority-1/format",
+                "priority-1/settings",
+                "priority-1/0.q",
+                "manifest"
+            ]
+        );
+        assert_eq!(files[3].1.len(), 14);
+
+        let manifest = String::from_utf8(files[7].1.clone()).unwrap();
+        assert_eq!(manifest.lines().count(), 7);
+        assert_eq!(
+            manifest.lines().nth(3).unwrap(),
+            format!("{:08x} 14 1.q", crc32fast::hash(&files[3].1))
+        );
+
+        // The receiver side must be free:
+        let receiver = crate::Receiver::open("data/export").unwrap();
+        assert!(export("data/export", &mut vec![]).is_err());
+        drop(receiver);
+    }
+
+    #[test]
+    fn test_import() {
+        let mut sender = SenderBuilder::new()
+            .segment_size(16)
+            .open("data/import-src")
+            .unwrap();
+        for i in 0..5u8 {
+            sender.try_send([i; 10]).unwrap();
+        }
+        sender.try_send_with_priority(1, b"urgent").unwrap();
+        drop(sender);
+
+        let mut receiver = crate::Receiver::open("data/import-src").unwrap();
+        receiver.try_recv().ok().unwrap().commit().unwrap();
+        drop(receiver);
+
+        // As if it was written before the segment footers:
+        std::fs::write("data/import-src/format", "1\n").unwrap();
+
+        let mut archive = vec![];
+        export("data/import-src", &mut archive).unwrap();
+
+        // Something to be replaced:
+        let mut sender = Sender::open("data/import-dst").unwrap();
+        sender.try_send(b"old").unwrap();
+        drop(sender);
+
+        import(&archive[..], "data/import-dst").unwrap();
+        assert!(!Path::new("data/import-dst.import").exists());
+
+        // The format of the queue is kept:
+        import(&archive[..], "data/import-fresh").unwrap();
+        drop(Sender::open("data/import-fresh").unwrap());
+        assert_eq!(
+            read_format_version(Path::new("data/import-fresh")).unwrap(),
+            1
+        );
+
+        let mut receiver = crate::Receiver::open("data/import-dst").unwrap();
+        let urgent = receiver.try_recv_prioritized().ok().unwrap();
+        assert_eq!(&*urgent, b"urgent");
+        urgent.commit().unwrap();
+        let batch = receiver.try_recv_batch(4).ok().unwrap();
+        assert_eq!(*batch, [[1; 10], [2; 10], [3; 10], [4; 10]]);
+        batch.commit().unwrap();
+
+        // Live queues are not overwritten:
+        assert!(import(&archive[..], "data/import-dst").is_err());
+        drop(receiver);
+
+        // Neither are queues by corrupted archives:
+        let position = archive
+            .windows(10)
+            .position(|window| window == [3; 10])
+            .unwrap();
+        archive[position] ^= 1;
+        let err = import(&archive[..], "data/import-dst").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(segment_filename("data/import-dst", 2).exists());
+        assert!(!segment_filename("data/import-dst", 1).exists());
+    }
+}
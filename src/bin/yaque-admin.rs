@@ -0,0 +1,172 @@
+//! Administration of `yaque` queues from the command line, enabled by the
+//! `admin` feature. Run `yaque-admin help` for the available commands.
+
+use std::env;
+use std::io;
+use std::path::Path;
+use std::process;
+
+use yaque::queue::{dump_queue, queue_disk_usage, queue_info, DumpFormat};
+use yaque::recovery::{self, RecvStrategy};
+
+const USAGE: &str = "\
+usage: yaque-admin <command> <queue folder> [options]
+
+commands:
+    info                       how much is waiting in the queue and its disk usage
+    verify                     checks the segments and the state of the queue
+    unlock                     removes the locks of dead senders and receivers
+    guess-metadata [strategy]  rebuilds the state of the receiver, where strategy
+                               is `oldest` (the default) or `send-position`
+    dump [format]              writes the elements waiting in the queue, where
+                               format is `json` (the default) or `hex`
+    purge                      discards everything waiting in the queue
+    help                       shows this message";
+
+/// What went wrong in a command.
+enum Failure {
+    /// The command line is wrong.
+    Usage(String),
+    /// The command failed.
+    Io(io::Error),
+    /// The command ran, but found problems.
+    Unhealthy,
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Failure {
+        Failure::Io(error)
+    }
+}
+
+fn info(base: &Path) -> Result<(), Failure> {
+    let info = queue_info(base)?;
+    let usage = queue_disk_usage(base)?;
+
+    println!("items waiting:  {}", info.items);
+    println!("bytes waiting:  {}", info.bytes);
+    println!("disk live:      {}", usage.live);
+    println!("disk consumed:  {}", usage.consumed);
+    println!("disk overhead:  {}", usage.overhead);
+    println!("disk total:     {}", usage.total());
+
+    Ok(())
+}
+
+fn verify(base: &Path) -> Result<(), Failure> {
+    let report = recovery::verify_queue(base)?;
+
+    println!("segments: {}", report.segments);
+    println!("items:    {}", report.items);
+
+    for problem in &report.problems {
+        println!("problem:  {:?}", problem);
+    }
+
+    if report.is_healthy() {
+        println!("the queue is healthy");
+        Ok(())
+    } else {
+        Err(Failure::Unhealthy)
+    }
+}
+
+fn unlock(base: &Path) -> Result<(), Failure> {
+    recovery::unlock_queue(base)?;
+    println!("queue unlocked");
+
+    Ok(())
+}
+
+fn guess_metadata(base: &Path, strategy: Option<&str>) -> Result<(), Failure> {
+    let strategy = match strategy {
+        None | Some("oldest") => RecvStrategy::Oldest,
+        Some("send-position") => RecvStrategy::SendPosition,
+        Some(other) => return Err(Failure::Usage(format!("unknown strategy `{}`", other))),
+    };
+
+    recovery::guess_recv_metadata_with_strategy(base, strategy)?;
+    println!("receiver state rebuilt with {:?}", strategy);
+
+    Ok(())
+}
+
+fn dump(base: &Path, format: Option<&str>) -> Result<(), Failure> {
+    let format = match format {
+        None | Some("json") => DumpFormat::Json,
+        Some("hex") => DumpFormat::Hex,
+        Some(other) => return Err(Failure::Usage(format!("unknown format `{}`", other))),
+    };
+
+    let stdout = io::stdout();
+    let dumped = dump_queue(base, format, io::BufWriter::new(stdout.lock()))?;
+    eprintln!("dumped {} items ({} bytes)", dumped.items, dumped.bytes);
+
+    Ok(())
+}
+
+fn purge(base: &Path) -> Result<(), Failure> {
+    let purged = yaque::blocking::Receiver::open(base)?.purge()?;
+    println!("purged {} items ({} bytes)", purged.items, purged.bytes);
+
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let command = args.first().map(String::as_str);
+
+    if command == Some("help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let base = match args.get(1) {
+        Some(base) => Path::new(base),
+        None => return Err(Failure::Usage("missing queue folder".to_owned())),
+    };
+
+    // Do not create queues by mistake:
+    if !base.is_dir() {
+        return Err(Failure::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no queue at {:?}", base),
+        )));
+    }
+
+    let options = &args[2..];
+
+    match (command, options.len()) {
+        (Some("info"), 0) => info(base),
+        (Some("verify"), 0) => verify(base),
+        (Some("unlock"), 0) => unlock(base),
+        (Some("guess-metadata"), 0) => guess_metadata(base, None),
+        (Some("guess-metadata"), 1) => guess_metadata(base, Some(&options[0])),
+        (Some("dump"), 0) => dump(base, None),
+        (Some("dump"), 1) => dump(base, Some(&options[0])),
+        (Some("purge"), 0) => purge(base),
+        (Some(command), _) => Err(Failure::Usage(format!(
+            "unknown command or wrong options: `{}`",
+            command
+        ))),
+        (None, _) => Err(Failure::Usage("missing command".to_owned())),
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    let code = match run(&args) {
+        Ok(()) => 0,
+        Err(Failure::Unhealthy) => 1,
+        Err(Failure::Io(error)) => {
+            eprintln!("error: {}", error);
+            1
+        }
+        Err(Failure::Usage(message)) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            2
+        }
+    };
+
+    process::exit(code);
+}
@@ -0,0 +1,246 @@
+//! Synchronous (blocking) mirrors of [`crate::Sender`] and [`crate::Receiver`],
+//! for programs without an asynchronous runtime. These are thin wrappers that
+//! block the current thread on the asynchronous implementation, so they use the
+//! same on-disk format and the same locks. Blocking and asynchronous senders
+//! and receivers can therefore be used on the same queue, even from different
+//! processes.
+//!
+//! To configure a queue, build the asynchronous version with
+//! [`crate::SenderBuilder`] or [`crate::ReceiverBuilder`] and convert it:
+//! ```ignore
+//! let sender = yaque::blocking::Sender::from(
+//!     SenderBuilder::new().segment_size(512).open("data/my-queue")?,
+//! );
+//! ```
+//!
+//! Do not use this module from inside an asynchronous context, since it will
+//! block the executor.
+
+use futures::executor::block_on;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::{TryRecvError, TrySendError};
+use crate::queue::{self, QueueInfo, RecvGuard};
+
+/// Convenience function for opening the queue for both sending and receiving.
+pub fn channel<P: AsRef<Path>>(base: P) -> io::Result<(Sender, Receiver)> {
+    Ok((Sender::open(base.as_ref())?, Receiver::open(base.as_ref())?))
+}
+
+/// The blocking sender part of the queue. See [`crate::Sender`].
+pub struct Sender {
+    inner: queue::Sender,
+}
+
+impl From<queue::Sender> for Sender {
+    fn from(inner: queue::Sender) -> Sender {
+        Sender { inner }
+    }
+}
+
+impl Sender {
+    /// Opens a queue on a folder indicated by the `base` path for sending. See
+    /// [`crate::Sender::open`].
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Sender> {
+        Ok(Sender::from(queue::Sender::open(base)?))
+    }
+
+    /// Gets the asynchronous sender back.
+    pub fn into_inner(self) -> queue::Sender {
+        self.inner
+    }
+
+    /// Sends some data into the queue, blocking while the queue is full. See
+    /// [`crate::Sender::send`].
+    pub fn send<D: AsRef<[u8]>>(&mut self, data: D) -> io::Result<()> {
+        block_on(self.inner.send(data))
+    }
+
+    /// Tries to send some data into the queue. This never blocks. See
+    /// [`crate::Sender::try_send`].
+    pub fn try_send<D: AsRef<[u8]>>(&mut self, data: D) -> Result<(), TrySendError<D>> {
+        self.inner.try_send(data)
+    }
+
+    /// Serializes a value and sends it into the queue, blocking while the queue
+    /// is full. See [`crate::Sender::send_serialized`].
+    #[cfg(feature = "serde")]
+    pub fn send_serialized<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        block_on(self.inner.send_serialized(value))
+    }
+
+    /// Sends all the contents of an iterable into the queue, blocking while the
+    /// queue is full. See [`crate::Sender::send_batch`].
+    pub fn send_batch<I>(&mut self, it: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        block_on(self.inner.send_batch(it))
+    }
+
+    /// Sends all the elements from an iterator into the queue, blocking while
+    /// the queue is full. Returns how many were sent. See
+    /// [`crate::Sender::send_all`].
+    pub fn send_all<I>(&mut self, it: I) -> io::Result<u64>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        block_on(self.inner.send_all(it))
+    }
+
+    /// Tries to send all the contents of an iterable into the queue. This never
+    /// blocks. See [`crate::Sender::try_send_batch`].
+    pub fn try_send_batch<I>(&mut self, it: I) -> Result<(), TrySendError<Vec<I::Item>>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        self.inner.try_send_batch(it)
+    }
+}
+
+/// The blocking receiver part of the queue. See [`crate::Receiver`].
+pub struct Receiver {
+    inner: queue::Receiver,
+}
+
+impl From<queue::Receiver> for Receiver {
+    fn from(inner: queue::Receiver) -> Receiver {
+        Receiver { inner }
+    }
+}
+
+impl Receiver {
+    /// Opens a queue on a folder indicated by the `base` path for receiving.
+    /// See [`crate::Receiver::open`].
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Receiver> {
+        Ok(Receiver::from(queue::Receiver::open(base)?))
+    }
+
+    /// Gets the asynchronous receiver back.
+    pub fn into_inner(self) -> queue::Receiver {
+        self.inner
+    }
+
+    /// Retrieves an element from the queue, blocking until there is one. See
+    /// [`crate::Receiver::recv`].
+    pub fn recv(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
+        block_on(self.inner.recv())
+    }
+
+    /// Tries to retrieve an element from the queue. This never blocks. See
+    /// [`crate::Receiver::try_recv`].
+    pub fn try_recv(&mut self) -> Result<RecvGuard<'_, Vec<u8>>, TryRecvError> {
+        self.inner.try_recv()
+    }
+
+    /// Retrieves an element from the queue, blocking for at most `timeout`.
+    /// If no element arrives in time, `Ok(None)` is returned. See
+    /// [`crate::Receiver::recv_timeout`].
+    pub fn recv_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<RecvGuard<'_, Vec<u8>>>> {
+        block_on(self.inner.recv_timeout_duration(timeout))
+    }
+
+    /// Removes a number of elements from the queue, blocking until all of them
+    /// are there. See [`crate::Receiver::recv_batch`].
+    pub fn recv_batch(&mut self, n: usize) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        block_on(self.inner.recv_batch(n))
+    }
+
+    /// Tries to remove a number of elements from the queue. This never blocks.
+    /// See [`crate::Receiver::try_recv_batch`].
+    pub fn try_recv_batch(
+        &mut self,
+        n: usize,
+    ) -> Result<RecvGuard<'_, Vec<Vec<u8>>>, TryRecvError> {
+        self.inner.try_recv_batch(n)
+    }
+
+    /// Removes at most `n` elements from the queue, blocking for at most
+    /// `timeout`. See [`crate::Receiver::recv_batch_timeout`].
+    pub fn recv_batch_timeout(
+        &mut self,
+        n: usize,
+        timeout: Duration,
+    ) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        block_on(self.inner.recv_batch_timeout_duration(n, timeout))
+    }
+
+    /// Removes at most `n` elements from the queue, blocking only until the
+    /// first one is there. See [`crate::Receiver::recv_batch_up_to`].
+    pub fn recv_batch_up_to(&mut self, n: usize) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        block_on(self.inner.recv_batch_up_to(n))
+    }
+
+    /// Discards everything waiting in the queue, returning how much was
+    /// discarded. See [`crate::Receiver::purge`].
+    pub fn purge(&mut self) -> io::Result<QueueInfo> {
+        block_on(self.inner.purge())
+    }
+
+    /// Drops all but the newest elements waiting in the queue, returning how
+    /// much was dropped. See [`crate::Receiver::keep_latest`].
+    pub fn keep_latest(
+        &mut self,
+        max_items: Option<u64>,
+        max_bytes: Option<u64>,
+    ) -> io::Result<QueueInfo> {
+        block_on(self.inner.keep_latest(max_items, max_bytes))
+    }
+
+    /// Blocks until at least `items` elements are waiting in the queue,
+    /// returning how much is waiting. See [`crate::Receiver::wait_for_depth`].
+    pub fn wait_for_depth(&self, items: u64) -> io::Result<QueueInfo> {
+        block_on(self.inner.wait_for_depth(items))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocking_channel() {
+        let (mut sender, mut receiver) = channel("data/blocking-channel").unwrap();
+
+        let consumer = std::thread::spawn(move || {
+            let mut received = vec![];
+
+            while received.len() < 100 {
+                let guard = receiver.recv_batch_up_to(10).unwrap();
+                received.extend(guard.try_into_inner().unwrap());
+            }
+
+            assert!(receiver
+                .recv_timeout(Duration::from_millis(100))
+                .unwrap()
+                .is_none());
+
+            received
+        });
+
+        let data = (0..100u8).map(|i| vec![i]).collect::<Vec<_>>();
+
+        for item in &data {
+            sender.send(item).unwrap();
+        }
+
+        assert_eq!(consumer.join().unwrap(), data);
+    }
+
+    #[test]
+    fn test_blocking_interoperates_with_async() {
+        let (mut sender, receiver) = crate::channel("data/blocking-interoperates").unwrap();
+        let mut receiver = Receiver::from(receiver);
+
+        block_on(sender.send(b"from async")).unwrap();
+        assert_eq!(&*receiver.recv().unwrap(), b"from async");
+    }
+}
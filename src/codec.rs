@@ -0,0 +1,115 @@
+//! Optional serialization of the items in the queue with serde, enabled by the
+//! `serde` feature. See [`WireFormat`].
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+
+/// How the values sent with [`crate::Sender::send_serialized`] are written
+/// into the queue and read back with [`crate::queue::RecvGuard::deserialize`].
+/// See [`crate::SenderBuilder::wire_format`] and
+/// [`crate::ReceiverBuilder::wire_format`].
+///
+/// The format is recorded in the settings of the queue by the first sender (see
+/// [`crate::SenderBuilder::open`]), so that later senders and receivers use it
+/// whatever they were built with. Serialized items are just items, so they can
+/// also be received as plain bytes (and the other way around).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// JSON, with `serde_json`. Human readable, which is handy with
+    /// [`crate::queue::dump_queue`], but the most verbose.
+    #[default]
+    Json,
+    /// Bincode, with the `bincode` feature. Compact and fast, but not
+    /// self-describing: the receiver has to expect the exact same type.
+    #[cfg(feature = "bincode")]
+    Bincode,
+    /// CBOR, with the `cbor` feature. Compact and self-describing.
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl WireFormat {
+    /// Serializes a value in this format.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the value cannot be serialized in this format.
+    pub fn serialize<T: Serialize + ?Sized>(self, value: &T) -> io::Result<Vec<u8>> {
+        let invalid_input = |err: String| io::Error::new(io::ErrorKind::InvalidInput, err);
+
+        match self {
+            WireFormat::Json => {
+                serde_json::to_vec(value).map_err(|err| invalid_input(err.to_string()))
+            }
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => {
+                bincode::serialize(value).map_err(|err| invalid_input(err.to_string()))
+            }
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(value, &mut data)
+                    .map_err(|err| invalid_input(err.to_string()))?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Deserializes a value from data in this format.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+    /// if the data is not a value of the given type in this format.
+    pub fn deserialize<T: DeserializeOwned>(self, data: &[u8]) -> io::Result<T> {
+        let invalid_data = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+
+        match self {
+            WireFormat::Json => {
+                serde_json::from_slice(data).map_err(|err| invalid_data(err.to_string()))
+            }
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode => {
+                bincode::deserialize(data).map_err(|err| invalid_data(err.to_string()))
+            }
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor => {
+                ciborium::from_reader(data).map_err(|err| invalid_data(err.to_string()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn formats() -> Vec<WireFormat> {
+        vec![
+            WireFormat::Json,
+            #[cfg(feature = "bincode")]
+            WireFormat::Bincode,
+            #[cfg(feature = "cbor")]
+            WireFormat::Cbor,
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let value: BTreeMap<String, Vec<u32>> =
+            vec![("a".to_owned(), vec![1, 2]), ("b".to_owned(), vec![])]
+                .into_iter()
+                .collect();
+
+        for format in formats() {
+            let data = format.serialize(&value).unwrap();
+            assert_eq!(format.deserialize::<BTreeMap<_, _>>(&data).unwrap(), value);
+
+            let err = format.deserialize::<String>(&[0xff; 3]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
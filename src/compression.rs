@@ -0,0 +1,95 @@
+//! Optional compression of the items in the queue. See [`Compression`].
+
+use std::io;
+
+/// The id of the LZ4 codec in the metadata of an item.
+const CODEC_LZ4: u8 = 1;
+
+/// A compression algorithm for the items in the queue, enabled by the
+/// `compression` feature. See [`crate::SenderBuilder::compression`].
+///
+/// Segments are not compressed as a whole, since the receiver follows a
+/// segment while it is still being written. Instead, each item is compressed
+/// on its own and marked as such in its metadata. Receivers decompress marked
+/// items transparently, whatever the configuration of the sender, so
+/// compressed and uncompressed items can live in the same queue. Items that
+/// would not get any smaller are stored uncompressed.
+///
+/// Receivers built without the `compression` feature (and older versions of
+/// `yaque`) fail with an error of kind [`io::ErrorKind::InvalidData`] when
+/// they find a compressed item.
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// The LZ4 algorithm, which is fast enough to be used on the hot path.
+    Lz4,
+}
+
+#[cfg(feature = "compression")]
+impl Compression {
+    /// The id of the codec in the metadata of an item.
+    pub(crate) fn codec(self) -> u8 {
+        match self {
+            Compression::Lz4 => CODEC_LZ4,
+        }
+    }
+
+    /// Compresses some data. Returns `None` if the data would not get any
+    /// smaller.
+    pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed = match self {
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        };
+
+        if compressed.len() < data.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decompresses some data compressed with a given codec.
+///
+/// # Errors
+///
+/// This function returns an error of kind [`io::ErrorKind::InvalidData`] if
+/// the codec is unknown (or not enabled) or if the data is corrupted.
+pub(crate) fn decompress(codec: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+    #[cfg(not(feature = "compression"))]
+    let _ = data;
+
+    match codec {
+        #[cfg(feature = "compression")]
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        #[cfg(not(feature = "compression"))]
+        CODEC_LZ4 => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "item compressed with lz4, but the `compression` feature is not enabled",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("item compressed with unknown codec {}", codec),
+        )),
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compress_decompress() {
+        let data = br#"{"hello": "world", "hello again": "world"}"#.repeat(20);
+        let compressed = Compression::Lz4.compress(&data).unwrap();
+
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(Compression::Lz4.codec(), &compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn incompressible() {
+        assert!(Compression::Lz4.compress(b"xyz").is_none());
+    }
+}
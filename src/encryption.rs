@@ -0,0 +1,144 @@
+//! Optional encryption of the items in the queue. See [`KeyProvider`].
+
+use std::io;
+
+use crate::metadata::Encryption;
+
+/// An AES-256 key.
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub type Key = [u8; 32];
+
+/// Provides the keys to encrypt and decrypt the items in the queue, enabled by
+/// the `encryption` feature. See [`crate::SenderBuilder::key_provider`] and
+/// [`crate::ReceiverBuilder::key_provider`].
+///
+/// Each item is encrypted on its own with AES-256-GCM, after compression (if
+/// any), with a random nonce. The id of the key and the nonce are stored in the
+/// metadata of the item, so that keys can be rotated: new items are encrypted
+/// with [`KeyProvider::current_key`], while older items can still be decrypted
+/// with [`KeyProvider::key`]. The headers of the items are not encrypted, so
+/// the number and the sizes of the items in the queue are not hidden.
+///
+/// Receivers fail with an error of kind [`io::ErrorKind::InvalidData`] when
+/// they find an encrypted item without a key provider or when the item cannot
+/// be decrypted (e.g., because the key is wrong).
+#[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+pub trait KeyProvider: Send + Sync {
+    /// The id and the value of the key to encrypt new items with.
+    fn current_key(&self) -> io::Result<(u32, Key)>;
+
+    /// The value of the key with a given id, to decrypt an item.
+    fn key(&self, id: u32) -> io::Result<Key>;
+}
+
+/// Encrypts some data with the current key of a key provider.
+#[cfg(feature = "encryption")]
+pub(crate) fn encrypt(
+    key_provider: &dyn KeyProvider,
+    data: &[u8],
+) -> io::Result<(Encryption, Vec<u8>)> {
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Nonce};
+
+    let (key_id, key) = key_provider.current_key()?;
+    let nonce: [u8; 12] = rand::random();
+    let encrypted = Aes256Gcm::new(&key.into())
+        .encrypt(Nonce::from_slice(&nonce), data)
+        .map_err(|_| io::Error::other("failed to encrypt item"))?;
+
+    Ok((Encryption { key_id, nonce }, encrypted))
+}
+
+/// Without the `encryption` feature, there can be no key provider to encrypt
+/// with.
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn encrypt(
+    key_provider: &dyn KeyProvider,
+    data: &[u8],
+) -> io::Result<(Encryption, Vec<u8>)> {
+    let _ = (key_provider, data);
+    Err(io::Error::other("the `encryption` feature is not enabled"))
+}
+
+/// Decrypts some data encrypted with a key of a key provider.
+///
+/// # Errors
+///
+/// This function returns an error of kind [`io::ErrorKind::InvalidData`] if
+/// there is no key provider (or if encryption is not enabled) or if the data
+/// cannot be decrypted.
+pub(crate) fn decrypt(
+    key_provider: Option<&dyn KeyProvider>,
+    encryption: &Encryption,
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    let key_provider = key_provider.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "item is encrypted, but there is no key provider",
+        )
+    })?;
+
+    #[cfg(feature = "encryption")]
+    {
+        use aes_gcm::aead::{Aead, KeyInit};
+        use aes_gcm::{Aes256Gcm, Nonce};
+
+        let key = key_provider.key(encryption.key_id)?;
+        Aes256Gcm::new(&key.into())
+            .decrypt(Nonce::from_slice(&encryption.nonce), data)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to decrypt item with key {}", encryption.key_id),
+                )
+            })
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    {
+        let _ = (key_provider, encryption, data);
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "item is encrypted, but the `encryption` feature is not enabled",
+        ))
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod test {
+    use super::*;
+
+    struct TestKeys;
+
+    impl KeyProvider for TestKeys {
+        fn current_key(&self) -> io::Result<(u32, Key)> {
+            Ok((2, [2; 32]))
+        }
+
+        fn key(&self, id: u32) -> io::Result<Key> {
+            Ok([id as u8; 32])
+        }
+    }
+
+    #[test]
+    fn encrypt_decrypt() {
+        let (encryption, encrypted) = encrypt(&TestKeys, b"secret").unwrap();
+
+        assert_eq!(encryption.key_id, 2);
+        assert_ne!(&encrypted[..6], b"secret");
+        assert_eq!(
+            decrypt(Some(&TestKeys), &encryption, &encrypted).unwrap(),
+            b"secret"
+        );
+    }
+
+    #[test]
+    fn decrypt_with_wrong_key() {
+        let (mut encryption, encrypted) = encrypt(&TestKeys, b"secret").unwrap();
+        encryption.key_id = 1;
+
+        assert!(decrypt(Some(&TestKeys), &encryption, &encrypted).is_err());
+        assert!(decrypt(None, &encryption, &encrypted).is_err());
+    }
+}
@@ -16,6 +16,15 @@ pub enum TrySendError<T> {
         /// The path for the queue where this problem happened.
         base: PathBuf,
     },
+    /// Sending would take the queue over its quota, as set in
+    /// `SenderBuilder::max_segments` and `SenderBuilder::max_total_bytes`.
+    /// Unlike a full queue, this is not waited upon.
+    QuotaExceeded {
+        /// The thing that we were trying to send.
+        item: T,
+        /// The path for the queue where this problem happened.
+        base: PathBuf,
+    },
 }
 
 impl<T> From<io::Error> for TrySendError<T> {
@@ -31,6 +40,9 @@ impl<T> fmt::Display for TrySendError<T> {
             TrySendError::QueueFull { base, .. } => {
                 write!(f, "the queue `{:?}` is full", base)
             }
+            TrySendError::QuotaExceeded { base, .. } => {
+                write!(f, "the queue `{:?}` is over its quota", base)
+            }
         }
     }
 }
@@ -47,10 +59,132 @@ impl<T> TrySendError<T> {
                 "was expecting TrySendError::Io; got TrySendError::QueueFull at queue `{:?}`",
                 base
             ),
+            TrySendError::QuotaExceeded { base, .. } => panic!(
+                "was expecting TrySendError::Io; got TrySendError::QuotaExceeded at queue `{:?}`",
+                base
+            ),
         }
     }
 }
 
+/// The error inside the IO error returned by an `async` send that would take
+/// the queue over its quota. See [`TrySendError::QuotaExceeded`].
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    /// The path for the queue where this problem happened.
+    pub base: PathBuf,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the queue `{:?}` is over its quota", self.base)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+impl From<QuotaExceeded> for io::Error {
+    fn from(error: QuotaExceeded) -> io::Error {
+        io::Error::other(error)
+    }
+}
+
+/// The error inside the IO error (of kind [`io::ErrorKind::InvalidInput`])
+/// returned when sending an element bigger than the limit set in
+/// `SenderBuilder::max_item_size` or than the `2 ^ 26 - 1` bytes an element
+/// can have in any case. Nothing is written in that case.
+#[derive(Debug)]
+pub struct ItemTooBig {
+    /// The path for the queue where this problem happened.
+    pub base: PathBuf,
+    /// The size of the element, as it would be written to the queue.
+    pub size: u64,
+    /// The largest element the queue accepts.
+    pub max_item_size: u64,
+}
+
+impl fmt::Display for ItemTooBig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "element of {} bytes is bigger than the {} bytes allowed in queue `{:?}`",
+            self.size, self.max_item_size, self.base
+        )
+    }
+}
+
+impl std::error::Error for ItemTooBig {}
+
+impl From<ItemTooBig> for io::Error {
+    fn from(error: ItemTooBig) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// The error inside the IO error (of kind [`io::ErrorKind::InvalidData`])
+/// returned when receiving an element whose checksum does not match, e.g.,
+/// because of bit rot or a torn write, or whose length is bigger than the
+/// queue allows. See `SenderBuilder::checksum` and
+/// `SenderBuilder::max_item_size`.
+///
+/// The receiver stays at the corrupted element, so receiving again fails
+/// again. Use `Receiver::seek` to skip it.
+#[derive(Debug)]
+pub struct Corruption {
+    /// The path for the queue where this problem happened.
+    pub base: PathBuf,
+    /// The segment of the corrupted element.
+    pub segment: u64,
+    /// The position of the corrupted element in its segment.
+    pub position: u64,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupted element in queue `{:?}` at segment {}, position {}",
+            self.base, self.segment, self.position
+        )
+    }
+}
+
+impl std::error::Error for Corruption {}
+
+impl From<Corruption> for io::Error {
+    fn from(error: Corruption) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// The error inside the IO error (of kind [`io::ErrorKind::InvalidData`])
+/// returned by the `recovery` module when a lock file cannot be parsed.
+#[derive(Debug)]
+pub struct MalformedLock {
+    /// The path of the lock file.
+    pub path: PathBuf,
+    /// The field of the lock file that is missing or cannot be parsed.
+    pub field: &'static str,
+}
+
+impl fmt::Display for MalformedLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed lock file `{:?}`: bad or missing {}",
+            self.path, self.field
+        )
+    }
+}
+
+impl std::error::Error for MalformedLock {}
+
+impl From<MalformedLock> for io::Error {
+    fn from(error: MalformedLock) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
 /// An error that occurs when trying to receive from an empty queue.
 pub enum TryRecvError {
     /// An underlying IO error occurred.
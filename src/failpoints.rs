@@ -0,0 +1,86 @@
+//! Fault injection, to test how an application copes with the failures of a
+//! queue (e.g., whether it gets everything back after a crash). Failpoints
+//! are set for a queue, by the path it was opened with, and make the queue
+//! fail at that point until they are removed. This module is only available
+//! with the `failpoints` feature, which is not meant for production builds.
+//! ```rust
+//! use yaque::failpoints::{self, Failpoint};
+//! use yaque::queue::Durability;
+//! use yaque::SenderBuilder;
+//!
+//! let mut sender = SenderBuilder::new()
+//!     .durability(Durability::EveryItem)
+//!     .open("data/my-failpoint")
+//!     .unwrap();
+//!
+//! failpoints::set("data/my-failpoint", Failpoint::Fsync);
+//! assert!(sender.try_send(b"some data").is_err());
+//!
+//! failpoints::remove("data/my-failpoint", Failpoint::Fsync);
+//! sender.try_send(b"some data").unwrap();
+//! ```
+
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The failpoints set, with the queue they are set for.
+    static ref FAILPOINTS: Mutex<HashSet<(PathBuf, Failpoint)>> = Mutex::default();
+}
+
+/// A point where a queue can be made to fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failpoint {
+    /// Syncing a segment to the disk fails (see [`crate::queue::Durability`] and
+    /// [`crate::Sender::sync`]). What was sent is still written to the segment,
+    /// but the sender returns an error.
+    Fsync,
+    /// A write to a segment is torn: the sender writes only the first half
+    /// of what it sends to the segment, drops the rest and returns an error,
+    /// as if the process crashed in the middle of the write. Drop the sender
+    /// and open the queue again afterwards, as after a crash.
+    PartialWrite,
+    /// Saving the state of the receiver fails, as if the receiver crashed
+    /// after deleting the segments it read through but before saving where it
+    /// is. This also fails committing when it is time to save and the save on
+    /// drop, so that the receiver starts over from the state saved last once
+    /// the queue is opened again.
+    StateSave,
+}
+
+/// Sets a failpoint for the queue on a folder, as given when opening it.
+pub fn set<P: AsRef<Path>>(base: P, failpoint: Failpoint) {
+    let mut failpoints = FAILPOINTS.lock().expect("poisoned");
+    failpoints.insert((base.as_ref().to_owned(), failpoint));
+}
+
+/// Removes a failpoint for the queue on a folder. Removing a failpoint that
+/// is not set does nothing.
+pub fn remove<P: AsRef<Path>>(base: P, failpoint: Failpoint) {
+    let mut failpoints = FAILPOINTS.lock().expect("poisoned");
+    failpoints.remove(&(base.as_ref().to_owned(), failpoint));
+}
+
+/// Removes all the failpoints for the queue on a folder.
+pub fn clear<P: AsRef<Path>>(base: P) {
+    let mut failpoints = FAILPOINTS.lock().expect("poisoned");
+    failpoints.retain(|(set_for, _)| set_for != base.as_ref());
+}
+
+/// Fails if a failpoint is set for the queue on a folder.
+pub(crate) fn check(base: &Path, failpoint: Failpoint) -> io::Result<()> {
+    let failpoints = FAILPOINTS.lock().expect("poisoned");
+
+    if failpoints.contains(&(base.to_owned(), failpoint)) {
+        log::debug!("failpoint {:?} hit in {:?}", failpoint, base);
+        Err(io::Error::other(format!(
+            "failpoint {:?} hit in queue {:?}",
+            failpoint, base
+        )))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+//! C bindings, for non-Rust programs to use the same queues as Rust ones (and
+//! with the same locks, so both can use the same queue at once).
+//!
+//! These block the calling thread on the asynchronous implementation, just
+//! like [`crate::blocking`]. Every function returns one of the `YAQUE_*` codes
+//! and, on error, leaves a message to be read with [`yaque_last_error`]. A
+//! received element stays pending (and its data stays valid) until it is
+//! committed or rolled back, and only one element may be pending at a time.
+//!
+//! The header for these is in `include/yaque.h`, generated from this module
+//! with [cbindgen](https://github.com/mozilla/cbindgen):
+//! ```text
+//! cbindgen --config cbindgen.toml --output include/yaque.h
+//! ```
+//! To link against the crate from C, build it as either kind of library:
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! cargo rustc --release --features ffi --crate-type staticlib
+//! ```
+
+use futures::executor::block_on;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+
+use crate::error::{TryRecvError, TrySendError};
+use crate::queue;
+
+/// The call succeeded.
+pub const YAQUE_OK: c_int = 0;
+/// A pointer was null or a path was not valid UTF-8.
+pub const YAQUE_INVALID_ARGUMENT: c_int = 1;
+/// An underlying IO error occurred (including the queue being in use).
+pub const YAQUE_IO_ERROR: c_int = 2;
+/// The queue is empty (or nothing arrived before the timeout).
+pub const YAQUE_QUEUE_EMPTY: c_int = 3;
+/// The queue is full.
+pub const YAQUE_QUEUE_FULL: c_int = 4;
+/// Sending would take the queue over its quota.
+pub const YAQUE_QUOTA_EXCEEDED: c_int = 5;
+/// There is no pending element to commit or roll back.
+pub const YAQUE_NOT_PENDING: c_int = 6;
+/// An element is still pending: commit it or roll it back first.
+pub const YAQUE_STILL_PENDING: c_int = 7;
+/// The library panicked. The handle should not be used anymore.
+pub const YAQUE_PANIC: c_int = 8;
+
+thread_local! {
+    /// The message of the last error on this thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Why a call failed.
+enum Failure {
+    InvalidArgument(&'static str),
+    Io(io::Error),
+    QueueEmpty,
+    QueueFull,
+    QuotaExceeded,
+    NotPending,
+    StillPending,
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Failure {
+        Failure::Io(err)
+    }
+}
+
+impl Failure {
+    fn code(&self) -> c_int {
+        match self {
+            Failure::InvalidArgument(_) => YAQUE_INVALID_ARGUMENT,
+            Failure::Io(_) => YAQUE_IO_ERROR,
+            Failure::QueueEmpty => YAQUE_QUEUE_EMPTY,
+            Failure::QueueFull => YAQUE_QUEUE_FULL,
+            Failure::QuotaExceeded => YAQUE_QUOTA_EXCEEDED,
+            Failure::NotPending => YAQUE_NOT_PENDING,
+            Failure::StillPending => YAQUE_STILL_PENDING,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Failure::InvalidArgument(what) => format!("invalid argument: {}", what),
+            Failure::Io(err) => err.to_string(),
+            Failure::QueueEmpty => "the queue is empty".to_owned(),
+            Failure::QueueFull => "the queue is full".to_owned(),
+            Failure::QuotaExceeded => "sending would exceed the quota of the queue".to_owned(),
+            Failure::NotPending => "no element is pending".to_owned(),
+            Failure::StillPending => "an element is still pending".to_owned(),
+        }
+    }
+}
+
+fn set_last_error(message: String) {
+    // (messages with NULs are cut short)
+    let message = CString::new(message).unwrap_or_else(|err| {
+        let end = err.nul_position();
+        CString::new(&err.into_vec()[..end]).expect("no NUL before the first")
+    });
+
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Runs the body of a binding, turning its outcome (or panic) into a code.
+fn guarded<F: FnOnce() -> Result<(), Failure>>(body: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => YAQUE_OK,
+        Ok(Err(failure)) => {
+            set_last_error(failure.message());
+            failure.code()
+        }
+        Err(_) => {
+            set_last_error("the library panicked".to_owned());
+            YAQUE_PANIC
+        }
+    }
+}
+
+/// Reads a path given by C.
+unsafe fn path<'a>(base: *const c_char) -> Result<&'a str, Failure> {
+    if base.is_null() {
+        return Err(Failure::InvalidArgument("null path"));
+    }
+
+    CStr::from_ptr(base)
+        .to_str()
+        .map_err(|_| Failure::InvalidArgument("path is not valid UTF-8"))
+}
+
+/// Reads data given by C. Null data is fine if it is empty.
+unsafe fn data<'a>(data: *const u8, len: usize) -> Result<&'a [u8], Failure> {
+    if len == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(Failure::InvalidArgument("null data"))
+    } else {
+        Ok(slice::from_raw_parts(data, len))
+    }
+}
+
+/// Gets a handle given by C.
+unsafe fn handle<'a, T>(handle: *mut T) -> Result<&'a mut T, Failure> {
+    handle
+        .as_mut()
+        .ok_or(Failure::InvalidArgument("null handle"))
+}
+
+/// The sending side of a queue, opened with [`yaque_sender_open`].
+pub struct YaqueSender {
+    inner: queue::Sender,
+}
+
+/// The receiving side of a queue, opened with [`yaque_receiver_open`].
+pub struct YaqueReceiver {
+    inner: queue::Receiver,
+    /// The element received but not yet committed or rolled back, if any.
+    pending: Option<Vec<u8>>,
+}
+
+impl YaqueReceiver {
+    /// Hands a received element over to C, until it is committed or rolled
+    /// back.
+    unsafe fn hand_over(
+        &mut self,
+        item: Vec<u8>,
+        data: *mut *const u8,
+        len: *mut usize,
+    ) -> Result<(), Failure> {
+        let item = self.pending.insert(item);
+        *data = item.as_ptr();
+        *len = item.len();
+
+        Ok(())
+    }
+
+    fn check_not_pending(&self) -> Result<(), Failure> {
+        if self.pending.is_some() {
+            Err(Failure::StillPending)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Gets the message of the last error on the calling thread, or null if there
+/// was none. The message is valid until the next failed call on the thread.
+#[no_mangle]
+pub extern "C" fn yaque_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map(|message| message.as_ptr())
+            .unwrap_or(ptr::null())
+    })
+}
+
+/// Opens a queue at the directory `base` for sending, writing the handle to
+/// `sender`. See [`crate::Sender::open`].
+///
+/// # Safety
+///
+/// `base` must be a valid C string and `sender` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_sender_open(
+    base: *const c_char,
+    sender: *mut *mut YaqueSender,
+) -> c_int {
+    guarded(|| {
+        if sender.is_null() {
+            return Err(Failure::InvalidArgument("null handle"));
+        }
+
+        let inner = queue::Sender::open(path(base)?)?;
+        *sender = Box::into_raw(Box::new(YaqueSender { inner }));
+
+        Ok(())
+    })
+}
+
+/// Sends `len` bytes from `data` into the queue, blocking while the queue is
+/// full. See [`crate::Sender::send`].
+///
+/// # Safety
+///
+/// `sender` must come from [`yaque_sender_open`] and `data` must be valid for
+/// `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_send(
+    sender: *mut YaqueSender,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guarded(|| {
+        let sender = handle(sender)?;
+        block_on(sender.inner.send(self::data(data, len)?))?;
+
+        Ok(())
+    })
+}
+
+/// Tries to send `len` bytes from `data` into the queue, returning
+/// `YAQUE_QUEUE_FULL` instead of blocking. See [`crate::Sender::try_send`].
+///
+/// # Safety
+///
+/// `sender` must come from [`yaque_sender_open`] and `data` must be valid for
+/// `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_try_send(
+    sender: *mut YaqueSender,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guarded(|| {
+        let sender = handle(sender)?;
+
+        match sender.inner.try_send(self::data(data, len)?) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Io(err)) => Err(Failure::Io(err)),
+            Err(TrySendError::QueueFull { .. }) => Err(Failure::QueueFull),
+            Err(TrySendError::QuotaExceeded { .. }) => Err(Failure::QuotaExceeded),
+        }
+    })
+}
+
+/// Closes the sending side of a queue, saving its state. Closing null does
+/// nothing.
+///
+/// # Safety
+///
+/// `sender` must come from [`yaque_sender_open`] (or be null) and must not be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_sender_close(sender: *mut YaqueSender) {
+    if !sender.is_null() {
+        guarded(|| {
+            drop(Box::from_raw(sender));
+            Ok(())
+        });
+    }
+}
+
+/// Opens a queue at the directory `base` for receiving, writing the handle to
+/// `receiver`. See [`crate::Receiver::open`].
+///
+/// # Safety
+///
+/// `base` must be a valid C string and `receiver` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_receiver_open(
+    base: *const c_char,
+    receiver: *mut *mut YaqueReceiver,
+) -> c_int {
+    guarded(|| {
+        if receiver.is_null() {
+            return Err(Failure::InvalidArgument("null handle"));
+        }
+
+        let inner = queue::Receiver::open(path(base)?)?;
+        *receiver = Box::into_raw(Box::new(YaqueReceiver {
+            inner,
+            pending: None,
+        }));
+
+        Ok(())
+    })
+}
+
+/// Receives an element from the queue, blocking until there is one. Its data
+/// and length are written to `data` and `len`, and stay valid until the
+/// element is committed or rolled back. See [`crate::Receiver::recv`].
+///
+/// # Safety
+///
+/// `receiver` must come from [`yaque_receiver_open`] and `data` and `len` must
+/// be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_recv(
+    receiver: *mut YaqueReceiver,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    guarded(|| {
+        let receiver = handle(receiver)?;
+        receiver.check_not_pending()?;
+
+        let item = block_on(receiver.inner.recv())?.detach();
+        receiver.hand_over(item, data, len)
+    })
+}
+
+/// Tries to receive an element from the queue, returning `YAQUE_QUEUE_EMPTY`
+/// instead of blocking. See [`yaque_recv`].
+///
+/// # Safety
+///
+/// Same as [`yaque_recv`].
+#[no_mangle]
+pub unsafe extern "C" fn yaque_try_recv(
+    receiver: *mut YaqueReceiver,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    guarded(|| {
+        let receiver = handle(receiver)?;
+        receiver.check_not_pending()?;
+
+        let item = match receiver.inner.try_recv() {
+            Ok(guard) => guard.detach(),
+            Err(TryRecvError::Io(err)) => return Err(Failure::Io(err)),
+            Err(TryRecvError::QueueEmpty) => return Err(Failure::QueueEmpty),
+        };
+        receiver.hand_over(item, data, len)
+    })
+}
+
+/// Receives an element from the queue, blocking for at most `timeout_ms`
+/// milliseconds. If nothing arrives in time, this returns
+/// `YAQUE_QUEUE_EMPTY`. See [`yaque_recv`].
+///
+/// # Safety
+///
+/// Same as [`yaque_recv`].
+#[no_mangle]
+pub unsafe extern "C" fn yaque_recv_timeout(
+    receiver: *mut YaqueReceiver,
+    timeout_ms: u64,
+    data: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    guarded(|| {
+        let receiver = handle(receiver)?;
+        receiver.check_not_pending()?;
+
+        let timeout = Duration::from_millis(timeout_ms);
+        let item = match block_on(receiver.inner.recv_timeout_duration(timeout))? {
+            Some(guard) => guard.detach(),
+            None => return Err(Failure::QueueEmpty),
+        };
+        receiver.hand_over(item, data, len)
+    })
+}
+
+/// Commits the pending element, removing it from the queue for good. Its
+/// data is no longer valid afterwards. See [`crate::queue::RecvGuard::commit`].
+///
+/// # Safety
+///
+/// `receiver` must come from [`yaque_receiver_open`].
+#[no_mangle]
+pub unsafe extern "C" fn yaque_commit(receiver: *mut YaqueReceiver) -> c_int {
+    guarded(|| {
+        let receiver = handle(receiver)?;
+        if receiver.pending.is_none() {
+            return Err(Failure::NotPending);
+        }
+
+        receiver.inner.end()?;
+        receiver.pending = None;
+
+        Ok(())
+    })
+}
+
+/// Rolls the pending element back, so that it is received again. Its data is
+/// no longer valid afterwards. See [`crate::queue::RecvGuard::rollback`].
+///
+/// # Safety
+///
+/// `receiver` must come from [`yaque_receiver_open`].
+#[no_mangle]
+pub unsafe extern "C" fn yaque_rollback(receiver: *mut YaqueReceiver) -> c_int {
+    guarded(|| {
+        let receiver = handle(receiver)?;
+        if receiver.pending.take().is_none() {
+            return Err(Failure::NotPending);
+        }
+
+        receiver.inner.rollback()?;
+
+        Ok(())
+    })
+}
+
+/// Closes the receiving side of a queue, rolling back the pending element (if
+/// any) and saving its state. Closing null does nothing.
+///
+/// # Safety
+///
+/// `receiver` must come from [`yaque_receiver_open`] (or be null) and must not
+/// be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn yaque_receiver_close(receiver: *mut YaqueReceiver) {
+    if !receiver.is_null() {
+        guarded(|| {
+            let mut receiver = Box::from_raw(receiver);
+            if receiver.pending.take().is_some() {
+                receiver.inner.rollback()?;
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(base: &str) -> (*mut YaqueSender, *mut YaqueReceiver) {
+        let _ = std::fs::remove_dir_all(base);
+        let base = CString::new(base).unwrap();
+        let mut sender = ptr::null_mut();
+        let mut receiver = ptr::null_mut();
+
+        unsafe {
+            assert_eq!(yaque_sender_open(base.as_ptr(), &mut sender), YAQUE_OK);
+            assert_eq!(yaque_receiver_open(base.as_ptr(), &mut receiver), YAQUE_OK);
+        }
+
+        (sender, receiver)
+    }
+
+    #[test]
+    fn send_recv_commit() {
+        let (sender, receiver) = open("data/ffi-send-recv-commit");
+        let (mut data, mut len) = (ptr::null(), 0);
+
+        unsafe {
+            assert_eq!(yaque_send(sender, b"hello".as_ptr(), 5), YAQUE_OK);
+            assert_eq!(yaque_try_send(sender, b"world".as_ptr(), 5), YAQUE_OK);
+
+            assert_eq!(yaque_recv(receiver, &mut data, &mut len), YAQUE_OK);
+            assert_eq!(slice::from_raw_parts(data, len), b"hello");
+            assert_eq!(
+                yaque_try_recv(receiver, &mut data, &mut len),
+                YAQUE_STILL_PENDING
+            );
+
+            // Rolled back elements are received again:
+            assert_eq!(yaque_rollback(receiver), YAQUE_OK);
+            assert_eq!(yaque_try_recv(receiver, &mut data, &mut len), YAQUE_OK);
+            assert_eq!(slice::from_raw_parts(data, len), b"hello");
+            assert_eq!(yaque_commit(receiver), YAQUE_OK);
+            assert_eq!(yaque_commit(receiver), YAQUE_NOT_PENDING);
+
+            assert_eq!(
+                yaque_recv_timeout(receiver, 10, &mut data, &mut len),
+                YAQUE_OK
+            );
+            assert_eq!(slice::from_raw_parts(data, len), b"world");
+            assert_eq!(yaque_commit(receiver), YAQUE_OK);
+
+            assert_eq!(
+                yaque_try_recv(receiver, &mut data, &mut len),
+                YAQUE_QUEUE_EMPTY
+            );
+            assert_eq!(
+                yaque_recv_timeout(receiver, 10, &mut data, &mut len),
+                YAQUE_QUEUE_EMPTY
+            );
+
+            yaque_sender_close(sender);
+            yaque_receiver_close(receiver);
+        }
+    }
+
+    #[test]
+    fn report_errors() {
+        let (sender, receiver) = open("data/ffi-report-errors");
+        let base = CString::new("data/ffi-report-errors").unwrap();
+        let mut other = ptr::null_mut();
+
+        unsafe {
+            // The queue is in use:
+            assert_eq!(yaque_sender_open(base.as_ptr(), &mut other), YAQUE_IO_ERROR);
+            assert!(other.is_null());
+
+            let message = CStr::from_ptr(yaque_last_error()).to_str().unwrap();
+            assert!(message.contains("already in use"), "{}", message);
+
+            assert_eq!(
+                yaque_send(ptr::null_mut(), ptr::null(), 0),
+                YAQUE_INVALID_ARGUMENT
+            );
+            assert_eq!(yaque_send(sender, ptr::null(), 1), YAQUE_INVALID_ARGUMENT);
+
+            yaque_sender_close(sender);
+            yaque_receiver_close(receiver);
+        }
+    }
+}
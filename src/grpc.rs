@@ -0,0 +1,567 @@
+//! A gRPC front-end, so that clients on other hosts, written in any language,
+//! can use queues without reimplementing their format. This module is only
+//! available with the `grpc` feature.
+//!
+//! A [`Broker`] fronts a set of queues, each in its own folder and known to
+//! the clients by a name. The service is described in `proto/yaque.proto`,
+//! which is all a client needs:
+//!
+//! * `Push` sends items into a queue, atomically, like
+//!   [`Sender::send_batch`].
+//! * `Pull` takes at most `max_items` items from a queue, waiting up to
+//!   `timeout_ms` for the first one, under a _lease_. Until the lease is
+//!   acknowledged with `Ack`, nothing more can be pulled from the queue. A
+//!   lease that is not acknowledged within the lease timeout (see
+//!   [`Broker::lease_timeout`]) is rolled back on the next pull, so that the
+//!   items are pulled again.
+//! * `Ack` commits the items pulled under a lease or, with `rollback`, rolls
+//!   them back.
+//! * `Stats` tells how much is waiting in a queue, like [`Receiver::info`].
+//!
+//! ```rust,no_run
+//! use yaque::grpc::Broker;
+//!
+//! # async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut broker = Broker::new();
+//! broker.add_queue("orders", "data/orders")?;
+//! broker.add_queue("invoices", "data/invoices")?;
+//! broker.serve("0.0.0.0:50051".parse()?).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The broker holds the sender and the receiver of each of its queues for as
+//! long as it runs; nothing else can send into or receive from them (see
+//! [`crate::queue::Durability`] for how much is lost if the broker crashes).
+//! Errors of the queues are returned as the gRPC status `INTERNAL`, unknown
+//! queues as `NOT_FOUND`.
+
+use futures::future::{self, Either};
+use futures::lock::Mutex;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Service, StdError};
+use tonic::{Code, Status};
+
+use crate::queue::is_valid_name;
+use crate::timer::Delay;
+use crate::{Receiver, Sender};
+
+/// The requests and responses of the service, as in `proto/yaque.proto`.
+pub mod proto {
+    /// See `Broker.Push`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PushRequest {
+        #[prost(string, tag = "1")]
+        pub queue: String,
+        #[prost(bytes = "vec", repeated, tag = "2")]
+        pub items: Vec<Vec<u8>>,
+    }
+
+    /// See `Broker.Push`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PushResponse {}
+
+    /// See `Broker.Pull`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PullRequest {
+        #[prost(string, tag = "1")]
+        pub queue: String,
+        #[prost(uint32, tag = "2")]
+        pub max_items: u32,
+        #[prost(uint32, tag = "3")]
+        pub timeout_ms: u32,
+    }
+
+    /// See `Broker.Pull`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PullResponse {
+        #[prost(bytes = "vec", repeated, tag = "1")]
+        pub items: Vec<Vec<u8>>,
+        #[prost(uint64, tag = "2")]
+        pub lease: u64,
+    }
+
+    /// See `Broker.Ack`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AckRequest {
+        #[prost(string, tag = "1")]
+        pub queue: String,
+        #[prost(uint64, tag = "2")]
+        pub lease: u64,
+        #[prost(bool, tag = "3")]
+        pub rollback: bool,
+    }
+
+    /// See `Broker.Ack`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AckResponse {}
+
+    /// See `Broker.Stats`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsRequest {
+        #[prost(string, tag = "1")]
+        pub queue: String,
+    }
+
+    /// See `Broker.Stats`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatsResponse {
+        #[prost(uint64, tag = "1")]
+        pub items: u64,
+        #[prost(uint64, tag = "2")]
+        pub bytes: u64,
+        #[prost(uint64, tag = "3")]
+        pub pulled: u64,
+    }
+}
+
+use proto::*;
+
+/// The name of the service, as in `proto/yaque.proto`.
+const SERVICE_NAME: &str = "yaque.Broker";
+
+/// The source of the ids of the leases, which are never `0`.
+static NEXT_LEASE: AtomicU64 = AtomicU64::new(1);
+
+/// Items pulled from a queue but not acknowledged yet.
+struct Lease {
+    id: u64,
+    items: u64,
+    expires_at: Instant,
+}
+
+/// The receiving side of a queue of the broker.
+struct Pulling {
+    receiver: Receiver,
+    lease: Option<Lease>,
+}
+
+/// A queue of the broker.
+struct BrokerQueue {
+    sender: Mutex<Sender>,
+    pulling: Mutex<Pulling>,
+}
+
+/// Fronts a set of queues with a gRPC service. See the [module
+/// documentation](self).
+pub struct Broker {
+    queues: BTreeMap<String, BrokerQueue>,
+    lease_timeout: Duration,
+}
+
+impl Default for Broker {
+    fn default() -> Broker {
+        Broker {
+            queues: BTreeMap::new(),
+            lease_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Broker {
+    /// Creates a broker without any queue.
+    pub fn new() -> Broker {
+        Broker::default()
+    }
+
+    /// Sets for how long the items pulled from a queue are leased to the
+    /// client. Once the lease expires, the next pull from the queue rolls the
+    /// items back and pulls them again.
+    ///
+    /// Default value: 30 seconds
+    pub fn lease_timeout(mut self, lease_timeout: Duration) -> Broker {
+        self.lease_timeout = lease_timeout;
+        self
+    }
+
+    /// Opens the queue in a folder, for the clients to use by a name. The name
+    /// may only contain ASCII letters, digits, `-` and `_`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the name is not valid and of kind [`io::ErrorKind::AlreadyExists`]
+    /// if the broker already has a queue by that name. Also, it returns any
+    /// underlying errors encountered while opening the queue.
+    pub fn add_queue<P: AsRef<Path>>(&mut self, name: &str, base: P) -> io::Result<()> {
+        if !is_valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a valid queue name", name),
+            ));
+        } else if self.queues.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("the broker already has a queue named {:?}", name),
+            ));
+        }
+
+        let (sender, receiver) = crate::channel(base)?;
+        self.queues.insert(
+            name.to_owned(),
+            BrokerQueue {
+                sender: Mutex::new(sender),
+                pulling: Mutex::new(Pulling {
+                    receiver,
+                    lease: None,
+                }),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Turns the broker into a service, to be served along with others by a
+    /// [`tonic::transport::Server`].
+    pub fn into_service(self) -> BrokerService {
+        BrokerService {
+            broker: Arc::new(self),
+        }
+    }
+
+    /// Serves the broker on an address, until an error occurs.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any errors of the transport.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+        tonic::transport::Server::builder()
+            .add_service(self.into_service())
+            .serve(addr)
+            .await
+    }
+
+    // (statuses are what the calls return anyway)
+    #[allow(clippy::result_large_err)]
+    fn queue(&self, name: &str) -> Result<&BrokerQueue, Status> {
+        self.queues
+            .get(name)
+            .ok_or_else(|| Status::not_found(format!("no queue named {:?}", name)))
+    }
+
+    async fn push(&self, request: PushRequest) -> Result<PushResponse, Status> {
+        let queue = self.queue(&request.queue)?;
+        let mut sender = queue.sender.lock().await;
+        sender.send_batch(&request.items).await.map_err(status)?;
+
+        Ok(PushResponse {})
+    }
+
+    async fn pull(&self, request: PullRequest) -> Result<PullResponse, Status> {
+        let queue = self.queue(&request.queue)?;
+        let mut pulling = queue.pulling.lock().await;
+
+        if let Some(lease) = &pulling.lease {
+            if lease.expires_at > Instant::now() {
+                return Err(Status::failed_precondition(format!(
+                    "the items pulled from {:?} under lease {} are not acknowledged yet",
+                    request.queue, lease.id
+                )));
+            }
+
+            log::debug!("lease {} expired; rolling back", lease.id);
+            pulling.receiver.rollback().map_err(status)?;
+            pulling.lease = None;
+        }
+
+        let max_items = request.max_items.max(1) as usize;
+        let timeout = Delay::new(Duration::from_millis(request.timeout_ms.into()));
+        let recv = Box::pin(pulling.receiver.recv_batch_up_to(max_items));
+        let items = match future::select(recv, timeout).await {
+            Either::Left((guard, _)) => guard.map_err(status)?.detach(),
+            Either::Right(_) => vec![],
+        };
+
+        if items.is_empty() {
+            return Ok(PullResponse { items, lease: 0 });
+        }
+
+        let id = NEXT_LEASE.fetch_add(1, Ordering::Relaxed);
+        pulling.lease = Some(Lease {
+            id,
+            items: items.len() as u64,
+            expires_at: Instant::now() + self.lease_timeout,
+        });
+
+        Ok(PullResponse { items, lease: id })
+    }
+
+    async fn ack(&self, request: AckRequest) -> Result<AckResponse, Status> {
+        let queue = self.queue(&request.queue)?;
+        let mut pulling = queue.pulling.lock().await;
+
+        match &pulling.lease {
+            Some(lease) if lease.id == request.lease => {}
+            _ => {
+                return Err(Status::failed_precondition(format!(
+                    "{:?} has no lease {} (it may have expired)",
+                    request.queue, request.lease
+                )))
+            }
+        }
+
+        pulling.lease = None;
+        if request.rollback {
+            pulling.receiver.rollback().map_err(status)?;
+        } else {
+            pulling.receiver.end().map_err(status)?;
+        }
+
+        Ok(AckResponse {})
+    }
+
+    async fn stats(&self, request: StatsRequest) -> Result<StatsResponse, Status> {
+        let queue = self.queue(&request.queue)?;
+        let pulling = queue.pulling.lock().await;
+        let info = pulling.receiver.info().map_err(status)?;
+
+        Ok(StatsResponse {
+            items: info.items,
+            bytes: info.bytes,
+            pulled: pulling.lease.as_ref().map_or(0, |lease| lease.items),
+        })
+    }
+}
+
+fn status(err: io::Error) -> Status {
+    match err.kind() {
+        io::ErrorKind::InvalidInput => Status::invalid_argument(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+/// A [`Broker`] as a gRPC service, as returned by [`Broker::into_service`].
+#[derive(Clone)]
+pub struct BrokerService {
+    broker: Arc<Broker>,
+}
+
+impl tonic::server::NamedService for BrokerService {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+/// One of the calls of the service, handled by the broker.
+struct Call(Arc<Broker>);
+
+macro_rules! unary_call {
+    ($request:ty, $response:ty, $method:ident) => {
+        impl tonic::server::UnaryService<$request> for Call {
+            type Response = $response;
+            type Future = BoxFuture<tonic::Response<$response>, Status>;
+
+            fn call(&mut self, request: tonic::Request<$request>) -> Self::Future {
+                let broker = self.0.clone();
+                Box::pin(async move {
+                    let response = broker.$method(request.into_inner()).await?;
+                    Ok(tonic::Response::new(response))
+                })
+            }
+        }
+    };
+}
+
+unary_call!(PushRequest, PushResponse, push);
+unary_call!(PullRequest, PullResponse, pull);
+unary_call!(AckRequest, AckResponse, ack);
+unary_call!(StatsRequest, StatsResponse, stats);
+
+impl<B> Service<http::Request<B>> for BrokerService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let call = Call(self.broker.clone());
+        let method = request
+            .uri()
+            .path()
+            .strip_prefix('/')
+            .and_then(|path| path.strip_prefix(SERVICE_NAME))
+            .and_then(|path| path.strip_prefix('/'));
+
+        match method {
+            Some("Push") => unary::<PushRequest, PushResponse, B>(call, request),
+            Some("Pull") => unary::<PullRequest, PullResponse, B>(call, request),
+            Some("Ack") => unary::<AckRequest, AckResponse, B>(call, request),
+            Some("Stats") => unary::<StatsRequest, StatsResponse, B>(call, request),
+            _ => Box::pin(async move {
+                let response = http::Response::builder()
+                    .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                    .header(http::header::CONTENT_TYPE, "application/grpc")
+                    .body(empty_body())
+                    .expect("valid response");
+                Ok(response)
+            }),
+        }
+    }
+}
+
+/// Handles a unary call of the service.
+fn unary<Req, Res, B>(
+    call: Call,
+    request: http::Request<B>,
+) -> BoxFuture<http::Response<tonic::body::BoxBody>, Infallible>
+where
+    Call: tonic::server::UnaryService<
+        Req,
+        Response = Res,
+        Future = BoxFuture<tonic::Response<Res>, Status>,
+    >,
+    Req: prost::Message + Default + Send + 'static,
+    Res: prost::Message + Send + 'static,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    Box::pin(async move {
+        let codec = tonic::codec::ProstCodec::<Res, Req>::default();
+        Ok(tonic::server::Grpc::new(codec).unary(call, request).await)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tonic::transport::Channel;
+
+    async fn call<Req, Res>(
+        channel: &Channel,
+        path: &'static str,
+        request: Req,
+    ) -> Result<Res, Status>
+    where
+        Req: prost::Message + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        let mut grpc = tonic::client::Grpc::new(channel.clone());
+        grpc.ready()
+            .await
+            .map_err(|err| Status::unknown(err.to_string()))?;
+        let codec = tonic::codec::ProstCodec::<Req, Res>::default();
+        let path = http::uri::PathAndQuery::from_static(path);
+        let response = grpc
+            .unary(tonic::Request::new(request), path, codec)
+            .await?;
+
+        Ok(response.into_inner())
+    }
+
+    fn pull(max_items: u32, timeout_ms: u32) -> PullRequest {
+        PullRequest {
+            queue: "jobs".to_owned(),
+            max_items,
+            timeout_ms,
+        }
+    }
+
+    fn ack(lease: u64, rollback: bool) -> AckRequest {
+        AckRequest {
+            queue: "jobs".to_owned(),
+            lease,
+            rollback,
+        }
+    }
+
+    #[test]
+    fn push_pull_ack() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut broker = Broker::new();
+        broker.add_queue("jobs", "data/grpc-jobs").unwrap();
+        assert!(broker.add_queue("jobs", "data/grpc-other").is_err());
+        assert!(broker.add_queue("../jobs", "data/grpc-other").is_err());
+
+        runtime.block_on(async move {
+            tokio::spawn(broker.serve(addr));
+            let channel = loop {
+                let endpoint = Channel::from_shared(format!("http://{}", addr)).unwrap();
+                match endpoint.connect().await {
+                    Ok(channel) => break channel,
+                    Err(_) => Delay::new(Duration::from_millis(10)).await,
+                }
+            };
+
+            let push = PushRequest {
+                queue: "jobs".to_owned(),
+                items: vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()],
+            };
+            let _: PushResponse = call(&channel, "/yaque.Broker/Push", push).await.unwrap();
+
+            let stats = StatsRequest {
+                queue: "jobs".to_owned(),
+            };
+            let got: StatsResponse = call(&channel, "/yaque.Broker/Stats", stats.clone())
+                .await
+                .unwrap();
+            assert_eq!(got.items, 3);
+
+            let got: PullResponse = call(&channel, "/yaque.Broker/Pull", pull(2, 0))
+                .await
+                .unwrap();
+            assert_eq!(got.items, vec![b"a".to_vec(), b"b".to_vec()]);
+            assert_ne!(got.lease, 0);
+
+            // Nothing more until the lease is acknowledged:
+            let err = call::<_, PullResponse>(&channel, "/yaque.Broker/Pull", pull(2, 0))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::FailedPrecondition);
+            let pulled: StatsResponse = call(&channel, "/yaque.Broker/Stats", stats.clone())
+                .await
+                .unwrap();
+            assert_eq!(pulled.pulled, 2);
+
+            let _: AckResponse = call(&channel, "/yaque.Broker/Ack", ack(got.lease, true))
+                .await
+                .unwrap();
+            let err = call::<_, AckResponse>(&channel, "/yaque.Broker/Ack", ack(got.lease, false))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::FailedPrecondition);
+
+            let got: PullResponse = call(&channel, "/yaque.Broker/Pull", pull(5, 0))
+                .await
+                .unwrap();
+            assert_eq!(got.items, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+            let _: AckResponse = call(&channel, "/yaque.Broker/Ack", ack(got.lease, false))
+                .await
+                .unwrap();
+
+            let got: PullResponse = call(&channel, "/yaque.Broker/Pull", pull(5, 10))
+                .await
+                .unwrap();
+            assert!(got.items.is_empty());
+            assert_eq!(got.lease, 0);
+
+            let unknown = StatsRequest {
+                queue: "nope".to_owned(),
+            };
+            let err = call::<_, StatsResponse>(&channel, "/yaque.Broker/Stats", unknown)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::NotFound);
+        });
+    }
+}
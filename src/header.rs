@@ -27,6 +27,10 @@ const P4: u32 = 0b11_1111_1100_0000_0111_1111_0000;
 /// Hamming fifth parity bit mask.
 const P5: u32 = 0b11_1111_1111_1111_1000_0000_0000;
 
+/// The largest length a header can hold: the last 6 bits are for parities,
+/// leaving 26 bits (about 67MB).
+pub const MAX_LEN: u32 = 0x03_FF_FF_FF;
+
 /// A structure holding all the header info.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Header {
@@ -38,11 +42,7 @@ impl Header {
     /// Creates a new [`Header`] from header info (just the length by now).
     pub fn new(len: u32) -> Header {
         // last 6bits clean or 26bit available ~= 67MB:
-        assert!(
-            len == len & 0x03_FF_FF_FFu32,
-            "length too big: {} > 2 ^ 26",
-            len
-        );
+        assert!(len <= MAX_LEN, "length too big: {} > 2 ^ 26", len);
 
         Header { len }
     }
@@ -67,6 +67,20 @@ impl Header {
         u32::to_be_bytes(encoded)
     }
 
+    /// Decodes a header from the actual bits, returning `None` instead of
+    /// panicking if the header is corrupted. Use this only when corruption is
+    /// expected and can be handled.
+    pub fn try_decode(header: [u8; 4]) -> Option<Header> {
+        let decoded = Header::new(u32::from_be_bytes(header) & 0x03_FF_FF_FFu32);
+
+        // All parities are right iff the header encodes back to the same bits:
+        if decoded.encode() == header {
+            Some(decoded)
+        } else {
+            None
+        }
+    }
+
     /// Decodes a header from the actual bits.
     pub fn decode(header: [u8; 4]) -> Header {
         let encoded = u32::from_be_bytes(header);
@@ -132,6 +146,7 @@ impl Header {
 }
 
 #[cfg(test)]
+#[allow(clippy::unused_enumerate_index)]
 mod test {
     use super::*;
 
@@ -151,6 +166,15 @@ mod test {
         }
     }
 
+    #[test]
+    fn try_decode_gibberish() {
+        assert_eq!(Header::try_decode(*b"Asbt"), None);
+        assert_eq!(
+            Header::try_decode(Header::new(1234).encode()),
+            Some(Header::new(1234))
+        );
+    }
+
     #[test]
     #[should_panic]
     fn encode_gibberish() {
@@ -0,0 +1,446 @@
+//! A tiny HTTP API to inspect and fix queues from afar, as the `yaque-admin`
+//! command does from the command line. This module is only available with the
+//! `http-admin` feature.
+//!
+//! An [`AdminApi`] knows a set of queues by name and answers, in JSON:
+//!
+//! * `GET /queues`: the names of the queues.
+//! * `GET /queues/<name>`: how much is waiting in the queue, its disk usage,
+//!   its consumer groups and its topics.
+//! * `GET /queues/<name>/verify`: the report of [`recovery::verify_queue`].
+//! * `POST /queues/<name>/purge`: discards everything waiting in the queue,
+//!   like [`crate::Receiver::purge`].
+//! * `POST /queues/<name>/unlock`: removes the locks of dead senders and
+//!   receivers, like [`recovery::unlock_queue`].
+//!
+//! It can serve these by itself on a listener of its own (see
+//! [`AdminApi::serve`]) or be mounted in the HTTP server of the application,
+//! by handing it the requests under some prefix (see [`AdminApi::handle`]).
+//! ```rust,no_run
+//! use std::net::TcpListener;
+//! use yaque::http_admin::AdminApi;
+//!
+//! let mut api = AdminApi::new();
+//! api.add_queue("orders", "data/orders").unwrap();
+//!
+//! let listener = TcpListener::bind("127.0.0.1:9090").unwrap();
+//! std::thread::spawn(move || api.serve(&listener).unwrap());
+//! ```
+//!
+//! There is no authentication whatsoever: do not expose the API to untrusted
+//! networks. Use [`AdminApi::read_only`] to disable the actions.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+
+use crate::queue::{
+    consumer_groups, is_valid_name, queue_disk_usage, queue_info, topics, write_json_string,
+};
+use crate::recovery;
+
+/// The longest line accepted in a request.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// The answer to a request, always a JSON body. See [`AdminApi::handle`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdminResponse {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The JSON body.
+    pub body: String,
+}
+
+impl AdminResponse {
+    fn new(status: u16, body: Vec<u8>) -> AdminResponse {
+        AdminResponse {
+            status,
+            body: String::from_utf8(body).expect("JSON is UTF-8"),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> AdminResponse {
+        let mut body = b"{\"error\":".to_vec();
+        write_json_string(&mut body, message).expect("writing to a vector");
+        body.push(b'}');
+
+        AdminResponse::new(status, body)
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl From<io::Error> for AdminResponse {
+    fn from(err: io::Error) -> AdminResponse {
+        let status = match err.kind() {
+            io::ErrorKind::NotFound => 404,
+            io::ErrorKind::InvalidInput => 400,
+            _ => 500,
+        };
+
+        AdminResponse::error(status, &err.to_string())
+    }
+}
+
+/// Serves the administration of a set of queues over HTTP. See the [module
+/// documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct AdminApi {
+    queues: BTreeMap<String, PathBuf>,
+    read_only: bool,
+}
+
+impl AdminApi {
+    /// Creates an API without any queue.
+    pub fn new() -> AdminApi {
+        AdminApi::default()
+    }
+
+    /// Whether to refuse the actions (purging and unlocking), with the status
+    /// `403 Forbidden`, and only allow inspecting the queues.
+    ///
+    /// Default value: false
+    pub fn read_only(mut self, read_only: bool) -> AdminApi {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Makes the queue in a folder available by a name, which may only contain
+    /// ASCII letters, digits, `-` and `_`. The queue is not opened: it is only
+    /// read when asked about.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the name is not valid and of kind [`io::ErrorKind::AlreadyExists`]
+    /// if there already is a queue by that name.
+    pub fn add_queue<P: Into<PathBuf>>(&mut self, name: &str, base: P) -> io::Result<()> {
+        if !is_valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a valid queue name", name),
+            ));
+        } else if self.queues.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("there already is a queue named {:?}", name),
+            ));
+        }
+
+        self.queues.insert(name.to_owned(), base.into());
+
+        Ok(())
+    }
+
+    /// Answers a request, given its method and its path (without the prefix
+    /// the API is mounted at, if any). The query string, if any, is ignored.
+    /// This is the way to mount the API in the HTTP server of the application.
+    pub fn handle(&self, method: &str, path: &str) -> AdminResponse {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+
+        match segments.as_slice() {
+            ["queues"] => self.with_method(method, "GET", || self.list()),
+            ["queues", name] => self.on_queue(name, method, "GET", stats),
+            ["queues", name, "verify"] => self.on_queue(name, method, "GET", verify),
+            ["queues", name, "purge"] => self.on_action(name, method, purge),
+            ["queues", name, "unlock"] => self.on_action(name, method, unlock),
+            _ => AdminResponse::error(404, "no such endpoint"),
+        }
+    }
+
+    fn with_method<F>(&self, method: &str, expected: &str, f: F) -> AdminResponse
+    where
+        F: FnOnce() -> io::Result<Vec<u8>>,
+    {
+        if method != expected {
+            return AdminResponse::error(405, &format!("use {}", expected));
+        }
+
+        match f() {
+            Ok(body) => AdminResponse::new(200, body),
+            Err(err) => err.into(),
+        }
+    }
+
+    fn on_queue<F>(&self, name: &str, method: &str, expected: &str, f: F) -> AdminResponse
+    where
+        F: FnOnce(&Path) -> io::Result<Vec<u8>>,
+    {
+        let base = match self.queues.get(name) {
+            Some(base) => base,
+            None => return AdminResponse::error(404, &format!("no queue named {:?}", name)),
+        };
+
+        // Do not create queues by mistake:
+        if !base.is_dir() {
+            return AdminResponse::error(404, &format!("no queue at {:?}", base));
+        }
+
+        self.with_method(method, expected, || f(base))
+    }
+
+    fn on_action<F>(&self, name: &str, method: &str, f: F) -> AdminResponse
+    where
+        F: FnOnce(&Path) -> io::Result<Vec<u8>>,
+    {
+        if self.read_only {
+            return AdminResponse::error(403, "the API is read-only");
+        }
+
+        self.on_queue(name, method, "POST", f)
+    }
+
+    fn list(&self) -> io::Result<Vec<u8>> {
+        let mut body = b"{\"queues\":".to_vec();
+        write_json_strings(&mut body, self.queues.keys())?;
+        body.push(b'}');
+
+        Ok(body)
+    }
+
+    /// Serves the API on a listener, one connection at a time, forever. Errors
+    /// on a connection are logged and the next connection is served. This only
+    /// returns if accepting a connection fails.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while accepting
+    /// a connection.
+    pub fn serve(&self, listener: &TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            if let Err(err) = self.handle_connection(stream?) {
+                log::warn!("failed to serve an admin request: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers the request on a connection and closes it. Requests with a body
+    /// have it ignored.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while reading
+    /// the request or writing the response.
+    pub fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let request_line = read_line(&mut reader)?;
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method, path),
+            _ => return write_response(stream, &AdminResponse::error(400, "bad request line")),
+        };
+
+        // Skips the headers, and then the body, if any:
+        let mut content_len = 0;
+        loop {
+            let line = read_line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_len = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        io::copy(&mut reader.take(content_len), &mut io::sink())?;
+
+        log::debug!("admin request: {} {}", method, path);
+        write_response(stream, &self.handle(method, path))
+    }
+}
+
+/// Reads a line of a request, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE_LEN).read_line(&mut line)?;
+
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long or incomplete",
+        ));
+    }
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn write_response(mut stream: TcpStream, response: &AdminResponse) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.body.len(),
+        response.body
+    )?;
+
+    stream.flush()
+}
+
+fn write_json_strings<I, S>(body: &mut Vec<u8>, strings: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    body.push(b'[');
+    for (i, string) in strings.into_iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        write_json_string(body, string.as_ref())?;
+    }
+    body.push(b']');
+
+    Ok(())
+}
+
+fn stats(base: &Path) -> io::Result<Vec<u8>> {
+    let info = queue_info(base)?;
+    let usage = queue_disk_usage(base)?;
+
+    let mut body = Vec::new();
+    write!(
+        body,
+        "{{\"items\":{},\"bytes\":{},\"disk\":{{\"live\":{},\"consumed\":{},\"overhead\":{},\
+         \"total\":{}}},\"consumer_groups\":",
+        info.items,
+        info.bytes,
+        usage.live,
+        usage.consumed,
+        usage.overhead,
+        usage.total()
+    )?;
+    write_json_strings(&mut body, consumer_groups(base)?)?;
+    write!(body, ",\"topics\":")?;
+    write_json_strings(&mut body, topics(base)?)?;
+    body.push(b'}');
+
+    Ok(body)
+}
+
+fn verify(base: &Path) -> io::Result<Vec<u8>> {
+    let report = recovery::verify_queue(base)?;
+
+    let mut body = Vec::new();
+    write!(
+        body,
+        "{{\"healthy\":{},\"segments\":{},\"items\":{},\"problems\":",
+        report.is_healthy(),
+        report.segments,
+        report.items
+    )?;
+    let problems = report
+        .problems
+        .iter()
+        .map(|problem| format!("{:?}", problem));
+    write_json_strings(&mut body, problems)?;
+    body.push(b'}');
+
+    Ok(body)
+}
+
+fn purge(base: &Path) -> io::Result<Vec<u8>> {
+    let purged = crate::blocking::Receiver::open(base)?.purge()?;
+
+    let mut body = Vec::new();
+    write!(
+        body,
+        "{{\"purged\":{{\"items\":{},\"bytes\":{}}}}}",
+        purged.items, purged.bytes
+    )?;
+
+    Ok(body)
+}
+
+fn unlock(base: &Path) -> io::Result<Vec<u8>> {
+    recovery::unlock_queue(base)?;
+
+    Ok(b"{\"unlocked\":true}".to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inspect_and_purge() {
+        let mut sender = crate::Sender::open("data/http-admin").unwrap();
+        sender.try_send(b"stuck").unwrap();
+        sender.try_send(b"stuck too").unwrap();
+        drop(sender);
+
+        let mut api = AdminApi::new();
+        api.add_queue("stuck", "data/http-admin").unwrap();
+        api.add_queue("missing", "data/http-admin-missing").unwrap();
+        assert!(api.add_queue("stuck", "data/http-admin").is_err());
+
+        let list = api.handle("GET", "/queues");
+        assert_eq!(list.body, r#"{"queues":["missing","stuck"]}"#);
+
+        let stats = api.handle("GET", "/queues/stuck?pretty");
+        assert_eq!(stats.status, 200);
+        assert!(stats.body.starts_with(r#"{"items":2,"#));
+
+        let report = api.handle("GET", "/queues/stuck/verify");
+        assert!(report.body.starts_with(r#"{"healthy":true,"#));
+
+        assert_eq!(api.handle("GET", "/queues/missing").status, 404);
+        assert_eq!(api.handle("GET", "/queues/nope").status, 404);
+        assert_eq!(api.handle("GET", "/queues/stuck/purge").status, 405);
+        assert_eq!(
+            api.clone()
+                .read_only(true)
+                .handle("POST", "/queues/stuck/purge")
+                .status,
+            403
+        );
+
+        let purged = api.handle("POST", "/queues/stuck/purge");
+        assert!(purged.body.starts_with(r#"{"purged":{"items":2,"#));
+        assert!(api
+            .handle("GET", "/queues/stuck")
+            .body
+            .starts_with(r#"{"items":0,"#));
+    }
+
+    #[test]
+    fn serve_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            AdminApi::new().handle_connection(stream).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /queues HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"queues\":[]}"));
+    }
+}
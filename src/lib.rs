@@ -4,11 +4,11 @@
 //! implements an SPSC channel using your OS' filesystem. Its main advantages
 //! over a simple `VecDeque<T>` are that
 //! * You are not constrained by your RAM size, just by your disk size. This
-//! means you can store gigabytes of data without getting OOM killed.
+//!   means you can store gigabytes of data without getting OOM killed.
 //! * Your data is safe even if you program panics. All the queue state is
-//! written to the disk when the queue is dropped.
+//!   written to the disk when the queue is dropped.
 //! * Your data can *persist*, that is, can exist through multiple executions
-//! of your program. Think of it as a very rudimentary kind of database.
+//!   of your program. Think of it as a very rudimentary kind of database.
 //! * You can pass data between two processes.
 //!
 //! Yaque is _asynchronous_ and built directly on top of `mio` and `notify`.
@@ -78,6 +78,10 @@
 //! rolling back, you may call [`queue::RecvGuard::rollback`] which _will_ return the
 //! underlying error.
 //!
+//! If you need to receive many times and then commit everything at once, use
+//! [`Receiver::transaction`]. The returned [`queue::RecvTransaction`] behaves
+//! just like a guard for everything it receives.
+//!
 //! ## Batches
 //!
 //! You can use the `yaque` queue to send and receive batches of data ,
@@ -124,6 +128,13 @@
 //! });
 //! ```
 //!
+//! ## No async runtime? Use the blocking API
+//!
+//! The [`blocking`] module has a [`blocking::Sender`] and a
+//! [`blocking::Receiver`] that block the current thread instead of returning
+//! futures. They share the on-disk format and the locks with their
+//! asynchronous counterparts, so both can be used on the same queue.
+//!
 //! ## `Ctrl+C` and other unexpected events
 //!
 //! First of all, "Don't panic©"! Writing to the queue is an atomic operation.
@@ -157,8 +168,8 @@
 //! use cases:
 //!
 //! 1. Recover with replay (the standard): we can reconstruct a _lower bound_
-//! of the actual state of the queue during the crash, which consists of the
-//! _maximum_ of the following two positions:
+//!    of the actual state of the queue during the crash, which consists of the
+//!    _maximum_ of the following two positions:
 //!     * the bottom of the smallest segment still present in the directory.
 //!     * the position indicated in the metadata file.
 //!
@@ -167,12 +178,12 @@
 //! whatsoever.
 //!
 //! 2. Recover with loss: we can also reconstruct an _upper bound_ for the
-//! actual state of the queue: the bottom of the second smallest segment in
-//! the queue. In this case, the smallest segment is simply erased and the
-//! receiver caries on as if nothing has happened. If replays are intollerable,
-//! but some data loss is, this might be the right alternative for you. You can
-//! limit data loss by constraining the segment size, configuring this option on
-//! [`SenderBuilder`].
+//!    actual state of the queue: the bottom of the second smallest segment in
+//!    the queue. In this case, the smallest segment is simply erased and the
+//!    receiver caries on as if nothing has happened. If replays are intollerable,
+//!    but some data loss is, this might be the right alternative for you. You can
+//!    limit data loss by constraining the segment size, configuring this option on
+//!    [`SenderBuilder`].
 //!
 //! If you really want to err on the safer side, you may use [`Receiver::save`]
 //! to periodically back the receiver state up. Just choose you favorite timer
@@ -183,27 +194,70 @@
 //! ## Known issues and next steps
 //!
 //! * ~~This is a brand new project. Although I have tested it and it will
-//! certainly not implode your computer, don't trust your life on it yet.~~ This code
-//! is running in production for non-critical applications.
+//!   certainly not implode your computer, don't trust your life on it yet.~~ This code
+//!   is running in production for non-critical applications.
 //! * Wastes too much kernel time when the queue is small enough and the sender
-//! sends many frequent small messages non-atomically. You can mitigate that by
-//! writing in batches to the queue.
+//!   sends many frequent small messages non-atomically. You can mitigate that by
+//!   writing in batches to the queue.
 //! * There are probably unknown bugs hidden in some corner case. If you find
-//! one, please [fill an issue on GitHub](https://github.com/tokahuke/yaque/issues/new).
-//! Pull requests and contributions are also greatly appreciated.
+//!   one, please [fill an issue on GitHub](https://github.com/tokahuke/yaque/issues/new).
+//!   Pull requests and contributions are also greatly appreciated.
 //!
 
+mod backend;
+#[cfg(feature = "serde")]
+mod codec;
+mod compression;
+mod encryption;
 mod error;
 mod header;
+mod metadata;
+mod segment_file;
+mod sharing;
 mod state;
 mod sync;
+mod telemetry;
+mod timer;
+mod transform;
 mod version;
 mod watcher;
 
+pub mod backup;
+pub mod blocking;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(all(feature = "http-admin", not(target_os = "wasi")))]
+pub mod http_admin;
+pub mod mem;
 pub mod mutex;
 pub mod queue;
-#[cfg(feature = "recovery")]
+#[cfg(all(feature = "recovery", not(target_os = "wasi")))]
 pub mod recovery;
+#[cfg(feature = "replication")]
+pub mod replication;
+pub mod storage;
 
-pub use error::{TryRecvError, TrySendError};
-pub use queue::{channel, Receiver, ReceiverBuilder, Sender, SenderBuilder, QueueIter};
+pub use backend::{QueueGuard, QueueReceiver, QueueSender};
+#[cfg(feature = "serde")]
+pub use codec::WireFormat;
+#[cfg(feature = "compression")]
+pub use compression::Compression;
+#[cfg(feature = "encryption")]
+pub use encryption::{Key, KeyProvider};
+pub use error::{
+    Corruption, ItemTooBig, MalformedLock, QuotaExceeded, TryRecvError, TrySendError,
+};
+pub use queue::{
+    channel, ChannelBuilder, QueueIter, Receiver, ReceiverBuilder, Replay, Sender, SenderBuilder,
+};
+#[cfg(unix)]
+pub use sharing::FilePermissions;
+#[cfg(feature = "metrics")]
+pub use telemetry::MetricsExporter;
+pub use telemetry::QueueMetrics;
+pub use transform::Codec;
+pub use version::FORMAT_VERSION;
@@ -0,0 +1,422 @@
+//! Queues in memory, with the API of the queues on disk, for the tests of
+//! code that uses `yaque`: no temporary folders to clean up and no syncing to
+//! wait for. See also [`crate::QueueSender`] and [`crate::QueueReceiver`] to
+//! write code that works with both.
+//!
+//! Queues are identified by their paths, just as on disk, but nothing is ever
+//! written there. They live as long as the process (or until cleared with
+//! [`try_clear`]), so that a queue can be closed and opened again. As on disk,
+//! each queue has at most one sender and one receiver at a time, and
+//! receiving is transactional. Unlike on disk, queues never get full.
+//! ```rust
+//! use yaque::mem::channel;
+//!
+//! futures::executor::block_on(async {
+//!     let (mut sender, mut receiver) = channel("my-queue-in-memory").unwrap();
+//!
+//!     sender.send(b"some data").await.unwrap();
+//!
+//!     let data = receiver.recv().await.unwrap();
+//!     assert_eq!(&*data, b"some data");
+//!     data.commit().unwrap();
+//! });
+//! ```
+
+use futures::future::{self, Either};
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Poll, Waker};
+
+use crate::error::{TryRecvError, TrySendError};
+
+lazy_static! {
+    /// All the queues in memory, by path.
+    static ref QUEUES: Mutex<HashMap<PathBuf, Arc<Queue>>> = Mutex::default();
+}
+
+/// A queue in memory.
+#[derive(Default)]
+struct Queue {
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<Vec<u8>>,
+    /// The receiver waiting for items, if any.
+    waker: Option<Waker>,
+    has_sender: bool,
+    has_receiver: bool,
+}
+
+impl Queue {
+    /// Gets the queue at a given path, creating it if it does not exist yet.
+    fn get(base: &Path) -> Arc<Queue> {
+        lock(&QUEUES).entry(base.to_owned()).or_default().clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        lock(&self.state)
+    }
+}
+
+/// Locks a mutex, ignoring poisoning: the state of the queues is always
+/// consistent between calls.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Convenience function for opening the queue for both sending and receiving.
+pub fn channel<P: AsRef<Path>>(base: P) -> io::Result<(Sender, Receiver)> {
+    Ok((Sender::open(base.as_ref())?, Receiver::open(base.as_ref())?))
+}
+
+/// Tries to deletes a queue at the given path. This function will fail if the
+/// queue is in use either for sending or receiving.
+pub fn try_clear<P: AsRef<Path>>(base: P) -> io::Result<()> {
+    let mut queues = lock(&QUEUES);
+
+    if let Some(queue) = queues.get(base.as_ref()) {
+        let state = queue.state();
+        if state.has_sender || state.has_receiver {
+            return Err(io::Error::other(format!(
+                "queue `{}` is in use",
+                base.as_ref().to_string_lossy()
+            )));
+        }
+    }
+
+    queues.remove(base.as_ref());
+
+    Ok(())
+}
+
+/// The sender part of a queue in memory. See [`crate::Sender`].
+pub struct Sender {
+    queue: Arc<Queue>,
+}
+
+impl Sender {
+    /// Opens a queue in memory for sending, creating it if it does not exist
+    /// yet.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the queue is already in use for
+    /// sending.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Sender> {
+        let queue = Queue::get(base.as_ref());
+
+        {
+            let mut state = queue.state();
+            if state.has_sender {
+                return Err(io::Error::other(format!(
+                    "queue `{}` sender side already in use",
+                    base.as_ref().to_string_lossy()
+                )));
+            }
+            state.has_sender = true;
+        }
+
+        Ok(Sender { queue })
+    }
+
+    /// Sends some data into the queue. This never waits, but it is `async`
+    /// for the sake of the API of [`crate::Sender::send`].
+    pub async fn send<D: AsRef<[u8]>>(&mut self, data: D) -> io::Result<()> {
+        self.push(vec![data.as_ref().to_vec()]);
+        Ok(())
+    }
+
+    /// Sends some data into the queue. This never fails, but it returns a
+    /// result for the sake of the API of [`crate::Sender::try_send`].
+    pub fn try_send<D: AsRef<[u8]>>(&mut self, data: D) -> Result<(), TrySendError<D>> {
+        self.push(vec![data.as_ref().to_vec()]);
+        Ok(())
+    }
+
+    /// Sends all the contents of an iterable into the queue, all at once.
+    pub async fn send_batch<I>(&mut self, it: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        self.push(it.into_iter().map(|item| item.as_ref().to_vec()).collect());
+        Ok(())
+    }
+
+    /// Sends all the contents of an iterable into the queue, all at once.
+    /// This never fails. See [`Sender::try_send`].
+    pub fn try_send_batch<I>(&mut self, it: I) -> Result<(), TrySendError<I>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        self.push(it.into_iter().map(|item| item.as_ref().to_vec()).collect());
+        Ok(())
+    }
+
+    fn push(&mut self, items: Vec<Vec<u8>>) {
+        let mut state = self.queue.state();
+        state.items.extend(items);
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.queue.state().has_sender = false;
+    }
+}
+
+/// The receiver part of a queue in memory. See [`crate::Receiver`].
+pub struct Receiver {
+    queue: Arc<Queue>,
+}
+
+impl Receiver {
+    /// Opens a queue in memory for receiving, creating it if it does not
+    /// exist yet.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the queue is already in use for
+    /// receiving.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Receiver> {
+        let queue = Queue::get(base.as_ref());
+
+        {
+            let mut state = queue.state();
+            if state.has_receiver {
+                return Err(io::Error::other(format!(
+                    "queue `{}` receiver side already in use",
+                    base.as_ref().to_string_lossy()
+                )));
+            }
+            state.has_receiver = true;
+        }
+
+        Ok(Receiver { queue })
+    }
+
+    /// Waits for `n` elements to be in the queue and takes them out.
+    fn take(&mut self, n: usize) -> impl Future<Output = Vec<Vec<u8>>> + '_ {
+        future::poll_fn(move |context| {
+            let mut state = self.queue.state();
+
+            if state.items.len() >= n {
+                Poll::Ready(state.items.drain(..n).collect())
+            } else {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+
+    fn guard<T: Items>(&mut self, item: T) -> RecvGuard<'_, T> {
+        RecvGuard {
+            receiver: self,
+            item: Some(item),
+        }
+    }
+
+    /// Retrieves an element from the queue. The returned value is a guard
+    /// that puts the element back in the queue unless committed. See
+    /// [`crate::Receiver::recv`].
+    pub async fn recv(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
+        let mut items = self.take(1).await;
+        Ok(self.guard(items.remove(0)))
+    }
+
+    /// Tries to retrieve an element from the queue, without waiting. See
+    /// [`Receiver::recv`].
+    pub fn try_recv(&mut self) -> Result<RecvGuard<'_, Vec<u8>>, TryRecvError> {
+        match self.take(1).now_or_never() {
+            Some(mut items) => Ok(self.guard(items.remove(0))),
+            None => Err(TryRecvError::QueueEmpty),
+        }
+    }
+
+    /// Retrieves an element from the queue until a given future finishes,
+    /// whichever comes first. See [`crate::Receiver::recv_timeout`].
+    pub async fn recv_timeout<F>(
+        &mut self,
+        timeout: F,
+    ) -> io::Result<Option<RecvGuard<'_, Vec<u8>>>>
+    where
+        F: Future<Output = ()> + Unpin,
+    {
+        let mut items = match future::select(self.take(1), timeout).await {
+            Either::Left((items, _)) => items,
+            Either::Right(((), _)) => return Ok(None),
+        };
+
+        Ok(Some(self.guard(items.remove(0))))
+    }
+
+    /// Retrieves `n` elements from the queue, waiting for all of them. See
+    /// [`crate::Receiver::recv_batch`].
+    pub async fn recv_batch(&mut self, n: usize) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        let items = self.take(n).await;
+        Ok(self.guard(items))
+    }
+
+    /// Tries to retrieve `n` elements from the queue, without waiting. See
+    /// [`Receiver::recv_batch`].
+    pub fn try_recv_batch(
+        &mut self,
+        n: usize,
+    ) -> Result<RecvGuard<'_, Vec<Vec<u8>>>, TryRecvError> {
+        match self.take(n).now_or_never() {
+            Some(items) => Ok(self.guard(items)),
+            None => Err(TryRecvError::QueueEmpty),
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.queue.state().has_receiver = false;
+    }
+}
+
+/// What can be put back into the queue when a guard is rolled back.
+pub trait Items: sealed::Sealed {
+    #[doc(hidden)]
+    fn into_items(self) -> Vec<Vec<u8>>;
+}
+
+impl Items for Vec<u8> {
+    fn into_items(self) -> Vec<Vec<u8>> {
+        vec![self]
+    }
+}
+
+impl Items for Vec<Vec<u8>> {
+    fn into_items(self) -> Vec<Vec<u8>> {
+        self
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for Vec<u8> {}
+    impl Sealed for Vec<Vec<u8>> {}
+}
+
+/// A guard that puts what was received back at the front of the queue when
+/// dropped, unless committed. See [`crate::queue::RecvGuard`].
+pub struct RecvGuard<'a, T: Items> {
+    receiver: &'a mut Receiver,
+    /// What was received, unless committed or rolled back.
+    item: Option<T>,
+}
+
+impl<'a, T: Items> RecvGuard<'a, T> {
+    /// Commits the transaction and returns the underlying value.
+    pub fn try_into_inner(mut self) -> io::Result<T> {
+        Ok(self.item.take().expect("unreachable"))
+    }
+
+    /// Commits the changes to the queue, consuming this `RecvGuard`.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.item = None;
+        Ok(())
+    }
+
+    /// Puts what was received back at the front of the queue, consuming this
+    /// `RecvGuard`.
+    pub fn rollback(mut self) -> io::Result<()> {
+        self.rollback_mut();
+        Ok(())
+    }
+
+    fn rollback_mut(&mut self) {
+        if let Some(item) = self.item.take() {
+            let mut state = self.receiver.queue.state();
+
+            for item in item.into_items().into_iter().rev() {
+                state.items.push_front(item);
+            }
+        }
+    }
+}
+
+impl<'a, T: Items> Deref for RecvGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("unreachable")
+    }
+}
+
+impl<'a, T: Items> Drop for RecvGuard<'a, T> {
+    fn drop(&mut self) {
+        self.rollback_mut();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn send_and_receive() {
+        let (mut sender, mut receiver) = channel("mem/send-and-receive").unwrap();
+
+        futures::executor::block_on(async {
+            let (received, ()) = futures::join!(
+                async {
+                    let batch = receiver.recv_batch(3).await.unwrap();
+                    batch.try_into_inner().unwrap()
+                },
+                async {
+                    sender.send(b"a").await.unwrap();
+                    sender.send_batch(vec![b"b", b"c"]).await.unwrap();
+                },
+            );
+
+            assert_eq!(received, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        });
+
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+    }
+
+    #[test]
+    fn rollback_and_reopen() {
+        let base = "mem/rollback-and-reopen";
+        let (mut sender, mut receiver) = channel(base).unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+
+        // One side at a time:
+        assert!(Sender::open(base).is_err());
+        assert!(Receiver::open(base).is_err());
+        assert!(try_clear(base).is_err());
+
+        drop(receiver.try_recv_batch(2).ok().unwrap());
+        receiver.try_recv().ok().unwrap().rollback().unwrap();
+
+        // The queue outlives its handles:
+        drop((sender, receiver));
+        let mut receiver = Receiver::open(base).unwrap();
+        let guard = receiver.try_recv().ok().unwrap();
+        assert_eq!(&*guard, b"first");
+        guard.commit().unwrap();
+        drop(receiver);
+
+        try_clear(base).unwrap();
+        let mut receiver = Receiver::open(base).unwrap();
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+    }
+}
@@ -0,0 +1,425 @@
+//! Optional metadata attached to items in the queue. Items without metadata
+//! are stored just as before: a header and the payload. Items with metadata
+//! are preceded by a _metadata marker_, followed by a regular header with the
+//! length of the metadata and the metadata itself:
+//! ```text
+//! [marker] [metadata header] [metadata...] [header] [payload...]
+//! ```
+//! The marker is not a valid Hamming-encoded header, so it can never be
+//! mistaken for the header of a regular item. Be aware that older versions
+//! of `yaque` cannot read items with metadata.
+//!
+//! The metadata is a sequence of fields, each encoded as a tag (`u8`) and a
+//! length (`u16`, big endian), followed by the value. Unknown tags are
+//! skipped when decoding.
+
+use bytes::Bytes;
+use std::convert::TryInto;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compression::decompress;
+use crate::encryption::{decrypt, KeyProvider};
+
+/// The value of the metadata marker.
+pub(crate) const HEADER_METADATA: [u8; 4] = [255, 255, 255, 254];
+
+/// Tag for [`Metadata::not_before`]: milliseconds since the UNIX epoch (rounded
+/// up) as a big endian `u64`.
+const TAG_NOT_BEFORE: u8 = 1;
+/// Tag for [`Metadata::expires_at`]: milliseconds since the UNIX epoch (rounded
+/// down) as a big endian `u64`.
+const TAG_EXPIRES_AT: u8 = 2;
+/// Tag for [`Metadata::compression`]: the id of the codec as a `u8`.
+const TAG_COMPRESSION: u8 = 3;
+/// Tag for [`Metadata::encryption`]: the id of the key as a big endian `u32`,
+/// followed by the nonce.
+const TAG_ENCRYPTION: u8 = 4;
+/// Tag for [`Metadata::checksum`]: the CRC32 as a big endian `u32`.
+const TAG_CHECKSUM: u8 = 5;
+/// Tag for [`Metadata::id`]: the bytes of the id.
+const TAG_ID: u8 = 6;
+/// Tag for each of [`Metadata::headers`]: the length of the name as a `u8`,
+/// followed by the name (UTF-8) and by the value.
+const TAG_HEADER: u8 = 7;
+/// Tag for [`Metadata::enqueued_at`]: milliseconds since the UNIX epoch
+/// (rounded down) as a big endian `u64`.
+const TAG_ENQUEUED_AT: u8 = 8;
+/// Tag for the chunks of an item split over segments: a `u8` with
+/// [`CHUNK_MORE`] set if more chunks follow and [`CHUNK_CONTINUATION`] set if
+/// the chunk continues an item. See [`crate::SenderBuilder::split_items`].
+const TAG_CHUNK: u8 = 9;
+
+/// Set in a [`TAG_CHUNK`] field if more chunks of the item follow.
+const CHUNK_MORE: u8 = 0b01;
+/// Set in a [`TAG_CHUNK`] field if the chunk continues an item.
+const CHUNK_CONTINUATION: u8 = 0b10;
+
+/// The longest id of an item, in bytes.
+pub(crate) const MAX_ID_LEN: usize = 255;
+
+/// The longest name of a header of an item, in bytes.
+pub(crate) const MAX_HEADER_NAME_LEN: usize = 255;
+
+/// The longest value of a header of an item, in bytes (so that the whole
+/// header fits in a metadata field).
+pub(crate) const MAX_HEADER_VALUE_LEN: usize = u16::MAX as usize - 1 - MAX_HEADER_NAME_LEN;
+
+/// The headers of an item, as names and values, in the order they were given.
+pub(crate) type Headers = Vec<(String, Vec<u8>)>;
+
+/// The metadata of an item in the queue.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Metadata {
+    /// The item must not be delivered before this instant.
+    pub(crate) not_before: Option<SystemTime>,
+    /// The item must be discarded if not delivered until this instant.
+    pub(crate) expires_at: Option<SystemTime>,
+    /// The codec the payload was compressed with. See [`crate::Compression`].
+    pub(crate) compression: Option<u8>,
+    /// How the payload was encrypted. See [`crate::encryption::KeyProvider`].
+    pub(crate) encryption: Option<Encryption>,
+    /// The CRC32 of the payload, as written to the queue.
+    pub(crate) checksum: Option<u32>,
+    /// The id given by the sender, for the receiver to skip duplicates. See
+    /// [`crate::Sender::try_send_with_id`].
+    pub(crate) id: Option<Vec<u8>>,
+    /// The headers given by the sender. See [`crate::Sender::try_send_with_headers`].
+    pub(crate) headers: Headers,
+    /// When the sender wrote the item. See [`crate::SenderBuilder::timestamp`].
+    pub(crate) enqueued_at: Option<SystemTime>,
+    /// Whether the item is split over segments and more chunks of it follow.
+    /// See [`crate::SenderBuilder::split_items`].
+    pub(crate) more_chunks: bool,
+    /// Whether this is a chunk continuing an item split over segments. Only
+    /// the first chunk carries the rest of the metadata of the item.
+    pub(crate) is_continuation: bool,
+}
+
+/// How the payload of an item was encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Encryption {
+    /// The id of the key, as given by the key provider.
+    pub(crate) key_id: u32,
+    /// The nonce the payload was encrypted with.
+    pub(crate) nonce: [u8; 12],
+}
+
+impl Metadata {
+    /// Whether there is no metadata at all. Items with empty metadata are
+    /// stored without the metadata marker.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.not_before.is_none()
+            && self.expires_at.is_none()
+            && self.compression.is_none()
+            && self.encryption.is_none()
+            && self.checksum.is_none()
+            && self.id.is_none()
+            && self.headers.is_empty()
+            && self.enqueued_at.is_none()
+            && !self.more_chunks
+            && !self.is_continuation
+    }
+
+    /// Whether the item has expired.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= SystemTime::now())
+            .unwrap_or(false)
+    }
+
+    /// Whether the item was written longer ago than a maximum age. Items
+    /// without a timestamp are never too old.
+    pub(crate) fn is_older_than(&self, max_age: Duration) -> bool {
+        self.enqueued_at
+            .and_then(|enqueued_at| enqueued_at.elapsed().ok())
+            .map(|age| age > max_age)
+            .unwrap_or(false)
+    }
+
+    /// The number of bytes this metadata takes in the queue, including the
+    /// marker and the metadata header.
+    pub(crate) fn encoded_len(&self) -> u64 {
+        if self.is_empty() {
+            0
+        } else {
+            8 + self.encode().len() as u64
+        }
+    }
+
+    /// Encodes this metadata into bytes.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut encoded = vec![];
+
+        if let Some(not_before) = self.not_before {
+            // Round up, so that the item is never delivered early:
+            let since_epoch = not_before.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let millis = since_epoch.as_millis() as u64
+                + (since_epoch.subsec_nanos() % 1_000_000 != 0) as u64;
+            push_field(&mut encoded, TAG_NOT_BEFORE, &millis.to_be_bytes());
+        }
+
+        if let Some(expires_at) = self.expires_at {
+            let millis = expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            push_field(&mut encoded, TAG_EXPIRES_AT, &millis.to_be_bytes());
+        }
+
+        if let Some(enqueued_at) = self.enqueued_at {
+            let millis = enqueued_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            push_field(&mut encoded, TAG_ENQUEUED_AT, &millis.to_be_bytes());
+        }
+
+        if let Some(codec) = self.compression {
+            push_field(&mut encoded, TAG_COMPRESSION, &[codec]);
+        }
+
+        if let Some(encryption) = self.encryption {
+            let mut value = encryption.key_id.to_be_bytes().to_vec();
+            value.extend_from_slice(&encryption.nonce);
+            push_field(&mut encoded, TAG_ENCRYPTION, &value);
+        }
+
+        if let Some(checksum) = self.checksum {
+            push_field(&mut encoded, TAG_CHECKSUM, &checksum.to_be_bytes());
+        }
+
+        if let Some(id) = &self.id {
+            push_field(&mut encoded, TAG_ID, id);
+        }
+
+        for (name, value) in &self.headers {
+            let mut field = vec![name.len() as u8];
+            field.extend_from_slice(name.as_bytes());
+            field.extend_from_slice(value);
+            push_field(&mut encoded, TAG_HEADER, &field);
+        }
+
+        if self.more_chunks || self.is_continuation {
+            let mut flags = 0;
+            if self.more_chunks {
+                flags |= CHUNK_MORE;
+            }
+            if self.is_continuation {
+                flags |= CHUNK_CONTINUATION;
+            }
+            push_field(&mut encoded, TAG_CHUNK, &[flags]);
+        }
+
+        encoded
+    }
+
+    /// Decodes metadata from bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+    /// if the metadata is truncated or if a known field is malformed.
+    pub(crate) fn decode(mut encoded: &[u8]) -> io::Result<Metadata> {
+        let mut metadata = Metadata::default();
+
+        while !encoded.is_empty() {
+            if encoded.len() < 3 {
+                return Err(invalid("truncated metadata field"));
+            }
+
+            let tag = encoded[0];
+            let len = u16::from_be_bytes([encoded[1], encoded[2]]) as usize;
+            let value = encoded
+                .get(3..3 + len)
+                .ok_or_else(|| invalid("truncated metadata value"))?;
+
+            match tag {
+                TAG_NOT_BEFORE => metadata.not_before = Some(decode_timestamp(value)?),
+                TAG_EXPIRES_AT => metadata.expires_at = Some(decode_timestamp(value)?),
+                TAG_COMPRESSION => match value {
+                    [codec] => metadata.compression = Some(*codec),
+                    _ => return Err(invalid("malformed codec")),
+                },
+                TAG_ENCRYPTION => metadata.encryption = Some(decode_encryption(value)?),
+                TAG_CHECKSUM => {
+                    let checksum = value.try_into().map_err(|_| invalid("malformed checksum"))?;
+                    metadata.checksum = Some(u32::from_be_bytes(checksum));
+                }
+                TAG_ID if value.len() <= MAX_ID_LEN => metadata.id = Some(value.to_vec()),
+                TAG_ID => return Err(invalid("malformed id")),
+                TAG_HEADER => metadata.headers.push(decode_header(value)?),
+                TAG_ENQUEUED_AT => metadata.enqueued_at = Some(decode_timestamp(value)?),
+                TAG_CHUNK => match value {
+                    [flags] => {
+                        metadata.more_chunks = flags & CHUNK_MORE != 0;
+                        metadata.is_continuation = flags & CHUNK_CONTINUATION != 0;
+                    }
+                    _ => return Err(invalid("malformed chunk")),
+                },
+                _ => log::trace!("skipping unknown metadata tag {}", tag),
+            }
+
+            encoded = &encoded[3 + len..];
+        }
+
+        Ok(metadata)
+    }
+
+    /// Whether the payload of an item, as written to the queue, matches its
+    /// checksum. Items without a checksum always match.
+    pub(crate) fn matches_checksum(&self, data: &[u8]) -> bool {
+        self.checksum
+            .map(|checksum| checksum == crc32fast::hash(data))
+            .unwrap_or(true)
+    }
+
+    /// Undoes the encryption and the compression of the payload of an item,
+    /// if any.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+    /// if the payload cannot be decrypted or decompressed.
+    pub(crate) fn decode_payload(
+        &self,
+        mut data: Vec<u8>,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> io::Result<Vec<u8>> {
+        if let Some(encryption) = &self.encryption {
+            data = decrypt(key_provider, encryption, &data)?;
+        }
+
+        if let Some(codec) = self.compression {
+            data = decompress(codec, &data)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Decodes the payload of an element as read, like
+    /// [`Metadata::decode_payload`], without copying it if it is stored as
+    /// it is.
+    pub(crate) fn decode_payload_bytes(
+        &self,
+        data: Bytes,
+        key_provider: Option<&dyn KeyProvider>,
+    ) -> io::Result<Bytes> {
+        if self.encryption.is_none() && self.compression.is_none() {
+            return Ok(data);
+        }
+
+        self.decode_payload(data.to_vec(), key_provider)
+            .map(Bytes::from)
+    }
+}
+
+/// Appends a field to encoded metadata.
+fn push_field(encoded: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    assert!(value.len() <= u16::MAX as usize, "metadata value too big");
+    encoded.push(tag);
+    encoded.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    encoded.extend_from_slice(value);
+}
+
+/// Decodes a timestamp field.
+fn decode_timestamp(value: &[u8]) -> io::Result<SystemTime> {
+    let millis = u64::from_be_bytes(
+        value
+            .try_into()
+            .map_err(|_| invalid("malformed timestamp"))?,
+    );
+
+    Ok(UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Decodes an encryption field.
+fn decode_encryption(value: &[u8]) -> io::Result<Encryption> {
+    if value.len() != 16 {
+        return Err(invalid("malformed encryption"));
+    }
+
+    Ok(Encryption {
+        key_id: u32::from_be_bytes(value[..4].try_into().expect("4 bytes")),
+        nonce: value[4..].try_into().expect("12 bytes"),
+    })
+}
+
+/// Decodes a header field.
+fn decode_header(value: &[u8]) -> io::Result<(String, Vec<u8>)> {
+    let (&name_len, rest) = value
+        .split_first()
+        .ok_or_else(|| invalid("malformed header"))?;
+    let name = rest
+        .get(..name_len as usize)
+        .and_then(|name| std::str::from_utf8(name).ok())
+        .ok_or_else(|| invalid("malformed header"))?;
+
+    Ok((name.to_owned(), rest[name.len()..].to_vec()))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_metadata() {
+        let metadata = Metadata {
+            not_before: Some(UNIX_EPOCH + Duration::from_millis(1_234_567)),
+            expires_at: Some(UNIX_EPOCH + Duration::from_millis(7_654_321)),
+            compression: Some(1),
+            encryption: Some(Encryption {
+                key_id: 7,
+                nonce: [3; 12],
+            }),
+            checksum: Some(0xdead_beef),
+            id: Some(b"some id".to_vec()),
+            headers: vec![
+                ("content-type".to_owned(), b"text/plain".to_vec()),
+                ("empty".to_owned(), vec![]),
+            ],
+            enqueued_at: Some(UNIX_EPOCH + Duration::from_millis(1_000_000)),
+            more_chunks: true,
+            is_continuation: false,
+        };
+
+        assert_eq!(metadata, Metadata::decode(&metadata.encode()).unwrap());
+        assert_eq!(Metadata::default(), Metadata::decode(&[]).unwrap());
+
+        let chunk = Metadata {
+            more_chunks: false,
+            is_continuation: true,
+            ..Metadata::default()
+        };
+        assert!(!chunk.is_empty());
+        assert_eq!(chunk, Metadata::decode(&chunk.encode()).unwrap());
+    }
+
+    #[test]
+    fn skip_unknown_tags() {
+        let mut encoded = vec![];
+        push_field(&mut encoded, 200, b"from the future");
+        encoded.extend(
+            Metadata {
+                not_before: Some(UNIX_EPOCH),
+                ..Metadata::default()
+            }
+            .encode(),
+        );
+
+        assert_eq!(
+            Metadata::decode(&encoded).unwrap().not_before,
+            Some(UNIX_EPOCH)
+        );
+    }
+
+    #[test]
+    fn truncated_metadata() {
+        assert!(Metadata::decode(&[TAG_NOT_BEFORE, 0, 8, 1, 2]).is_err());
+        assert!(Metadata::decode(&[TAG_HEADER, 0, 3, 5, b'a', b'b']).is_err());
+    }
+}
@@ -8,6 +8,7 @@ use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 
+use crate::sharing;
 pub use crate::sync::FileGuard;
 
 /// A persistent mutex implementation using the atomicity of [`OpenOptions::create_new`].
@@ -26,6 +27,7 @@ impl Mutex {
     /// mutex.
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Mutex> {
         fs::create_dir_all(&path)?;
+        sharing::created(path.as_ref())?;
         Ok(Mutex {
             path: path.as_ref().to_owned(),
         })
@@ -38,7 +40,9 @@ impl Mutex {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(self.path.join("contents"))?;
+        sharing::created(&self.path.join("contents"))?;
 
         Ok(MutexGuard {
             _file_guard: file_guard,
@@ -55,7 +59,9 @@ impl Mutex {
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(self.path.join("contents"))?;
+            sharing::created(&self.path.join("contents"))?;
 
             Ok(Some(MutexGuard {
                 _file_guard: file_guard,
@@ -77,8 +83,11 @@ pub struct MutexGuard {
 impl MutexGuard {
     /// Reas all the contents of the content file into a vector.
     pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
         (&self.file).seek(io::SeekFrom::Start(0))?;
-        (&self.file).bytes().collect::<io::Result<Vec<_>>>()
+        (&self.file).read_to_end(&mut contents)?;
+
+        Ok(contents)
     }
 
     /// Writes some data to the content file, ovewritting all the previous content.
@@ -98,6 +107,7 @@ impl MutexGuard {
 // The drop order doesn't matter. Therefore, no `Drop` implementation.
 
 #[cfg(test)]
+#[allow(clippy::explicit_auto_deref)]
 mod test {
     use super::*;
 
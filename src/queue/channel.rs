@@ -0,0 +1,137 @@
+//! A builder configuring both ends of a channel at once.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use super::{Durability, Receiver, ReceiverBuilder, Sender, SenderBuilder};
+
+/// A builder for both ends of a channel, so that the sender and the receiver
+/// of one logical channel are configured in one place. Finish building it by
+/// invoking [`ChannelBuilder::open`]. Settings without a shortcut here can
+/// be set through [`ChannelBuilder::sender`] and [`ChannelBuilder::receiver`].
+#[derive(Default)]
+pub struct ChannelBuilder {
+    sender: SenderBuilder,
+    receiver: ReceiverBuilder,
+}
+
+impl ChannelBuilder {
+    /// Creates a new channel builder with the defaults of [`SenderBuilder`]
+    /// and [`ReceiverBuilder`].
+    pub fn new() -> ChannelBuilder {
+        ChannelBuilder::default()
+    }
+
+    /// See [`SenderBuilder::segment_size`].
+    ///
+    /// Default value: `4 * 1024 * 1024`, or 4MB.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is zero.
+    pub fn segment_size(mut self, size: u64) -> ChannelBuilder {
+        self.sender = self.sender.segment_size(size);
+        self
+    }
+
+    /// See [`SenderBuilder::max_queue_size`].
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is zero.
+    pub fn max_queue_size(mut self, size: Option<u64>) -> ChannelBuilder {
+        self.sender = self.sender.max_queue_size(size);
+        self
+    }
+
+    /// See [`SenderBuilder::max_queue_bytes`].
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is zero.
+    pub fn max_queue_bytes(mut self, size: Option<u64>) -> ChannelBuilder {
+        self.sender = self.sender.max_queue_bytes(size);
+        self
+    }
+
+    /// See [`SenderBuilder::max_queue_items`].
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `n` is zero.
+    pub fn max_queue_items(mut self, n: Option<u64>) -> ChannelBuilder {
+        self.sender = self.sender.max_queue_items(n);
+        self
+    }
+
+    /// See [`SenderBuilder::durability`].
+    ///
+    /// Default value: `Durability::Never`
+    pub fn durability(mut self, durability: Durability) -> ChannelBuilder {
+        self.sender = self.sender.durability(durability);
+        self
+    }
+
+    /// See [`ReceiverBuilder::save_every_nth`].
+    ///
+    /// Default value: every 250 elements.
+    pub fn save_every_nth(mut self, nth: Option<usize>) -> ChannelBuilder {
+        self.receiver = self.receiver.save_every_nth(nth);
+        self
+    }
+
+    /// See [`ReceiverBuilder::save_every`].
+    ///
+    /// Default value: every 350 milliseconds.
+    pub fn save_every(mut self, duration: Option<Duration>) -> ChannelBuilder {
+        self.receiver = self.receiver.save_every(duration);
+        self
+    }
+
+    /// Locks both ends of the channel within this process only. See
+    /// [`SenderBuilder::in_process`] and [`ReceiverBuilder::in_process`].
+    ///
+    /// Default value: `false`
+    pub fn in_process(mut self, in_process: bool) -> ChannelBuilder {
+        self.sender = self.sender.in_process(in_process);
+        self.receiver = self.receiver.in_process(in_process);
+        self
+    }
+
+    /// Configures the sender further, for the settings without a shortcut
+    /// in this builder.
+    pub fn sender<F>(mut self, configure: F) -> ChannelBuilder
+    where
+        F: FnOnce(SenderBuilder) -> SenderBuilder,
+    {
+        self.sender = configure(self.sender);
+        self
+    }
+
+    /// Configures the receiver further, for the settings without a shortcut
+    /// in this builder.
+    pub fn receiver<F>(mut self, configure: F) -> ChannelBuilder
+    where
+        F: FnOnce(ReceiverBuilder) -> ReceiverBuilder,
+    {
+        self.receiver = configure(self.receiver);
+        self
+    }
+
+    /// Opens the queue for both sending and receiving. The sender is opened
+    /// first, so that the settings of a new queue are recorded before the
+    /// receiver opens it.
+    pub fn open<P: AsRef<Path>>(self, base: P) -> io::Result<(Sender, Receiver)> {
+        let sender = self.sender.open(base.as_ref())?;
+        let receiver = self.receiver.open(base.as_ref())?;
+
+        Ok((sender, receiver))
+    }
+}
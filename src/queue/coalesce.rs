@@ -0,0 +1,199 @@
+use futures::channel::oneshot;
+use futures::lock::Mutex as AsyncMutex;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::timer::Delay;
+
+use super::Sender;
+
+/// What the task writing a group tells the other tasks in the group.
+enum Message {
+    /// The group was written, with this outcome.
+    Done(io::Result<()>),
+    /// The task that was to write the group was dropped. Write it instead.
+    Lead,
+}
+
+/// An element waiting to be written with its group.
+struct Pending {
+    data: Vec<u8>,
+    /// How to tell the task that sent the element how it went, unless it is
+    /// the task writing the group.
+    notify: Option<oneshot::Sender<Message>>,
+}
+
+/// The elements that came in since the last group was taken to be written.
+#[derive(Default)]
+struct Group {
+    pending: Vec<Pending>,
+    /// Whether some task is going to write this group.
+    has_leader: bool,
+}
+
+struct Shared {
+    sender: AsyncMutex<Sender>,
+    group: Mutex<Group>,
+    window: Option<Duration>,
+}
+
+/// A handle to send into a queue from many tasks concurrently, created by
+/// [`Sender::into_coalescing`]. Clone it to get more handles to the same
+/// sender.
+///
+/// Instead of one write (and, depending on the durability of the sender, one
+/// sync) per element, the elements sent concurrently are written in groups:
+/// the first send of a group waits for the _coalesce window_ of the sender
+/// (see [`crate::SenderBuilder::coalesce_window`]) and then writes every
+/// element sent in the meantime as a single batch, as in
+/// [`Sender::send_batch`]. Each send returns once its group is written, with
+/// the outcome of the whole group.
+///
+/// Dropping the future of a send does not take the element back: it is still
+/// written with its group.
+#[derive(Clone)]
+pub struct CoalescingSender {
+    shared: Arc<Shared>,
+}
+
+impl CoalescingSender {
+    pub(crate) fn new(sender: Sender, window: Option<Duration>) -> CoalescingSender {
+        CoalescingSender {
+            shared: Arc::new(Shared {
+                sender: AsyncMutex::new(sender),
+                group: Mutex::new(Group::default()),
+                window,
+            }),
+        }
+    }
+
+    /// Sends some data into the queue with the next group of elements. This
+    /// function is `async` because it waits for the group to be written,
+    /// which includes waiting for the queue to have room, just as
+    /// [`Sender::send_batch`] does.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing
+    /// the group, in which case none of the elements of the group may have
+    /// been sent. If the task writing the group is dropped midway, this
+    /// returns an error of kind [`io::ErrorKind::Interrupted`].
+    pub async fn send<D: AsRef<[u8]>>(&self, data: D) -> io::Result<()> {
+        let data = data.as_ref().to_vec();
+
+        let receiver = {
+            let mut group = self.shared.group.lock().expect("poisoned");
+
+            if group.has_leader {
+                let (notify, receiver) = oneshot::channel();
+                group.pending.push(Pending {
+                    data,
+                    notify: Some(notify),
+                });
+                Some(receiver)
+            } else {
+                group.has_leader = true;
+                group.pending.push(Pending { data, notify: None });
+                None
+            }
+        };
+
+        let receiver = match receiver {
+            Some(receiver) => receiver,
+            None => return self.lead().await,
+        };
+
+        match receiver.await {
+            Ok(Message::Done(outcome)) => outcome,
+            Ok(Message::Lead) => self.lead().await,
+            Err(oneshot::Canceled) => Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "the send writing the group was dropped",
+            )),
+        }
+    }
+
+    /// Waits for the window to close and writes the current group.
+    async fn lead(&self) -> io::Result<()> {
+        let mut leadership = Leadership {
+            shared: &self.shared,
+            has_taken: false,
+        };
+
+        if let Some(window) = self.shared.window {
+            Delay::new(window).await;
+        }
+
+        let mut sender = self.shared.sender.lock().await;
+        let group = leadership.take();
+
+        log::trace!("writing a group of {} elements", group.len());
+        let outcome = sender
+            .send_batch(group.iter().map(|pending| &pending.data))
+            .await;
+        drop(sender);
+
+        for pending in group {
+            if let Some(notify) = pending.notify {
+                // (the task may have given up on the send)
+                let _ = notify.send(Message::Done(copy_outcome(&outcome)));
+            }
+        }
+
+        outcome
+    }
+}
+
+/// The task that is going to write the current group. If it is dropped before
+/// taking the group, another task in the group takes over.
+struct Leadership<'a> {
+    shared: &'a Shared,
+    has_taken: bool,
+}
+
+impl<'a> Leadership<'a> {
+    /// Takes the current group to be written. The next element starts a new
+    /// group.
+    fn take(&mut self) -> Vec<Pending> {
+        let mut group = self.shared.group.lock().expect("poisoned");
+        group.has_leader = false;
+        self.has_taken = true;
+
+        mem::take(&mut group.pending)
+    }
+}
+
+impl<'a> Drop for Leadership<'a> {
+    fn drop(&mut self) {
+        if self.has_taken {
+            return;
+        }
+
+        let mut group = match self.shared.group.lock() {
+            Ok(group) => group,
+            Err(_) => return,
+        };
+        group.has_leader = false;
+
+        // Hand the group over to the first task still waiting on it. If there
+        // is none, the group is written with the next element sent:
+        for pending in &mut group.pending {
+            if let Some(notify) = pending.notify.take() {
+                if notify.send(Message::Lead).is_ok() {
+                    group.has_leader = true;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Copies the outcome of writing a group for every task in it.
+fn copy_outcome(outcome: &io::Result<()>) -> io::Result<()> {
+    match outcome {
+        Ok(()) => Ok(()),
+        Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+    }
+}
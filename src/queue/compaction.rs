@@ -0,0 +1,298 @@
+use std::fs::*;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::header::Header;
+use crate::metadata::HEADER_METADATA;
+use crate::sharing;
+use crate::state::{Deliveries, QueueState, QueueStatePersistence, SeenIds};
+
+use super::footer::FooterBuilder;
+use super::receiver::try_acquire_recv_lock;
+use super::sender::try_acquire_send_lock;
+use super::{lane_priority, list_segments, segment_filename, surviving_segment, HEADER_EOF};
+
+/// The folder in the queue folder where the compacted segments are written
+/// before replacing the old ones.
+fn compaction_dirname<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("compaction")
+}
+
+/// The file in the compaction folder saying that all the compacted segments
+/// were written and what to do with them.
+pub(crate) fn marker_filename(base: &Path) -> PathBuf {
+    compaction_dirname(base).join("marker")
+}
+
+/// What a compaction does once all the compacted segments were written. All
+/// of these steps can be redone, so that an interrupted compaction can be
+/// finished later.
+struct Plan {
+    /// The state of the receiver before the compaction.
+    old_state: QueueState,
+    /// The first segment that was compacted.
+    first: u64,
+    /// The first compacted segment. The segments from `first` up to this one
+    /// are removed; the others are replaced.
+    new_segment: u64,
+}
+
+impl Plan {
+    fn encode(&self) -> String {
+        format!(
+            "{} {} {} {}\n",
+            self.old_state.segment, self.old_state.position, self.first, self.new_segment
+        )
+    }
+
+    fn decode(encoded: &str) -> io::Result<Plan> {
+        let numbers = encoded
+            .split_whitespace()
+            .map(|number| number.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>();
+
+        match numbers.as_deref() {
+            Ok(&[segment, position, first, new_segment]) => Ok(Plan {
+                old_state: QueueState { segment, position },
+                first,
+                new_segment,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed compaction marker: `{}`", encoded),
+            )),
+        }
+    }
+}
+
+/// Merges the closed segments of a queue that are waiting to be received into
+/// fewer, bigger segments of at least `segment_size` bytes each (whenever
+/// there is enough data), and moves the receiver to the start of the first of
+/// the merged segments. The segment the sender is writing to is not touched.
+/// Priority lanes are compacted as well. Returns the number of segments that
+/// were saved.
+///
+/// Use this when the elements are big compared to the segment size of the
+/// sender and the queue piled up in many small segments. The bytes of the
+/// current segment of the receiver that were already received are left out.
+///
+/// The merged segments are written to a folder in the queue folder before
+/// replacing the old ones. If the compaction is interrupted after that, the
+/// queue cannot be opened until this function is called again to finish it.
+///
+/// # Errors
+///
+/// This function fails if the queue is in use either for sending or
+/// receiving. It also fails with an error of kind
+/// [`io::ErrorKind::InvalidData`] if a closed segment is corrupted. Any
+/// underlying errors encountered while compacting are also returned.
+///
+/// # Panics
+///
+/// This function panics if `segment_size` is zero or if there is a file in
+/// the queue folder with extension `.q` whose name is not an integer, such as
+/// `foo.q`.
+pub fn compact_queue<P: AsRef<Path>>(base: P, segment_size: u64) -> io::Result<u64> {
+    assert!(segment_size > 0, "segment size must be positive");
+
+    let base = base.as_ref();
+    let _send_lock = try_acquire_send_lock(base, None)?;
+    let _recv_lock = try_acquire_recv_lock(base, None)?;
+
+    // Finish what was interrupted or start from scratch:
+    let mut saved = match read_to_string(marker_filename(base)) {
+        Ok(marker) => {
+            log::info!("finishing interrupted compaction of {:?}", base);
+            finish(base, &Plan::decode(&marker)?)?;
+            0
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => compact(base, segment_size)?,
+        Err(err) => return Err(err),
+    };
+
+    // Priority lanes are queues of their own:
+    for maybe_entry in read_dir(base)? {
+        let path = maybe_entry?.path();
+        if lane_priority(&path).is_some() {
+            saved += compact_queue(path, segment_size)?;
+        }
+    }
+
+    Ok(saved)
+}
+
+/// Writes the compacted segments and replaces the old ones with them.
+fn compact(base: &Path, segment_size: u64) -> io::Result<u64> {
+    let old_state = QueueStatePersistence::new().open(base)?;
+    let first = surviving_segment(base, old_state.segment)?;
+    let segments = list_segments(base)?
+        .into_iter()
+        .filter(|&segment| segment >= first)
+        .collect::<Vec<_>>();
+
+    // The last segment is the one being written to:
+    let closed = match segments.split_last() {
+        Some((_last, closed)) if closed.len() > 1 => closed,
+        _ => return Ok(0),
+    };
+
+    match remove_dir_all(compaction_dirname(base)) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    create_dir(compaction_dirname(base))?;
+
+    // Merge the elements of the closed segments, in order. The merged
+    // segments are numbered from zero, until their actual numbers are known:
+    let mut merged = 0;
+    let mut output: Option<(File, FooterBuilder)> = None;
+
+    for &segment in closed {
+        let contents = read(segment_filename(base, segment))?;
+        let start = if segment == old_state.segment {
+            old_state.position as usize
+        } else {
+            0
+        };
+
+        let (end, items) = walk_closed_segment(&contents, start).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("closed segment {} of {:?} is corrupted", segment, base),
+            )
+        })?;
+
+        let (file, footer) = match &mut output {
+            Some(output) => output,
+            None => output.insert((
+                File::create(segment_filename(compaction_dirname(base), merged))?,
+                FooterBuilder::default(),
+            )),
+        };
+
+        file.write_all(&contents[start..end])?;
+        footer.update(&contents[start..end]);
+        for _ in 0..items {
+            footer.item_done();
+        }
+
+        if footer.position >= segment_size {
+            close_merged(output.take().expect("was just written to"))?;
+            merged += 1;
+        }
+    }
+
+    if let Some(output) = output.take() {
+        close_merged(output)?;
+        merged += 1;
+    }
+
+    if merged >= closed.len() as u64 {
+        remove_dir_all(compaction_dirname(base))?;
+        return Ok(0);
+    }
+
+    // The merged segments take the numbers of the newest closed segments:
+    let plan = Plan {
+        old_state,
+        first,
+        new_segment: closed[closed.len() - 1] + 1 - merged,
+    };
+
+    // From now on, the compaction can only go forward:
+    let mut marker = File::create(marker_filename(base))?;
+    marker.write_all(plan.encode().as_bytes())?;
+    marker.sync_all()?;
+
+    finish(base, &plan)?;
+
+    let saved = closed.len() as u64 - merged;
+    log::info!("compaction of {:?} saved {} segments", base, saved);
+
+    Ok(saved)
+}
+
+/// Ends a merged segment with its EOF header and footer.
+fn close_merged((mut file, footer): (File, FooterBuilder)) -> io::Result<()> {
+    file.write_all(&HEADER_EOF)?;
+    file.write_all(&footer.finish())?;
+    file.sync_all()
+}
+
+/// Replaces the old segments with the compacted ones, according to the plan.
+fn finish(base: &Path, plan: &Plan) -> io::Result<()> {
+    // (merged segments that are missing were already renamed)
+    for maybe_entry in read_dir(compaction_dirname(base))? {
+        let path = maybe_entry?.path();
+        let merged = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".q"))
+            .and_then(|merged| merged.parse::<u64>().ok());
+
+        if let Some(merged) = merged {
+            sharing::rename(&path, segment_filename(base, plan.new_segment + merged))?;
+        }
+    }
+
+    let new_state = QueueState {
+        segment: plan.new_segment,
+        position: 0,
+    };
+
+    let mut persistence = QueueStatePersistence::new();
+    persistence.open(base)?;
+    persistence.save(&new_state)?;
+
+    let mut deliveries = Deliveries::load(base)?;
+    if deliveries.at == plan.old_state {
+        deliveries.at = new_state;
+        deliveries.save(base)?;
+    }
+
+    // The elements not yet read were moved, and are seen anew where they are:
+    let mut seen_ids = SeenIds::load(base, usize::MAX)?;
+    if seen_ids.forget_from(plan.old_state) {
+        seen_ids.save(base)?;
+    }
+
+    for segment in plan.first..plan.new_segment {
+        match sharing::remove_file(segment_filename(base, segment)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    remove_dir_all(compaction_dirname(base))
+}
+
+/// Walks a closed segment from a given position, returning the position of
+/// its EOF header and the number of elements walked. Returns `None` if the
+/// segment is corrupted or has no EOF header.
+fn walk_closed_segment(contents: &[u8], mut position: usize) -> Option<(usize, u64)> {
+    let read_header = |position: usize| {
+        contents
+            .get(position..position + 4)
+            .map(|header| [header[0], header[1], header[2], header[3]])
+    };
+
+    let mut items = 0;
+
+    loop {
+        let mut header = read_header(position)?;
+
+        if header == HEADER_EOF {
+            return Some((position, items));
+        }
+
+        if header == HEADER_METADATA {
+            position += 8 + Header::try_decode(read_header(position + 4)?)?.len() as usize;
+            header = read_header(position)?;
+        }
+
+        position += 4 + Header::try_decode(header)?.len() as usize;
+        items += 1;
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::*;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::header::Header;
+use crate::metadata::HEADER_METADATA;
+use crate::state::{QueueState, QueueStatePersistence};
+
+use super::{segment_filename, QueueInfo, HEADER_EOF};
+
+/// How [`dump_queue`] writes the payload of the elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One line per element:
+    /// ```text
+    /// <segment>:<position> <length> <payload in hex>
+    /// ```
+    Hex,
+    /// One JSON object per line (JSON Lines), with the payload as a string,
+    /// replacing invalid UTF-8 with `U+FFFD`:
+    /// ```text
+    /// {"segment":0,"position":0,"len":5,"metadata":0,"payload":"hello"}
+    /// ```
+    /// where `metadata` is the length of the metadata of the element, if any.
+    Json,
+}
+
+/// Writes every element waiting in a queue to be received to `writer`, with
+/// its position in the queue, its length and its payload, from the state
+/// saved by the receiver to the end of what was written so far. Returns how
+/// much was dumped.
+///
+/// This only reads the queue, so it can be used while the queue is in use,
+/// to find out what is actually stuck in it. The payload is dumped as it is
+/// in the segments: compressed or encrypted elements are not decoded.
+/// Priority lanes are queues of their own and are not included.
+///
+/// # Errors
+///
+/// This function returns an error of kind [`io::ErrorKind::InvalidData`] if a
+/// header is corrupted. It also returns any underlying errors encountered
+/// while loading the state of the receiver, reading the segments or writing
+/// to `writer`.
+pub fn dump_queue<P, W>(base: P, format: DumpFormat, mut writer: W) -> io::Result<QueueInfo>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    let base = base.as_ref();
+    let mut state = QueueStatePersistence::new().open(base)?;
+    let mut info = QueueInfo::default();
+
+    loop {
+        let contents = match read(segment_filename(base, state.segment)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err),
+        };
+
+        if !dump_segment(&contents, &mut state, format, &mut writer, &mut info)? {
+            break;
+        }
+
+        // The rest of the queue is in the next segment:
+        state.advance_segment();
+    }
+
+    writer.flush()?;
+
+    Ok(info)
+}
+
+/// Dumps a segment from the given state, returning whether its EOF header was
+/// reached. Otherwise, the rest of the segment was not written yet.
+fn dump_segment<W: Write>(
+    contents: &[u8],
+    state: &mut QueueState,
+    format: DumpFormat,
+    writer: &mut W,
+    info: &mut QueueInfo,
+) -> io::Result<bool> {
+    let read_header = |position: u64| {
+        contents
+            .get(position as usize..position as usize + 4)
+            .map(|header| [header[0], header[1], header[2], header[3]])
+    };
+    let segment = state.segment;
+    let decode = |header: [u8; 4], position: u64| {
+        Header::try_decode(header)
+            .map(|header| header.len() as u64)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupted header at {}:{}", segment, position),
+                )
+            })
+    };
+
+    loop {
+        let mut position = state.position;
+        let mut header = match read_header(position) {
+            Some(header) => header,
+            None => return Ok(false),
+        };
+
+        if header == HEADER_EOF {
+            return Ok(true);
+        }
+
+        let mut metadata_len = 0;
+        if header == HEADER_METADATA {
+            metadata_len = match read_header(position + 4) {
+                Some(header) => decode(header, position + 4)?,
+                None => return Ok(false),
+            };
+            position += 8 + metadata_len;
+            header = match read_header(position) {
+                Some(header) => header,
+                None => return Ok(false),
+            };
+        }
+
+        let len = decode(header, position)?;
+        let start = position as usize + 4;
+        let payload = match contents.get(start..start + len as usize) {
+            Some(payload) => payload,
+            None => return Ok(false),
+        };
+
+        match format {
+            DumpFormat::Hex => {
+                write!(writer, "{}:{} {} ", state.segment, state.position, len)?;
+                for byte in payload {
+                    write!(writer, "{:02x}", byte)?;
+                }
+                writeln!(writer)?;
+            }
+            DumpFormat::Json => {
+                write!(
+                    writer,
+                    "{{\"segment\":{},\"position\":{},\"len\":{},\"metadata\":{},\"payload\":",
+                    state.segment, state.position, len, metadata_len
+                )?;
+                write_json_string(writer, &String::from_utf8_lossy(payload))?;
+                writeln!(writer, "}}")?;
+            }
+        }
+
+        let size = start as u64 + len - state.position;
+        info.items += 1;
+        info.bytes += size;
+        state.advance_position(size);
+    }
+}
+
+/// Writes a string as a JSON string literal.
+pub(crate) fn write_json_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+    write!(writer, "\"")?;
+
+    for c in string.chars() {
+        match c {
+            '"' => write!(writer, "\\\"")?,
+            '\\' => write!(writer, "\\\\")?,
+            '\n' => write!(writer, "\\n")?,
+            '\r' => write!(writer, "\\r")?,
+            '\t' => write!(writer, "\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+
+    write!(writer, "\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn escape_json_string() {
+        let mut escaped = Vec::new();
+        write_json_string(&mut escaped, "a \"quoted\"\\\n\u{1}é").unwrap();
+
+        assert_eq!(
+            String::from_utf8(escaped).unwrap(),
+            r#""a \"quoted\"\\\n\u0001é""#
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::convert::TryInto;
+use std::fs::*;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::header::Header;
+use crate::metadata::HEADER_METADATA;
+
+use super::{segment_filename, HEADER_EOF};
+
+/// The value that ends a segment footer.
+const FOOTER_MAGIC: [u8; 4] = *b"yqft";
+
+/// The length of a segment footer, in bytes.
+const FOOTER_LEN: usize = 16;
+
+/// The footer of a closed segment. When the sender moves on to a new segment,
+/// it writes this footer right after the EOF header of the old segment:
+/// ```text
+/// [elements...] [EOF header] [items (u64)] [checksum (u32)] [magic]
+/// ```
+/// Receivers stop reading a segment at its EOF header, so the footer is
+/// invisible to them (and to older versions of `yaque`). Segments closed by
+/// older versions have no footer. Use [`verify_segment`] to check a closed
+/// segment in one pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFooter {
+    /// The number of elements in the segment.
+    pub items: u64,
+    /// The CRC32 of the segment up to its EOF header, excluded.
+    pub checksum: u32,
+}
+
+impl SegmentFooter {
+    /// Encodes this footer into bytes.
+    fn encode(&self) -> [u8; FOOTER_LEN] {
+        let mut encoded = [0; FOOTER_LEN];
+        encoded[..8].copy_from_slice(&self.items.to_be_bytes());
+        encoded[8..12].copy_from_slice(&self.checksum.to_be_bytes());
+        encoded[12..].copy_from_slice(&FOOTER_MAGIC);
+        encoded
+    }
+
+    /// Decodes the footer at the end of a segment, if there is one.
+    pub(crate) fn decode(segment: &[u8]) -> Option<SegmentFooter> {
+        let len = segment.len();
+
+        if len < FOOTER_LEN + 4
+            || segment[len - 4..] != FOOTER_MAGIC
+            || segment[len - FOOTER_LEN - 4..len - FOOTER_LEN] != HEADER_EOF
+        {
+            return None;
+        }
+
+        let footer = &segment[len - FOOTER_LEN..];
+        Some(SegmentFooter {
+            items: u64::from_be_bytes(footer[..8].try_into().expect("8 bytes")),
+            checksum: u32::from_be_bytes(footer[8..12].try_into().expect("4 bytes")),
+        })
+    }
+}
+
+/// Keeps track of what was written to the current segment, so that the
+/// sender can write its footer when moving on.
+#[derive(Clone, Default)]
+pub(crate) struct FooterBuilder {
+    /// The number of elements written so far.
+    items: u64,
+    /// The checksum of what was written so far.
+    hasher: crc32fast::Hasher,
+    /// The number of bytes written so far.
+    pub(crate) position: u64,
+}
+
+impl FooterBuilder {
+    /// Starts tracking a segment from what was already written to it, up to
+    /// `len`.
+    pub(crate) fn scan<R: Read>(mut segment: R, len: u64) -> io::Result<FooterBuilder> {
+        let mut written = vec![0; len as usize];
+        segment.read_exact(&mut written)?;
+
+        let mut builder = FooterBuilder::default();
+        builder.update(&written);
+
+        // Count the elements, walking the headers:
+        let mut position = 0;
+        while position + 4 <= written.len() {
+            let mut header = read_header(&written, position)?;
+            position += 4;
+
+            if header == HEADER_METADATA {
+                header = read_header(&written, position)?;
+                position += 4 + Header::decode(header).len() as usize;
+                header = read_header(&written, position)?;
+                position += 4;
+            }
+
+            position += Header::decode(header).len() as usize;
+            builder.items += 1;
+        }
+
+        Ok(builder)
+    }
+
+    /// Tracks some bytes written to the segment.
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.position += bytes.len() as u64;
+    }
+
+    /// Tracks an element written to the segment (after its bytes).
+    pub(crate) fn item_done(&mut self) {
+        self.items += 1;
+    }
+
+    /// Encodes the footer of everything tracked so far.
+    pub(crate) fn finish(&self) -> [u8; FOOTER_LEN] {
+        SegmentFooter {
+            items: self.items,
+            checksum: self.hasher.clone().finalize(),
+        }
+        .encode()
+    }
+}
+
+/// Reads a header from a segment in memory.
+fn read_header(segment: &[u8], position: usize) -> io::Result<[u8; 4]> {
+    segment
+        .get(position..position + 4)
+        .map(|header| header.try_into().expect("4 bytes"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated segment"))
+}
+
+/// Verifies a closed segment of a queue against its footer, in one pass and
+/// without decoding the headers of its elements. Returns the footer if the
+/// segment is fine or `None` if the segment has no footer (e.g., because it
+/// is still being written to).
+///
+/// # Errors
+///
+/// This function returns an error of kind [`io::ErrorKind::InvalidData`] if
+/// the segment does not match its footer. It also returns any underlying
+/// errors encountered while reading the segment.
+pub fn verify_segment<P: AsRef<Path>>(base: P, segment: u64) -> io::Result<Option<SegmentFooter>> {
+    let contents = read(segment_filename(base.as_ref(), segment))?;
+
+    let footer = if let Some(footer) = SegmentFooter::decode(&contents) {
+        footer
+    } else {
+        return Ok(None);
+    };
+
+    let checksum = crc32fast::hash(&contents[..contents.len() - FOOTER_LEN - 4]);
+
+    if checksum == footer.checksum {
+        Ok(Some(footer))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "segment {} of {:?} does not match its footer",
+                segment,
+                base.as_ref()
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_footer() {
+        let footer = SegmentFooter {
+            items: 42,
+            checksum: 0xdead_beef,
+        };
+
+        let mut segment = b"some elements".to_vec();
+        assert_eq!(SegmentFooter::decode(&segment), None);
+
+        segment.extend_from_slice(&HEADER_EOF);
+        segment.extend_from_slice(&footer.encode());
+        assert_eq!(SegmentFooter::decode(&segment), Some(footer));
+    }
+}
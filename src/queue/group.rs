@@ -0,0 +1,110 @@
+use std::fs::*;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::state::{recv_persistence_exists, QueueState, QueueStatePersistence};
+
+use super::{is_valid_name, try_acquire_recv_lock};
+
+/// The prefix of the folders of the consumer groups in the queue folder.
+const GROUP_PREFIX: &str = "group-";
+
+/// The name of the folder of a consumer group in the queue folder, which holds
+/// the lock and the state of the receivers of the group. The segments are
+/// those of the queue itself.
+pub(crate) fn group_dirname<P: AsRef<Path>>(base: P, group: &str) -> PathBuf {
+    base.as_ref().join(format!("{}{}", GROUP_PREFIX, group))
+}
+
+/// Lists the consumer groups of a queue, in alphabetical order. A group exists
+/// from the first time a receiver of the group is opened until it is removed
+/// with [`remove_consumer_group`]. See
+/// [`ReceiverBuilder::consumer_group`](crate::ReceiverBuilder::consumer_group).
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while listing the
+/// queue folder. A queue that does not exist has no groups.
+pub fn consumer_groups<P: AsRef<Path>>(base: P) -> io::Result<Vec<String>> {
+    let read_dir = match read_dir(base.as_ref()) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let mut groups = vec![];
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        let group = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(GROUP_PREFIX));
+
+        if let Some(group) = group.filter(|&group| is_valid_name(group)) {
+            if dir_entry.file_type()?.is_dir() {
+                groups.push(group.to_owned());
+            }
+        }
+    }
+    groups.sort_unstable();
+
+    Ok(groups)
+}
+
+/// Removes a consumer group from a queue, with its state, so that it no longer
+/// holds back the removal of the segments it did not read yet. Opening a
+/// receiver of the group again starts it over from the oldest segment left.
+/// Removing a group that does not exist does nothing.
+///
+/// # Errors
+///
+/// This function returns an error if a receiver of the group is in use, which
+/// is indicated by its lock file. Also, it returns any underlying errors
+/// encountered while removing the folder of the group.
+pub fn remove_consumer_group<P: AsRef<Path>>(base: P, group: &str) -> io::Result<()> {
+    let dirname = group_dirname(base, group);
+    if !dirname.exists() {
+        return Ok(());
+    }
+
+    let mut recv_lock = try_acquire_recv_lock(&dirname, None)?;
+
+    // Sets the the lock to ignore when its file magically disappears.
+    recv_lock.ignore();
+
+    remove_dir_all(dirname)
+}
+
+/// The saved states of the receivers of a queue other than the receivers of
+/// a given group (or than the receiver without a group, for `None`). The
+/// receiver without a group only counts once it has saved a state, so that
+/// queues used only by groups are not held back by it.
+pub(crate) fn other_cursors(base: &Path, group: Option<&str>) -> io::Result<Vec<QueueState>> {
+    let mut cursors = vec![];
+
+    if group.is_some() && recv_persistence_exists(base) {
+        cursors.push(QueueStatePersistence::new().open(base)?);
+    }
+
+    for other in consumer_groups(base)? {
+        if Some(other.as_str()) != group {
+            // (a group that never saved a state is still at the start)
+            cursors.push(QueueStatePersistence::new().open(group_dirname(base, &other))?);
+        }
+    }
+
+    Ok(cursors)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_names() {
+        assert!(is_valid_name("billing_v2-eu"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("../escape"));
+        assert!(!is_valid_name(&"a".repeat(65)));
+    }
+}
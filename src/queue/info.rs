@@ -0,0 +1,384 @@
+use std::cmp::Ordering;
+use std::fs::*;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::header::Header;
+use crate::metadata::{Metadata, HEADER_METADATA};
+use crate::state::{QueueState, QueueStatePersistence};
+use crate::storage::{self, FileStorage, SegmentRead, Storage};
+
+use super::HEADER_EOF;
+
+/// How much is waiting in a queue to be received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueInfo {
+    /// The number of elements waiting to be received.
+    pub items: u64,
+    /// The number of bytes taken by the elements waiting to be received,
+    /// including their headers and metadata.
+    pub bytes: u64,
+}
+
+/// The disk space used by a queue, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiskUsage {
+    /// The bytes in the segments that are waiting to be received.
+    pub live: u64,
+    /// The bytes in the segments that were already received, but that were not
+    /// removed yet (segments are only removed when completely received).
+    pub consumed: u64,
+    /// The bytes in the other files of the queue folder, such as the state of
+    /// the receiver and the locks.
+    pub overhead: u64,
+}
+
+impl DiskUsage {
+    /// The total disk space used by the queue, in bytes.
+    pub fn total(&self) -> u64 {
+        self.live + self.consumed + self.overhead
+    }
+}
+
+/// Gets the disk space used by a queue, given the state saved by the receiver.
+/// Since the receiver saves its state from time to time, some of the bytes
+/// reported as live may have already been received. Priority lanes are queues
+/// of their own and are not included.
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while loading the
+/// state of the receiver or while listing the queue folder.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+pub fn queue_disk_usage<P: AsRef<Path>>(base: P) -> io::Result<DiskUsage> {
+    let state = QueueStatePersistence::new().open(base.as_ref())?;
+    let mut usage = DiskUsage::default();
+
+    for maybe_entry in read_dir(base.as_ref())? {
+        let entry = maybe_entry?;
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+
+        if !metadata.is_file() {
+            continue;
+        }
+
+        if path.extension().map(|ext| ext == "q").unwrap_or(false) {
+            let segment = path
+                .file_stem()
+                .expect("has extension, therefore has stem")
+                .to_string_lossy()
+                .parse::<u64>()
+                .expect("failed to parse segment filename");
+
+            let consumed = match segment.cmp(&state.segment) {
+                Ordering::Less => metadata.len(),
+                Ordering::Equal => u64::min(state.position, metadata.len()),
+                Ordering::Greater => 0,
+            };
+
+            usage.consumed += consumed;
+            usage.live += metadata.len() - consumed;
+        } else {
+            usage.overhead += metadata.len();
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Gets how much is waiting in a queue to be received, from the state saved
+/// by the receiver to the end of what was written so far. Since the receiver
+/// saves its state from time to time, this can be more than what is actually
+/// waiting.
+///
+/// This walks the headers of the elements, but does not read them. Elements
+/// that are not due yet or that have expired are also counted.
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while loading the
+/// state of the receiver or while reading the segments.
+pub fn queue_info<P: AsRef<Path>>(base: P) -> io::Result<QueueInfo> {
+    let state = QueueStatePersistence::new().open(base.as_ref())?;
+    queue_info_from(&FileStorage::new(base), state)
+}
+
+/// Gets how much is waiting in a queue to be received from a given state. See
+/// [`queue_info`].
+pub(crate) fn queue_info_from(storage: &dyn Storage, state: QueueState) -> io::Result<QueueInfo> {
+    walk_queue_from(storage, state, |_size| true).map(|(info, _end)| info)
+}
+
+/// Tells whether anything is waiting in a queue to be received, from the state
+/// saved by the receiver. Since the receiver saves its state from time to
+/// time, this can be `true` when everything was actually received.
+///
+/// Unlike [`queue_info`], this does not walk the queue: it compares the state
+/// with the lengths of the segments, reading at most one header (to tell an
+/// element from the end of a closed segment). Elements that are not due yet
+/// or that have expired are also counted and priority lanes are not looked
+/// at.
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while loading the
+/// state of the receiver or while listing the segments.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+pub fn has_pending<P: AsRef<Path>>(base: P) -> io::Result<bool> {
+    let state = QueueStatePersistence::new().open(base.as_ref())?;
+    has_pending_from(&FileStorage::new(base), state)
+}
+
+/// Tells whether anything was written after a given state. See
+/// [`has_pending`].
+pub(crate) fn has_pending_from(storage: &dyn Storage, state: QueueState) -> io::Result<bool> {
+    // (segments may be removed by the receiver in the meantime)
+    let segments = storage::segment_lens(storage)?;
+    let newest = segments.last().map(|&(segment, _len)| segment);
+
+    for (segment, len) in segments
+        .into_iter()
+        .filter(|&(segment, _len)| segment >= state.segment)
+    {
+        if segment > state.segment {
+            if len > 0 {
+                return Ok(true);
+            }
+        } else if Some(segment) == newest {
+            // (an element whose header is not complete is not there yet)
+            return Ok(len >= state.position + 4);
+        } else if len >= state.position + 4 {
+            // What is left of a closed segment may be just its end:
+            let mut file = match storage.peek(segment)? {
+                Some(file) => file,
+                None => continue,
+            };
+            file.seek(io::SeekFrom::Start(state.position))?;
+            let mut header = [0; 4];
+            file.read_exact(&mut header)?;
+
+            if header != HEADER_EOF {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Gets how long the element at the front of a queue has been waiting to be
+/// received, from the state saved by the receiver. Since the receiver saves
+/// its state from time to time, the element may have been received already.
+/// This is `None` if the queue is empty or if the element was sent without a
+/// timestamp (see [`SenderBuilder::timestamp`](super::SenderBuilder::timestamp)).
+///
+/// Expired elements are skipped, as the receiver does, and so are the chunks
+/// of elements whose start is gone (see
+/// [`SenderBuilder::split_items`](super::SenderBuilder::split_items)), but
+/// duplicates and elements too old for a receiver are counted.
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while loading the
+/// state of the receiver or while reading the element, and an error of kind
+/// [`io::ErrorKind::InvalidData`] if its metadata is corrupted.
+pub fn oldest_item_age<P: AsRef<Path>>(base: P) -> io::Result<Option<Duration>> {
+    let state = QueueStatePersistence::new().open(base.as_ref())?;
+    oldest_item_age_from(&FileStorage::new(base), state, |_metadata| false)
+}
+
+/// Gets how long the first element from a given state has been waiting,
+/// skipping also the elements for which `skip` returns `true`. See
+/// [`oldest_item_age`].
+pub(crate) fn oldest_item_age_from<F>(
+    storage: &dyn Storage,
+    mut state: QueueState,
+    skip: F,
+) -> io::Result<Option<Duration>>
+where
+    F: Fn(&Metadata) -> bool,
+{
+    state = QueueState {
+        segment: storage::surviving_segment(storage, state.segment)?,
+        ..state
+    };
+
+    loop {
+        let (file, len) = match open_segment(storage, state.segment)? {
+            Some(opened) => opened,
+            None => return Ok(None),
+        };
+        let mut file = BufReader::new(file);
+        file.seek(io::SeekFrom::Start(state.position))?;
+
+        // Finds the first element that is not skipped in the segment:
+        loop {
+            // Nothing was written after the state yet:
+            let mut header = [0; 4];
+            if state.position + 4 > len {
+                return Ok(None);
+            }
+            file.read_exact(&mut header)?;
+
+            // The element is in the next segment:
+            if header == HEADER_EOF {
+                break;
+            }
+
+            // Only elements with metadata can have a timestamp:
+            if header != HEADER_METADATA || state.position + 8 > len {
+                return Ok(None);
+            }
+            file.read_exact(&mut header)?;
+
+            let mut encoded = vec![0; Header::decode(header).len() as usize];
+            if state.position + 12 + encoded.len() as u64 > len {
+                return Ok(None);
+            }
+            file.read_exact(&mut encoded)?;
+            let metadata = Metadata::decode(&encoded)?;
+
+            if metadata.is_expired() || metadata.is_continuation || skip(&metadata) {
+                file.read_exact(&mut header)?;
+                let data_len = Header::decode(header).len() as u64;
+                file.seek_relative(data_len as i64)?;
+                state.advance_position(12 + encoded.len() as u64 + data_len);
+                continue;
+            }
+
+            let age = metadata.enqueued_at.map(|enqueued_at| {
+                SystemTime::now()
+                    .duration_since(enqueued_at)
+                    .unwrap_or_default()
+            });
+
+            return Ok(age);
+        }
+
+        state.advance_segment();
+    }
+}
+
+/// Walks a queue from a given state to the end of what was written so far,
+/// returning how much was walked and the state at the end. The walk stops
+/// early, just before an element, when `visit` returns `false` for the size
+/// of the element. Elements split over segments are walked as one element.
+pub(crate) fn walk_queue_from<F>(
+    storage: &dyn Storage,
+    mut state: QueueState,
+    mut visit: F,
+) -> io::Result<(QueueInfo, QueueState)>
+where
+    F: FnMut(u64) -> bool,
+{
+    let mut info = QueueInfo::default();
+    // The start and the size so far of an element split over segments:
+    let mut chunked: Option<(QueueState, u64)> = None;
+    // (an element split over segments is only walked once it is whole)
+    let end = |state: QueueState, chunked: Option<(QueueState, u64)>| {
+        chunked.map_or(state, |(start, _)| start)
+    };
+
+    loop {
+        let (file, len) = match open_segment(storage, state.segment)? {
+            Some(opened) => opened,
+            None => return Ok((info, end(state, chunked))),
+        };
+        let mut file = BufReader::new(file);
+        file.seek(io::SeekFrom::Start(state.position))?;
+
+        // Walks the segment, returning at the end of what was written so far:
+        loop {
+            let mut header = [0; 4];
+            if state.position + 4 > len {
+                return Ok((info, end(state, chunked)));
+            }
+            file.read_exact(&mut header)?;
+
+            // The rest of the queue is in the next segment:
+            if header == HEADER_EOF {
+                break;
+            }
+
+            // Read the metadata, if any:
+            let mut size = 4;
+            let mut metadata = Metadata::default();
+            if header == HEADER_METADATA {
+                if state.position + 12 > len {
+                    return Ok((info, end(state, chunked)));
+                }
+                file.read_exact(&mut header)?;
+                let mut encoded = vec![0; Header::decode(header).len() as usize];
+                size += 8 + encoded.len() as u64;
+
+                if state.position + size > len {
+                    return Ok((info, end(state, chunked)));
+                }
+                file.read_exact(&mut encoded)?;
+                file.read_exact(&mut header)?;
+                metadata = Metadata::decode(&encoded)?;
+            }
+
+            let data_len = Header::decode(header).len() as u64;
+            size += data_len;
+
+            if state.position + size > len {
+                return Ok((info, end(state, chunked)));
+            }
+            file.seek_relative(data_len as i64)?;
+
+            // Elements split over segments count once, as a whole:
+            let (start, total) = match (chunked.take(), metadata.is_continuation) {
+                (Some((start, so_far)), true) => (start, so_far + size),
+                // (the start of the element is gone)
+                (None, true) => {
+                    state.advance_position(size);
+                    continue;
+                }
+                // (the rest of the element, if any, is gone)
+                (_, false) => (state, size),
+            };
+
+            if metadata.more_chunks {
+                chunked = Some((start, total));
+                state.advance_position(size);
+                continue;
+            }
+
+            if !visit(total) {
+                return Ok((info, start));
+            }
+
+            info.items += 1;
+            info.bytes += total;
+            state.advance_position(size);
+        }
+
+        state.advance_segment();
+    }
+}
+
+/// Opens a segment for walking it, together with its length, or returns `None`
+/// if the segment does not exist.
+fn open_segment(
+    storage: &dyn Storage,
+    segment: u64,
+) -> io::Result<Option<(Box<dyn SegmentRead>, u64)>> {
+    let mut file = match storage.peek(segment)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let len = file.seek(io::SeekFrom::End(0))?;
+
+    Ok(Some((file, len)))
+}
@@ -1,14 +1,21 @@
 use std::fs::*;
 use std::io::{self};
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::encryption::KeyProvider;
+use crate::error::Corruption;
 use crate::header::Header;
+use crate::metadata::{Metadata, HEADER_METADATA};
+use crate::storage::{FileStorage, Storage};
 use crate::sync::{FileGuard, SyncFollower};
+use crate::transform::{self, Codec};
 use crate::version::check_queue_version;
 use crate::state::{QueueStatePersistence, QueueState};
 
-use super::try_acquire_recv_lock;
-use super::{segment_filename, HEADER_EOF};
+use super::{try_acquire_recv_lock, Receiver};
+use super::HEADER_EOF;
 
 /// An [`Iterator`] that iterates over the elements of the queue, until it hts
 /// the end for the first time. Use this structure instead of
@@ -18,17 +25,20 @@ use super::{segment_filename, HEADER_EOF};
 /// 
 /// 1. The API is synchronous, since there is no need to wait for new elements.
 /// 2. There is no transactional mechanism involved, since there is no need for
-/// one and, because of this,
+///    one and, because of this,
 /// 3. Elements are not buffered in memory, as opposed to what
-/// [`crate::Receiver::recv_batch`] does.
+///    [`crate::Receiver::recv_batch`] does.
 /// 
 /// And you also get some extra percents of performance from a simpler
 /// implementation. Don't pay for what you don't use!
+///
+/// Since it only reads what is stored, the iterator also yields elements that
+/// are not due yet (see [`crate::Sender::send_after`]) or that have expired
+/// (see [`crate::Sender::send_with_ttl`]). Checksums (see
+/// [`crate::SenderBuilder::checksum`]) are always verified.
 pub struct QueueIter {
     _file_guard: FileGuard,
-    base: PathBuf,
-    state: QueueState,
-    sync_follower: SyncFollower,
+    cursor: Cursor,
 }
 
 impl QueueIter {
@@ -55,23 +65,192 @@ impl QueueIter {
         check_queue_version(base.as_ref())?;
 
         // Acquire guard and state:
-        let file_guard = try_acquire_recv_lock(base.as_ref())?;
+        let file_guard = try_acquire_recv_lock(base.as_ref(), None)?;
         let mut persistence = QueueStatePersistence::new();
         let state = persistence.open(base.as_ref())?;
 
         log::trace!("receiver lock acquired. Iter state now is {:?}", state);
 
+        Ok(QueueIter {
+            _file_guard: file_guard,
+            cursor: Cursor::open(base.as_ref(), Arc::new(FileStorage::new(base.as_ref())), state)?,
+        })
+    }
+
+    /// Adds a codec on top of the codecs to decode the elements with. See
+    /// [`crate::Codec`].
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> QueueIter {
+        self.cursor.codecs.push(codec);
+        self
+    }
+
+    /// Sets the provider of the keys to decrypt the elements with. See
+    /// [`crate::KeyProvider`].
+    #[cfg(feature = "encryption")]
+    pub fn key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> QueueIter {
+        self.cursor.key_provider = Some(key_provider);
+        self
+    }
+}
+
+impl Iterator for QueueIter {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        self.cursor.next()
+    }
+}
+
+/// An [`Iterator`] that walks forward over the elements of the queue from the
+/// current position of a [`Receiver`], until it hits the end for the first
+/// time. See [`Receiver::browse`].
+///
+/// Browsing neither commits nor advances the state of the receiver. As with
+/// [`QueueIter`], elements that are not due yet or that have expired are also
+/// yielded.
+pub struct Browse<'a> {
+    cursor: Cursor,
+    _receiver: PhantomData<&'a Receiver>,
+}
+
+impl<'a> Browse<'a> {
+    /// Starts browsing a queue at a given state. The caller must guarantee
+    /// that nobody receives from the queue in the meantime.
+    pub(crate) fn new(
+        base: &Path,
+        storage: Arc<dyn Storage>,
+        state: QueueState,
+        codecs: Vec<Arc<dyn Codec>>,
+        key_provider: Option<Arc<dyn KeyProvider>>,
+    ) -> io::Result<Browse<'a>> {
+        let mut cursor = Cursor::open(base, storage, state)?;
+        cursor.codecs = codecs;
+        cursor.key_provider = key_provider;
+
+        Ok(Browse {
+            cursor,
+            _receiver: PhantomData,
+        })
+    }
+}
+
+impl<'a> Iterator for Browse<'a> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        self.cursor.next()
+    }
+}
+
+/// An [`Iterator`] that replays the elements of a queue from the begining of
+/// the oldest segment still present in the queue folder, until it hits the end
+/// for the first time. This includes the elements of that segment that were
+/// already received, but not the segments that were already removed.
+///
+/// Replaying takes no locks, never removes segments and never changes the
+/// state of the receiver, so it can be done while the queue is in use, e.g.,
+/// to reprocess the data after a bug fix. However, a replay can fail with an
+/// IO error if the receiver removes the next segment before the replay gets
+/// to it. As with [`QueueIter`], elements that are not due yet or that have
+/// expired are also yielded.
+pub struct Replay {
+    cursor: Cursor,
+}
+
+impl Replay {
+    /// Opens a queue for replaying from the oldest segment present in it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while listing
+    /// the queue folder or while opening the oldest segment.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if there is a file in the queue folder with extension
+    /// `.q` whose name is not an integer, such as `foo.q`.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Replay> {
+        // Guarantee that the queue exists:
+        create_dir_all(base.as_ref())?;
+        check_queue_version(base.as_ref())?;
+
+        // Find smallest segment:
+        let mut min_segment = None;
+        for maybe_entry in read_dir(base.as_ref())? {
+            let path = maybe_entry?.path();
+            if path.extension().map(|ext| ext == "q").unwrap_or(false) {
+                let segment = path
+                    .file_stem()
+                    .expect("has extension, therefore has stem")
+                    .to_string_lossy()
+                    .parse::<u64>()
+                    .expect("failed to parse segment filename");
+
+                min_segment = Some(min_segment.map_or(segment, |min| u64::min(segment, min)));
+            }
+        }
+
+        let state = QueueState {
+            segment: min_segment.unwrap_or_default(),
+            position: 0,
+        };
+
+        log::trace!("replaying {:?} from {:?}", base.as_ref(), state);
+
+        Ok(Replay {
+            cursor: Cursor::open(base.as_ref(), Arc::new(FileStorage::new(base.as_ref())), state)?,
+        })
+    }
+
+    /// Adds a codec on top of the codecs to decode the elements with. See
+    /// [`crate::Codec`].
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> Replay {
+        self.cursor.codecs.push(codec);
+        self
+    }
+
+    /// Sets the provider of the keys to decrypt the elements with. See
+    /// [`crate::KeyProvider`].
+    #[cfg(feature = "encryption")]
+    pub fn key_provider(mut self, key_provider: Arc<dyn KeyProvider>) -> Replay {
+        self.cursor.key_provider = Some(key_provider);
+        self
+    }
+}
+
+impl Iterator for Replay {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        self.cursor.next()
+    }
+}
+
+/// Reads the elements of a queue synchronously, from a given state.
+struct Cursor {
+    base: PathBuf,
+    storage: Arc<dyn Storage>,
+    state: QueueState,
+    sync_follower: SyncFollower,
+    codecs: Vec<Arc<dyn Codec>>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+}
+
+impl Cursor {
+    fn open(base: &Path, storage: Arc<dyn Storage>, state: QueueState) -> io::Result<Cursor> {
         // Put the needle on the groove (oh! the 70's):
-        let mut sync_follower = SyncFollower::open(segment_filename(base.as_ref(), state.segment))?;
+        let mut sync_follower = SyncFollower::open(&*storage, state.segment)?;
         sync_follower.seek(io::SeekFrom::Start(state.position))?;
 
         log::trace!("last segment opened fo reading");
 
-        Ok(QueueIter {
-            _file_guard: file_guard,
+        Ok(Cursor {
             state,
-            base: PathBuf::from(base.as_ref()),
+            base: PathBuf::from(base),
+            storage,
             sync_follower,
+            codecs: Vec::new(),
+            key_provider: None,
         })
     }
 
@@ -86,14 +265,15 @@ impl QueueIter {
         log::debug!("advanced segment from {:?} to {:?}", current_segment, next_segment);
 
         log::debug!("opening segment {}", next_segment);
-        self.sync_follower =
-            SyncFollower::open(segment_filename(&self.base, next_segment))?;
+        self.sync_follower = SyncFollower::open(&*self.storage, next_segment)?;
 
         Ok(())
     }
 
-    /// Reads the header. This operation is atomic.
-    fn read_header(&mut self) -> io::Result<Header> {
+    /// Reads the header, together with the metadata of the element, if any.
+    /// Returns also the state where the element starts. This operation is
+    /// atomic.
+    fn read_header(&mut self) -> io::Result<(QueueState, Metadata, Header)> {
         // Read header:
         let mut header = [0; 4];
         self.sync_follower.read_exact(&mut header)?;
@@ -108,31 +288,83 @@ impl QueueIter {
             self.sync_follower.read_exact(&mut header)?;
         }
 
+        let started_at = self.state;
+        self.state.advance_position(4);
+
+        // Read the metadata, if any:
+        let metadata = if header == HEADER_METADATA {
+            self.sync_follower.read_exact(&mut header)?;
+            let mut encoded = vec![0; Header::decode(header).len() as usize];
+            self.sync_follower.read_exact(&mut encoded)?;
+            self.sync_follower.read_exact(&mut header)?;
+            self.state.advance_position(8 + encoded.len() as u64);
+            Metadata::decode(&encoded)?
+        } else {
+            Metadata::default()
+        };
+
         // Now, you set the header!
         let decoded = Header::decode(header);
 
         log::trace!("got header {:?} (read {} bytes)", header, decoded.len());
 
-        Ok(decoded)
-
+        Ok((started_at, metadata, decoded))
     }
 
-    /// Reads one element from the queue.
-    fn read_one(&mut self) -> io::Result<Vec<u8>> {
+    /// Reads the next element (or chunk of an element) as it is in the
+    /// segment. Returns also the state where it starts and its metadata.
+    fn read_element(&mut self) -> io::Result<(QueueState, Metadata, Vec<u8>)> {
         // Get the length:
-        let header = self.read_header()?;
+        let (started_at, metadata, header) = self.read_header()?;
 
         // With the length, read the data:
         let mut data = vec![0; header.len() as usize];
         self.sync_follower
             .read_exact(&mut data)
             .expect("poisoned queue");
+        self.state.advance_position(data.len() as u64);
 
-        Ok(data)
+        Ok((started_at, metadata, data))
+    }
+
+    /// Reads one element from the queue.
+    fn read_one(&mut self) -> io::Result<Vec<u8>> {
+        let (mut started_at, mut metadata, mut data) = self.read_element()?;
+
+        // Put the chunks of an element split over segments back together:
+        while metadata.is_continuation || metadata.more_chunks {
+            let (chunk_at, chunk_metadata, chunk) = self.read_element()?;
+
+            if metadata.is_continuation {
+                log::warn!("skipping a chunk of an element whose start is gone");
+            } else if chunk_metadata.is_continuation {
+                data.extend_from_slice(&chunk);
+                metadata.more_chunks = chunk_metadata.more_chunks;
+                continue;
+            } else {
+                log::warn!("discarding an element cut short at {:?}", started_at);
+            }
+
+            started_at = chunk_at;
+            metadata = chunk_metadata;
+            data = chunk;
+        }
+
+        if !metadata.matches_checksum(&data) {
+            return Err(Corruption {
+                base: self.base.clone(),
+                segment: started_at.segment,
+                position: started_at.position,
+            }
+            .into());
+        }
+
+        let data = metadata.decode_payload(data, self.key_provider.as_deref())?;
+        transform::decode(&self.codecs, data)
     }
 }
 
-impl Iterator for QueueIter {
+impl Iterator for Cursor {
     type Item = io::Result<Vec<u8>>;
 
     fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
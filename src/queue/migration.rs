@@ -0,0 +1,146 @@
+use std::fs::*;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::header::Header;
+use crate::metadata::HEADER_METADATA;
+use crate::version::{read_format_version, write_format_version, FORMAT_VERSION};
+
+use super::footer::{FooterBuilder, SegmentFooter};
+use super::receiver::try_acquire_recv_lock;
+use super::sender::try_acquire_send_lock;
+use super::{lane_priority, list_segments, segment_filename, HEADER_EOF};
+
+/// A step upgrading a queue from one format to the next one.
+type Migration = fn(&Path) -> io::Result<()>;
+
+/// The migrations between formats. The migration at index `i` upgrades a
+/// queue from format `i + 1` to format `i + 2`.
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize - 1] = [add_segment_footers];
+
+/// Upgrades a queue written by an older version of `yaque` to the current
+/// [`crate::FORMAT_VERSION`], in place. Priority lanes are upgraded as well.
+/// Returns the format the queue was in before (queues already in the current
+/// format are left untouched).
+///
+/// Queues of formats older than the current one can still be opened, but may
+/// miss some of the newer features. For example, segments closed before the
+/// segment footers were introduced cannot be checked with
+/// [`super::verify_segment`] until the queue is migrated.
+///
+/// # Errors
+///
+/// This function fails if the queue is in use either for sending or
+/// receiving. It also fails with an error of kind
+/// [`io::ErrorKind::InvalidData`] if the queue is of a newer format than the
+/// current one or if a segment is corrupted. Any underlying errors
+/// encountered while upgrading are also returned.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+pub fn migrate_queue<P: AsRef<Path>>(base: P) -> io::Result<u32> {
+    let base = base.as_ref();
+    let _send_lock = try_acquire_send_lock(base, None)?;
+    let _recv_lock = try_acquire_recv_lock(base, None)?;
+
+    let format = read_format_version(base)?;
+
+    if format > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "queue `{:?}` is of format {}, which is newer than {}",
+                base, format, FORMAT_VERSION
+            ),
+        ));
+    }
+
+    for migration in &MIGRATIONS[format as usize - 1..] {
+        migration(base)?;
+    }
+
+    if format < FORMAT_VERSION {
+        write_format_version(base, FORMAT_VERSION)?;
+        log::info!(
+            "migrated queue {:?} from format {} to {}",
+            base,
+            format,
+            FORMAT_VERSION
+        );
+    }
+
+    // Priority lanes are queues of their own:
+    for maybe_entry in read_dir(base)? {
+        let path = maybe_entry?.path();
+        if lane_priority(&path).is_some() {
+            migrate_queue(path)?;
+        }
+    }
+
+    Ok(format)
+}
+
+/// Format `1` to `2`: writes a footer after the EOF header of the closed
+/// segments.
+fn add_segment_footers(base: &Path) -> io::Result<()> {
+    for segment in list_segments(base)? {
+        let path = segment_filename(base, segment);
+        let contents = read(&path)?;
+
+        if SegmentFooter::decode(&contents).is_some() {
+            continue;
+        }
+
+        if let Some(eof) = find_eof(&contents).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("segment {} of {:?} is corrupted", segment, base),
+            )
+        })? {
+            // Only closed segments get a footer:
+            if eof + 4 == contents.len() {
+                let footer = FooterBuilder::scan(File::open(&path)?, eof as u64)?.finish();
+                let mut file = OpenOptions::new().append(true).open(&path)?;
+                file.write_all(&footer)?;
+                file.sync_all()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Walks the headers of a segment, returning the position of its EOF header,
+/// if it was written. Returns `None` if a header is corrupted.
+fn find_eof(contents: &[u8]) -> Option<Option<usize>> {
+    let read_header = |position: usize| {
+        contents
+            .get(position..position + 4)
+            .map(|header| [header[0], header[1], header[2], header[3]])
+    };
+
+    let mut position = 0;
+    while let Some(mut header) = read_header(position) {
+        if header == HEADER_EOF {
+            return Some(Some(position));
+        }
+
+        if header == HEADER_METADATA {
+            let metadata_header = match read_header(position + 4) {
+                Some(header) => header,
+                None => return Some(None),
+            };
+            position += 8 + Header::try_decode(metadata_header)?.len() as usize;
+            header = match read_header(position) {
+                Some(header) => header,
+                None => return Some(None),
+            };
+        }
+
+        position += 4 + Header::try_decode(header)?.len() as usize;
+    }
+
+    Some(None)
+}
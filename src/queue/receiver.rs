@@ -1,43 +1,162 @@
+use bytes::{Bytes, BytesMut};
 use futures::future;
 use futures::FutureExt;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::*;
 use std::future::Future;
-use std::io::{self};
+use std::io::{self, BufReader, Read};
+use std::mem;
+use std::num::NonZeroU64;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, Instant};
-
-use crate::error::TryRecvError;
-use crate::header::Header;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "serde")]
+use crate::codec::WireFormat;
+use crate::error::{Corruption, TryRecvError, TrySendError};
+#[cfg(feature = "failpoints")]
+use crate::failpoints::{self, Failpoint};
+use crate::header::{self, Header};
+use crate::encryption::KeyProvider;
+use crate::metadata::{Metadata, HEADER_METADATA};
+use crate::sharing;
+#[cfg(unix)]
+use crate::sharing::FilePermissions;
 use crate::state::QueueState;
-use crate::state::QueueStatePersistence;
-use crate::sync::{FileGuard, TailFollower};
+use crate::state::{Deliveries, SeenIds};
+use crate::storage::{self, FileStorage, Storage};
+use crate::sync::{ChangeEvent, CreationEvent, FileGuard, TailFollower};
+use crate::telemetry::{default_metrics, QueueMetrics};
+use crate::timer::Delay;
+use crate::transform::{self, Codec};
 use crate::version::check_queue_version;
 
-use super::{segment_filename, HEADER_EOF};
+use super::group::{group_dirname, other_cursors};
+use super::info::{has_pending_from, oldest_item_age_from, queue_info_from, walk_queue_from};
+use super::settings::QueueSettings;
+use super::topic::{check_topic, topic_dirname};
+use super::{
+    is_valid_name, lane_dirname, lane_priority, Browse, QueueInfo, RecvStream, Sender,
+    SenderBuilder, HEADER_EOF,
+};
+
+/// A hook called with the queue folder, the number and the contents of every
+/// segment the receiver consumed, before deleting it. See
+/// [`ReceiverBuilder::on_segment_consumed`].
+type SegmentConsumedHook = Arc<dyn Fn(&Path, u64, &mut dyn Read) -> io::Result<()> + Send + Sync>;
+
+/// How long [`Receiver::wait_for_depth`] waits for the sender before
+/// checking the queue again, in case a change was missed.
+const RECHECK_DEPTH_EVERY: Duration = Duration::from_millis(100);
+
+/// The default size of the buffer the segments are read through, which is
+/// the default of [`BufReader`].
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The capacity of the buffer the segments are actually read through (a
+/// memory map needs no buffer).
+fn read_buffer_capacity(read_buffer_size: usize, mmap: bool) -> usize {
+    if mmap {
+        0
+    } else {
+        read_buffer_size
+    }
+}
 
 /// The name of the receiver lock in the queue folder.
 pub(crate) fn recv_lock_filename<P: AsRef<Path>>(base: P) -> PathBuf {
     base.as_ref().join("recv.lock")
 }
 
+/// The error for a queue whose receiver lock is taken.
+fn recv_side_in_use(base: &Path) -> io::Error {
+    io::Error::other(format!(
+        "queue `{}` receiver side already in use",
+        base.to_string_lossy()
+    ))
+}
+
 /// Tries to acquire the receiver lock for a queue.
-pub(crate) fn try_acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
-    FileGuard::try_lock(recv_lock_filename(base.as_ref()))?.ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "queue `{}` receiver side already in use",
-                base.as_ref().to_string_lossy()
-            ),
-        )
-    })
+pub(crate) fn try_acquire_recv_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+) -> io::Result<FileGuard> {
+    FileGuard::try_lock_leased(recv_lock_filename(base.as_ref()), lease)?
+        .ok_or_else(|| recv_side_in_use(base.as_ref()))
 }
 
 /// Acquire the receiver lock for a queue, awaiting if locked.
-pub(crate) async fn acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
-    FileGuard::lock(recv_lock_filename(base.as_ref())).await
+pub(crate) async fn acquire_recv_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+) -> io::Result<FileGuard> {
+    FileGuard::lock_leased(recv_lock_filename(base.as_ref()), lease).await
+}
+
+/// The default dead-letter queue of a queue: a sibling folder with the same
+/// name, suffixed by `-dead-letter`.
+fn dead_letter_dirname<P: AsRef<Path>>(base: P) -> PathBuf {
+    let mut dirname = base.as_ref().components().as_path().as_os_str().to_owned();
+    dirname.push("-dead-letter");
+    PathBuf::from(dirname)
+}
+
+/// Tests whether a position in the queue is the start of an element (or the
+/// end of what was written so far), by walking its segment from the begining.
+///
+/// # Errors
+///
+/// This function returns an error if the segment does not exist or if there is
+/// a corrupted element before the position, since then nothing can be said.
+fn is_element_boundary(base: &Path, storage: &dyn Storage, state: QueueState) -> io::Result<bool> {
+    let mut file = storage.peek(state.segment)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("segment {} of {:?} does not exist", state.segment, base),
+        )
+    })?;
+    let len = file.seek(io::SeekFrom::End(0))?;
+    file.seek(io::SeekFrom::Start(0))?;
+    let mut file = BufReader::new(file);
+    let mut position = 0;
+
+    let decode = |header| {
+        Header::try_decode(header)
+            .map(|header| header.len() as u64)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupted element in segment {} of {:?}", state.segment, base),
+                )
+            })
+    };
+
+    while position < state.position && position + 4 <= len {
+        let mut header = [0; 4];
+        file.read_exact(&mut header)?;
+
+        // Nothing comes after the EOF header:
+        if header == HEADER_EOF {
+            return Ok(false);
+        }
+
+        // Skip the metadata, if any:
+        if header == HEADER_METADATA {
+            file.read_exact(&mut header)?;
+            let metadata_len = decode(header)?;
+            file.seek_relative(metadata_len as i64)?;
+            file.read_exact(&mut header)?;
+            position += 8 + metadata_len;
+        }
+
+        let data_len = decode(header)?;
+        file.seek_relative(data_len as i64)?;
+        position += 4 + data_len;
+    }
+
+    Ok(position == state.position && position <= len)
 }
 
 /// A builder for the receiver side of the queue. Use this if you want to have
@@ -46,6 +165,29 @@ pub(crate) async fn acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<Fil
 pub struct ReceiverBuilder {
     save_every_nth: Option<usize>,
     save_every: Option<Duration>,
+    shared: bool,
+    max_deliveries: Option<u64>,
+    track_deliveries: bool,
+    dedup_window: Option<usize>,
+    max_age: Option<Duration>,
+    consumer_group: Option<String>,
+    dead_letter_queue: Option<PathBuf>,
+    codecs: Vec<Arc<dyn Codec>>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    verify_checksums: bool,
+    lease: Option<Duration>,
+    visibility_timeout: Option<Duration>,
+    metrics: Option<Arc<dyn QueueMetrics>>,
+    on_segment_consumed: Option<SegmentConsumedHook>,
+    read_buffer_size: usize,
+    mmap: bool,
+    poll_interval: Option<Duration>,
+    storage: Option<Arc<dyn Storage>>,
+    #[cfg(feature = "serde")]
+    wire_format: WireFormat,
+    #[cfg(unix)]
+    permissions: Option<FilePermissions>,
+    in_process: bool,
 }
 
 impl Default for ReceiverBuilder {
@@ -53,6 +195,29 @@ impl Default for ReceiverBuilder {
         ReceiverBuilder {
             save_every_nth: Some(250),
             save_every: Some(Duration::from_millis(350)),
+            shared: false,
+            max_deliveries: None,
+            track_deliveries: false,
+            dedup_window: None,
+            max_age: None,
+            consumer_group: None,
+            dead_letter_queue: None,
+            codecs: Vec::new(),
+            key_provider: None,
+            verify_checksums: true,
+            lease: None,
+            visibility_timeout: None,
+            metrics: default_metrics(),
+            on_segment_consumed: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            mmap: false,
+            poll_interval: None,
+            storage: None,
+            #[cfg(feature = "serde")]
+            wire_format: WireFormat::default(),
+            #[cfg(unix)]
+            permissions: None,
+            in_process: false,
         }
     }
 }
@@ -86,14 +251,375 @@ impl ReceiverBuilder {
         self
     }
 
+    /// Sets the receiver to share the queue with other shared receivers,
+    /// possibly in other processes. Instead of holding the `recv.lock` for its
+    /// whole lifetime, a shared receiver only _claims_ the queue while a
+    /// transaction is in progress. Every claim starts from the state persisted
+    /// by the last receiver to commit, so each item is delivered to exactly one
+    /// of the receivers.
+    ///
+    /// Transactions are therefore serialized between the receivers: while one
+    /// of them holds a [`RecvGuard`], the others wait. Also, the receiver state
+    /// is saved at the end of every transaction, regardless of the saving
+    /// policies set in this builder.
+    ///
+    /// Default value: `false`.
+    ///
+    /// # Note:
+    ///
+    /// If a receiving future is not polled to completion, the claim is only
+    /// released by the next transaction (or when the receiver is dropped).
+    /// The `try_*` and `*_timeout` methods release the claim when they find
+    /// nothing to receive.
+    pub fn shared(mut self, shared: bool) -> ReceiverBuilder {
+        self.shared = shared;
+        self
+    }
+
+    /// Locks the queue within this process only, without the `recv.lock`
+    /// file, for when the sender lives in the same process (as with
+    /// [`crate::channel`]) and no other process opens the queue. This saves
+    /// the lock files and their checks, and leaves nothing to recover after
+    /// an unclean shutdown. The sender should be set likewise (see
+    /// [`crate::SenderBuilder::in_process`]).
+    ///
+    /// Other processes do not see such a lock: do not use this if they may
+    /// open the queue. The [lease](ReceiverBuilder::lease) is ignored, and a
+    /// [visibility timeout](ReceiverBuilder::visibility_timeout) cannot be
+    /// set, since the lock goes away with the process.
+    ///
+    /// Default value: `false`.
+    pub fn in_process(mut self, in_process: bool) -> ReceiverBuilder {
+        self.in_process = in_process;
+        self
+    }
+
+    /// Sets the maximum number of times an element can be delivered without
+    /// being committed. An element that has reached this limit (e.g., a
+    /// "poison message" whose guard is always rolled back) is moved to the
+    /// [dead-letter queue](ReceiverBuilder::dead_letter_queue) instead of
+    /// being delivered again. Set it to `None` to redeliver elements forever.
+    ///
+    /// Only the element at the front of the queue is tracked, so a rolled
+    /// back batch only counts as a delivery of its first element. The count is
+    /// saved to the disk at every delivery and thus also survives crashes.
+    ///
+    /// Default value: `None`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max_deliveries` is zero.
+    pub fn max_deliveries(mut self, max_deliveries: Option<u64>) -> ReceiverBuilder {
+        assert_ne!(max_deliveries, Some(0), "got max_deliveries=0");
+        self.max_deliveries = max_deliveries;
+        self
+    }
+
+    /// Sets the receiver to count the deliveries of the elements, as with
+    /// [`ReceiverBuilder::max_deliveries`], but without a limit, so that
+    /// [`RecvGuard::redeliveries`] tells how many times the element was
+    /// delivered before. This is implied by `max_deliveries`. Since the count
+    /// is saved to the disk at every delivery, this costs a write per
+    /// transaction.
+    ///
+    /// Default value: `false`.
+    pub fn track_deliveries(mut self, track_deliveries: bool) -> ReceiverBuilder {
+        self.track_deliveries = track_deliveries;
+        self
+    }
+
+    /// Sets the receiver to skip the elements with an id (see
+    /// [`Sender::try_send_with_id`]) that is among the last `window` ids it
+    /// has seen. Then, a producer that retries a send that may have gone
+    /// through does not get the element received twice. An element that is
+    /// rolled back is still delivered again, since it is the same element,
+    /// not a duplicate. Elements without an id are never skipped.
+    ///
+    /// The ids seen are saved to the disk together with the state of the
+    /// receiver (see [`Receiver::save`]), so that they survive restarts.
+    ///
+    /// Default value: `None`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `window` is zero.
+    pub fn dedup_window(mut self, window: Option<usize>) -> ReceiverBuilder {
+        assert_ne!(window, Some(0), "got dedup_window=0");
+        self.dedup_window = window;
+        self
+    }
+
+    /// Sets the receiver to skip the elements that were sent longer ago than
+    /// `max_age`, as it does with the elements that have expired (see
+    /// [`Sender::try_send_with_ttl`]). Only elements sent with a timestamp
+    /// (see [`SenderBuilder::timestamp`]) are ever skipped. The age is taken
+    /// when the element is read, so that an element that is rolled back may
+    /// be skipped when read again.
+    ///
+    /// Default value: `None`.
+    pub fn max_age(mut self, max_age: Option<Duration>) -> ReceiverBuilder {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Sets the receiver to be part of a named consumer group, with a position
+    /// of its own in the queue. Each group receives every element of the
+    /// queue (and the receiver without a group, if it is used, receives them
+    /// too), so that the same data can be fanned out to different consumers.
+    /// Within a group, receivers take turns as usual (see
+    /// [`ReceiverBuilder::shared`]). A segment is only removed once every
+    /// group (and the receiver without a group, once it has saved a state) is
+    /// past it, so that a group that is no longer used has to be removed with
+    /// [`remove_consumer_group`](crate::queue::remove_consumer_group) for the
+    /// queue not to grow forever. A new group starts from the oldest segment
+    /// left in the queue.
+    ///
+    /// The state of a group is kept in a folder of its own inside the queue
+    /// folder, so groups need the default storage (see
+    /// [`ReceiverBuilder::storage`]). Everything else that works on the queue
+    /// folder, such as the limits of the senders, [`crate::queue::queue_info`],
+    /// compaction and recovery, only sees the receiver without a group.
+    ///
+    /// Default value: no group.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the name is empty, longer than 64 bytes or if
+    /// it has characters other than ASCII letters, digits, `-` and `_`.
+    pub fn consumer_group<S: Into<String>>(mut self, group: S) -> ReceiverBuilder {
+        let group = group.into();
+        assert!(is_valid_name(&group), "got consumer_group={:?}", group);
+        self.consumer_group = Some(group);
+        self
+    }
+
+    /// Sets the folder of the queue that receives the elements that were
+    /// delivered too many times. See [`ReceiverBuilder::max_deliveries`].
+    ///
+    /// Default value: a sibling of the queue folder, with the same name,
+    /// suffixed by `-dead-letter`.
+    pub fn dead_letter_queue<P: AsRef<Path>>(mut self, path: P) -> ReceiverBuilder {
+        self.dead_letter_queue = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Adds a codec on top of the codecs to decode each item with, which have
+    /// to be the ones the items were encoded with, added in the same order.
+    /// The dead-letter queue is encoded with the same codecs. See [`Codec`]
+    /// for the details.
+    ///
+    /// Default value: no codecs
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> ReceiverBuilder {
+        self.codecs.push(codec);
+        self
+    }
+
+    /// Sets the provider of the keys to decrypt the items with, if any. The
+    /// dead-letter queue is encrypted with the same keys. See [`KeyProvider`]
+    /// for the details.
+    ///
+    /// Default value: `None`
+    #[cfg(feature = "encryption")]
+    pub fn key_provider(mut self, key_provider: Option<Arc<dyn KeyProvider>>) -> ReceiverBuilder {
+        self.key_provider = key_provider;
+        self
+    }
+
+    /// Sets the format to read the values deserialized with
+    /// [`RecvGuard::deserialize`] in, which has to be the one they were sent
+    /// in. See [`WireFormat`] for the details.
+    ///
+    /// Default value: `WireFormat::Json`
+    #[cfg(feature = "serde")]
+    pub fn wire_format(mut self, wire_format: WireFormat) -> ReceiverBuilder {
+        self.wire_format = wire_format;
+        self
+    }
+
+    /// Sets the size of the buffer the receiver reads the segments through.
+    /// Elements are read from the buffer, which is refilled with one syscall
+    /// whenever it runs out. Elements bigger than the buffer are read
+    /// straight into their own memory, bypassing it. Thus, a big buffer
+    /// saves syscalls with many small elements, while a small buffer saves
+    /// memory (and copies) with big ones. Set this to `0` to read without a
+    /// buffer. This has no effect if the segments are read through a memory
+    /// map.
+    ///
+    /// Default value: 8KiB
+    pub fn read_buffer_size(mut self, size: usize) -> ReceiverBuilder {
+        self.read_buffer_size = size;
+        self
+    }
+
+    /// Sets the receiver to also check the segment for new elements every so
+    /// often while it waits for them. Normally, a waiting receiver is only
+    /// woken by filesystem events (from inotify, FSEvents or
+    /// ReadDirectoryChangesW). Use this on filesystems that do not deliver
+    /// events for changes made by other processes or hosts, such as many
+    /// network filesystems. If the segment cannot be watched at all, the
+    /// receiver polls it every 100ms, unless set otherwise.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `interval` is zero.
+    pub fn poll_interval(mut self, interval: Option<Duration>) -> ReceiverBuilder {
+        assert_ne!(interval, Some(Duration::ZERO), "got poll_interval=0");
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Sets where the segments of the queue and the state of the receiver are
+    /// kept. The senders have to use the same storage. See [`Storage`] for
+    /// what is still kept in the queue folder. A memory map (see
+    /// `ReceiverBuilder::mmap`) only applies to the default storage.
+    ///
+    /// Default value: a [`FileStorage`] in the queue folder
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> ReceiverBuilder {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Sets the permissions of the folder of the queue and of the files this
+    /// receiver creates in it (its state and its locks), whatever the umask
+    /// of the process, so that a sender running as another user can use
+    /// them. The sender should be set with the same permissions (see
+    /// [`crate::SenderBuilder::permissions`]). Only on Unix. See
+    /// [`FilePermissions`] for the details.
+    ///
+    /// Default value: `None`, leaving the permissions to the umask.
+    #[cfg(unix)]
+    pub fn permissions(mut self, permissions: Option<FilePermissions>) -> ReceiverBuilder {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Sets the receiver to read the segments through a memory map, so that
+    /// the elements are copied straight from the page cache instead of going
+    /// through an intermediate buffer. This pays off for big elements. Each
+    /// time the receiver catches up with the sender, the segment is mapped
+    /// again, so this is slower when the receiver follows the sender closely.
+    ///
+    /// Beware that the process gets a `SIGBUS` if a mapped segment is
+    /// truncated by someone else while it is being read. Yaque itself never
+    /// truncates a segment that a receiver may be reading. It only removes
+    /// whole segments, which keeps their mappings valid on Unix.
+    ///
+    /// Default value: `false`
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> ReceiverBuilder {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Sets the receiver to verify the checksums of the elements that have
+    /// one (see [`SenderBuilder::checksum`]). A corrupted element fails the
+    /// reception with an IO error of kind [`io::ErrorKind::InvalidData`]
+    /// wrapping a [`crate::Corruption`]. Turn this off if you care more about
+    /// speed.
+    ///
+    /// Default value: `true`
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> ReceiverBuilder {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Holds the receiver lock with a lease, which the receiver renews from
+    /// time to time. If the receiver dies without releasing the lock, other
+    /// processes break it once the lease expires. See [`SenderBuilder::lease`]
+    /// for the details.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `lease` is zero.
+    pub fn lease(mut self, lease: Option<Duration>) -> ReceiverBuilder {
+        assert_ne!(lease, Some(Duration::ZERO), "got lease=0");
+        self.lease = lease;
+        self
+    }
+
+    /// Sets how long a shared receiver has to commit the elements it delivers.
+    /// Once an element is delivered, the receiver stops renewing the lease of
+    /// its claim on the queue (see [`ReceiverBuilder::lease`]), which expires
+    /// after this timeout. Then, other receivers break the claim and get the
+    /// element again, as if the receiver had crashed. This is a safety net for
+    /// consumers that hang (or die without the OS releasing their locks).
+    ///
+    /// Committing after the timeout fails with an IO error of kind
+    /// [`io::ErrorKind::TimedOut`], since the element may have been delivered
+    /// again by then. It is delivered again by this receiver otherwise. While
+    /// waiting for elements, the claim is held with a lease of this duration,
+    /// in place of the lease set in `lease`.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `timeout` is zero.
+    ///
+    /// # Note:
+    ///
+    /// Only shared receivers (see [`ReceiverBuilder::shared`]) hand elements
+    /// over to other receivers. Opening an exclusive receiver with a
+    /// visibility timeout fails with an IO error of kind
+    /// [`io::ErrorKind::InvalidInput`].
+    pub fn visibility_timeout(mut self, timeout: Option<Duration>) -> ReceiverBuilder {
+        assert_ne!(timeout, Some(Duration::ZERO), "got visibility_timeout=0");
+        self.visibility_timeout = timeout;
+        self
+    }
+
+    /// Sets the callbacks reporting what the receiver does, if any, such as
+    /// the elements received and the states saved. The same callbacks are
+    /// used for the priority lanes and the dead-letter queue. See
+    /// [`QueueMetrics`] for the details.
+    ///
+    /// Default value: `None`, or a `MetricsExporter` with the `metrics` feature
+    pub fn metrics(mut self, metrics: Option<Arc<dyn QueueMetrics>>) -> ReceiverBuilder {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets a hook to be called with every segment the receiver consumed
+    /// completely, just before deleting it, with the queue folder, the number
+    /// of the segment and its contents, so that, e.g., the segment can be
+    /// shipped to an object storage for long-term retention. Archived
+    /// segments can be replayed by putting them back in a queue folder, as
+    /// `<segment>.q`. The same hook is used for the priority lanes and the
+    /// topics of the queue.
+    ///
+    /// An error returned by the hook fails the commit that consumed the
+    /// segment, which is not deleted. Segments are archived in order, right
+    /// before they are deleted, so segments still needed by other consumer
+    /// groups are archived by the last group to consume them.
+    /// Segments that the sender drops (see
+    /// [`SenderBuilder::retention`](crate::SenderBuilder::retention)) are not
+    /// archived.
+    ///
+    /// Default value: none
+    pub fn on_segment_consumed<F>(mut self, hook: F) -> ReceiverBuilder
+    where
+        F: Fn(&Path, u64, &mut dyn Read) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.on_segment_consumed = Some(Arc::new(hook));
+        self
+    }
+
     /// Opens a queue for reading. The access will be exclusive, based on the
-    /// existence of the temporary file `recv.lock` inside the queue folder.
+    /// existence of the temporary file `recv.lock` inside the queue folder,
+    /// unless the receiver is set to be [shared](ReceiverBuilder::shared).
     ///
     /// # Errors
     ///
     /// This function will return an IO error if the queue is already in use for
     /// receiving, which is indicated by a lock file. Also, any other IO error
-    /// encountered while opening will be sent.
+    /// encountered while opening will be sent. Shared receivers never fail
+    /// because of the lock file. If the settings of the queue cannot be read
+    /// (see [`crate::SenderBuilder::open`]), this returns an error of kind
+    /// [`io::ErrorKind::InvalidData`].
     ///
     /// # Panics
     ///
@@ -105,62 +631,282 @@ impl ReceiverBuilder {
 
         log::trace!("created queue directory");
 
+        #[cfg(unix)]
+        if let Some(permissions) = self.permissions {
+            sharing::set_permissions(base.as_ref(), permissions)?;
+        }
+
         // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
         check_queue_version(base.as_ref())?;
 
-        // Acquire guard and state:
-        let file_guard = try_acquire_recv_lock(base.as_ref())?;
-        let mut persistence = QueueStatePersistence::new();
-        let state = persistence.open(base.as_ref())?;
+        if self.visibility_timeout.is_some() && !self.shared {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a visibility timeout needs a shared receiver",
+            ));
+        }
+
+        if self.visibility_timeout.is_some() && self.in_process {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a visibility timeout needs a lock file",
+            ));
+        }
+
+        if self.consumer_group.is_some() && self.storage.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "consumer groups need the default storage",
+            ));
+        }
+
+        // The receiver has to read the values sent in the format recorded:
+        #[cfg(feature = "serde")]
+        let wire_format = QueueSettings::wire_format_for_receiver(base.as_ref(), self.wire_format)?;
+
+        // (a length beyond the limit recorded can only be corruption)
+        let max_item_size = QueueSettings::max_item_size_for_receiver(base.as_ref())?;
+
+        // Consumer groups keep their state in a folder of their own:
+        let state_base = match &self.consumer_group {
+            Some(group) => {
+                let state_base = group_dirname(base.as_ref(), group);
+                create_dir_all(&state_base)?;
+                sharing::created(&state_base)?;
+                state_base
+            }
+            None => PathBuf::from(base.as_ref()),
+        };
+
+        let mmap = self.mmap;
+        let custom_storage = self.storage.is_some();
+        let storage = self.storage.unwrap_or_else(|| {
+            Arc::new(
+                FileStorage::new(base.as_ref())
+                    .state_base(state_base.clone())
+                    .mmap(mmap),
+            )
+        });
+
+        // Shared receivers only get their state (and their segment) when they
+        // first claim the queue.
+        let (file_guard, state, tail_follower) = if self.shared {
+            (None, QueueState::default(), None)
+        } else {
+            // Acquire guard and state:
+            let file_guard = if self.in_process {
+                FileGuard::try_lock_in_process(recv_lock_filename(&state_base))
+                    .ok_or_else(|| recv_side_in_use(&state_base))?
+            } else {
+                try_acquire_recv_lock(&state_base, self.lease)?
+            };
+            let mut state = storage.load_state()?;
+
+            // The segment may have been dropped by the sender in the meantime:
+            let segment = storage::surviving_segment(&*storage, state.segment)?;
+            if segment != state.segment {
+                state = QueueState {
+                    segment,
+                    position: 0,
+                };
+            }
+
+            log::trace!("receiver lock acquired. Receiver state now is {:?}", state);
+
+            // Put the needle on the groove (oh! the 70's):
+            let mut tail_follower = TailFollower::open(
+                &*storage,
+                state.segment,
+                read_buffer_capacity(self.read_buffer_size, self.mmap),
+                self.poll_interval,
+            )?;
+            tail_follower.seek(io::SeekFrom::Start(state.position))?;
 
-        log::trace!("receiver lock acquired. Receiver state now is {:?}", state);
+            log::trace!("last segment opened fo reading");
 
-        // Put the needle on the groove (oh! the 70's):
-        let mut tail_follower = TailFollower::open(&segment_filename(base.as_ref(), state.segment))?;
-        tail_follower.seek(io::SeekFrom::Start(state.position))?;
+            (Some(file_guard), state, Some(tail_follower))
+        };
+
+        // (even if deliveries are not counted, there may be a delay to honor)
+        let deliveries = if file_guard.is_some() {
+            Deliveries::load(&state_base)?
+        } else {
+            Deliveries::default()
+        };
 
-        log::trace!("last segment opened fo reading");
+        let seen_ids = match self.dedup_window {
+            Some(window) if file_guard.is_some() => Some(SeenIds::load(&state_base, window)?),
+            _ => None,
+        };
 
         Ok(Receiver {
-            _file_guard: file_guard,
+            file_guard,
+            shared: self.shared,
             tail_follower,
-            maybe_header: None,
+            read_started_at: None,
             state,
             initial_state: state,
             base: PathBuf::from(base.as_ref()),
-            persistence,
+            consumer_group: self.consumer_group,
+            state_base,
+            storage,
+            custom_storage,
             read_and_unused: VecDeque::new(),
+            zero_copy: false,
+            read_buffer: BytesMut::new(),
             save_every: self.save_every,
             save_every_nth: self.save_every_nth,
             n_reads: 0,
             last_saved_at: Instant::now(),
+            lanes: BTreeMap::new(),
+            topic: None,
+            topics: BTreeMap::new(),
+            next_topic: 0,
+            max_deliveries: self.max_deliveries,
+            track_deliveries: self.track_deliveries,
+            deliveries,
+            is_delivery_counted: false,
+            dedup_window: self.dedup_window,
+            seen_ids,
+            max_age: self.max_age,
+            dead_letter_base: self
+                .dead_letter_queue
+                .unwrap_or_else(|| dead_letter_dirname(base.as_ref())),
+            dead_letter: None,
+            codecs: self.codecs,
+            key_provider: self.key_provider,
+            verify_checksums: self.verify_checksums,
+            max_item_size,
+            lease: self.lease,
+            visibility_timeout: self.visibility_timeout,
+            delivered_at: None,
+            delivered: None,
+            taken: Vec::new(),
+            metrics: self.metrics,
+            received: Vec::new(),
+            stats: ReceiverStats::default(),
+            wait_clock: Arc::default(),
+            on_segment_consumed: self.on_segment_consumed,
+            read_buffer_size: self.read_buffer_size,
+            mmap: self.mmap,
+            poll_interval: self.poll_interval,
+            #[cfg(feature = "serde")]
+            wire_format,
+            #[cfg(unix)]
+            permissions: self.permissions,
+            in_process: self.in_process,
         })
     }
 }
 
+/// What a [`Receiver`] did since it was opened. See [`Receiver::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReceiverStats {
+    /// The number of elements received and committed.
+    pub items_received: u64,
+    /// The number of bytes in those elements, as they were received.
+    pub bytes_received: u64,
+    /// The number of transactions rolled back with elements in them, in full
+    /// or after their first elements (see [`RecvGuard::commit_first`]).
+    pub rollbacks: u64,
+    /// The number of segments deleted after being read through.
+    pub segments_deleted: u64,
+    /// The time spent waiting for the receiver lock and for elements to be
+    /// sent (or to be due).
+    pub time_waiting: Duration,
+}
+
+impl ReceiverStats {
+    /// Adds the counters of another receiver to these.
+    fn add(&mut self, other: &ReceiverStats) {
+        self.items_received += other.items_received;
+        self.bytes_received += other.bytes_received;
+        self.rollbacks += other.rollbacks;
+        self.segments_deleted += other.segments_deleted;
+    }
+}
+
+/// The time spent waiting by a receiver, shared with the receivers of its
+/// priority lanes and topics. Since they all wait at once when receiving from
+/// the lanes or the topics, time is counted while any of them is waiting.
+#[derive(Default)]
+struct WaitClock {
+    waiting: usize,
+    since: Option<Instant>,
+    waited: Duration,
+}
+
+/// Counts the time until it is dropped as waiting, so that the time is
+/// counted even if the future waiting is not polled to completion.
+struct WaitTimer(Arc<Mutex<WaitClock>>);
+
+impl WaitTimer {
+    fn start(clock: &Arc<Mutex<WaitClock>>) -> WaitTimer {
+        let mut locked = clock.lock().expect("poisoned");
+        locked.waiting += 1;
+        locked.since.get_or_insert_with(Instant::now);
+
+        WaitTimer(clock.clone())
+    }
+}
+
+impl Drop for WaitTimer {
+    fn drop(&mut self) {
+        let mut locked = self.0.lock().expect("poisoned");
+        locked.waiting -= 1;
+
+        if locked.waiting == 0 {
+            if let Some(since) = locked.since.take() {
+                locked.waited += since.elapsed();
+            }
+        }
+    }
+}
+
 /// The receiver part of the queue. This part is asynchronous and therefore
 /// needs an executor that will the poll the futures to completion.
 pub struct Receiver {
     /// The path to the folder holding the queue.
     base: PathBuf,
-    /// The acquired receiver lock file for this queue.
-    _file_guard: FileGuard,
-    /// The current segment being tailed.
-    tail_follower: TailFollower,
-    /// The last header read from the queue.
-    maybe_header: Option<[u8; 4]>,
+    /// The acquired receiver lock file for this queue. A shared receiver only
+    /// holds it while it has a claim on the queue.
+    file_guard: Option<FileGuard>,
+    /// Whether this receiver shares the queue with other receivers.
+    shared: bool,
+    /// The current segment being tailed. This is only `None` for a shared
+    /// receiver that has never claimed the queue.
+    tail_follower: Option<TailFollower>,
+    /// The state just before the element being read, if its read was
+    /// interrupted (e.g., by a timeout).
+    read_started_at: Option<QueueState>,
     /// The current queue state.
     state: QueueState,
     /// The queue state as it was in the begining of the current transaction.
     initial_state: QueueState,
-    /// The queue state saver/loader.
-    persistence: QueueStatePersistence,
+    /// The consumer group of the receiver, if any.
+    consumer_group: Option<String>,
+    /// The folder with the lock and the state of the receiver, which is the
+    /// queue folder, unless the receiver is part of a consumer group.
+    state_base: PathBuf,
+    /// Where the segments and the queue state are kept.
+    storage: Arc<dyn Storage>,
+    /// Whether the storage was set with [`ReceiverBuilder::storage`]. The
+    /// nested queues are in folders of their own, so there are none then.
+    custom_storage: bool,
     /// Use this queue to buffer elements and provide "atomicity in an
     /// asynchronous context". We need to backup the state of the queue before
     /// the read so as to restore it as the "initial state" (the _actual_ state
     /// of the queue) at the end of a transaction. Otherwise, dataloss would
-    /// occur.
-    read_and_unused: VecDeque<Vec<u8>>,
+    /// occur. Each element is kept with the state just before it and with its
+    /// metadata.
+    read_and_unused: VecDeque<(Bytes, QueueState, Metadata)>,
+    /// Whether the elements are taken as [`Bytes`], in which case they are
+    /// read into `read_buffer`, which is reused once they are dropped.
+    /// Otherwise, each element is read into its own vector, which is handed
+    /// over as it is.
+    zero_copy: bool,
+    /// The buffer the elements are read into, if `zero_copy` is set.
+    read_buffer: BytesMut,
     /// Save the queue every n operations
     save_every_nth: Option<usize>,
     /// Save the queue every interval of time. This will be enforced 
@@ -170,6 +916,83 @@ pub struct Receiver {
     n_reads: usize,
     /// Last time the queue was saved:
     last_saved_at: Instant,
+    /// The receivers of the priority lanes found so far (lazy inited!).
+    lanes: BTreeMap<u8, Receiver>,
+    /// The topic of the parent queue that this receiver receives from, if it
+    /// was opened by [`Receiver::subscribe`].
+    topic: Option<String>,
+    /// The receivers of the topics subscribed to.
+    topics: BTreeMap<String, Receiver>,
+    /// The topic to look at first on the next reception, so that all topics
+    /// get their turn.
+    next_topic: usize,
+    /// The maximum number of deliveries of an element before it is moved to
+    /// the dead-letter queue.
+    max_deliveries: Option<u64>,
+    /// Whether to count the deliveries even without a maximum.
+    track_deliveries: bool,
+    /// The number of deliveries of the element at the front of the queue.
+    deliveries: Deliveries,
+    /// Whether the current transaction was already counted as a delivery.
+    is_delivery_counted: bool,
+    /// The number of ids to remember for skipping duplicates, if any.
+    dedup_window: Option<usize>,
+    /// The last ids seen, if duplicates are skipped (lazy inited for shared
+    /// receivers!).
+    seen_ids: Option<SeenIds>,
+    /// How long ago elements can have been sent and still be delivered, if
+    /// there is a limit.
+    max_age: Option<Duration>,
+    /// The path to the folder holding the dead-letter queue.
+    dead_letter_base: PathBuf,
+    /// The sender of the dead-letter queue (lazy inited!).
+    dead_letter: Option<Sender>,
+    /// The codecs to decode the elements with, in the order they encode.
+    codecs: Vec<Arc<dyn Codec>>,
+    /// The provider of the keys to decrypt the elements with.
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    /// Whether to verify the checksums of the elements.
+    verify_checksums: bool,
+    /// The largest element the queue records to accept, if any.
+    max_item_size: Option<NonZeroU64>,
+    /// The lease of the receiver lock, if any.
+    lease: Option<Duration>,
+    /// How long the elements delivered stay claimed without a commit, if
+    /// there is a limit.
+    visibility_timeout: Option<Duration>,
+    /// When the first element of the current transaction was delivered, if
+    /// the visibility timeout is running.
+    delivered_at: Option<Instant>,
+    /// The metadata of the first element taken in the current transaction.
+    delivered: Option<Metadata>,
+    /// Where each element taken in the current transaction starts, in order,
+    /// to commit only some of them (see [`RecvGuard::commit_first`]).
+    taken: Vec<QueueState>,
+    /// The callbacks reporting what this receiver does.
+    metrics: Option<Arc<dyn QueueMetrics>>,
+    /// The lengths of the elements taken in the current transaction, to be
+    /// counted once it is committed.
+    received: Vec<u64>,
+    /// What this receiver did so far, except for the time waiting.
+    stats: ReceiverStats,
+    /// The time spent waiting so far.
+    wait_clock: Arc<Mutex<WaitClock>>,
+    /// The hook to archive the segments with before deleting them.
+    on_segment_consumed: Option<SegmentConsumedHook>,
+    /// The size of the buffer the segments are read through.
+    read_buffer_size: usize,
+    /// Whether to read the segments through a memory map.
+    mmap: bool,
+    /// How often to check the segment for more data while waiting, if at all.
+    poll_interval: Option<Duration>,
+    /// The format of the values deserialized from the elements.
+    #[cfg(feature = "serde")]
+    wire_format: WireFormat,
+    /// The permissions of the files created, if not left to the umask.
+    #[cfg(unix)]
+    permissions: Option<FilePermissions>,
+    /// Whether the queue is locked within this process only.
+    in_process: bool,
 }
 
 impl Receiver {
@@ -190,124 +1013,587 @@ impl Receiver {
         ReceiverBuilder::default().open(base)
     }
 
-    /// Starts a transaction in the queue.
-    fn begin(&mut self) {
-        log::debug!("begin transaction in {:?} at {:?}", self.base, self.state);
+    /// The folder holding the queue.
+    #[cfg(feature = "serde")]
+    pub(crate) fn base(&self) -> &Path {
+        &self.base
     }
 
-    /// Puts the queue in another position in another segment. This forcibly
-    /// discards the old tail follower and fethces a fresh new one, so be
-    /// careful.
-    fn go_to(&mut self, state: QueueState) -> io::Result<()> {
-        let different_segment = self.state.segment != state.segment;
+    /// Starts a transaction in the queue. A shared receiver without a claim
+    /// on the queue will first await for the receiver lock and then move to
+    /// the state saved by the last receiver.
+    async fn begin(&mut self) -> io::Result<()> {
+        if self.file_guard.is_none() {
+            let lease = self.visibility_timeout.or(self.lease);
+            let file_guard = {
+                let _waiting = WaitTimer::start(&self.wait_clock);
+
+                if self.in_process {
+                    FileGuard::lock_in_process(recv_lock_filename(&self.state_base)).await
+                } else {
+                    match FileGuard::try_lock_leased(recv_lock_filename(&self.state_base), lease)? {
+                        Some(file_guard) => file_guard,
+                        None => acquire_recv_lock(&self.state_base, lease).await?,
+                    }
+                }
+            };
 
-        log::debug!("going from {:?} to {:?}", self.state, state);
-        self.state = state;
+            let state = self.storage.load_state()?;
+            log::trace!("queue claimed. Receiver state now is {:?}", state);
 
-        if different_segment {
-            log::debug!("opening segment {}", self.state.segment);
-            self.tail_follower =
-                TailFollower::open(&segment_filename(&self.base, self.state.segment))?;
+            self.go_to(state)?;
+            self.initial_state = state;
+            self.file_guard = Some(file_guard);
+
+            self.deliveries = Deliveries::load(&self.state_base)?;
+
+            if let Some(window) = self.dedup_window {
+                self.seen_ids = Some(SeenIds::load(&self.state_base, window)?);
+            }
         }
 
-        self.tail_follower
-            .seek(io::SeekFrom::Start(state.position))?;
+        self.move_dead_letters().await?;
+
+        log::debug!("begin transaction in {:?} at {:?}", self.base, self.state);
 
         Ok(())
     }
 
-    /// Deletes old segments from a given point in time and makes the current
-    /// state the initial state.
-    fn end(&mut self) -> io::Result<()> {
-        assert!(
-            self.state.segment >= self.initial_state.segment,
-            "advanced to a past position. Initial was {:?}; current is {:?}",
-            self.initial_state,
-            self.state
-        );
+    /// Whether the deliveries of the element at the front of the queue are
+    /// counted.
+    fn counts_deliveries(&self) -> bool {
+        self.max_deliveries.is_some() || self.track_deliveries
+    }
 
-        for segment_id in self.initial_state.segment..self.state.segment {
-            log::debug!("removing segment {} from {:?}", segment_id, self.base);
-            remove_file(segment_filename(&self.base, segment_id))?;
+    /// Does the bookkeeping for the elements about to be delivered: starts the
+    /// visibility timeout, if any, and counts a delivery of the element at the
+    /// front of the queue, if the deliveries are counted.
+    fn on_delivery(&mut self) -> io::Result<()> {
+        if self.visibility_timeout.is_some() && self.delivered_at.is_none() {
+            if let Some(file_guard) = &mut self.file_guard {
+                file_guard.stop_renewing()?;
+                self.delivered_at = Some(Instant::now());
+            }
         }
 
-        log::debug!(
-            "end transaction in {:?} at {:?} (from {:?})",
-            self.base,
-            self.state,
-            self.initial_state
-        );
+        if self.counts_deliveries() && !self.is_delivery_counted {
+            if self.deliveries.at == self.initial_state {
+                self.deliveries.count += 1;
+            } else {
+                self.deliveries = Deliveries {
+                    at: self.initial_state,
+                    count: 1,
+                    not_before: None,
+                };
+            }
 
-        // // Reason: think you read with timeout 7 items, but wanted 10. Then, you read with timeout
-        // // 3 items, leaving 4 read and unused. Therefore, the Receiver has read 4 elements ahead,
-        // // which you have not seen. Therefore, initial_state cannot be state in the case, since you
-        // // would lose 4 elements. It has to be the position of the _next_ element in the read and
-        // // unused queue.
-        // self.initial_state = if let Some((_data, state)) = self.read_and_unused.front() {
-        //     *state // the state that was before the next element was read.
-        // } else {
-        //     self.state
-        // };
-
-        // Everything you read has to be used. Otherwise, setting initial state to state loses data.
-        assert!(
-            self.read_and_unused.is_empty(),
-            "There were read and unused items at the end of transaction. Read and unused queue: {:?}",
-            self.read_and_unused
-        );
-        self.initial_state = self.state;
+            self.deliveries.save(&self.state_base)?;
+            self.is_delivery_counted = true;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the element at the front of the queue to the dead-letter queue
+    /// while it has already been delivered too many times.
+    async fn move_dead_letters(&mut self) -> io::Result<()> {
+        let max_deliveries = if let Some(max_deliveries) = self.max_deliveries {
+            max_deliveries
+        } else {
+            return Ok(());
+        };
+
+        while self.deliveries.at == self.initial_state && self.deliveries.count >= max_deliveries {
+            // Start from the front of the queue, forgetting whatever was read
+            // ahead (the element was delivered before, so it is all there):
+            if !self.read_and_unused.is_empty() {
+                self.read_and_unused.clear();
+                self.go_to(self.initial_state)?;
+            }
 
-        // Alternatively... if you make read and unused VecDeque<(Vec<u8>, QueueState)> to backup the
-        // state, you can do the following (deprecated code):
+            self.read_one().await?;
+            let (data, _, metadata) = self
+                .read_and_unused
+                .pop_front()
+                .expect("guaranteed to yield an element");
+
+            log::warn!(
+                "element at {:?} in {:?} was delivered {} times. Moving to {:?}",
+                self.initial_state,
+                self.base,
+                self.deliveries.count,
+                self.dead_letter_base
+            );
 
-        // // Reason: think you read with timeout 7 items, but wanted 10. Then, you read with timeout
-        // // 3 items, leaving 4 read and unused. Therefore, the Receiver has read 4 elements ahead,
-        // // which you have not seen. Therefore, initial_state cannot be state in the case, since you
-        // // would lose 4 elements. It has to be the position of the _next_ element in the read and
-        // // unused queue.
-        // self.initial_state = if let Some((_data, state)) = self.read_and_unused.front() {
-        //     *state // the state that was before the next element was read.
-        // } else {
-        //     self.state
-        // };
+            if self.dead_letter.is_none() {
+                let dead_letter = self
+                    .codecs
+                    .iter()
+                    .fold(SenderBuilder::new(), |builder, codec| {
+                        builder.codec(codec.clone())
+                    })
+                    .shared(self.shared)
+                    .with_key_provider(self.key_provider.clone())
+                    .metrics(self.metrics.clone())
+                    .open(&self.dead_letter_base)?;
+                self.dead_letter = Some(dead_letter);
+            }
 
-        // Finally save if it is time to save:
-        self.maybe_save()?;
+            // (the headers and the timestamp go along with the element)
+            let metadata = Metadata {
+                headers: metadata.headers,
+                enqueued_at: metadata.enqueued_at,
+                ..Metadata::default()
+            };
+            self.dead_letter
+                .as_mut()
+                .expect("dead-letter queue was just opened")
+                .send_with_metadata(&metadata, &data)
+                .await?;
+
+            // Commit the removal right away, but keep the claim:
+            self.settle()?;
+            self.save()?;
+        }
 
         Ok(())
     }
 
-    /// Reads the header. This operation is atomic.
-    async fn read_header(&mut self) -> io::Result<Header> {
-        // If the header was already read (by an incomplete operation), use it!
-        if let Some(header) = self.maybe_header {
-            return Ok(Header::decode(header));
+    /// Gives up the claim of a shared receiver on the queue, forgetting
+    /// everything that was read, but not yet delivered. This does nothing for
+    /// an exclusive receiver.
+    fn release(&mut self) {
+        if self.shared && self.file_guard.is_some() {
+            log::trace!("releasing claim on {:?}", self.base);
+            self.read_and_unused.clear();
+            self.received.clear();
+            self.taken.clear();
+            self.read_started_at = None;
+            self.delivered_at = None;
+            self.file_guard = None;
         }
+    }
 
-        // Read header:
-        let mut header = [0; 4];
-        self.tail_follower.read_exact(&mut header).await?;
+    /// Whether the visibility timeout of the elements delivered in the current
+    /// transaction has expired, so that the claim on the queue may have been
+    /// broken by another receiver.
+    fn is_visibility_expired(&self) -> bool {
+        match (self.visibility_timeout, self.delivered_at) {
+            (Some(timeout), Some(delivered_at)) => delivered_at.elapsed() >= timeout,
+            _ => false,
+        }
+    }
 
-        // If the header is EOF, advance segment:
-        if header == HEADER_EOF {
-            log::trace!("got EOF header. Advancing...");
-            let mut new_state = self.state.clone();
-            new_state.advance_segment();
-            self.go_to(new_state)?; // forces to open new segment.
+    /// Gives up an expired claim on the queue, without touching the lock
+    /// (which may belong to another receiver by now) and forgetting the
+    /// current transaction. See [`ReceiverBuilder::visibility_timeout`].
+    fn abandon(&mut self) {
+        log::warn!(
+            "visibility timeout expired in {:?} at {:?}; giving up the transaction",
+            self.base,
+            self.initial_state
+        );
 
-            // Re-read the header:
-            log::trace!("re-reading new header from new file");
-            self.tail_follower.read_exact(&mut header).await?;
+        if let Some(file_guard) = self.file_guard.take() {
+            file_guard.abandon();
         }
 
-        // Now, you set the header!
-        self.maybe_header = Some(header.clone());
-        let decoded = Header::decode(header);
-        self.state.advance_position(4);
+        self.read_and_unused.clear();
+        self.received.clear();
+        self.taken.clear();
+        self.read_started_at = None;
+        self.delivered_at = None;
+        self.delivered = None;
+        self.is_delivery_counted = false;
+    }
+
+    /// The tail follower for the current segment.
+    fn tail_follower(&mut self) -> &mut TailFollower {
+        self.tail_follower
+            .as_mut()
+            .expect("receiver has not claimed the queue")
+    }
+
+    /// Puts the queue in another position in another segment. This forcibly
+    /// discards the old tail follower and fethces a fresh new one, so be
+    /// careful. Any interrupted read is forgotten.
+    fn go_to(&mut self, mut state: QueueState) -> io::Result<()> {
+        let different_segment =
+            self.tail_follower.is_none() || self.state.segment != state.segment;
+
+        // The segment may have been dropped by the sender in the meantime:
+        if different_segment {
+            let segment = storage::surviving_segment(&*self.storage, state.segment)?;
+
+            if segment != state.segment {
+                log::debug!("segment {} was dropped; skipping to {}", state.segment, segment);
+                state = QueueState {
+                    segment,
+                    position: 0,
+                };
+            }
+        }
+
+        log::debug!("going from {:?} to {:?}", self.state, state);
+        self.state = state;
+        self.read_started_at = None;
+
+        if different_segment {
+            log::debug!("opening segment {}", self.state.segment);
+            self.tail_follower = Some(TailFollower::open(
+                &*self.storage,
+                self.state.segment,
+                read_buffer_capacity(self.read_buffer_size, self.mmap),
+                self.poll_interval,
+            )?);
+        }
+
+        self.tail_follower()
+            .seek(io::SeekFrom::Start(state.position))?;
+
+        Ok(())
+    }
+
+    /// Deletes old segments from a given point in time and makes the current
+    /// state the initial state.
+    pub(crate) fn end(&mut self) -> io::Result<()> {
+        // A shared receiver that never got to claim the queue has no
+        // transaction to end.
+        if self.file_guard.is_none() {
+            return Ok(());
+        }
+
+        if self.is_visibility_expired() {
+            self.abandon();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "visibility timeout expired in {:?}: the elements may have been delivered \
+                    again",
+                    self.base
+                ),
+            ));
+        }
+
+        self.settle()?;
+
+        // Finally save if it is time to save (shared receivers always save and
+        // let the others have their turn):
+        if self.shared {
+            self.save()?;
+            self.release();
+        } else {
+            self.maybe_save()?;
+        }
+
+        Ok(())
+    }
+
+    /// The state right before the next element to be delivered, that is, the
+    /// state the current transaction settles at.
+    fn next_state(&self) -> QueueState {
+        // Reason: think you read with timeout 7 items, but wanted 10. Then, you read with timeout
+        // 3 items, leaving 4 read and unused. Therefore, the Receiver has read 4 elements ahead,
+        // which you have not seen. Therefore, initial_state cannot be state in the case, since you
+        // would lose 4 elements. It has to be the position of the _next_ element in the read and
+        // unused queue (and an interrupted read has not consumed its element yet).
+        if let Some((_data, state, _)) = self.read_and_unused.front() {
+            *state // the state that was before the next element was read.
+        } else {
+            self.read_started_at.unwrap_or(self.state)
+        }
+    }
+
+    /// Deletes the segments that were read through since the begining of the
+    /// transaction and makes the current state the initial state, without
+    /// saving it.
+    fn settle(&mut self) -> io::Result<()> {
+        assert!(
+            self.state.segment >= self.initial_state.segment,
+            "advanced to a past position. Initial was {:?}; current is {:?}",
+            self.initial_state,
+            self.state
+        );
+
+        let settled_state = self.next_state();
+
+        // (segments with elements read ahead are still needed, and so are the
+        // segments that other consumer groups did not get past yet, as they
+        // last saved)
+        let mut removable = self.initial_state.segment..settled_state.segment;
+        if !removable.is_empty() {
+            let other_cursors = other_cursors(&self.base, self.consumer_group.as_deref())?;
+
+            if let Some(slowest) = other_cursors.iter().map(|cursor| cursor.segment).min() {
+                // (the slowest group may have stopped short of the segments
+                // this receiver went through before)
+                let oldest = self.storage.segments()?.into_iter().min();
+                removable = oldest.unwrap_or(0)..removable.end.min(slowest);
+            }
+        }
+
+        for segment_id in removable {
+            if let Some(on_segment_consumed) = &self.on_segment_consumed {
+                // (segments may have been dropped by the sender already)
+                if let Some(len) = self.storage.segment_len(segment_id)? {
+                    log::debug!("archiving segment {} from {:?}", segment_id, self.base);
+                    let mut segment = self.storage.read(segment_id)?.take(len);
+                    on_segment_consumed(&self.base, segment_id, &mut segment)?;
+                }
+            }
+
+            log::debug!("removing segment {} from {:?}", segment_id, self.base);
+            // (segments may have been dropped by the sender already)
+            self.storage.delete(segment_id)?;
+            self.stats.segments_deleted += 1;
+        }
+
+        log::debug!(
+            "end transaction in {:?} at {:?} (from {:?})",
+            self.base,
+            settled_state,
+            self.initial_state
+        );
+
+        self.initial_state = settled_state;
+        self.is_delivery_counted = false;
+        self.delivered = None;
+        self.taken.clear();
+
+        for len in self.received.drain(..) {
+            self.stats.items_received += 1;
+            self.stats.bytes_received += len;
+
+            if let Some(metrics) = &self.metrics {
+                metrics.item_received(&self.base, len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ends the current transaction after its first `n` elements, so that the
+    /// elements taken after them will be read again. See
+    /// [`RecvGuard::commit_first`].
+    fn end_first(&mut self, n: usize) -> io::Result<()> {
+        if let Some(&next) = self.taken.get(n) {
+            // Everything after the first `n` will be read again, including
+            // what was read ahead:
+            self.read_and_unused.clear();
+            self.stats.rollbacks += 1;
+
+            if let Some(metrics) = &self.metrics {
+                if self.received.len() > n {
+                    metrics.rolled_back(&self.base, (self.received.len() - n) as u64);
+                }
+            }
+            self.received.truncate(n);
+            self.go_to(next)?;
+        }
+
+        self.end()
+    }
+
+    /// Rolls the current transaction back, so that everything will be read
+    /// again, but not before a delay. Nothing else is read in the meantime.
+    /// See [`RecvGuard::nack_after`].
+    fn rollback_after(&mut self, delay: Duration) -> io::Result<()> {
+        // (if the visibility expired, the element may be delivered already)
+        if self.file_guard.is_some() && !self.is_visibility_expired() {
+            if self.deliveries.at != self.initial_state {
+                self.deliveries = Deliveries {
+                    at: self.initial_state,
+                    count: 0,
+                    not_before: None,
+                };
+            }
+
+            self.deliveries.not_before = Some(SystemTime::now() + delay);
+            self.deliveries.save(&self.state_base)?;
+        }
+
+        self.rollback()
+    }
+
+    /// Rolls the current transaction back, so that everything will be read
+    /// again.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "rollback", level = "debug", skip_all, fields(base = ?self.base))
+    )]
+    pub(crate) fn rollback(&mut self) -> io::Result<()> {
+        // (everything will be delivered again anyway)
+        if self.is_visibility_expired() {
+            self.abandon();
+            return Ok(());
+        }
+
+        if self.file_guard.is_some() {
+            // Everything will be read again, including what was read ahead:
+            self.read_and_unused.clear();
+
+            if !self.taken.is_empty() {
+                self.stats.rollbacks += 1;
+            }
+
+            if let Some(metrics) = &self.metrics {
+                if !self.received.is_empty() {
+                    metrics.rolled_back(&self.base, self.received.len() as u64);
+                }
+            }
+            self.received.clear();
+            self.go_to(self.initial_state)?;
+        }
+
+        self.end()
+    }
+
+    /// Reads the header, together with the metadata of the element, if any.
+    /// When `continuing` an element split over segments, the read still
+    /// starts where the element does, even if this moves to a new segment.
+    async fn read_header(&mut self, continuing: bool) -> io::Result<(Metadata, Header)> {
+        // Read header:
+        let mut header = [0; 4];
+        self.tail_follower().read_exact(&mut header).await?;
+
+        // If the header is EOF, advance segment:
+        if header == HEADER_EOF {
+            log::trace!("got EOF header. Advancing...");
+            let mut new_state = self.state;
+            new_state.advance_segment();
+            let started_at = self.read_started_at;
+            self.go_to(new_state)?; // forces to open new segment.
+
+            // (an element split over segments still starts where it did)
+            self.read_started_at = if continuing {
+                started_at
+            } else {
+                Some(self.state)
+            };
+
+            // Re-read the header:
+            log::trace!("re-reading new header from new file");
+            self.tail_follower().read_exact(&mut header).await?;
+        }
+
+        self.state.advance_position(4);
+
+        // If there is metadata, read it and then the actual header:
+        let metadata = if header == HEADER_METADATA {
+            self.tail_follower().read_exact(&mut header).await?;
+            self.state.advance_position(4);
+
+            let mut encoded = vec![0; Header::decode(header).len() as usize];
+            self.tail_follower().read_exact(&mut encoded).await?;
+            self.state.advance_position(encoded.len() as u64);
+
+            self.tail_follower().read_exact(&mut header).await?;
+            self.state.advance_position(4);
+
+            let metadata = Metadata::decode(&encoded)?;
+            log::trace!("got metadata {:?}", metadata);
+            metadata
+        } else {
+            Metadata::default()
+        };
+
+        let decoded = Header::decode(header);
 
         log::trace!("got header {:?} (read {} bytes)", header, decoded.len());
 
-        Ok(decoded)
+        Ok((metadata, decoded))
+    }
+
+    /// Checks the length of the element being read against the largest one
+    /// the queue accepts, so that a corrupted header is not trusted.
+    fn check_len(&self, len: u64) -> Result<(), Corruption> {
+        // (no element is bigger than what a header can tell, limit or no limit)
+        let max_item_size = self
+            .max_item_size
+            .map_or(u64::from(header::MAX_LEN), NonZeroU64::get);
+
+        if len > max_item_size {
+            let started_at = self.read_started_at.expect("read was started");
+            log::error!("element of {} bytes in {:?} at {:?}", len, self.base, started_at);
+
+            return Err(Corruption {
+                base: self.base.clone(),
+                segment: started_at.segment,
+                position: started_at.position,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next element (or chunk of an element) as it is in the
+    /// segment, together with its metadata.
+    async fn read_element(&mut self, continuing: bool) -> io::Result<(Metadata, Bytes)> {
+        // Get the length:
+        let (metadata, header) = self.read_header(continuing).await?;
+        self.check_len(u64::from(header.len()))?;
+
+        // With the length, read the data:
+        let data = if self.zero_copy {
+            let mut buffer = mem::take(&mut self.read_buffer);
+            buffer.resize(header.len() as usize, 0);
+            self.tail_follower()
+                .read_exact(&mut buffer)
+                .await
+                .expect("poisoned queue");
+
+            let data = buffer.split().freeze();
+            self.read_buffer = buffer;
+            data
+        } else {
+            let mut data = vec![0; header.len() as usize];
+            self.tail_follower()
+                .read_exact(&mut data)
+                .await
+                .expect("poisoned queue");
+
+            Bytes::from(data)
+        };
+
+        self.state.advance_position(data.len() as u64);
+
+        Ok((metadata, data))
+    }
+
+    /// Reads the rest of an element split over segments, given its first
+    /// chunk, and returns the whole element. Returns `None` if the element
+    /// was cut short (e.g., because its sender died), in which case the
+    /// receiver is put back at what comes after it. See
+    /// [`crate::SenderBuilder::split_items`].
+    async fn read_chunks(
+        &mut self,
+        mut metadata: Metadata,
+        first: Bytes,
+    ) -> io::Result<Option<(Metadata, Bytes)>> {
+        let mut data = BytesMut::from(&first[..]);
+
+        loop {
+            let chunk_at = self.state;
+            let (chunk_metadata, chunk) = self.read_element(true).await?;
+
+            if !chunk_metadata.is_continuation {
+                log::warn!(
+                    "discarding an element cut short in {:?} at {:?}",
+                    self.base,
+                    self.read_started_at
+                );
+                self.go_to(chunk_at)?;
+                return Ok(None);
+            }
+
+            data.extend_from_slice(&chunk);
+            self.check_len(data.len() as u64)?;
+
+            if !chunk_metadata.more_chunks {
+                metadata.more_chunks = false;
+                return Ok(Some((metadata, data.freeze())));
+            }
+        }
     }
 
     /// Reads one element from the queue, inevitably advancing the file reader.
@@ -319,24 +1605,94 @@ impl Receiver {
     /// This operation is also itlsef atomic. If the returned future is not
     /// polled to completion, as, e.g., when calling `select`, the operation
     /// will count as not done.
+    ///
+    /// Elements that are not due yet are awaited for here, so that the queue
+    /// _parks_ at them, and so are elements backing off after a nack (see
+    /// [`RecvGuard::nack_after`]). Expired elements are skipped.
     async fn read_one(&mut self) -> io::Result<()> {
-        // Get the length:
-        let header = self.read_header().await?;
+        let _waiting = WaitTimer::start(&self.wait_clock);
 
-        // With the length, read the data:
-        let mut data = vec![0; header.len() as usize];
-        self.tail_follower
-            .read_exact(&mut data)
-            .await
-            .expect("poisoned queue");
+        // Start over an element whose read was interrupted:
+        if let Some(state) = self.read_started_at {
+            self.go_to(state)?;
+        }
 
-        self.state.advance_position(data.len() as u64);
+        if let Some(not_before) = self.deliveries.not_before {
+            if self.deliveries.at == self.state {
+                if let Ok(backoff) = not_before.duration_since(SystemTime::now()) {
+                    log::debug!("element at {:?} backing off for {:?}", self.state, backoff);
+                    Delay::new(backoff).await;
+                }
+            }
+        }
+
+        let (data, metadata) = loop {
+            self.read_started_at = Some(self.state);
+            let (metadata, data) = self.read_element(false).await?;
+
+            // Put the chunks of an element split over segments back together:
+            let (metadata, data) = if metadata.is_continuation {
+                log::warn!(
+                    "skipping a chunk of an element whose start is gone in {:?}",
+                    self.base
+                );
+                continue;
+            } else if metadata.more_chunks {
+                match self.read_chunks(metadata, data).await? {
+                    Some(element) => element,
+                    None => continue,
+                }
+            } else {
+                (metadata, data)
+            };
 
-        // We are done! Unset header:
-        self.maybe_header = None;
+            if self.verify_checksums && !metadata.matches_checksum(&data) {
+                let started_at = self.read_started_at.expect("read was started in this loop");
+                log::error!("checksum mismatch in {:?} at {:?}", self.base, started_at);
+
+                return Err(Corruption {
+                    base: self.base.clone(),
+                    segment: started_at.segment,
+                    position: started_at.position,
+                }
+                .into());
+            }
+
+            // Wait for the element to be due:
+            if let Some(not_before) = metadata.not_before {
+                if let Ok(delay) = not_before.duration_since(SystemTime::now()) {
+                    log::trace!("element not due yet. Parking for {:?}", delay);
+                    Delay::new(delay).await;
+                }
+            }
+
+            // Expired elements, elements too old and duplicates are just skipped:
+            if metadata.is_expired() {
+                log::debug!("discarding element expired at {:?}", metadata.expires_at);
+            } else if self.is_too_old(&metadata) {
+                log::debug!("discarding element enqueued at {:?}", metadata.enqueued_at);
+            } else if self.is_duplicate(&metadata) {
+                log::debug!("discarding duplicate element with id {:?}", metadata.id);
+            } else {
+                let data = metadata.decode_payload_bytes(data, self.key_provider.as_deref())?;
+
+                if self.codecs.is_empty() {
+                    break (data, metadata);
+                } else {
+                    let data = transform::decode(&self.codecs, data.to_vec())?;
+                    break (data.into(), metadata);
+                }
+            }
+        };
+
+        // We are done! Unset the interrupted read:
+        let started_at = self
+            .read_started_at
+            .take()
+            .expect("read was started in this function");
 
         // Ready to be used:
-        self.read_and_unused.push_back(data);
+        self.read_and_unused.push_back((data, started_at, metadata));
 
         // Bookkeeping:
         self.n_reads += 1;
@@ -344,6 +1700,26 @@ impl Receiver {
         Ok(())
     }
 
+    /// Whether the element just read was sent longer ago than the maximum
+    /// age, if there is one.
+    fn is_too_old(&self, metadata: &Metadata) -> bool {
+        self.max_age
+            .map(|max_age| metadata.is_older_than(max_age))
+            .unwrap_or(false)
+    }
+
+    /// Whether the element just read has an id that was already seen at
+    /// another element, if duplicates are skipped.
+    fn is_duplicate(&mut self, metadata: &Metadata) -> bool {
+        match (&mut self.seen_ids, &metadata.id) {
+            (Some(seen_ids), Some(id)) => {
+                let at = self.read_started_at.expect("read was started");
+                seen_ids.is_duplicate(id, at)
+            }
+            _ => false,
+        }
+    }
+
     /// Reads one element from the queue until a future elapses. If the future
     /// elapses first, then `OK(false)` is returned and no element is put in
     /// the "read and unused" internal queue. Otherwise, `Ok(true)` is returned
@@ -372,8 +1748,12 @@ impl Receiver {
         // (careful! need to check if read something to avoid an eroneous POP
         // from the queue)
         if n > 0 {
-            while let Some(element) = self.read_and_unused.pop_front() {
-                data.push(element);
+            while let Some((element, state, metadata)) = self.read_and_unused.pop_front() {
+                self.delivered.get_or_insert(metadata);
+                self.taken.push(state);
+
+                self.received.push(element.len() as u64);
+                data.push(Vec::from(element));
 
                 if data.len() == n {
                     break;
@@ -384,122 +1764,1279 @@ impl Receiver {
         data
     }
 
-    /// Saves the receiver queue state. You do not need to use method in most
-    /// circumstances, since it is automatically done on drop (yes, it will be
-    /// called eve if your thread panics). However, you cawn use this function to
-    ///
-    /// 1. Make periodical backups. Use an external timer implementation for this.
-    ///
-    /// 2. Handle possible IO errors in logging the state of the queue to the disk
-    /// after commit. The `drop` implementation will ignore (but log) any io
-    /// errors, which may lead to data loss in an unreliable filesystem. It was
-    /// implemented this way because no errors are allowed to propagate on drop
-    /// and panicking will abort the program if drop is called during a panic.
-    pub fn save(&mut self) -> io::Result<()> {
-        self.persistence.save(&self.initial_state) // this aviods saving an in-flight
+    /// Wraps items taken from the queue in a guard for the current transaction.
+    fn guard<T>(&mut self, item: T) -> RecvGuard<'_, T> {
+        RecvGuard {
+            receiver: self,
+            item: Some(item),
+            was_finished: false,
+        }
     }
 
-    fn maybe_save(&mut self) -> io::Result<()> {
-        if let Some(save_every_nth) = self.save_every_nth {
-            if self.n_reads % save_every_nth == 0 {
-                self.save()?;
-            }
-        } else if let Some(save_every) = self.save_every {
-            if self.last_saved_at.elapsed() >= save_every {
-                self.save()?;
-            }
+    /// Begins a transaction and reads the next element ahead, if it was not
+    /// read yet. See [`Receiver::peek`].
+    async fn read_ahead_one(&mut self) -> io::Result<()> {
+        self.begin().await?;
+
+        if self.read_and_unused.is_empty() {
+            self.read_one().await?;
         }
 
         Ok(())
     }
 
-    /// Retrieves an element from the queue. The returned value is a
-    /// guard that will only commit state changes to the queue when dropped.
-    ///
-    /// This operation is atomic. If the returned future is not polled to
-    /// completion, as, e.g., when calling `select`, the operation will be
-    /// undone.
-    ///
-    /// # Panics
-    ///
-    /// This function will panic if it has to start reading a new segment and
-    /// it is not able to set up the notification handler to watch for file
-    /// changes.
-    pub async fn recv(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
-        self.begin();
+    /// Begins a transaction and takes one element from the queue. See
+    /// [`Receiver::recv`].
+    async fn take(&mut self) -> io::Result<Vec<u8>> {
+        self.take_bytes().await.map(Vec::from)
+    }
 
-        let data = if let Some(data) = self.read_and_unused.pop_front() {
-            data
-        } else {
-            self.read_one().await?;
-            self.read_and_unused
-                .pop_front()
-                .expect("guaranteed to yield an element")
-        };
+    /// Begins a transaction and takes one element from the queue, as it was
+    /// read. See [`Receiver::recv_bytes`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "recv", level = "debug", skip_all, fields(base = ?self.base))
+    )]
+    async fn take_bytes(&mut self) -> io::Result<Bytes> {
+        self.read_ahead_one().await?;
 
-        Ok(RecvGuard {
-            receiver: self,
-            item: Some(data),
-            was_finished: false,
-        })
+        let (data, state, metadata) = self
+            .read_and_unused
+            .pop_front()
+            .expect("guaranteed to yield an element");
+
+        self.on_delivery()?;
+        self.delivered.get_or_insert(metadata);
+        self.taken.push(state);
+
+        self.received.push(data.len() as u64);
+
+        Ok(data)
     }
 
-    /// Tries to retrieve an element from the queue. The returned value is a
-    /// guard that will only commit state changes to the queue when dropped.
+    /// Begins a transaction and takes `n` elements from the queue. See
+    /// [`Receiver::recv_batch`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "recv_batch",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    async fn take_batch(&mut self, n: usize) -> io::Result<Vec<Vec<u8>>> {
+        self.begin().await?;
+
+        // First, fetch what is missing from the disk:
+        if n > self.read_and_unused.len() {
+            for _ in 0..(n - self.read_and_unused.len()) {
+                self.read_one().await?;
+            }
+        }
+
+        // And now, drain!
+        let data = self.drain(n);
+
+        if !data.is_empty() {
+            self.on_delivery()?;
+        }
+
+        Ok(data)
+    }
+
+    /// Begins a transaction and takes at least one and at most `n` elements
+    /// from the queue, stopping as soon as no more elements are immediately
+    /// available. See [`Receiver::recv_batch_up_to`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "recv_batch_up_to",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    async fn take_batch_up_to(&mut self, n: usize) -> io::Result<Vec<Vec<u8>>> {
+        self.begin().await?;
+
+        // Wait only for the first element:
+        if n > 0 && self.read_and_unused.is_empty() {
+            self.read_one().await?;
+        }
+
+        // Then, take whatever is ready. An interrupted read is redone later:
+        while self.read_and_unused.len() < n {
+            match self.read_one().now_or_never() {
+                Some(outcome) => outcome?,
+                None => break,
+            }
+        }
+
+        // And now, drain!
+        let data = self.drain(n);
+
+        if !data.is_empty() {
+            self.on_delivery()?;
+        }
+
+        Ok(data)
+    }
+
+    /// Begins a transaction and takes elements from the queue until the
+    /// predicate is met. See [`Receiver::recv_until`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "recv_until",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    async fn take_until<P, Fut>(&mut self, mut predicate: P) -> io::Result<Vec<Vec<u8>>>
+    where
+        P: FnMut(Option<&[u8]>) -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        self.begin().await?;
+        let mut n_read = 0;
+
+        // Prepare:
+        predicate(None).await;
+
+        // Poor man's do-while (aka. until)
+        // Strategy: fill `read_and_unused` to the brim and then drain at the end.
+        loop {
+            // Need to fetch from disk?
+            if n_read == self.read_and_unused.len() {
+                self.read_one().await?;
+            }
+
+            let (item_ref, _, _) = &self.read_and_unused[n_read];
+
+            if !predicate(Some(item_ref)).await {
+                n_read += 1;
+            } else {
+                break;
+            }
+        }
+
+        // And now, drain!
+        let data = self.drain(n_read);
+
+        if !data.is_empty() {
+            self.on_delivery()?;
+        }
+
+        Ok(data)
+    }
+
+    /// Begins a transaction and takes elements from the queue while the
+    /// predicate holds, including the element where it stops holding. See
+    /// [`Receiver::recv_while`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "recv_while",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    async fn take_while<P>(&mut self, mut predicate: P) -> io::Result<Vec<Vec<u8>>>
+    where
+        P: FnMut(&[u8]) -> bool,
+    {
+        self.begin().await?;
+        let mut n_read = 0;
+
+        // Strategy: fill `read_and_unused` to the brim and then drain at the end.
+        loop {
+            // Need to fetch from disk?
+            if n_read == self.read_and_unused.len() {
+                self.read_one().await?;
+            }
+
+            let (item_ref, _, _) = &self.read_and_unused[n_read];
+            n_read += 1;
+
+            if !predicate(item_ref) {
+                break;
+            }
+        }
+
+        // And now, drain!
+        let data = self.drain(n_read);
+        self.on_delivery()?;
+
+        Ok(data)
+    }
+
+    /// The configuration of this receiver, for the queues nested in this
+    /// queue (the priority lanes and the topics), which have dead-letter
+    /// queues of their own.
+    fn nested_builder(&self, dead_letter_queue: PathBuf) -> ReceiverBuilder {
+        ReceiverBuilder {
+            save_every_nth: self.save_every_nth,
+            save_every: self.save_every,
+            shared: self.shared,
+            max_deliveries: self.max_deliveries,
+            track_deliveries: self.track_deliveries,
+            dedup_window: self.dedup_window,
+            max_age: self.max_age,
+            consumer_group: self.consumer_group.clone(),
+            dead_letter_queue: Some(dead_letter_queue),
+            codecs: self.codecs.clone(),
+            key_provider: self.key_provider.clone(),
+            verify_checksums: self.verify_checksums,
+            lease: self.lease,
+            visibility_timeout: self.visibility_timeout,
+            metrics: self.metrics.clone(),
+            on_segment_consumed: self.on_segment_consumed.clone(),
+            read_buffer_size: self.read_buffer_size,
+            mmap: self.mmap,
+            poll_interval: self.poll_interval,
+            storage: None,
+            #[cfg(feature = "serde")]
+            wire_format: self.wire_format,
+            #[cfg(unix)]
+            permissions: self.permissions,
+            in_process: self.in_process,
+        }
+    }
+
+    /// Opens the receivers of the priority lanes created since the last call,
+    /// with the same configuration as this receiver.
+    fn open_lanes(&mut self) -> io::Result<()> {
+        // (senders with a storage of their own cannot open lanes)
+        if self.custom_storage {
+            return Ok(());
+        }
+
+        for dir_entry in read_dir(&self.base)? {
+            let path = dir_entry?.path();
+
+            if let Some(priority) = lane_priority(&path) {
+                if !self.lanes.contains_key(&priority) {
+                    log::trace!("opening priority lane {} of {:?}", priority, self.base);
+                    let mut lane = self
+                        .nested_builder(lane_dirname(&self.dead_letter_base, priority))
+                        .open(path)?;
+                    lane.wait_clock = self.wait_clock.clone();
+                    self.lanes.insert(priority, lane);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Begins a transaction in the lane with the highest priority that has
+    /// an element and takes one element from it. See
+    /// [`Receiver::recv_prioritized`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "recv_prioritized",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    async fn take_prioritized(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        loop {
+            // Watch before looking, so that no new lane goes unnoticed:
+            let creation = CreationEvent::new(&self.base);
+            self.open_lanes()?;
+
+            // Race all lanes, from the highest priority down to this queue.
+            // Since `select_all` polls in order, the highest priority among
+            // the lanes with an element wins. (If this future is not polled to
+            // completion, the lanes are dropped and reopened on the next call.)
+            let mut lanes = std::mem::take(&mut self.lanes);
+            let outcome = {
+                type Take<'a> = Pin<Box<dyn Future<Output = (u8, io::Result<Vec<u8>>)> + 'a>>;
+                let mut takes = lanes
+                    .iter_mut()
+                    .rev()
+                    .map(|(&priority, lane)| -> Take<'_> {
+                        Box::pin(async move { (priority, lane.take().await) })
+                    })
+                    .collect::<Vec<_>>();
+                takes.push(Box::pin(async { (0, self.take().await) }));
+
+                match future::select(future::select_all(takes), creation).await {
+                    future::Either::Left((((priority, data), _, _), _)) => Some((priority, data)),
+                    future::Either::Right(((), _)) => None,
+                }
+            };
+
+            // Give back the claims that were not used:
+            let winner = outcome.as_ref().map(|&(priority, _)| priority);
+            for (&priority, lane) in lanes.iter_mut() {
+                if Some(priority) != winner {
+                    lane.release();
+                }
+            }
+            if winner != Some(0) {
+                self.release();
+            }
+
+            self.lanes = lanes;
+
+            if let Some((priority, data)) = outcome {
+                return Ok((priority, data?));
+            }
+
+            log::trace!("something was created in {:?}. Looking for lanes", self.base);
+        }
+    }
+
+    /// Tries to begin a transaction in the lane with the highest priority
+    /// that has an element and to take one element from it. See
+    /// [`Receiver::try_recv_prioritized`].
+    fn try_take_prioritized(&mut self) -> Option<io::Result<(u8, Vec<u8>)>> {
+        if let Err(err) = self.open_lanes() {
+            return Some(Err(err));
+        }
+
+        for (&priority, lane) in self.lanes.iter_mut().rev() {
+            if let Some(outcome) = lane.take().now_or_never() {
+                return Some(outcome.map(|data| (priority, data)));
+            } else {
+                lane.release();
+            }
+        }
+
+        let outcome = self.take().now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        outcome.map(|outcome| outcome.map(|data| (0, data)))
+    }
+
+    /// The index of the topic subscribed to to look at first in this
+    /// reception, so that all topics get their turn. Fails if there are no
+    /// topics subscribed to.
+    fn next_turn(&mut self) -> io::Result<usize> {
+        if self.topics.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("receiver of {:?} is not subscribed to any topic", self.base),
+            ));
+        }
+
+        let turn = self.next_topic % self.topics.len();
+        self.next_topic = turn + 1;
+
+        Ok(turn)
+    }
+
+    /// Begins a transaction in one of the topics subscribed to that has an
+    /// element and takes one element from it. See
+    /// [`Receiver::recv_subscribed`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "recv_subscribed",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    async fn take_subscribed(&mut self) -> io::Result<(String, Vec<u8>)> {
+        let turn = self.next_turn()?;
+
+        // Race all topics, starting with the one whose turn it is. (If this
+        // future is not polled to completion, nothing was taken.)
+        let (winner, data) = {
+            type Take<'a> = Pin<Box<dyn Future<Output = (String, io::Result<Vec<u8>>)> + 'a>>;
+            let mut takes = self
+                .topics
+                .iter_mut()
+                .map(|(topic, receiver)| -> Take<'_> {
+                    Box::pin(async move { (topic.clone(), receiver.take().await) })
+                })
+                .collect::<Vec<_>>();
+            takes.rotate_left(turn);
+
+            let ((winner, data), _, _) = future::select_all(takes).await;
+            (winner, data)
+        };
+
+        // Give back the claims that were not used:
+        for (topic, receiver) in self.topics.iter_mut() {
+            if *topic != winner {
+                receiver.release();
+            }
+        }
+
+        Ok((winner, data?))
+    }
+
+    /// Tries to begin a transaction in one of the topics subscribed to that
+    /// has an element and to take one element from it. See
+    /// [`Receiver::try_recv_subscribed`].
+    fn try_take_subscribed(&mut self) -> Option<io::Result<(String, Vec<u8>)>> {
+        let turn = match self.next_turn() {
+            Ok(turn) => turn,
+            Err(err) => return Some(Err(err)),
+        };
+
+        let mut turns = self.topics.keys().cloned().collect::<Vec<_>>();
+        turns.rotate_left(turn);
+
+        for topic in turns {
+            let receiver = self.topics.get_mut(&topic).expect("topic is subscribed");
+
+            if let Some(outcome) = receiver.take().now_or_never() {
+                return Some(outcome.map(|data| (topic, data)));
+            } else {
+                receiver.release();
+            }
+        }
+
+        None
+    }
+
+    /// Saves the receiver queue state. You do not need to use method in most
+    /// circumstances, since it is automatically done on drop (yes, it will be
+    /// called eve if your thread panics). However, you cawn use this function to
+    ///
+    /// 1. Make periodical backups. Use an external timer implementation for this.
+    ///
+    /// 2. Handle possible IO errors in logging the state of the queue to the disk
+    ///    after commit. The `drop` implementation will ignore (but log) any io
+    ///    errors, which may lead to data loss in an unreliable filesystem. It was
+    ///    implemented this way because no errors are allowed to propagate on drop
+    ///    and panicking will abort the program if drop is called during a panic.
+    pub fn save(&mut self) -> io::Result<()> {
+        #[cfg(feature = "failpoints")]
+        failpoints::check(&self.base, Failpoint::StateSave)?;
+
+        self.storage.persist_state(&self.initial_state)?;
+
+        if let Some(seen_ids) = &self.seen_ids {
+            // (this also keeps the ids of the elements still in flight)
+            seen_ids.save(&self.state_base)?;
+        }
+
+        if let Some(metrics) = &self.metrics {
+            let QueueState { segment, position } = self.initial_state;
+            metrics.state_saved(&self.base, segment, position);
+        }
+
+        Ok(())
+    }
+
+    // (`u64::is_multiple_of` is too recent, at Rust 1.87)
+    #[allow(clippy::manual_is_multiple_of)]
+    fn maybe_save(&mut self) -> io::Result<()> {
+        if let Some(save_every_nth) = self.save_every_nth {
+            if self.n_reads % save_every_nth == 0 {
+                self.save()?;
+            }
+        } else if let Some(save_every) = self.save_every {
+            if self.last_saved_at.elapsed() >= save_every {
+                self.save()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Retrieves an element from the queue. The returned value is a
+    /// guard that will only commit state changes to the queue when dropped.
+    ///
+    /// This operation is atomic. If the returned future is not polled to
+    /// completion, as, e.g., when calling `select`, the operation will be
+    /// undone.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
+        let data = self.take().await?;
+        Ok(self.guard(data))
+    }
+
+    /// Retrieves an element from the queue as [`Bytes`], in the same way as
+    /// [`Receiver::recv`]. Once this is used, the elements are read into a
+    /// buffer of the receiver, which is reused for the next elements once
+    /// the `Bytes` of the previous ones are dropped. Thus, no memory is
+    /// allocated per element, as long as the elements are not kept around.
+    /// Compressed and encrypted elements are still decoded into memory of
+    /// their own.
+    ///
+    /// Mixing this with the other receiving methods works, but the elements
+    /// they receive are then copied out of the buffer.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_bytes(&mut self) -> io::Result<RecvGuard<'_, Bytes>> {
+        self.zero_copy = true;
+        let data = self.take_bytes().await?;
+        Ok(self.guard(data))
+    }
+
+    /// Tries to retrieve an element from the queue as [`Bytes`]. See
+    /// [`Receiver::recv_bytes`] and [`Receiver::try_recv`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub fn try_recv_bytes(&mut self) -> Result<RecvGuard<'_, Bytes>, TryRecvError> {
+        self.zero_copy = true;
+        let outcome = self.take_bytes().now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        Ok(self.guard(TryRecvError::result_from_option(outcome)?))
+    }
+
+    /// Retrieves an element from the queue, taking it from the priority lane
+    /// with the highest priority that has an element. Elements sent with
+    /// [`Sender::send_with_priority`](crate::Sender::send_with_priority) are
+    /// thus delivered before any element of a lower priority (and elements
+    /// sent with [`Sender::send`] have the lowest priority, `0`). Within a
+    /// lane, elements are delivered in order. The returned value is a guard
+    /// that will only commit state changes to the lane when dropped.
+    ///
+    /// The other receiving methods only receive from the queue itself.
+    ///
+    /// This operation is atomic. If the returned future is not polled to
+    /// completion, as, e.g., when calling `select`, the operation will be
+    /// undone.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_prioritized(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
+        let (priority, data) = self.take_prioritized().await?;
+
+        if priority == 0 {
+            Ok(self.guard(data))
+        } else {
+            Ok(self
+                .lanes
+                .get_mut(&priority)
+                .expect("lane was just received from")
+                .guard(data))
+        }
+    }
+
+    /// Tries to retrieve an element from the queue, taking it from the
+    /// priority lane with the highest priority that has an element. See
+    /// [`Receiver::recv_prioritized`] for how priorities work.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub fn try_recv_prioritized(&mut self) -> Result<RecvGuard<'_, Vec<u8>>, TryRecvError> {
+        let (priority, data) = TryRecvError::result_from_option(self.try_take_prioritized())?;
+
+        if priority == 0 {
+            Ok(self.guard(data))
+        } else {
+            Ok(self
+                .lanes
+                .get_mut(&priority)
+                .expect("lane was just received from")
+                .guard(data))
+        }
+    }
+
+    /// Subscribes to topics of the queue (see
+    /// [`Sender::try_send_to`](crate::Sender::try_send_to)), so that
+    /// [`Receiver::recv_subscribed`] receives the elements sent to them. Each
+    /// topic is received from by a receiver of its own, opened with the same
+    /// configuration as this receiver, which creates the topic if nothing was
+    /// sent to it yet. Subscribing to a topic again does nothing.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if a topic name is not valid or if the queue has a storage of its own
+    /// (see [`ReceiverBuilder::storage`]). Also, it returns any error
+    /// encountered while opening the receiver of a topic, such as the topic
+    /// being already in use for receiving, in which case the topics before it
+    /// are still subscribed to.
+    pub fn subscribe<I, S>(&mut self, topics: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for topic in topics {
+            let topic = topic.as_ref();
+
+            if !self.topics.contains_key(topic) {
+                if self.custom_storage {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "topics need the default storage",
+                    ));
+                }
+
+                check_topic(topic)?;
+                log::trace!("subscribing to topic {:?} of {:?}", topic, self.base);
+                let mut receiver = self
+                    .nested_builder(topic_dirname(&self.dead_letter_base, topic))
+                    .open(topic_dirname(&self.base, topic))?;
+                receiver.topic = Some(topic.to_owned());
+                receiver.wait_clock = self.wait_clock.clone();
+                self.topics.insert(topic.to_owned(), receiver);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribes from a topic of the queue, closing its receiver (which
+    /// saves its state, as on drop). Unsubscribing from a topic that was not
+    /// subscribed to does nothing. The topic itself is kept; see
+    /// [`crate::queue::remove_topic`].
+    pub fn unsubscribe(&mut self, topic: &str) {
+        if let Some(receiver) = self.topics.remove(topic) {
+            self.stats.add(&receiver.stats());
+        }
+    }
+
+    /// Retrieves an element from one of the topics subscribed to (see
+    /// [`Receiver::subscribe`]). Topics take turns: the topic looked at first
+    /// changes with every reception, so that a busy topic does not starve the
+    /// others. Within a topic, elements are delivered in order. The returned
+    /// value is a guard that will only commit state changes to the topic when
+    /// dropped, and that tells which topic the element came from (see
+    /// [`RecvGuard::topic`]).
+    ///
+    /// The other receiving methods only receive from the queue itself.
+    ///
+    /// This operation is atomic. If the returned future is not polled to
+    /// completion, as, e.g., when calling `select`, the operation will be
+    /// undone.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the receiver is not subscribed to any topic.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_subscribed(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
+        let (topic, data) = self.take_subscribed().await?;
+
+        Ok(self
+            .topics
+            .get_mut(&topic)
+            .expect("topic was just received from")
+            .guard(data))
+    }
+
+    /// Tries to retrieve an element from one of the topics subscribed to. See
+    /// [`Receiver::recv_subscribed`] for how topics take turns.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the receiver is not subscribed to any topic.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub fn try_recv_subscribed(&mut self) -> Result<RecvGuard<'_, Vec<u8>>, TryRecvError> {
+        let (topic, data) = TryRecvError::result_from_option(self.try_take_subscribed())?;
+
+        Ok(self
+            .topics
+            .get_mut(&topic)
+            .expect("topic was just received from")
+            .guard(data))
+    }
+
+    /// Gets a view of the next element in the queue without taking it. This
+    /// leaves the queue state untouched: the next element received will be
+    /// this same element and there is nothing to commit or to roll back.
+    ///
+    /// This operation is atomic. If the returned future is not polled to
+    /// completion, as, e.g., when calling `select`, the operation will be
+    /// undone.
+    ///
+    /// # Note
+    ///
+    /// A shared receiver keeps its claim on the queue after peeking, until its
+    /// next transaction ends.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn peek(&mut self) -> io::Result<&[u8]> {
+        self.read_ahead_one().await?;
+        Ok(&self
+            .read_and_unused
+            .front()
+            .expect("guaranteed to yield an element")
+            .0)
+    }
+
+    /// Starts a transaction that can receive elements many times and then
+    /// commit them all at once (or roll them all back). This is useful if you
+    /// need to process elements received separately together. As with
+    /// [`RecvGuard`], the transaction is rolled back when dropped without a
+    /// commit.
+    pub fn transaction(&mut self) -> RecvTransaction<'_> {
+        RecvTransaction {
+            receiver: self,
+            items: vec![],
+            was_finished: false,
+        }
+    }
+
+    /// Turns this receiver into a [`RecvStream`], a [`futures::Stream`] of
+    /// the elements of the queue. Every element is committed as it is
+    /// yielded by the stream.
+    pub fn into_stream(self) -> RecvStream {
+        RecvStream::new(self)
+    }
+
+    /// Gets a cursor that walks forward over the elements of the queue from
+    /// the current position of this receiver, until it hits the end of the
+    /// queue. Browsing neither commits nor advances the state of the queue.
+    /// This is useful, e.g., to inspect a backlog.
+    ///
+    /// # Note
+    ///
+    /// A shared receiver browses from the state saved by the last receiver to
+    /// commit, unless it has a claim on the queue. Since other receivers may
+    /// carry on in the meantime, this is only a best-effort view.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while loading
+    /// the state of the queue or while opening the current segment.
+    pub fn browse(&self) -> io::Result<Browse<'_>> {
+        let state = if self.file_guard.is_some() {
+            self.initial_state
+        } else {
+            self.storage.load_state()?
+        };
+
+        Browse::new(
+            &self.base,
+            self.storage.clone(),
+            state,
+            self.codecs.clone(),
+            self.key_provider.clone(),
+        )
+    }
+
+    /// Gets what this receiver did since it was opened, counting what was
+    /// received from its priority lanes and from the topics subscribed to.
+    /// Elements taken in the current transaction only count once committed.
+    pub fn stats(&self) -> ReceiverStats {
+        let mut stats = self.stats;
+
+        for receiver in self.lanes.values().chain(self.topics.values()) {
+            stats.add(&receiver.stats());
+        }
+
+        // (waiting right now counts too)
+        let clock = self.wait_clock.lock().expect("poisoned");
+        let waiting = clock.since.map_or(Duration::ZERO, |since| since.elapsed());
+        stats.time_waiting = clock.waited + waiting;
+
+        stats
+    }
+
+    /// Gets how much is waiting in the queue to be received after what this
+    /// receiver has committed. See [`queue_info`](super::queue_info).
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while loading
+    /// the state of the queue or while reading the segments.
+    pub fn info(&self) -> io::Result<QueueInfo> {
+        queue_info_from(&*self.storage, self.committed_state()?)
+    }
+
+    /// Tells whether anything is waiting in the queue to be received after
+    /// what this receiver has committed, without walking the queue and
+    /// without waiting for anything. Use this to decide whether to receive at
+    /// all. See [`has_pending`](super::has_pending).
+    ///
+    /// # Errors
+    ///
+    /// This function returns the same errors as
+    /// [`has_pending`](super::has_pending).
+    pub fn has_pending(&self) -> io::Result<bool> {
+        has_pending_from(&*self.storage, self.committed_state()?)
+    }
+
+    /// Gets how long the element at the front of the queue, after what this
+    /// receiver has committed, has been waiting to be received. This is
+    /// `None` if the queue is empty or if the element was sent without a
+    /// timestamp. See [`oldest_item_age`](super::oldest_item_age). Elements
+    /// too old for this receiver (see [`ReceiverBuilder::max_age`]) are
+    /// skipped too, but duplicates are counted.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the same errors as
+    /// [`oldest_item_age`](super::oldest_item_age).
+    pub fn oldest_item_age(&self) -> io::Result<Option<Duration>> {
+        oldest_item_age_from(&*self.storage, self.committed_state()?, |metadata| {
+            self.is_too_old(metadata)
+        })
+    }
+
+    /// The state after what this receiver has committed or, if it has no
+    /// claim on the queue, the state saved by the last receiver.
+    fn committed_state(&self) -> io::Result<QueueState> {
+        if self.file_guard.is_some() {
+            Ok(self.initial_state)
+        } else {
+            self.storage.load_state()
+        }
+    }
+
+    /// Waits until at least `items` elements are waiting in the queue to be
+    /// received after what this receiver has committed, returning how much is
+    /// waiting. Use this to wake up only once enough work has piled up, e.g.,
+    /// before a [`Receiver::recv_batch`]. See [`Receiver::info`] for what
+    /// counts as waiting. Priority lanes are not included.
+    ///
+    /// The queue is only walked from where the last check stopped, every time
+    /// the sender writes to it, so waiting is cheap even when many elements
+    /// are sent one by one.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while loading
+    /// the state of the queue or while reading the segments.
+    pub async fn wait_for_depth(&self, items: u64) -> io::Result<QueueInfo> {
+        let mut change_event = ChangeEvent::new(&self.base);
+        let mut state = self.committed_state()?;
+        let mut pending = QueueInfo::default();
+
+        loop {
+            let (walked, end) = walk_queue_from(&*self.storage, state, |_size| true)?;
+            pending.items += walked.items;
+            pending.bytes += walked.bytes;
+            state = end;
+
+            if pending.items >= items {
+                return Ok(pending);
+            }
+
+            // (changes between the walk and the first poll may be missed)
+            let recheck = Delay::new(RECHECK_DEPTH_EVERY);
+            future::select(&mut change_event, recheck).await;
+        }
+    }
+
+    /// Moves the receiver to a given position in a given segment, as if
+    /// everything before it had been received (or as if nothing after it had
+    /// been received, if the position is in the past). Use this to skip over a
+    /// known-bad region of the queue or to rewind into the data that is still
+    /// retained. Skipping past the end of a segment removes it. The new state
+    /// is saved right away.
+    ///
+    /// The position is validated by walking the segment from its begining, so
+    /// it must be the start of an element, the end of what was written so far
+    /// or `0` (the start of a segment). Elements that were read ahead, e.g.,
+    /// with [`Receiver::peek`], are discarded.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the position is not the start of an element, of kind
+    /// [`io::ErrorKind::InvalidData`] if there is a corrupted element in the
+    /// segment before the position (seek to the start of the next segment in
+    /// this case) and any underlying IO error, as when the segment does not
+    /// exist.
+    pub async fn seek(&mut self, segment: u64, position: u64) -> io::Result<()> {
+        let target = QueueState { segment, position };
+        self.begin().await?;
+
+        let is_boundary = match is_element_boundary(&self.base, &*self.storage, target) {
+            Ok(is_boundary) => is_boundary,
+            Err(err) => {
+                self.release();
+                return Err(err);
+            }
+        };
+
+        if !is_boundary {
+            self.release();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not the start of an element in {:?}", target, self.base),
+            ));
+        }
+
+        log::debug!("seeking {:?} to {:?}", self.base, target);
+        self.read_and_unused.clear();
+        self.go_to(target)?;
+
+        // When going back, there are no segments to remove:
+        if target < self.initial_state {
+            self.initial_state = target;
+        }
+
+        self.settle()?;
+        self.save()?;
+        self.release();
+
+        Ok(())
+    }
+
+    /// Commits everything up to a position in the queue in one shot, as if
+    /// all the elements before it had been received and committed. This is
+    /// for consumers that track their progress elsewhere (e.g., as the
+    /// [positions](RecvGuard::position) of the elements they processed) and
+    /// only sync it back into the queue from time to time. Skipping past the
+    /// end of a segment removes it. The new state is saved right away.
+    ///
+    /// Positions at or before what was already committed are ignored, so that
+    /// syncing a stale position is harmless; use [`Receiver::seek`] to rewind.
+    /// As in [`Receiver::seek`], the position must be the start of an element,
+    /// the end of what was written so far or `0` (the start of a segment).
+    ///
+    /// # Errors
+    ///
+    /// This function returns the same errors as [`Receiver::seek`].
+    pub async fn commit_up_to(&mut self, segment: u64, position: u64) -> io::Result<()> {
+        let target = QueueState { segment, position };
+        self.begin().await?;
+
+        if target <= self.initial_state {
+            log::trace!("{:?} was already committed in {:?}", target, self.base);
+            self.release();
+            return Ok(());
+        }
+
+        let outcome =
+            is_element_boundary(&self.base, &*self.storage, target).and_then(|is_boundary| {
+                if !is_boundary {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{:?} is not the start of an element in {:?}", target, self.base),
+                    ));
+                }
+
+                log::debug!("committing {:?} up to {:?}", self.base, target);
+                self.skip_to(target)
+            });
+        self.release();
+
+        outcome
+    }
+
+    /// Walks the queue from the start of the current transaction. See
+    /// [`walk_queue_from`].
+    fn walk<F>(&self, visit: F) -> io::Result<(QueueInfo, QueueState)>
+    where
+        F: FnMut(u64) -> bool,
+    {
+        let mut from = self.initial_state;
+        let segment = storage::surviving_segment(&*self.storage, from.segment)?;
+
+        if segment != from.segment {
+            from = QueueState {
+                segment,
+                position: 0,
+            };
+        }
+
+        walk_queue_from(&*self.storage, from, visit)
+    }
+
+    /// Moves the receiver forward to the start of an element (or to the end of
+    /// what was written so far), as if everything before it had been
+    /// received. Skipping past the end of a segment removes it. The new state
+    /// is saved right away.
+    fn skip_to(&mut self, state: QueueState) -> io::Result<()> {
+        self.read_and_unused.clear();
+        self.go_to(state)?;
+        self.settle()?;
+        self.save()
+    }
+
+    /// Discards everything waiting in the queue and in its priority lanes, as
+    /// if it had all been received, and returns how much was discarded. The
+    /// segments read through are removed and the new state is saved right
+    /// away, together with a fresh count of deliveries. Unlike
+    /// [`super::clear`], this keeps the queue folder (and its settings) and
+    /// can be used while a sender is running: elements sent while purging may
+    /// or may not be discarded.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while walking
+    /// the queue or while saving the new state.
+    pub async fn purge(&mut self) -> io::Result<QueueInfo> {
+        self.begin().await?;
+
+        let outcome = self.walk(|_size| true).and_then(|(purged, end)| {
+            log::debug!("purging {:?} up to {:?}", self.base, end);
+            self.skip_to(end)?;
+
+            if self.counts_deliveries() {
+                self.deliveries = Deliveries::default();
+                self.deliveries.save(&self.state_base)?;
+            }
+
+            Ok(purged)
+        });
+
+        self.release();
+        let mut purged = outcome?;
+
+        // Priority lanes are queues of their own:
+        self.open_lanes()?;
+        for lane in self.lanes.values_mut() {
+            let lane_purged = Box::pin(lane.purge()).await?;
+            purged.items += lane_purged.items;
+            purged.bytes += lane_purged.bytes;
+        }
+
+        Ok(purged)
+    }
+
+    /// Drops all but the newest elements waiting in the queue, as if the older
+    /// ones had been received, and returns how much was dropped. At most
+    /// `max_items` elements, taking at most `max_bytes` bytes (including their
+    /// headers and metadata), are kept; `None` means no limit. The segments
+    /// read through are removed and the new state is saved right away. Use
+    /// this to catch up with a queue that piled up while nobody was receiving.
+    ///
+    /// Priority lanes are queues of their own and are not included.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while walking
+    /// the queue or while saving the new state.
+    pub async fn keep_latest(
+        &mut self,
+        max_items: Option<u64>,
+        max_bytes: Option<u64>,
+    ) -> io::Result<QueueInfo> {
+        self.begin().await?;
+
+        let outcome = self.walk(|_size| true).and_then(|(mut left, _end)| {
+            // Walk again, now dropping elements until few enough are left:
+            let (dropped, kept_from) = self.walk(|size| {
+                let is_over = max_items.map(|max| left.items > max).unwrap_or(false)
+                    || max_bytes.map(|max| left.bytes > max).unwrap_or(false);
+
+                if is_over {
+                    left.items -= 1;
+                    left.bytes -= size;
+                }
+
+                is_over
+            })?;
+
+            log::debug!("keeping {:?} from {:?} on", self.base, kept_from);
+            self.skip_to(kept_from)?;
+
+            Ok(dropped)
+        });
+
+        self.release();
+        outcome
+    }
+
+    /// Tries to get a view of the next element in the queue without taking
+    /// it. See [`Receiver::peek`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub fn try_peek(&mut self) -> Result<&[u8], TryRecvError> {
+        let outcome = self.read_ahead_one().now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        TryRecvError::result_from_option(outcome)?;
+
+        Ok(&self
+            .read_and_unused
+            .front()
+            .expect("guaranteed to yield an element")
+            .0)
+    }
+
+    /// Tries to retrieve an element from the queue. The returned value is a
+    /// guard that will only commit state changes to the queue when dropped.
+    ///
+    /// This function never waits for the tail of the queue: if there is no
+    /// complete element ready to be decoded, it returns immediately with
+    /// [`TryRecvError::QueueEmpty`] and the receiver is left as it was, so
+    /// that it is safe to call from a busy event loop.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub fn try_recv(&mut self) -> Result<RecvGuard<'_, Vec<u8>>, TryRecvError> {
+        let outcome = self.take().now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        Ok(self.guard(TryRecvError::result_from_option(outcome)?))
+    }
+
+    /// Retrieves an element from the queue until a given future
+    /// finishes, whichever comes first. If an element arrives first, the
+    /// returned value is a guard that will only commit state changes to the
+    /// queue when dropped. Otherwise, `Ok(None)` is returned.
+    ///
+    /// This operation is atomic. If the returned future is not polled to
+    /// completion, as, e.g., when calling `select`, the operation will be
+    /// undone.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_timeout<F>(
+        &mut self,
+        timeout: F,
+    ) -> io::Result<Option<RecvGuard<'_, Vec<u8>>>>
+    where
+        F: Future<Output = ()> + Unpin,
+    {
+        let outcome = match future::select(Box::pin(self.take()), timeout).await {
+            future::Either::Left((data, _)) => Some(data?),
+            future::Either::Right((_, _)) => None,
+        };
+
+        if let Some(data) = outcome {
+            Ok(Some(self.guard(data)))
+        } else {
+            self.release();
+            Ok(None)
+        }
+    }
+
+    /// Retrieves an element from the queue unless a cancellation signal, such
+    /// as a shutdown notice, comes first. This is the same as
+    /// [`Receiver::recv_timeout`], but any future can be used as the signal,
+    /// even if it is not [`Unpin`].
+    ///
+    /// If the signal comes first, `Ok(None)` is returned. In this case, the
+    /// reception is undone, so no element is lost, and the claim of a shared
+    /// receiver on the queue is released, so that other receivers can carry
+    /// on.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_with_cancel<C>(
+        &mut self,
+        cancel: C,
+    ) -> io::Result<Option<RecvGuard<'_, Vec<u8>>>>
+    where
+        C: Future<Output = ()>,
+    {
+        self.recv_timeout(Box::pin(cancel)).await
+    }
+
+    /// Retrieves an element from the queue, awaiting up to a given duration.
+    /// This is the same as [`Receiver::recv_timeout`] with a timer: if no
+    /// element arrives in time, `Ok(None)` is returned and nothing is lost.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_timeout_duration(
+        &mut self,
+        timeout: Duration,
+    ) -> io::Result<Option<RecvGuard<'_, Vec<u8>>>> {
+        self.recv_timeout(Delay::new(timeout)).await
+    }
+
+    /// Removes a number of elements from the queue. The returned value is a
+    /// guard that will only commit state changes to the queue when dropped.
+    ///
+    /// # Note
+    ///
+    /// This operation is atomic in an asynchronous context. This means that you
+    /// will not lose the elements if you do not await this function to
+    /// completion.
     ///
     /// # Panics
     ///
     /// This function will panic if it has to start reading a new segment and
     /// it is not able to set up the notification handler to watch for file
     /// changes.
-    pub fn try_recv(&mut self) -> Result<RecvGuard<'_, Vec<u8>>, TryRecvError> {
-        TryRecvError::result_from_option(self.recv().now_or_never())
+    pub async fn recv_batch(&mut self, n: usize) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        let data = self.take_batch(n).await?;
+        Ok(self.guard(data))
     }
 
-    /// Retrieves an element from the queue until a given future
-    /// finishes, whichever comes first. If an element arrives first, the
-    /// returned value is a guard that will only commit state changes to the
-    /// queue when dropped. Otherwise, `Ok(None)` is returned.
-    ///
-    /// This operation is atomic. If the returned future is not polled to
-    /// completion, as, e.g., when calling `select`, the operation will be
-    /// undone.
+    /// Tries to remove a number of elements from the queue. The returned value
+    /// is a guard that will only commit state changes to the queue when
+    /// dropped.
     ///
     /// # Panics
     ///
     /// This function will panic if it has to start reading a new segment and
     /// it is not able to set up the notification handler to watch for file
     /// changes.
-    pub async fn recv_timeout<F>(
+    pub fn try_recv_batch(
         &mut self,
-        timeout: F,
-    ) -> io::Result<Option<RecvGuard<'_, Vec<u8>>>>
-    where
-        F: Future<Output = ()> + Unpin,
-    {
-        self.begin();
+        n: usize,
+    ) -> Result<RecvGuard<'_, Vec<Vec<u8>>>, TryRecvError> {
+        let outcome = self.take_batch(n).now_or_never();
 
-        let data = if let Some(data) = self.read_and_unused.pop_front() {
-            data
-        } else {
-            if self.read_one_timeout(timeout).await? {
-                self.read_and_unused
-                    .pop_front()
-                    .expect("guaranteed to yield an element")
-            } else {
-                return Ok(None);
-            }
-        };
+        if outcome.is_none() {
+            self.release();
+        }
 
-        Ok(Some(RecvGuard {
-            receiver: self,
-            item: Some(data),
-            was_finished: false,
-        }))
+        Ok(self.guard(TryRecvError::result_from_option(outcome)?))
     }
 
-    /// Removes a number of elements from the queue. The returned value is a
-    /// guard that will only commit state changes to the queue when dropped.
+    /// Removes at most `n` elements from the queue, returning as soon as no
+    /// more elements are immediately available. This only waits for the first
+    /// element, so it is the right shape for consumers that process whatever
+    /// arrived since the last time they looked. The returned value is a guard
+    /// that will only commit state changes to the queue when dropped.
     ///
     /// # Note
     ///
@@ -512,40 +3049,35 @@ impl Receiver {
     /// This function will panic if it has to start reading a new segment and
     /// it is not able to set up the notification handler to watch for file
     /// changes.
-    pub async fn recv_batch(&mut self, n: usize) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
-        self.begin();
-
-        // First, fetch what is missing from the disk:
-        if n > self.read_and_unused.len() {
-            for _ in 0..(n - self.read_and_unused.len()) {
-                self.read_one().await?;
-            }
-        }
-
-        // And now, drain!
-        let data = self.drain(n);
-
-        Ok(RecvGuard {
-            receiver: self,
-            item: Some(data),
-            was_finished: false,
-        })
+    pub async fn recv_batch_up_to(
+        &mut self,
+        n: usize,
+    ) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        let data = self.take_batch_up_to(n).await?;
+        Ok(self.guard(data))
     }
 
-    /// Tries to remove a number of elements from the queue. The returned value
-    /// is a guard that will only commit state changes to the queue when
-    /// dropped.
+    /// Tries to remove at most `n` elements from the queue, taking only the
+    /// elements that are immediately available. If there is none, this returns
+    /// [`TryRecvError::QueueEmpty`]. The returned value is a guard that will
+    /// only commit state changes to the queue when dropped.
     ///
     /// # Panics
     ///
     /// This function will panic if it has to start reading a new segment and
     /// it is not able to set up the notification handler to watch for file
     /// changes.
-    pub fn try_recv_batch(
+    pub fn try_recv_batch_up_to(
         &mut self,
         n: usize,
     ) -> Result<RecvGuard<'_, Vec<Vec<u8>>>, TryRecvError> {
-        TryRecvError::result_from_option(self.recv_batch(n).now_or_never())
+        let outcome = self.take_batch_up_to(n).now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        Ok(self.guard(TryRecvError::result_from_option(outcome)?))
     }
 
     /// Tries to remove a number of elements from the queue until a given future
@@ -573,11 +3105,19 @@ impl Receiver {
     where
         F: Future<Output = ()> + Unpin,
     {
-        self.begin();
+        // A shared receiver might not get to claim the queue in time:
+        let claimed = match future::select(Box::pin(self.begin()), &mut timeout).await {
+            future::Either::Left((begin, _)) => {
+                begin?;
+                true
+            }
+            future::Either::Right((_, _)) => false,
+        };
+
         let mut n_read = 0;
 
         // First, fetch what is missing from the disk:
-        if n > self.read_and_unused.len() {
+        if claimed && n > self.read_and_unused.len() {
             for _ in 0..(n - self.read_and_unused.len()) {
                 if !self.read_one_timeout(&mut timeout).await? {
                     break;
@@ -590,11 +3130,29 @@ impl Receiver {
         // And now, drain!
         let data = self.drain(n_read);
 
-        Ok(RecvGuard {
-            receiver: self,
-            item: Some(data),
-            was_finished: false,
-        })
+        if !data.is_empty() {
+            self.on_delivery()?;
+        }
+
+        Ok(self.guard(data))
+    }
+
+    /// Tries to remove a number of elements from the queue, awaiting up to a
+    /// given duration. This is the same as [`Receiver::recv_batch_timeout`]
+    /// with a timer.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_batch_timeout_duration(
+        &mut self,
+        n: usize,
+        timeout: Duration,
+    ) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
+        self.recv_batch_timeout(n, Delay::new(timeout))
+            .await
     }
 
     /// Takes a number of elements from the queue until a certain asynchronous
@@ -626,43 +3184,14 @@ impl Receiver {
     /// changes.
     pub async fn recv_until<P, Fut>(
         &mut self,
-        mut predicate: P,
+        predicate: P,
     ) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>>
     where
         P: FnMut(Option<&[u8]>) -> Fut,
         Fut: std::future::Future<Output = bool>,
     {
-        self.begin();
-        let mut n_read = 0;
-
-        // Prepare:
-        predicate(None).await;
-
-        // Poor man's do-while (aka. until)
-        // Strategy: fill `read_and_unused` to the brim and then drain at the end.
-        loop {
-            // Need to fetch from disk?
-            if n_read == self.read_and_unused.len() {
-                self.read_one().await?;
-            }
-
-            let item_ref = &self.read_and_unused[n_read];
-
-            if !predicate(Some(item_ref)).await {
-                n_read += 1;
-            } else {
-                break;
-            }
-        }
-
-        // And now, drain!
-        let data = self.drain(n_read);
-
-        Ok(RecvGuard {
-            receiver: self,
-            item: Some(data),
-            was_finished: false,
-        })
+        let data = self.take_until(predicate).await?;
+        Ok(self.guard(data))
     }
 
     /// Tries to take a number of elements from the queue until a certain
@@ -693,18 +3222,86 @@ impl Receiver {
     where
         P: FnMut(Option<&[u8]>) -> bool,
     {
-        TryRecvError::result_from_option(
-            self.recv_until(move |el| {
+        let outcome = self
+            .take_until(move |el| {
                 let outcome = predicate(el);
                 async move { outcome }
             })
-            .now_or_never(),
-        )
+            .now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        Ok(self.guard(TryRecvError::result_from_option(outcome)?))
+    }
+
+    /// Takes elements from the queue while a predicate holds. The run ends at
+    /// the first element for which the predicate returns `false`, and _this
+    /// element is included_ in the run. This is the shape of records that end
+    /// with an end-of-transaction marker. The returned value is a guard that
+    /// will commit (or roll back) the whole run at once.
+    ///
+    /// # Note
+    ///
+    /// This operation is atomic in an asynchronous context. This means that you
+    /// will not lose the elements if you do not await this function to
+    /// completion. The predicate might be called more than once for the same
+    /// element in this case.
+    ///
+    /// # Example
+    ///
+    /// Receive up to (and including) an end marker:
+    /// ```ignore
+    /// let recv_guard = receiver.recv_while(|element| element != b"end").await;
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_while<P>(&mut self, predicate: P) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>>
+    where
+        P: FnMut(&[u8]) -> bool,
+    {
+        let data = self.take_while(predicate).await?;
+        Ok(self.guard(data))
+    }
+
+    /// Tries to take elements from the queue while a predicate holds. See
+    /// [`Receiver::recv_while`]. If the run has not ended yet, nothing is
+    /// taken and [`TryRecvError::QueueEmpty`] is returned.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub fn try_recv_while<P>(
+        &mut self,
+        predicate: P,
+    ) -> Result<RecvGuard<'_, Vec<Vec<u8>>>, TryRecvError>
+    where
+        P: FnMut(&[u8]) -> bool,
+    {
+        let outcome = self.take_while(predicate).now_or_never();
+
+        if outcome.is_none() {
+            self.release();
+        }
+
+        Ok(self.guard(TryRecvError::result_from_option(outcome)?))
     }
 }
 
 impl Drop for Receiver {
     fn drop(&mut self) {
+        // A shared receiver without a claim has nothing to save.
+        if self.file_guard.is_none() {
+            return;
+        }
+
         if let Err(err) = self.save() {
             log::error!(
                 "(probably) could not save queue state during `Drop`: {}",
@@ -723,8 +3320,8 @@ impl Drop for Receiver {
 /// the error.  
 ///
 /// This struct implements `Deref` and `DerefMut`. If you really, really want
-/// ownership, there is `RecvGuard::into_inner`, but be careful, because you
-/// lose your chance to rollback if anything unexpected occurs.
+/// ownership, there is [`RecvGuard::try_into_inner`], but be careful, because
+/// you lose your chance to rollback if anything unexpected occurs.
 pub struct RecvGuard<'a, T> {
     receiver: &'a mut Receiver,
     item: Option<T>,
@@ -755,8 +3352,14 @@ impl<'a, T> DerefMut for RecvGuard<'a, T> {
 }
 
 impl<'a, T> RecvGuard<'a, T> {
-    /// Commits the transaction and returns the underlying value. If you
-    /// accidentally lose this value from now on, it's your own fault!
+    /// Commits the transaction and returns the underlying value. The value is
+    /// moved out of the guard, not copied. If you accidentally lose this value
+    /// from now on, it's your own fault!
+    ///
+    /// # Errors
+    ///
+    /// If the commit fails, the error is returned and the transaction is
+    /// rolled back (on a "best effort" policy, as on drop).
     pub fn try_into_inner(mut self) -> io::Result<T> {
         let item = self.item.take().expect("unreachable");
         self.commit()?;
@@ -765,6 +3368,15 @@ impl<'a, T> RecvGuard<'a, T> {
     }
 
     /// Commits the changes to the queue, consuming this `RecvGuard`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "commit",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.receiver.base)
+        )
+    )]
     pub fn commit(mut self) -> io::Result<()> {
         self.receiver.end()?;
         self.was_finished = true;
@@ -772,10 +3384,140 @@ impl<'a, T> RecvGuard<'a, T> {
         Ok(())
     }
 
+    /// The number of times the element (or the first element, for a batch)
+    /// was delivered before without being committed, e.g., because its guard
+    /// was rolled back or because the receiver crashed. This is `None` unless
+    /// the receiver counts deliveries (see
+    /// [`ReceiverBuilder::track_deliveries`] and
+    /// [`ReceiverBuilder::max_deliveries`]).
+    pub fn redeliveries(&self) -> Option<u64> {
+        if self.receiver.counts_deliveries() {
+            Some(self.receiver.deliveries.count.saturating_sub(1))
+        } else {
+            None
+        }
+    }
+
+    /// The headers of the element (or of the first element, for a batch), as
+    /// given to [`crate::Sender::try_send_with_headers`], in the order they
+    /// were given. Elements sent without headers have none.
+    pub fn headers(&self) -> &[(String, Vec<u8>)] {
+        self.receiver
+            .delivered
+            .as_ref()
+            .map(|metadata| metadata.headers.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// When the element (or the first element, for a batch) was sent, if the
+    /// sender was set to write timestamps (see [`SenderBuilder::timestamp`]).
+    /// Together with [`SystemTime::now`], this tells how long the element
+    /// waited in the queue.
+    pub fn enqueued_at(&self) -> Option<SystemTime> {
+        self.receiver
+            .delivered
+            .as_ref()
+            .and_then(|metadata| metadata.enqueued_at)
+    }
+
+    /// The position in the queue right after the element (or after the last
+    /// element, for a batch), as the segment and the byte position within
+    /// it. This is what [`Receiver::commit_up_to`] takes to commit everything
+    /// up to this element. Elements received from a priority lane or a topic
+    /// have positions in that lane or topic.
+    pub fn position(&self) -> (u64, u64) {
+        // (elements read ahead start right after the guarded ones)
+        let state = self.receiver.next_state();
+        (state.segment, state.position)
+    }
+
+    /// The topic the element was received from, for the elements received by
+    /// [`Receiver::recv_subscribed`].
+    pub fn topic(&self) -> Option<&str> {
+        self.receiver.topic.as_deref()
+    }
+
+    /// The value of a header of the element (or of the first element, for a
+    /// batch), if it has one with this name. See [`RecvGuard::headers`].
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers()
+            .iter()
+            .find(|(found, _)| found == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Deserializes the element in the wire format of the receiver (see
+    /// [`crate::ReceiverBuilder::wire_format`]), as sent by
+    /// [`crate::Sender::send_serialized`]. This does not commit anything.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+    /// if the element is not a value of the given type in the wire format.
+    /// The element is then still there to be rolled back (or to be committed,
+    /// to get rid of it).
+    #[cfg(feature = "serde")]
+    pub fn deserialize<U: serde::de::DeserializeOwned>(&self) -> io::Result<U>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.receiver.wire_format.deserialize(self.deref().as_ref())
+    }
+
+    /// Takes the value out of the guard, leaving the transaction open: the
+    /// receiver has to be ended or rolled back by hand. This is for the
+    /// bindings and the gRPC front-end, which cannot hold on to the guard.
+    #[cfg(any(feature = "ffi", feature = "grpc"))]
+    pub(crate) fn detach(mut self) -> T {
+        self.was_finished = true;
+        self.item.take().expect("unreachable")
+    }
+
+    /// Where the transaction started in the queue, which identifies the
+    /// element for the replication.
+    #[cfg(feature = "replication")]
+    pub(crate) fn offset(&self) -> QueueState {
+        self.receiver.initial_state
+    }
+
     /// Same as rollback, but doesn't consume the guard. This is for internal use only.
     fn rollback_mut(&mut self) -> io::Result<()> {
-        self.receiver.go_to(self.receiver.initial_state)?;
-        self.receiver.end()?;
+        self.receiver.rollback()?;
+        self.was_finished = true;
+
+        Ok(())
+    }
+
+    /// Rolls the reader back, like [`RecvGuard::rollback`], but delivers the
+    /// element (or the batch) again only after a delay, consuming this
+    /// `RecvGuard`. The delay is saved with the deliveries of the receiver, so
+    /// it is honored after a restart too. Together with
+    /// [`RecvGuard::redeliveries`], this allows retrying with a backoff:
+    /// ```rust,no_run
+    /// # use std::time::Duration;
+    /// # async fn handle(receiver: &mut yaque::Receiver) -> std::io::Result<()> {
+    /// let guard = receiver.recv().await?;
+    /// # let processing_failed = true;
+    /// if processing_failed {
+    ///     let tries = guard.redeliveries().unwrap_or(0).min(10) as u32;
+    ///     guard.nack_after(Duration::from_millis(100) * 2u32.pow(tries))?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// This blocks the queue: the element stays at its front in the meantime
+    /// and no element behind it is delivered until the delay is over, as for
+    /// elements that are not due yet (see [`crate::Sender::try_send_after`]).
+    /// To let the elements behind go first, use [`RecvGuard::requeue`]
+    /// instead.
+    ///
+    /// # Errors
+    ///
+    /// If there is some error while saving the delay or moving the reader
+    /// back, this error will be returned.
+    pub fn nack_after(mut self, delay: Duration) -> io::Result<()> {
+        self.receiver.rollback_after(delay)?;
         self.was_finished = true;
 
         Ok(())
@@ -794,3 +3536,180 @@ impl<'a, T> RecvGuard<'a, T> {
         self.rollback_mut()
     }
 }
+
+impl<'a, T: AsRef<[u8]>> RecvGuard<'a, T> {
+    /// Sends the element again with a sender, at the end of the queue, and
+    /// commits it, consuming this `RecvGuard`. Unlike a rollback, which
+    /// delivers the element again right away, this lets the elements behind
+    /// it go first, so that a slow or bad element does not block the queue.
+    /// The headers, the timestamp and the expiration of the element go along
+    /// with it, but not its id (see [`crate::Sender::try_send_with_id`]).
+    ///
+    /// The sender is usually a sender of the same queue (e.g., a shared
+    /// sender, see [`SenderBuilder::shared`]), but it may as well be a sender
+    /// of a queue of retries. The element sent counts as a new element, with
+    /// no deliveries so far.
+    ///
+    /// This never waits for the queue to have room, as [`Sender::try_send`]:
+    /// a full queue may only get some once this element is committed, so
+    /// waiting for it here could wait forever.
+    ///
+    /// # Errors
+    ///
+    /// If the queue of the sender is full or over its quota, this returns
+    /// [`TrySendError::QueueFull`] or [`TrySendError::QuotaExceeded`] with
+    /// this `RecvGuard` back, to be rolled back or requeued later. If sending
+    /// fails otherwise, the error is returned and the transaction is rolled
+    /// back (on a "best effort" policy, as on drop). If the commit fails after
+    /// the element was sent, the error is returned and the element will be
+    /// delivered twice.
+    pub fn requeue(self, sender: &mut Sender) -> Result<(), TrySendError<Self>> {
+        let delivered = self.receiver.delivered.as_ref();
+        let metadata = Metadata {
+            headers: self.headers().to_vec(),
+            enqueued_at: self.enqueued_at(),
+            expires_at: delivered.and_then(|metadata| metadata.expires_at),
+            ..Metadata::default()
+        };
+
+        let item = self.item.as_ref().expect("unreachable");
+        let sent = match sender.try_send_with_metadata(&metadata, item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Io(err)) => Err(TrySendError::Io(err)),
+            Err(TrySendError::QueueFull { base, .. }) => {
+                Err(TrySendError::QueueFull { item: (), base })
+            }
+            Err(TrySendError::QuotaExceeded { base, .. }) => {
+                Err(TrySendError::QuotaExceeded { item: (), base })
+            }
+        };
+
+        match sent {
+            Ok(()) => Ok(self.commit()?),
+            Err(TrySendError::Io(err)) => Err(TrySendError::Io(err)),
+            Err(TrySendError::QueueFull { base, .. }) => {
+                Err(TrySendError::QueueFull { item: self, base })
+            }
+            Err(TrySendError::QuotaExceeded { base, .. }) => {
+                Err(TrySendError::QuotaExceeded { item: self, base })
+            }
+        }
+    }
+}
+
+impl<'a> RecvGuard<'a, Vec<Vec<u8>>> {
+    /// Commits only the first `n` elements of a batch, consuming this
+    /// `RecvGuard`, and rolls the others back, so that they are received
+    /// again, e.g., when processing failed in the middle of the batch. With
+    /// `n` as big as the batch or bigger, this is the same as
+    /// [`RecvGuard::commit`].
+    ///
+    /// # Errors
+    ///
+    /// If the commit fails, the error is returned and the transaction is
+    /// rolled back (on a "best effort" policy, as on drop).
+    pub fn commit_first(mut self, n: usize) -> io::Result<()> {
+        self.receiver.end_first(n)?;
+        self.was_finished = true;
+
+        Ok(())
+    }
+}
+
+/// A transaction over many receptions from the queue, created by
+/// [`Receiver::transaction`]. Everything received through the transaction
+/// is only committed by a call to [`RecvTransaction::commit`], which saves
+/// the state of the queue once for all the elements. If it is dropped
+/// without a commit, everything is rolled back, just like a [`RecvGuard`].
+pub struct RecvTransaction<'a> {
+    receiver: &'a mut Receiver,
+    items: Vec<Vec<u8>>,
+    was_finished: bool,
+}
+
+impl<'a> Drop for RecvTransaction<'a> {
+    fn drop(&mut self) {
+        if !self.was_finished {
+            if let Err(err) = self.receiver.rollback() {
+                log::error!("unable to rollback on drop: {}", err);
+            }
+        }
+    }
+}
+
+impl<'a> RecvTransaction<'a> {
+    /// Retrieves an element from the queue into the transaction, returning a
+    /// view of it. See [`Receiver::recv`].
+    pub async fn recv(&mut self) -> io::Result<&[u8]> {
+        let data = self.receiver.take().await?;
+        self.items.push(data);
+
+        Ok(self.items.last().expect("item was just pushed"))
+    }
+
+    /// Tries to retrieve an element from the queue into the transaction,
+    /// returning a view of it. See [`Receiver::try_recv`].
+    pub fn try_recv(&mut self) -> Result<&[u8], TryRecvError> {
+        let data = TryRecvError::result_from_option(self.receiver.take().now_or_never())?;
+        self.items.push(data);
+
+        Ok(self.items.last().expect("item was just pushed"))
+    }
+
+    /// Retrieves a number of elements from the queue into the transaction,
+    /// returning a view of them. See [`Receiver::recv_batch`].
+    pub async fn recv_batch(&mut self, n: usize) -> io::Result<&[Vec<u8>]> {
+        let data = self.receiver.take_batch(n).await?;
+        let start = self.items.len();
+        self.items.extend(data);
+
+        Ok(&self.items[start..])
+    }
+
+    /// Tries to retrieve a number of elements from the queue into the
+    /// transaction, returning a view of them. See [`Receiver::try_recv_batch`].
+    pub fn try_recv_batch(&mut self, n: usize) -> Result<&[Vec<u8>], TryRecvError> {
+        let data =
+            TryRecvError::result_from_option(self.receiver.take_batch(n).now_or_never())?;
+        let start = self.items.len();
+        self.items.extend(data);
+
+        Ok(&self.items[start..])
+    }
+
+    /// All the elements received in this transaction so far, in order.
+    pub fn items(&self) -> &[Vec<u8>] {
+        &self.items
+    }
+
+    /// Commits all the elements received in this transaction at once and
+    /// returns them. The receiver state is saved as on any other commit.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "commit",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.receiver.base)
+        )
+    )]
+    pub fn commit(mut self) -> io::Result<Vec<Vec<u8>>> {
+        self.receiver.end()?;
+        self.was_finished = true;
+
+        Ok(std::mem::take(&mut self.items))
+    }
+
+    /// Rolls back all the elements received in this transaction. This is also
+    /// done on drop, but on drop the possible IO error is ignored (but logged
+    /// as an error). Use this if you want to control errors at rollback.
+    ///
+    /// # Errors
+    ///
+    /// If there is some error while moving the reader back, this error will be
+    /// return.
+    pub fn rollback(mut self) -> io::Result<()> {
+        self.was_finished = true;
+        self.receiver.rollback()
+    }
+}
@@ -1,32 +1,102 @@
+use futures::{future, Stream, StreamExt};
+use std::collections::{BTreeMap, HashSet};
 use std::fs::*;
-use std::io::{self, Write};
+use std::io::{self, IoSlice, Write};
+use std::iter::Peekable;
 use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::error::TrySendError;
-use crate::header::Header;
-use crate::state::QueueState;
-use crate::sync::{DeletionEvent, FileGuard};
+#[cfg(feature = "serde")]
+use crate::codec::WireFormat;
+#[cfg(feature = "compression")]
+use crate::compression::Compression;
+use crate::encryption::{encrypt, KeyProvider};
+use crate::error::{ItemTooBig, QuotaExceeded, TrySendError};
+#[cfg(feature = "failpoints")]
+use crate::failpoints::{self, Failpoint};
+use crate::header::{self, Header};
+use crate::metadata::{
+    Headers, Metadata, HEADER_METADATA, MAX_HEADER_NAME_LEN, MAX_HEADER_VALUE_LEN, MAX_ID_LEN,
+};
+use crate::segment_file::write_all_vectored;
+#[cfg(unix)]
+use crate::sharing::{self, FilePermissions};
+use crate::state::{QueueState, SentKeys};
+use crate::storage::{self, FileStorage, SegmentAppend, Storage};
+use crate::sync::{ChangeEvent, FileGuard};
+use crate::telemetry::{default_metrics, QueueMetrics};
+use crate::timer::Delay;
+use crate::transform::{self, Codec};
 use crate::version::check_queue_version;
 
-use super::{segment_filename, HEADER_EOF};
+use super::info::queue_info_from;
+use super::footer::FooterBuilder;
+use super::settings::QueueSettings;
+use super::topic::{check_topic, topic_dirname};
+use super::{lane_dirname, segment_filename, CoalescingSender, SendSink, HEADER_EOF};
+
+/// A hook called with the path and the size of every segment the sender
+/// closes. See [`SenderBuilder::on_segment_closed`].
+type SegmentClosedHook = Arc<dyn Fn(&Path, u64) -> io::Result<()> + Send + Sync>;
+
+/// How long a shared sender first waits for another shared sender to be done
+/// sending. The wait doubles every time, up to [`MAX_SHARED_LOCK_BACKOFF`].
+const MIN_SHARED_LOCK_BACKOFF: Duration = Duration::from_micros(50);
+
+/// The longest a shared sender waits before checking if the queue is free.
+const MAX_SHARED_LOCK_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The marker of the lock held by an exclusive sender, which shared senders
+/// look for so as not to wait for it forever.
+const EXCLUSIVE_SENDER_MARKER: &str = "sender=exclusive";
+
+/// How long a sender waits for the receiver to make progress before checking
+/// again if a full queue has room.
+const RECHECK_FULL_QUEUE_EVERY: Duration = Duration::from_millis(100);
+
+/// The most elements of a stream that [`Sender::send_stream`] writes at a
+/// time.
+const SEND_STREAM_CHUNK: usize = 1024;
 
 /// The name of the sender lock in the queue folder.
 pub(crate) fn send_lock_filename<P: AsRef<Path>>(base: P) -> PathBuf {
     base.as_ref().join("send.lock")
 }
 
-/// Tries to acquire the sender lock for a queue.
-pub(crate) fn try_acquire_send_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
-    FileGuard::try_lock(send_lock_filename(base.as_ref()))?.ok_or_else(|| {
-        io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "queue `{}` sender side already in use",
-                base.as_ref().to_string_lossy()
-            ),
-        )
-    })
+/// The error for a queue whose sender lock is taken.
+fn send_side_in_use(base: &Path) -> io::Error {
+    io::Error::other(format!(
+        "queue `{}` sender side already in use",
+        base.to_string_lossy()
+    ))
+}
+
+/// Tries to acquire the sender lock for a queue, with an optional lease.
+pub(crate) fn try_acquire_send_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+) -> io::Result<FileGuard> {
+    FileGuard::try_lock_leased(send_lock_filename(base.as_ref()), lease)?
+        .ok_or_else(|| send_side_in_use(base.as_ref()))
+}
+
+/// Tries to acquire the sender lock for a queue for an exclusive sender, with
+/// an optional lease (or within this process only).
+fn try_acquire_exclusive_send_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+    in_process: bool,
+) -> io::Result<FileGuard> {
+    let path = send_lock_filename(base.as_ref());
+    let file_guard = if in_process {
+        FileGuard::try_lock_in_process_marked(path, EXCLUSIVE_SENDER_MARKER)
+    } else {
+        FileGuard::try_lock_marked(path, lease, EXCLUSIVE_SENDER_MARKER)?
+    };
+
+    file_guard.ok_or_else(|| send_side_in_use(base.as_ref()))
 }
 
 /// Acquire the sender lock for a queue, awaiting if locked.
@@ -34,31 +104,236 @@ pub(crate) async fn acquire_send_lock<P: AsRef<Path>>(base: P) -> io::Result<Fil
     FileGuard::lock(send_lock_filename(base.as_ref())).await
 }
 
+/// Tries to acquire the sender lock for a queue for a shared sender, with an
+/// optional lease (or within this process only). Returns `Ok(None)` if the
+/// lock is taken, but fails if an exclusive sender holds it, since it will
+/// not let go of it.
+fn try_acquire_shared_send_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+    in_process: bool,
+) -> io::Result<Option<FileGuard>> {
+    let path = send_lock_filename(base.as_ref());
+    let file_guard = if in_process {
+        FileGuard::try_lock_in_process(&path)
+    } else {
+        FileGuard::try_lock_leased(&path, lease)?
+    };
+
+    if file_guard.is_none() && FileGuard::is_marked(&path, EXCLUSIVE_SENDER_MARKER)? {
+        return Err(io::Error::other(format!(
+            "queue `{}` is held by an exclusive sender",
+            base.as_ref().to_string_lossy()
+        )));
+    }
+
+    Ok(file_guard)
+}
+
+/// Acquire the sender lock for a queue for a shared sender, blocking with a
+/// backoff while other shared senders hold it. Only use this for very short
+/// critical sections, such as a single send.
+fn wait_acquire_shared_send_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+    in_process: bool,
+) -> io::Result<FileGuard> {
+    let mut backoff = MIN_SHARED_LOCK_BACKOFF;
+
+    loop {
+        if let Some(file_guard) = try_acquire_shared_send_lock(base.as_ref(), lease, in_process)? {
+            break Ok(file_guard);
+        }
+
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_SHARED_LOCK_BACKOFF);
+    }
+}
+
+/// Acquire the sender lock for a queue for a shared sender, awaiting with a
+/// backoff while other shared senders hold it. See
+/// [`wait_acquire_shared_send_lock`].
+async fn acquire_shared_send_lock<P: AsRef<Path>>(
+    base: P,
+    lease: Option<Duration>,
+    in_process: bool,
+) -> io::Result<FileGuard> {
+    let mut backoff = MIN_SHARED_LOCK_BACKOFF;
+
+    loop {
+        if let Some(file_guard) = try_acquire_shared_send_lock(base.as_ref(), lease, in_process)? {
+            break Ok(file_guard);
+        }
+
+        Delay::new(backoff).await;
+        backoff = (backoff * 2).min(MAX_SHARED_LOCK_BACKOFF);
+    }
+}
+
 pub(crate) struct QueueSize {
     pub(crate) in_bytes: u64,
     pub(crate) in_segments: u64,
 }
 
 /// Non-recursively get the directory size of a given path.
+#[cfg(test)]
 pub(crate) fn get_queue_size<P: AsRef<Path>>(base: P) -> io::Result<QueueSize> {
-    let mut in_bytes = 0;
-    let mut in_segments = 0;
+    storage_size(&FileStorage::new(base))
+}
 
-    for dir_entry in read_dir(base.as_ref())? {
-        let dir_entry = dir_entry?;
+/// The size of the segments in a storage.
+fn storage_size(storage: &dyn Storage) -> io::Result<QueueSize> {
+    let segments = storage::segment_lens(storage)?;
 
-        if let Some(extension) = dir_entry.path().extension() {
-            if extension == "q" {
-                in_bytes += dir_entry.metadata()?.len();
-                in_segments += 1;
-            }
+    Ok(QueueSize {
+        in_bytes: segments.iter().map(|&(_, len)| len).sum(),
+        in_segments: segments.len() as u64,
+    })
+}
+
+/// Checks that an id for an item is not too long. See
+/// [`Sender::try_send_with_id`].
+fn checked_id(id: &[u8]) -> io::Result<Vec<u8>> {
+    if id.len() > MAX_ID_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "id of {} bytes is longer than {} bytes",
+                id.len(),
+                MAX_ID_LEN
+            ),
+        ));
+    }
+
+    Ok(id.to_vec())
+}
+
+/// Checks that the headers for an item are not too long and that no name is
+/// repeated. See [`Sender::try_send_with_headers`].
+fn checked_headers(headers: &[(&str, &[u8])]) -> io::Result<Headers> {
+    let mut checked = Headers::with_capacity(headers.len());
+
+    for &(name, value) in headers {
+        let problem = if name.len() > MAX_HEADER_NAME_LEN {
+            Some(format!(
+                "header name {:?} is longer than {} bytes",
+                name, MAX_HEADER_NAME_LEN
+            ))
+        } else if value.len() > MAX_HEADER_VALUE_LEN {
+            Some(format!("value of header {:?} is too long", name))
+        } else if checked.iter().any(|(seen, _)| seen == name) {
+            Some(format!("header {:?} given more than once", name))
+        } else {
+            None
+        };
+
+        if let Some(problem) = problem {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, problem));
         }
+
+        checked.push((name.to_owned(), value.to_vec()));
     }
 
-    Ok(QueueSize {
-        in_bytes,
-        in_segments,
-    })
+    Ok(checked)
+}
+
+/// What a sender does with the segments that the receiver did not get to yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetentionPolicy {
+    /// Keep every segment until the receiver is done with it.
+    #[default]
+    KeepAll,
+    /// Delete the oldest whole segments whenever the segments of the queue
+    /// take more than `max_bytes`, so that the queue keeps only the newest
+    /// elements. The segment being written to is never deleted. The receiver
+    /// skips the deleted segments, losing their elements.
+    DropOldest {
+        /// The size of the segments above which the oldest segments are
+        /// deleted.
+        max_bytes: u64,
+    },
+}
+
+/// How hard a sender tries to get the elements to the disk before a send
+/// returns. Every send is always flushed to the operating system, so that the
+/// elements survive the sender process crashing. What is not synced yet can
+/// still be lost if the whole machine crashes (e.g., on a power failure).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never sync, not even when a segment is closed or the sender is
+    /// dropped: the operating system writes the elements to the disk when it
+    /// sees fit.
+    #[default]
+    Never,
+    /// Sync after every send (or batch), before returning. This is the
+    /// safest, but also the slowest.
+    EveryItem,
+    /// Sync after a send once `items` elements were sent since the last sync
+    /// or once `interval` has passed since then, whichever comes first. The
+    /// interval is only checked when sending; no timers involved. At most
+    /// what was sent since the last sync can be lost.
+    Periodic {
+        /// The number of elements sent that triggers a sync, if any.
+        items: Option<u64>,
+        /// The time since the last sync that triggers a sync, if any.
+        interval: Option<Duration>,
+    },
+}
+
+/// An element encoded to be written in a batch, before the slices of it are
+/// taken. See [`Sender::try_send_batch`].
+struct EncodedItem {
+    /// The header and the encoding of the metadata, if there is any.
+    metadata: Option<([u8; 4], Vec<u8>)>,
+    header: [u8; 4],
+    /// The encoded payload, if it is not the item itself.
+    payload: Option<Vec<u8>>,
+}
+
+impl EncodedItem {
+    fn new(metadata: &Metadata, item: &[u8], payload: Option<Vec<u8>>) -> EncodedItem {
+        let metadata = if metadata.is_empty() {
+            None
+        } else {
+            let encoded = metadata.encode();
+            Some((Header::new(encoded.len() as u32).encode(), encoded))
+        };
+
+        EncodedItem {
+            metadata,
+            header: Header::new(payload.as_deref().unwrap_or(item).len() as u32).encode(),
+            payload,
+        }
+    }
+
+    /// The number of bytes the element (which is `item`, before being
+    /// encoded) takes in the segment.
+    fn len(&self, item: &[u8]) -> u64 {
+        let metadata_len = self
+            .metadata
+            .as_ref()
+            .map_or(0, |(_, encoded)| 8 + encoded.len() as u64);
+        let payload = self.payload.as_deref().unwrap_or(item);
+
+        metadata_len + 4 + payload.len() as u64
+    }
+
+    /// Pushes the slices to be written for the element (which is `item`,
+    /// before being encoded), leaving out the empty ones.
+    fn push_slices<'a>(&'a self, item: &'a [u8], slices: &mut Vec<IoSlice<'a>>) {
+        if let Some((header, encoded)) = &self.metadata {
+            slices.push(IoSlice::new(&HEADER_METADATA));
+            slices.push(IoSlice::new(header));
+            slices.push(IoSlice::new(encoded));
+        }
+
+        slices.push(IoSlice::new(&self.header));
+
+        let payload = self.payload.as_deref().unwrap_or(item);
+        if !payload.is_empty() {
+            slices.push(IoSlice::new(payload));
+        }
+    }
 }
 
 /// A builder for the sender side of the queue. Use this if you want to have fine-grained control
@@ -66,7 +341,8 @@ pub(crate) fn get_queue_size<P: AsRef<Path>>(base: P) -> io::Result<QueueSize> {
 pub struct SenderBuilder {
     /// The segment size in bytes that will trigger a new segment to be created. Segments an be
     /// bigger than this to accomodate the last element, but nothing beyond that (each segment
-    /// must store at least one element).
+    /// must store at least one element), unless the elements are split (see
+    /// [`SenderBuilder::split_items`]).
     ///
     /// Default value: 4MB
     segment_size: NonZeroU64,
@@ -81,6 +357,142 @@ pub struct SenderBuilder {
     ///
     /// Default value: None
     max_queue_size: Option<NonZeroU64>,
+
+    /// The maximum number of bytes that can be pending in the queue, waiting
+    /// to be received. Unlike `max_queue_size`, this is checked before every
+    /// send, including the size of the item being sent. Set this to `None` to
+    /// disable the check.
+    ///
+    /// Default value: None
+    max_queue_bytes: Option<NonZeroU64>,
+
+    /// The maximum number of items that can be pending in the queue, waiting
+    /// to be received. This is checked before every send. Set this to `None`
+    /// to disable the check.
+    ///
+    /// Default value: None
+    max_queue_items: Option<NonZeroU64>,
+
+    /// The maximum number of segments in the queue folder. The sender refuses
+    /// to create more segments than that. Set this to `None` to disable the
+    /// check.
+    ///
+    /// Default value: None
+    max_segments: Option<NonZeroU64>,
+
+    /// The maximum number of bytes in the segments of the queue folder,
+    /// received or not. This is checked before every send, including the size
+    /// of the item being sent. Set this to `None` to disable the check.
+    ///
+    /// Default value: None
+    max_total_bytes: Option<NonZeroU64>,
+
+    /// What to do with the segments that the receiver did not get to yet.
+    ///
+    /// Default value: `RetentionPolicy::KeepAll`
+    retention: RetentionPolicy,
+
+    /// When to sync the elements sent to the disk.
+    ///
+    /// Default value: `Durability::Never`
+    durability: Durability,
+
+    /// How long a [`CoalescingSender`] waits for more elements before writing
+    /// a group of them, if at all.
+    ///
+    /// Default value: `None`
+    coalesce_window: Option<Duration>,
+
+    /// The algorithm to compress each item with, if any.
+    ///
+    /// Default value: `None`
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+
+    /// The format to write the values sent with `send_serialized` in.
+    ///
+    /// Default value: `WireFormat::Json`
+    #[cfg(feature = "serde")]
+    wire_format: WireFormat,
+
+    /// The codecs to encode each item with, in order.
+    ///
+    /// Default value: none
+    codecs: Vec<Arc<dyn Codec>>,
+
+    /// The provider of the keys to encrypt each item with, if any.
+    ///
+    /// Default value: `None`
+    key_provider: Option<Arc<dyn KeyProvider>>,
+
+    /// Whether to write a checksum with each item.
+    ///
+    /// Default value: `false`
+    checksum: bool,
+
+    /// Whether to write when each item was written with it.
+    ///
+    /// Default value: `false`
+    timestamp: bool,
+
+    /// What to do when the sender closes a segment, if anything.
+    ///
+    /// Default value: `None`
+    on_segment_closed: Option<SegmentClosedHook>,
+
+    /// The callbacks reporting what the sender does, if any.
+    ///
+    /// Default value: `None`, or a `MetricsExporter` with the `metrics` feature
+    metrics: Option<Arc<dyn QueueMetrics>>,
+
+    /// Whether the queue is shared with other shared senders, possibly in other processes.
+    ///
+    /// Default value: `false`
+    shared: bool,
+
+    /// The lease of the sender lock, if any.
+    ///
+    /// Default value: `None`
+    lease: Option<Duration>,
+
+    /// Where the segments are kept, if not in files in the queue folder.
+    ///
+    /// Default value: `None`
+    storage: Option<Arc<dyn Storage>>,
+
+    /// The number of elements sent last among which duplicates are skipped,
+    /// if any.
+    ///
+    /// Default value: `None`
+    dedup_window: Option<usize>,
+
+    /// How long elements sent are remembered to skip duplicates, if not for
+    /// as long as they are in the window.
+    ///
+    /// Default value: `None`
+    dedup_max_age: Option<Duration>,
+
+    /// The permissions of the files and folders created, if not left to the
+    /// umask.
+    ///
+    /// Default value: `None`
+    #[cfg(unix)]
+    permissions: Option<FilePermissions>,
+
+    /// Whether the queue is locked within this process only.
+    ///
+    /// Default value: `false`
+    in_process: bool,
+
+    /// Whether the elements bigger than a segment are split over segments.
+    ///
+    /// Default value: `false`
+    split_items: bool,
+
+    /// The largest element accepted, as written to the queue, if any.
+    ///
+    /// Default value: `None`
+    max_item_size: Option<NonZeroU64>,
 }
 
 impl Default for SenderBuilder {
@@ -88,6 +500,33 @@ impl Default for SenderBuilder {
         SenderBuilder {
             segment_size: NonZeroU64::new(1024 * 1024 * 4).expect("impossible"), // 4MB
             max_queue_size: None,
+            max_queue_bytes: None,
+            max_queue_items: None,
+            max_segments: None,
+            max_total_bytes: None,
+            retention: RetentionPolicy::KeepAll,
+            durability: Durability::Never,
+            coalesce_window: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "serde")]
+            wire_format: WireFormat::default(),
+            codecs: Vec::new(),
+            key_provider: None,
+            checksum: false,
+            timestamp: false,
+            on_segment_closed: None,
+            metrics: default_metrics(),
+            shared: false,
+            lease: None,
+            storage: None,
+            dedup_window: None,
+            dedup_max_age: None,
+            #[cfg(unix)]
+            permissions: None,
+            in_process: false,
+            split_items: false,
+            max_item_size: None,
         }
     }
 }
@@ -100,7 +539,8 @@ impl SenderBuilder {
 
     /// The segment size in bytes that will trigger a new segment to be created. Segments an be
     /// bigger than this to accomodate the last element, but nothing beyond that (each segment
-    /// must store at least one element).
+    /// must store at least one element), unless the elements are split (see
+    /// [`SenderBuilder::split_items`]).
     ///
     /// Default value: `4 * 1024 * 1024`, or 4MB.
     ///
@@ -132,121 +572,1106 @@ impl SenderBuilder {
         self
     }
 
-    /// Opens a queue on a folder indicated by the `base` path for sending. The
-    /// folder will be created if it does not already exist.
+    /// The maximum number of bytes that can be pending in the queue, waiting
+    /// to be received. Unlike [`SenderBuilder::max_queue_size`], this limit
+    /// is checked before every send and takes the size of the item being
+    /// sent into account, so that `try_send` fails fast with
+    /// [`TrySendError::QueueFull`] instead of appending to the disk and `send`
+    /// waits for the receiver to catch up. Set this to `None` to disable the
+    /// check.
     ///
-    /// # Errors
+    /// The pending bytes are estimated from the last saved state of the
+    /// receiver, so the estimate may be larger than the real thing (never
+    /// smaller). An item is always accepted into an empty queue, even if it
+    /// is bigger than the limit, since the queue would deadlock otherwise.
+    /// Batches are only checked against the bytes already pending.
     ///
-    /// This function will return an IO error if the queue is already in use for
-    /// sending, which is indicated by a lock file. Also, any other IO error
-    /// encountered while opening will be sent.
-    pub fn open<P: AsRef<Path>>(self, base: P) -> io::Result<Sender> {
-        // Guarantee that the queue exists:
-        create_dir_all(base.as_ref())?;
-
-        log::trace!("created queue directory");
-
-        // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
-        check_queue_version(base.as_ref())?;
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is zero.
+    pub fn max_queue_bytes(mut self, size: Option<u64>) -> SenderBuilder {
+        let size = size.map(|s| NonZeroU64::new(s).expect("got max_queue_bytes=0"));
+        self.max_queue_bytes = size;
+        self
+    }
 
-        // Acquire lock and guess statestate:
-        let file_guard = try_acquire_send_lock(base.as_ref())?;
-        let state = QueueState::for_send_metadata(base.as_ref())?;
+    /// The maximum number of items that can be pending in the queue, waiting
+    /// to be received. As with [`SenderBuilder::max_queue_bytes`], this limit
+    /// is checked before every send: `try_send` fails fast with
+    /// [`TrySendError::QueueFull`] and `send` waits for the receiver to catch
+    /// up. Set this to `None` to disable the check.
+    ///
+    /// The pending items are counted from the last saved state of the
+    /// receiver by walking the headers of the queue, which costs a bit for
+    /// big limits. An item is always accepted into an empty queue and
+    /// batches are only checked against the items already pending.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `n` is zero.
+    pub fn max_queue_items(mut self, n: Option<u64>) -> SenderBuilder {
+        let n = n.map(|n| NonZeroU64::new(n).expect("got max_queue_items=0"));
+        self.max_queue_items = n;
+        self
+    }
 
-        log::trace!("sender lock acquired. Sender state now is {:?}", state);
+    /// Sets the maximum number of segments in the queue folder, as part of a
+    /// hard quota for the queue. A send that would need a new segment beyond
+    /// that fails with [`TrySendError::QuotaExceeded`]. Unlike
+    /// [`SenderBuilder::max_queue_size`], the sender does not wait for the
+    /// receiver: even `send` fails, with an IO error wrapping
+    /// [`crate::QuotaExceeded`].
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `n` is zero.
+    pub fn max_segments(mut self, n: Option<u64>) -> SenderBuilder {
+        let n = n.map(|n| NonZeroU64::new(n).expect("got max_segments=0"));
+        self.max_segments = n;
+        self
+    }
 
-        // See the docs on OpenOptions::append for why the BufWriter here.
-        let file = io::BufWriter::new(
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(segment_filename(base.as_ref(), state.segment))?,
-        );
+    /// Sets the maximum number of bytes in the segments of the queue folder,
+    /// received or not, as part of a hard quota for the queue. A send that
+    /// would take the segments over that fails just as with
+    /// [`SenderBuilder::max_segments`]. Priority lanes are queues of their own
+    /// and have quotas of their own.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is zero.
+    pub fn max_total_bytes(mut self, size: Option<u64>) -> SenderBuilder {
+        let size = size.map(|s| NonZeroU64::new(s).expect("got max_total_bytes=0"));
+        self.max_total_bytes = size;
+        self
+    }
 
-        log::trace!("last segment opened for appending");
+    /// Sets what the sender does with the segments that the receiver did not
+    /// get to yet. With [`RetentionPolicy::DropOldest`], the sender deletes the
+    /// oldest segments every time it creates a new segment and the queue is
+    /// over the limit. Since only whole segments are deleted, the queue can be
+    /// bigger than the limit by up to one segment. Unlike
+    /// [`SenderBuilder::max_queue_size`], this never blocks the sender.
+    ///
+    /// Default value: `RetentionPolicy::KeepAll`
+    pub fn retention(mut self, retention: RetentionPolicy) -> SenderBuilder {
+        self.retention = retention;
+        self
+    }
 
-        Ok(Sender {
-            segment_size: self.segment_size,
-            max_queue_size: self.max_queue_size,
-            _file_guard: file_guard,
-            file,
-            state,
-            deletion_stream: None,
-            base: PathBuf::from(base.as_ref()),
-        })
+    /// Sets when the sender syncs the elements it sends to the disk, trading
+    /// the latency of the sends against what can be lost if the machine
+    /// crashes. See [`Durability`] for the options. Unless it is
+    /// [`Durability::Never`], the segments are also synced when they are
+    /// closed and what was not synced yet is synced when the sender is
+    /// dropped.
+    ///
+    /// Default value: `Durability::Never`
+    pub fn durability(mut self, durability: Durability) -> SenderBuilder {
+        self.durability = durability;
+        self
     }
-}
 
-/// The sender part of the queue. This part is lock-free and therefore can be
-/// used outside an asynchronous context.
-pub struct Sender {
-    segment_size: NonZeroU64,
-    max_queue_size: Option<NonZeroU64>,
-    _file_guard: FileGuard,
-    file: io::BufWriter<File>,
-    state: QueueState,
-    deletion_stream: Option<DeletionEvent>, // lazy inited!
-    base: PathBuf,
-}
+    /// Sets how long a [`CoalescingSender`] made from this sender waits for
+    /// more elements after the first one of a group comes in, before writing
+    /// (and syncing) all of them at once. A longer window adds latency to
+    /// each send, but makes for bigger groups, that is, fewer syscalls and
+    /// syncs per element. Set this to `None` to only group the elements that
+    /// come in while the previous group is being written. This has no effect
+    /// on the sender itself.
+    ///
+    /// Default value: `None`
+    pub fn coalesce_window(mut self, window: Option<Duration>) -> SenderBuilder {
+        self.coalesce_window = window;
+        self
+    }
 
-impl Sender {
-    /// Opens a queue on a folder indicated by the `base` path for sending. The
-    /// folder will be created if it does not already exist.
+    /// Sets the algorithm to compress each item with, if any. Items that would
+    /// not get any smaller are stored uncompressed. Receivers decompress the
+    /// items transparently, but they need the `compression` feature to do so.
+    /// See [`Compression`] for the details.
     ///
-    /// # Errors
+    /// Default value: `None`
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: Option<Compression>) -> SenderBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the format to write the values sent with
+    /// [`Sender::send_serialized`] in. The receivers have to read them in the
+    /// same format. See [`WireFormat`] for the details.
     ///
-    /// This function will return an IO error if the queue is already in use for
-    /// sending, which is indicated by a lock file. Also, any other IO error
-    /// encountered while opening will be sent.
-    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Sender> {
-        SenderBuilder::default().open(base)
+    /// Default value: `WireFormat::Json`
+    #[cfg(feature = "serde")]
+    pub fn wire_format(mut self, wire_format: WireFormat) -> SenderBuilder {
+        self.wire_format = wire_format;
+        self
     }
 
-    /// Saves the sender queue state. You do not need to use method in most
-    /// circumstances, since it is automatically done on drop (yes, it will be
-    /// called eve if your thread panics). However, you can use this function to
+    /// Adds a codec on top of the codecs to encode each item with. The
+    /// receivers need the same codecs, added in the same order, to decode the
+    /// items. See [`Codec`] for the details.
     ///
-    /// 1. Make periodical backups. Use an external timer implementation for this.
+    /// Default value: no codecs
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> SenderBuilder {
+        self.codecs.push(codec);
+        self
+    }
+
+    /// Sets the provider of the keys to encrypt each item with, if any. The
+    /// receivers need a key provider for the same keys to decrypt the items.
+    /// See [`KeyProvider`] for the details.
     ///
-    /// 2. Handle possible IO errors in sending. The `drop` implementation will
-    /// ignore (but log) any io errors, which may lead to data loss in an
-    /// unreliable filesystem. It was implmemented this way because no errors
-    /// are allowed to propagate on drop and panicking will abort the program if
-    /// drop is called during a panic.
-    #[deprecated(
-        since = "0.5.0",
-        note = "the sender state is now always inferred. There is no need to save anything"
-    )]
-    pub fn save(&mut self) -> io::Result<()> {
-        Ok(())
+    /// Default value: `None`
+    #[cfg(feature = "encryption")]
+    pub fn key_provider(self, key_provider: Option<Arc<dyn KeyProvider>>) -> SenderBuilder {
+        self.with_key_provider(key_provider)
     }
 
-    /// Just writes to the internal buffer, but doesn't flush it.
-    fn write(&mut self, data: &[u8]) -> io::Result<u64> {
-        // Get length of the data and write the header:
-        let len = data.as_ref().len();
-        assert!(len < std::u64::MAX as usize);
-        let header = Header::new(len as u32).encode();
+    /// Sets the provider of the keys to encrypt each item with, whether the
+    /// `encryption` feature is enabled or not.
+    pub(crate) fn with_key_provider(
+        mut self,
+        key_provider: Option<Arc<dyn KeyProvider>>,
+    ) -> SenderBuilder {
+        self.key_provider = key_provider;
+        self
+    }
 
-        // Write stuff to the file:
-        self.file.write_all(&header)?;
-        self.file.write_all(data.as_ref())?;
+    /// Sets the sender to write a CRC32 checksum with each item, so that the
+    /// receiver can detect bit rot and torn writes, failing with a
+    /// [`crate::Corruption`] error instead of delivering garbage. The checksum
+    /// takes 15 bytes per item. See also
+    /// [`ReceiverBuilder::verify_checksums`](crate::ReceiverBuilder::verify_checksums).
+    ///
+    /// Default value: `false`
+    pub fn checksum(mut self, checksum: bool) -> SenderBuilder {
+        self.checksum = checksum;
+        self
+    }
 
-        Ok(4 + len as u64)
+    /// Sets the sender to write with each item when it was written, with a
+    /// millisecond precision, so that the receiver can tell how long the
+    /// element waited in the queue (see
+    /// [`RecvGuard::enqueued_at`](crate::queue::RecvGuard::enqueued_at)) and
+    /// skip elements that are too old (see
+    /// [`ReceiverBuilder::max_age`](crate::ReceiverBuilder::max_age)). The
+    /// timestamps of a sender never go backwards, even if the clock of the
+    /// system does. The timestamp takes 19 bytes per item.
+    ///
+    /// Default value: `false`
+    pub fn timestamp(mut self, timestamp: bool) -> SenderBuilder {
+        self.timestamp = timestamp;
+        self
     }
 
-    /// Tests whether the queue is past the end of the current segment.
-    fn is_past_end(&self) -> bool {
-        self.state.position > self.segment_size.get()
+    /// Sets a hook to be called every time the sender closes a segment and
+    /// moves on to the next one, with the path of the closed segment and its
+    /// size in bytes. The hook is called after the next segment is created,
+    /// but before anything is written to it, so that, e.g., the queue folder
+    /// can be synced or the closed segment archived. The same hook is used
+    /// for the priority lanes of the queue.
+    ///
+    /// An error returned by the hook fails the send that closed the segment
+    /// (the segment stays closed and nothing of the send was written yet).
+    ///
+    /// Default value: none
+    pub fn on_segment_closed<F>(mut self, hook: F) -> SenderBuilder
+    where
+        F: Fn(&Path, u64) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.on_segment_closed = Some(Arc::new(hook));
+        self
     }
 
-    /// Caps off a segment by writing an EOF header and then moves segment.
-    /// This function returns `Ok(true)` if it has created a new segment or
-    /// `Ok(false)` if it has not (because the queue was too big).
-    #[must_use = "you need to always check if a segment was created or not!"]
+    /// Sets the callbacks reporting what the sender does, if any, such as the
+    /// elements sent and the segments rotated. The same callbacks are used
+    /// for the priority lanes of the queue. See [`QueueMetrics`] for the
+    /// details.
+    ///
+    /// Default value: `None`, or a `MetricsExporter` with the `metrics` feature
+    pub fn metrics(mut self, metrics: Option<Arc<dyn QueueMetrics>>) -> SenderBuilder {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets the sender to share the queue with other shared senders, possibly
+    /// in other processes. Instead of holding the `send.lock` for its whole
+    /// lifetime, a shared sender only locks the queue while sending and
+    /// always appends to the current top of the queue, wherever the other
+    /// senders have left it. Each send (or batch) is still atomic and the
+    /// items of each sender keep their order.
+    ///
+    /// While another shared sender is sending, a shared sender waits, checking
+    /// again with a backoff. The `async` sends await in the meantime; the
+    /// `try_` ones block the thread, which is fine since sends are short. A
+    /// shared sender does not wait for an exclusive sender, which holds the
+    /// queue for as long as it is open: opening or sending fails right away
+    /// then.
+    ///
+    /// Default value: `false`
+    pub fn shared(mut self, shared: bool) -> SenderBuilder {
+        self.shared = shared;
+        self
+    }
+
+    /// Locks the queue within this process only, without the `send.lock`
+    /// file, for when the receiver lives in the same process (as with
+    /// [`crate::channel`]) and no other process opens the queue. This saves
+    /// the lock files and their checks, and leaves nothing to recover after
+    /// an unclean shutdown. The receiver should be set likewise (see
+    /// [`crate::ReceiverBuilder::in_process`]).
+    ///
+    /// Other processes do not see such a lock: do not use this if they may
+    /// open the queue. The [lease](SenderBuilder::lease) is ignored, since
+    /// the lock goes away with the process.
+    ///
+    /// Default value: `false`
+    pub fn in_process(mut self, in_process: bool) -> SenderBuilder {
+        self.in_process = in_process;
+        self
+    }
+
+    /// Splits the elements bigger than the [segment size](SenderBuilder::segment_size)
+    /// into chunks over as many segments as needed, instead of writing them
+    /// into an oversized segment, so that the segments stay bounded. Each
+    /// chunk is flagged in its metadata and the receiver puts the chunks back
+    /// together, delivering the element as if it was written in one piece.
+    /// The elements of a batch are never split, and no element can be bigger
+    /// than `2 ^ 26 - 1` bytes, split or not (see
+    /// [`SenderBuilder::max_item_size`]).
+    ///
+    /// If the sender dies in the middle of an element, the chunks written so
+    /// far are skipped by the receiver. Older versions of `yaque` deliver the
+    /// chunks as elements of their own.
+    ///
+    /// Default value: `false`
+    pub fn split_items(mut self, split_items: bool) -> SenderBuilder {
+        self.split_items = split_items;
+        self
+    }
+
+    /// Sets the size of the largest element the queue accepts. Sending a
+    /// bigger element fails with an IO error wrapping [`crate::ItemTooBig`]
+    /// before anything is written. The size is that of the element as written
+    /// to the queue, that is, after the codecs, the compression and the
+    /// encryption, not counting its header and metadata. Set this to `None`
+    /// to disable the check.
+    ///
+    /// The limit is recorded with the settings of a new queue, so that a
+    /// receiver treats a longer element as corruption instead of trying to
+    /// read (and allocate) it. Whatever the limit, no element can be bigger
+    /// than `2 ^ 26 - 1` bytes (about 67MB), which is the most its header can
+    /// tell, even if it is [split](SenderBuilder::split_items). Opening a
+    /// sender with a bigger limit fails.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `size` is zero.
+    pub fn max_item_size(mut self, size: Option<u64>) -> SenderBuilder {
+        let size = size.map(|size| NonZeroU64::new(size).expect("got max_item_size=0"));
+        self.max_item_size = size;
+        self
+    }
+
+    /// Holds the sender lock with a lease, which the sender renews from time
+    /// to time. If the sender dies without releasing the lock, other processes
+    /// break it once the lease expires, instead of failing because the queue
+    /// is in use. Unlike the PIDs the `recovery` module relies on, this works
+    /// across hosts (e.g., containers sharing a volume), as long as their
+    /// clocks agree to within a fraction of the lease. Set this to `None` to
+    /// hold the lock until released.
+    ///
+    /// Default value: `None`
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `lease` is zero.
+    pub fn lease(mut self, lease: Option<Duration>) -> SenderBuilder {
+        assert_ne!(lease, Some(Duration::ZERO), "got lease=0");
+        self.lease = lease;
+        self
+    }
+
+    /// Sets where the segments of the queue are kept. The receiver has to use
+    /// the same storage. See [`Storage`] for what is still kept in the queue
+    /// folder. The hook set with [`SenderBuilder::on_segment_closed`] is still
+    /// called with the path the segment would have in the folder.
+    ///
+    /// Default value: a [`FileStorage`](crate::storage::FileStorage) in the
+    /// queue folder
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> SenderBuilder {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Sets the sender to skip the elements that it already sent among the
+    /// last `window` elements. An element is recognized by its id, if it has
+    /// one (see [`Sender::try_send_with_id`]), or else by a hash of its
+    /// contents. Then, a producer that replays what it sent before crashing
+    /// does not get the elements into the queue twice. Sending a duplicate
+    /// succeeds without writing anything.
+    ///
+    /// The elements sent are remembered in the queue folder, so that they
+    /// survive restarts. Since they are remembered by this sender, this
+    /// needs an exclusive sender (see [`SenderBuilder::shared`]). Unlike
+    /// [`crate::ReceiverBuilder::dedup_window`], this also skips elements
+    /// without ids, but it cannot tell a retry of an element whose send
+    /// failed after writing from a crash.
+    ///
+    /// Default value: `None`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `window` is zero.
+    pub fn dedup_window(mut self, window: Option<usize>) -> SenderBuilder {
+        assert_ne!(window, Some(0), "got dedup_window=0");
+        self.dedup_window = window;
+        self
+    }
+
+    /// Sets the sender to forget the elements sent longer ago than `max_age`
+    /// when skipping duplicates, even if they are still in the window (see
+    /// [`SenderBuilder::dedup_window`]). Set this to `None` to remember them
+    /// for as long as they are in the window.
+    ///
+    /// Default value: `None`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max_age` is zero.
+    pub fn dedup_max_age(mut self, max_age: Option<Duration>) -> SenderBuilder {
+        assert_ne!(max_age, Some(Duration::ZERO), "got dedup_max_age=0");
+        self.dedup_max_age = max_age;
+        self
+    }
+
+    /// Sets the permissions of the folder of the queue and of the files this
+    /// sender creates in it (segments, locks and state), whatever the umask
+    /// of the process, so that a receiver running as another user can use
+    /// them. The receiver should be set with the same permissions (see
+    /// [`crate::ReceiverBuilder::permissions`]). Only on Unix. See
+    /// [`FilePermissions`] for the details.
+    ///
+    /// Default value: `None`, leaving the permissions to the umask.
+    #[cfg(unix)]
+    pub fn permissions(mut self, permissions: Option<FilePermissions>) -> SenderBuilder {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Opens a queue on a folder indicated by the `base` path for sending. The
+    /// folder will be created if it does not already exist.
+    ///
+    /// # Settings
+    ///
+    /// The first sender to open a queue records the segment size, the
+    /// checksums, the maximum element size, the compression and the wire
+    /// format it was built with in the folder of the queue. Senders and
+    /// receivers opening the queue later use the recorded settings instead of
+    /// their own, logging a warning if they differ. To change the settings of
+    /// a queue, edit (or remove) the `settings` file in its folder.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an IO error if the queue is already in use for
+    /// sending, which is indicated by a lock file. Also, any other IO error
+    /// encountered while opening will be sent. Shared senders never fail
+    /// because of the lock file. If the settings of the queue cannot be read,
+    /// this returns an error of kind [`io::ErrorKind::InvalidData`]. If the
+    /// [maximum element size](SenderBuilder::max_item_size) is bigger than an
+    /// element can be, this returns an error of kind
+    /// [`io::ErrorKind::InvalidInput`].
+    pub fn open<P: AsRef<Path>>(mut self, base: P) -> io::Result<Sender> {
+        // Guarantee that the queue exists:
+        create_dir_all(base.as_ref())?;
+
+        log::trace!("created queue directory");
+
+        #[cfg(unix)]
+        if let Some(permissions) = self.permissions {
+            sharing::set_permissions(base.as_ref(), permissions)?;
+        }
+
+        // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
+        check_queue_version(base.as_ref())?;
+
+        if self.dedup_window.is_some() && self.shared {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "skipping duplicates needs an exclusive sender",
+            ));
+        }
+
+        if self
+            .max_item_size
+            .is_some_and(|size| size.get() > u64::from(header::MAX_LEN))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("elements cannot be bigger than {} bytes", header::MAX_LEN),
+            ));
+        }
+
+        // Acquire lock and guess statestate:
+        let file_guard = if self.shared {
+            wait_acquire_shared_send_lock(base.as_ref(), self.lease, self.in_process)?
+        } else {
+            try_acquire_exclusive_send_lock(base.as_ref(), self.lease, self.in_process)?
+        };
+        let settings = QueueSettings::for_sender(
+            base.as_ref(),
+            QueueSettings {
+                segment_size: self.segment_size,
+                checksum: self.checksum,
+                max_item_size: self.max_item_size,
+                #[cfg(feature = "compression")]
+                compression: self.compression,
+                #[cfg(feature = "serde")]
+                wire_format: Some(self.wire_format),
+            },
+        )?;
+        self.segment_size = settings.segment_size;
+        self.checksum = settings.checksum;
+        self.max_item_size = settings.max_item_size;
+        #[cfg(feature = "compression")]
+        {
+            self.compression = settings.compression;
+        }
+        #[cfg(feature = "serde")]
+        {
+            self.wire_format = settings.wire_format.unwrap_or(self.wire_format);
+        }
+
+        let custom_storage = self.storage.is_some();
+        let storage = self
+            .storage
+            .unwrap_or_else(|| Arc::new(FileStorage::new(base.as_ref())));
+        let state = storage::top(&*storage)?;
+
+        log::trace!("sender lock acquired. Sender state now is {:?}", state);
+
+        // See the docs on OpenOptions::append for why the BufWriter here.
+        let file = io::BufWriter::new(storage.append(state.segment)?);
+
+        log::trace!("last segment opened for appending");
+
+        let sent_keys = match self.dedup_window {
+            Some(window) => Some(SentKeys::open(base.as_ref(), window, self.dedup_max_age)?),
+            None => None,
+        };
+
+        Ok(Sender {
+            segment_size: self.segment_size,
+            max_queue_size: self.max_queue_size,
+            max_queue_bytes: self.max_queue_bytes,
+            max_queue_items: self.max_queue_items,
+            max_segments: self.max_segments,
+            max_total_bytes: self.max_total_bytes,
+            retention: self.retention,
+            durability: self.durability,
+            coalesce_window: self.coalesce_window,
+            unsynced: 0,
+            last_synced_at: Instant::now(),
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            #[cfg(feature = "serde")]
+            wire_format: self.wire_format,
+            codecs: self.codecs,
+            key_provider: self.key_provider,
+            checksum: self.checksum,
+            timestamp: self.timestamp,
+            last_timestamp: UNIX_EPOCH,
+            on_segment_closed: self.on_segment_closed,
+            metrics: self.metrics,
+            // shared senders release the lock right away:
+            _file_guard: if self.shared { None } else { Some(file_guard) },
+            shared: self.shared,
+            claimed: None,
+            lease: self.lease,
+            storage,
+            custom_storage,
+            file,
+            state,
+            change_event: None,
+            // (the segment will be scanned if it has something)
+            footer: if state.position == 0 {
+                Some(FooterBuilder::default())
+            } else {
+                None
+            },
+            base: PathBuf::from(base.as_ref()),
+            lanes: BTreeMap::new(),
+            topics: BTreeMap::new(),
+            dedup_window: self.dedup_window,
+            dedup_max_age: self.dedup_max_age,
+            sent_keys,
+            #[cfg(unix)]
+            permissions: self.permissions,
+            in_process: self.in_process,
+            split_items: self.split_items,
+            max_item_size: self.max_item_size,
+            stats: SenderStats::default(),
+        })
+    }
+}
+
+/// What a [`Sender`] did since it was opened. See [`Sender::stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SenderStats {
+    /// The number of elements sent.
+    pub items_sent: u64,
+    /// The number of bytes written to the segments, including the headers,
+    /// the metadata and the footers.
+    pub bytes_written: u64,
+    /// The number of segments the sender moved on to (not counting the one
+    /// it was opened on).
+    pub segments_created: u64,
+    /// The number of times the segments (or the queue folder) were synced to
+    /// the disk.
+    pub fsyncs: u64,
+}
+
+impl SenderStats {
+    /// Adds the counters of another sender to these.
+    fn add(&mut self, other: &SenderStats) {
+        self.items_sent += other.items_sent;
+        self.bytes_written += other.bytes_written;
+        self.segments_created += other.segments_created;
+        self.fsyncs += other.fsyncs;
+    }
+}
+
+/// The sender part of the queue. This part is lock-free and therefore can be
+/// used outside an asynchronous context.
+pub struct Sender {
+    segment_size: NonZeroU64,
+    max_queue_size: Option<NonZeroU64>,
+    max_queue_bytes: Option<NonZeroU64>,
+    max_queue_items: Option<NonZeroU64>,
+    max_segments: Option<NonZeroU64>,
+    max_total_bytes: Option<NonZeroU64>,
+    retention: RetentionPolicy,
+    durability: Durability,
+    coalesce_window: Option<Duration>,
+    /// The number of elements sent since the last sync.
+    unsynced: u64,
+    last_synced_at: Instant,
+    #[cfg(feature = "compression")]
+    compression: Option<Compression>,
+    #[cfg(feature = "serde")]
+    wire_format: WireFormat,
+    codecs: Vec<Arc<dyn Codec>>,
+    key_provider: Option<Arc<dyn KeyProvider>>,
+    checksum: bool,
+    timestamp: bool,
+    /// The last timestamp written, which the next ones cannot go below.
+    last_timestamp: SystemTime,
+    on_segment_closed: Option<SegmentClosedHook>,
+    metrics: Option<Arc<dyn QueueMetrics>>,
+    _file_guard: Option<FileGuard>,
+    shared: bool,
+    /// The lock a shared sender awaited for its next send. See
+    /// [`Sender::claim_async`].
+    claimed: Option<FileGuard>,
+    lease: Option<Duration>,
+    storage: Arc<dyn Storage>,
+    /// Whether the storage was set with [`SenderBuilder::storage`]. The
+    /// nested queues are in folders of their own, so there are none then.
+    custom_storage: bool,
+    file: io::BufWriter<Box<dyn SegmentAppend>>,
+    state: QueueState,
+    change_event: Option<ChangeEvent>, // lazy inited!
+    footer: Option<FooterBuilder>,     // lazy inited!
+    base: PathBuf,
+    /// The senders of the priority lanes used so far (lazy inited!).
+    lanes: BTreeMap<u8, Sender>,
+    /// The senders of the topics used so far (lazy inited!).
+    topics: BTreeMap<String, Sender>,
+    dedup_window: Option<usize>,
+    dedup_max_age: Option<Duration>,
+    /// The keys of the elements sent last, if duplicates are skipped.
+    sent_keys: Option<SentKeys>,
+    #[cfg(unix)]
+    permissions: Option<FilePermissions>,
+    in_process: bool,
+    split_items: bool,
+    max_item_size: Option<NonZeroU64>,
+    /// What this sender did so far.
+    stats: SenderStats,
+}
+
+impl Sender {
+    /// Opens a queue on a folder indicated by the `base` path for sending. The
+    /// folder will be created if it does not already exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an IO error if the queue is already in use for
+    /// sending, which is indicated by a lock file. Also, any other IO error
+    /// encountered while opening will be sent.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Sender> {
+        SenderBuilder::default().open(base)
+    }
+
+    /// The folder holding the queue.
+    #[cfg(any(feature = "serde", feature = "replication"))]
+    pub(crate) fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Gets what this sender did since it was opened, counting what was sent
+    /// to its priority lanes and topics.
+    pub fn stats(&self) -> SenderStats {
+        let mut stats = self.stats;
+
+        for sender in self.lanes.values().chain(self.topics.values()) {
+            stats.add(&sender.stats());
+        }
+
+        stats
+    }
+
+    /// Saves the sender queue state. You do not need to use method in most
+    /// circumstances, since it is automatically done on drop (yes, it will be
+    /// called eve if your thread panics). However, you can use this function to
+    ///
+    /// 1. Make periodical backups. Use an external timer implementation for this.
+    ///
+    /// 2. Handle possible IO errors in sending. The `drop` implementation will
+    ///    ignore (but log) any io errors, which may lead to data loss in an
+    ///    unreliable filesystem. It was implmemented this way because no errors
+    ///    are allowed to propagate on drop and panicking will abort the program if
+    ///    drop is called during a panic.
+    #[deprecated(
+        since = "0.5.0",
+        note = "the sender state is now always inferred. There is no need to save anything"
+    )]
+    pub fn save(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The key of an element to be sent, if duplicates are skipped. See
+    /// [`SenderBuilder::dedup_window`].
+    fn dedup_key(&self, id: Option<&[u8]>, data: &[u8]) -> Option<Vec<u8>> {
+        self.sent_keys.as_ref().map(|_| SentKeys::key_of(id, data))
+    }
+
+    /// Whether an element with this key was sent recently, so that it is to
+    /// be skipped.
+    fn was_sent(&mut self, key: Option<&[u8]>) -> bool {
+        match (&mut self.sent_keys, key) {
+            (Some(sent_keys), Some(key)) => sent_keys.was_sent(key),
+            _ => false,
+        }
+    }
+
+    /// Leaves out of a batch the elements that were sent recently (or earlier
+    /// in the batch), returning the keys of the others.
+    fn skip_sent<D: AsRef<[u8]>>(&mut self, items: &mut Vec<D>) -> Vec<Vec<u8>> {
+        let sent_keys = match &mut self.sent_keys {
+            Some(sent_keys) => sent_keys,
+            None => return vec![],
+        };
+
+        let mut keys = Vec::with_capacity(items.len());
+        let mut in_batch = HashSet::new();
+        items.retain(|item| {
+            let key = SentKeys::key_of(None, item.as_ref());
+            let is_new = !sent_keys.was_sent(&key) && in_batch.insert(key.clone());
+            if is_new {
+                keys.push(key);
+            }
+            is_new
+        });
+
+        keys
+    }
+
+    /// Keeps the keys of the elements just sent, so that they are skipped if
+    /// sent again.
+    fn keep_sent<I: IntoIterator<Item = Vec<u8>>>(&mut self, keys: I) -> io::Result<()> {
+        if let Some(sent_keys) = &mut self.sent_keys {
+            for key in keys {
+                sent_keys.insert(&self.base, key)?;
+            }
+            sent_keys.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Awaits the lock on the queue for the next send, if this sender is
+    /// shared, so that [`Sender::claim`] does not block in async code.
+    async fn claim_async(&mut self) -> io::Result<()> {
+        if self.shared && self.claimed.is_none() {
+            self.claimed =
+                Some(acquire_shared_send_lock(&self.base, self.lease, self.in_process).await?);
+        }
+
+        Ok(())
+    }
+
+    /// Locks the queue for a single send, if this sender is shared, moving to
+    /// the current top of the queue. The lock is released when the returned
+    /// guard is dropped. Exclusive senders already hold the lock.
+    fn claim(&mut self) -> io::Result<Option<FileGuard>> {
+        if !self.shared {
+            return Ok(None);
+        }
+
+        let file_guard = match self.claimed.take() {
+            Some(file_guard) => file_guard,
+            None => wait_acquire_shared_send_lock(&self.base, self.lease, self.in_process)?,
+        };
+        let state = storage::top(&*self.storage)?;
+
+        // Other senders may have moved the queue to a new segment:
+        if state.segment != self.state.segment {
+            log::trace!("moving shared sender to segment {}", state.segment);
+            *self.file.get_mut() = self.storage.append(state.segment)?;
+        }
+
+        self.state = state;
+
+        // Other senders may have written to the segment:
+        if self.footer.as_ref().map(|footer| footer.position) != Some(state.position) {
+            self.footer = None;
+        }
+
+        Ok(Some(file_guard))
+    }
+
+    /// Just writes to the internal buffer, but doesn't flush it.
+    fn write(&mut self, metadata: &Metadata, data: &[u8]) -> io::Result<u64> {
+        let mut written = 0;
+
+        // Write the metadata (if any) before the item:
+        if !metadata.is_empty() {
+            let encoded = metadata.encode();
+            let header = Header::new(encoded.len() as u32).encode();
+
+            self.write_tracked(&HEADER_METADATA)?;
+            self.write_tracked(&header)?;
+            self.write_tracked(&encoded)?;
+
+            written += 8 + encoded.len() as u64;
+        }
+
+        // Get length of the data and write the header:
+        let len = data.as_ref().len();
+        assert!(len < u64::MAX as usize);
+        let header = Header::new(len as u32).encode();
+
+        // Write stuff to the file:
+        self.write_tracked(&header)?;
+        self.write_tracked(data.as_ref())?;
+
+        if let Some(footer) = &mut self.footer {
+            footer.item_done();
+        }
+
+        Ok(written + 4 + len as u64)
+    }
+
+    /// Writes an element bigger than a segment in chunks over as many
+    /// segments as needed, starting with what is left of the current segment.
+    /// Each chunk is flushed before moving on to a new segment, whatever the
+    /// size of the queue (the element was already let in). Returns the number
+    /// of bytes written. See [`SenderBuilder::split_items`].
+    fn write_chunks(&mut self, metadata: &Metadata, mut data: &[u8]) -> io::Result<u64> {
+        let mut metadata = Metadata {
+            more_chunks: true,
+            ..metadata.clone()
+        };
+        let mut written = 0;
+
+        loop {
+            let overhead = metadata.encoded_len() + 4;
+            let room = self.segment_size.get().saturating_sub(self.state.position);
+
+            // No room for even the start of a chunk here:
+            if room <= overhead && self.state.position > 0 {
+                self.cap_off_and_move()?;
+                continue;
+            }
+
+            // (every chunk takes at least one byte, however small the segments)
+            let len = room.saturating_sub(overhead).max(1).min(data.len() as u64);
+            let (chunk, rest) = data.split_at(len as usize);
+            metadata.more_chunks = !rest.is_empty();
+
+            let chunk_written = self.write(&metadata, chunk)?;
+            self.file.flush()?;
+            self.state.advance_position(chunk_written);
+            written += chunk_written;
+
+            if rest.is_empty() {
+                return Ok(written);
+            }
+
+            self.cap_off_and_move()?;
+            data = rest;
+            metadata = Metadata {
+                more_chunks: true,
+                is_continuation: true,
+                ..Metadata::default()
+            };
+        }
+    }
+
+    /// Writes slices holding `items` elements straight to the segment, with as
+    /// few syscalls as possible, keeping track of them for the footer of the
+    /// segment. Returns the number of bytes written.
+    fn write_vectored(&mut self, slices: &mut [IoSlice<'_>], items: u64) -> io::Result<u64> {
+        // Nothing can get ahead of what is in the buffer:
+        self.file.flush()?;
+
+        if let Some(footer) = &mut self.footer {
+            for slice in slices.iter() {
+                footer.update(slice);
+            }
+            for _ in 0..items {
+                footer.item_done();
+            }
+        }
+
+        let written = slices.iter().map(|slice| slice.len() as u64).sum();
+
+        #[cfg(feature = "failpoints")]
+        if let Err(err) = failpoints::check(&self.base, Failpoint::PartialWrite) {
+            let pending = slices.iter().flat_map(|slice| slice.iter().copied());
+            return Err(self.write_partially(&pending.collect::<Vec<_>>(), err));
+        }
+
+        if let Err(err) = write_all_vectored(self.file.get_mut(), slices) {
+            // (the footer will have to be scanned from the segment)
+            self.footer = None;
+            return Err(err);
+        }
+
+        Ok(written)
+    }
+
+    /// Writes the first half of some bytes straight to the segment, dropping
+    /// the rest and whatever is in the internal buffer, as if the process
+    /// crashed in the middle of the write. Returns the error to fail with. See
+    /// [`Failpoint::PartialWrite`].
+    #[cfg(feature = "failpoints")]
+    fn write_partially(&mut self, pending: &[u8], err: io::Error) -> io::Error {
+        // (the footer will have to be scanned from the segment)
+        self.footer = None;
+
+        let fresh = match self.storage.append(self.state.segment) {
+            Ok(fresh) => io::BufWriter::new(fresh),
+            Err(err) => return err,
+        };
+        let (mut segment, _dropped) = std::mem::replace(&mut self.file, fresh).into_parts();
+
+        segment.write_all(&pending[..pending.len() / 2]).err().unwrap_or(err)
+    }
+
+    /// Writes some bytes to the internal buffer, keeping track of them for the
+    /// footer of the segment.
+    fn write_tracked(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+
+        if let Some(footer) = &mut self.footer {
+            footer.update(bytes);
+        }
+
+        Ok(())
+    }
+
+    /// The timestamp for the next item: now, unless the clock went backwards
+    /// since the last item. See [`SenderBuilder::timestamp`].
+    fn next_timestamp(&mut self) -> SystemTime {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // (as written, so that going backwards is compared in milliseconds)
+        let now = UNIX_EPOCH + Duration::from_millis(since_epoch.as_millis() as u64);
+
+        self.last_timestamp = self.last_timestamp.max(now);
+        self.last_timestamp
+    }
+
+    /// Encodes an item to be written, transforming, compressing, encrypting and
+    /// checksumming and timestamping it as the sender is set to. Returns the metadata to be
+    /// written with the item and the encoded item, if it is not the item itself.
+    fn encode(
+        &mut self,
+        metadata: &Metadata,
+        data: &[u8],
+    ) -> io::Result<(Metadata, Option<Vec<u8>>)> {
+        let mut metadata = metadata.clone();
+
+        // (items moved from another queue keep their timestamp)
+        if self.timestamp && metadata.enqueued_at.is_none() {
+            metadata.enqueued_at = Some(self.next_timestamp());
+        }
+
+        let transformed = transform::encode(&self.codecs, data)?;
+        let data = transformed.as_deref().unwrap_or(data);
+
+        let compressed = self.compress(data).map(|(codec, compressed)| {
+            metadata.compression = Some(codec);
+            compressed
+        });
+
+        let encrypted = if let Some(key_provider) = &self.key_provider {
+            let (encryption, encrypted) =
+                encrypt(&**key_provider, compressed.as_deref().unwrap_or(data))?;
+            metadata.encryption = Some(encryption);
+            Some(encrypted)
+        } else {
+            None
+        };
+
+        let encoded = encrypted.or(compressed);
+
+        if self.checksum {
+            metadata.checksum = Some(crc32fast::hash(encoded.as_deref().unwrap_or(data)));
+        }
+
+        Ok((metadata, encoded.or(transformed)))
+    }
+
+    /// Compresses an item, if the sender is set to and if it is worth it.
+    /// Returns the id of the codec and the compressed item.
+    #[cfg(feature = "compression")]
+    fn compress(&self, data: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let compression = self.compression?;
+        compression
+            .compress(data)
+            .map(|compressed| (compression.codec(), compressed))
+    }
+
+    /// Without the `compression` feature, items are never compressed.
+    #[cfg(not(feature = "compression"))]
+    fn compress(&self, _data: &[u8]) -> Option<(u8, Vec<u8>)> {
+        None
+    }
+
+    /// Syncs what was sent so far to the current segment to the disk. This is
+    /// what the durability of the sender does.
+    fn sync_segment(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        #[cfg(feature = "failpoints")]
+        failpoints::check(&self.base, Failpoint::Fsync)?;
+
+        self.file.get_mut().sync_data()?;
+        self.stats.fsyncs += 1;
+        self.unsynced = 0;
+        self.last_synced_at = Instant::now();
+
+        Ok(())
+    }
+
+    /// Makes every element previously sent through this sender (including to
+    /// its priority lanes and topics) durable on the disk, whatever the
+    /// durability of the sender. Use this as a barrier before acknowledging
+    /// upstream that something was received.
+    ///
+    /// The [durability](SenderBuilder::durability) of the sender only syncs
+    /// the segments. Besides them, this also syncs the folder of the queue,
+    /// which lists the segments created since, and the state of the sender,
+    /// such as the keys kept to skip duplicates.
+    ///
+    /// # Note
+    ///
+    /// This blocks the calling thread for as long as the disk takes to sync,
+    /// which may be a while. In an `async` context, consider calling it where
+    /// blocking is allowed (e.g., in `spawn_blocking`, with your runtime).
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while syncing.
+    /// If it fails, some of the elements sent may not be durable yet.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.sync_segment()?;
+
+        if let Some(sent_keys) = &mut self.sent_keys {
+            sent_keys.sync()?;
+        }
+
+        // The segments created since the last sync are only durable once the
+        // folder listing them is.
+        #[cfg(unix)]
+        {
+            File::open(&self.base)?.sync_all()?;
+            self.stats.fsyncs += 1;
+        }
+
+        for lane in self.lanes.values_mut() {
+            lane.sync()?;
+        }
+
+        for topic in self.topics.values_mut() {
+            topic.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs to the disk after `items` more elements were sent, if it is time,
+    /// as set in the durability of the sender.
+    fn maybe_sync(&mut self, items: u64) -> io::Result<()> {
+        self.unsynced += items;
+
+        let is_due = match self.durability {
+            Durability::Never => false,
+            Durability::EveryItem => true,
+            Durability::Periodic { items, interval } => {
+                let unsynced = self.unsynced;
+                let elapsed = self.last_synced_at.elapsed();
+                items.map(|items| unsynced >= items).unwrap_or(false)
+                    || interval
+                        .map(|interval| elapsed >= interval)
+                        .unwrap_or(false)
+            }
+        };
+
+        if is_due {
+            self.sync_segment()?;
+        }
+
+        Ok(())
+    }
+
+    /// Tests whether the queue is past the end of the current segment.
+    fn is_past_end(&self) -> bool {
+        self.state.position > self.segment_size.get()
+    }
+
+    /// Caps off a segment by writing an EOF header and then moves segment.
+    /// This function returns `Ok(true)` if it has created a new segment or
+    /// `Ok(false)` if it has not (because the queue was too big).
+    #[must_use = "you need to always check if a segment was created or not!"]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "rotate_segment",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base, segment = self.state.segment)
+        )
+    )]
     fn try_cap_off_and_move(&mut self) -> io::Result<bool> {
         if let Some(max_queue_size) = self.max_queue_size {
-            let queue_size = get_queue_size(&self.base)?;
+            let queue_size = storage_size(&*self.storage)?;
 
             // Have to check if the number of segments is at least one. Otherwise, the queue will
             // deadlock.
@@ -261,18 +1686,80 @@ impl Sender {
         }
 
         log::trace!("there is enough space for a new segment. Let's cap off and move on!");
+        self.cap_off_and_move()?;
+
+        Ok(true)
+    }
+
+    /// Caps off a segment by writing an EOF header and then moves segment,
+    /// whatever the size of the queue.
+    fn cap_off_and_move(&mut self) -> io::Result<()> {
+        // What was written to the segment may not be known (e.g., when other
+        // senders wrote to it):
+        let footer = match &self.footer {
+            Some(footer) if footer.position == self.state.position => footer.finish(),
+            _ => FooterBuilder::scan(self.storage.read(self.state.segment)?, self.state.position)?
+                .finish(),
+        };
 
-        // Write EOF header:
-        self.file.write(&HEADER_EOF)?;
+        // Write EOF header and the footer:
+        self.file.write_all(&HEADER_EOF)?;
+        self.file.write_all(&footer)?;
         self.file.flush()?;
 
+        if self.durability != Durability::Never {
+            self.sync_segment()?;
+        }
+
+        let closed = self.state;
+        let closed_len = closed.position + (HEADER_EOF.len() + footer.len()) as u64;
+
         // Preserves the already allocated buffer:
-        *self.file.get_mut() = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(segment_filename(&self.base, self.state.advance_segment()))?;
+        *self.file.get_mut() = self.storage.append(self.state.advance_segment())?;
+        self.footer = Some(FooterBuilder::default());
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(segment = self.state.segment, "moved on to a new segment");
+
+        self.stats.bytes_written += (HEADER_EOF.len() + footer.len()) as u64;
+        self.stats.segments_created += 1;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_written(&self.base, (HEADER_EOF.len() + footer.len()) as u64);
+            metrics.segment_rotated(&self.base, self.state.segment);
+        }
+
+        if let Some(on_segment_closed) = &self.on_segment_closed {
+            on_segment_closed(&segment_filename(&self.base, closed.segment), closed_len)?;
+        }
+
+        if let RetentionPolicy::DropOldest { max_bytes } = self.retention {
+            self.drop_oldest(max_bytes)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the oldest segments until the segments take at most `max_bytes`
+    /// or only the current segment is left.
+    fn drop_oldest(&mut self, max_bytes: u64) -> io::Result<()> {
+        let segments = storage::segment_lens(&*self.storage)?;
+        let mut in_bytes = segments.iter().map(|&(_, len)| len).sum::<u64>();
+
+        for (segment, len) in segments {
+            if in_bytes <= max_bytes || segment >= self.state.segment {
+                break;
+            }
+
+            log::debug!("dropping segment {} from {:?}", segment, self.base);
+
+            // (another sender or the receiver may have got there first)
+            self.storage.delete(segment)?;
+
+            in_bytes -= len;
+        }
 
-        Ok(true)
+        Ok(())
     }
 
     fn maybe_cap_off_and_move<T>(&mut self, item: T) -> Result<T, TrySendError<T>> {
@@ -280,6 +1767,17 @@ impl Sender {
         if self.is_past_end() {
             log::trace!("is past the segment end. Trying to cap off and move");
 
+            if let Some(max_segments) = self.max_segments {
+                if storage_size(&*self.storage)?.in_segments >= max_segments.get() {
+                    log::trace!("no more segments allowed in queue `{:?}`", self.base);
+
+                    return Err(TrySendError::QuotaExceeded {
+                        item,
+                        base: self.base.clone(),
+                    });
+                }
+            }
+
             // If so, create a new file, if you are able to:
             if !self.try_cap_off_and_move()? {
                 log::trace!(
@@ -297,32 +1795,181 @@ impl Sender {
         Ok(item)
     }
 
-    /// Lazy inits the future that completes every time a file is deleted.
-    fn deletion_stream(&mut self) -> &mut DeletionEvent {
-        if self.deletion_stream.is_none() {
-            let deletion_stream = DeletionEvent::new(&self.base);
-            self.deletion_stream = Some(deletion_stream);
+    /// Estimates the number of bytes in the queue that were not yet received,
+    /// using the last saved state of the receiver.
+    fn pending_bytes(&self) -> io::Result<u64> {
+        let queue_size = storage_size(&*self.storage)?;
+        let recv_state = self.storage.load_state()?;
+
+        Ok(queue_size.in_bytes.saturating_sub(recv_state.position))
+    }
+
+    /// Checks whether an element of `size` bytes is accepted by the queue, as
+    /// set in `max_item_size`, and fits in a header, limit or no limit.
+    fn check_item_size(&self, size: u64) -> Result<(), ItemTooBig> {
+        let max_item_size = self
+            .max_item_size
+            .map_or(u64::from(header::MAX_LEN), NonZeroU64::get);
+
+        if size > max_item_size {
+            log::trace!("element of {} bytes too big for `{:?}`", size, self.base);
+
+            return Err(ItemTooBig {
+                base: self.base.clone(),
+                size,
+                max_item_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether `size` more bytes fit in the quota of the queue, as set
+    /// in `max_total_bytes`. Returns the item back as
+    /// [`TrySendError::QuotaExceeded`] if they do not.
+    fn check_quota<T>(&self, item: T, size: u64) -> Result<T, TrySendError<T>> {
+        if let Some(max_total_bytes) = self.max_total_bytes {
+            let in_bytes = storage_size(&*self.storage)?.in_bytes;
+
+            if in_bytes + size > max_total_bytes.get() {
+                log::trace!(
+                    "queue `{:?}` has {} bytes; no room for {} more",
+                    self.base,
+                    in_bytes,
+                    size
+                );
+
+                return Err(TrySendError::QuotaExceeded {
+                    item,
+                    base: self.base.clone(),
+                });
+            }
+        }
+
+        Ok(item)
+    }
+
+    /// Checks whether `items` more items with `size` bytes fit in the queue, as
+    /// set in `max_queue_bytes` and `max_queue_items`. Returns the item back as
+    /// [`TrySendError::QueueFull`] if they do not.
+    fn check_capacity<T>(&self, item: T, items: u64, size: u64) -> Result<T, TrySendError<T>> {
+        if let Some(max_queue_items) = self.max_queue_items {
+            let recv_state = self.storage.load_state()?;
+            let pending = queue_info_from(&*self.storage, recv_state)?.items;
+
+            if pending > 0 && pending + items > max_queue_items.get() {
+                log::trace!("{} items pending in queue `{:?}`", pending, self.base);
+
+                return Err(TrySendError::QueueFull {
+                    item,
+                    base: self.base.clone(),
+                });
+            }
+        }
+
+        if let Some(max_queue_bytes) = self.max_queue_bytes {
+            let pending = self.pending_bytes()?;
+
+            if pending > 0 && pending + size > max_queue_bytes.get() {
+                log::trace!(
+                    "{} bytes pending in queue `{:?}`; no room for {} more",
+                    pending,
+                    self.base,
+                    size
+                );
+
+                return Err(TrySendError::QueueFull {
+                    item,
+                    base: self.base.clone(),
+                });
+            }
+        }
+
+        Ok(item)
+    }
+
+    /// Waits for the receiver to make some progress, that is, to save its
+    /// state or to remove a segment, so that a full queue can be checked
+    /// again. Changes may be missed between the check and the wait, so this
+    /// also gives up after a short while.
+    async fn wait_for_receiver(&mut self) {
+        // Lazy inits the future that completes every time something changes:
+        if self.change_event.is_none() {
+            self.change_event = Some(ChangeEvent::new(&self.base));
         }
 
-        self.deletion_stream.as_mut().unwrap() // because if was not Some, now it is.
+        let change_event = self.change_event.as_mut().expect("was just inited");
+        future::select(change_event, Delay::new(RECHECK_FULL_QUEUE_EVERY)).await;
     }
 
     /// Tries to sends some data into the queue. If the queue is too big to
-    /// insert (as set in `max_queue_size`), this returns
+    /// insert (as set in `max_queue_size` or `max_queue_bytes`), this returns
     /// [`TrySendError::QueueFull`]. One send is always atomic.
     ///
     /// # Errors
     ///
     /// This function returns any underlying errors encountered while writing or
     /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
-    /// queue is too big.
+    /// queue is too big and [`TrySendError::QuotaExceeded`] if the queue is
+    /// over its quota.
     pub fn try_send<D: AsRef<[u8]>>(&mut self, data: D) -> Result<(), TrySendError<D>> {
+        self.try_send_with_metadata(&Metadata::default(), data)
+    }
+
+    /// Tries to send some data with some metadata into the queue. See
+    /// [`Sender::try_send`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "send", level = "debug", skip_all, fields(base = ?self.base))
+    )]
+    pub(crate) fn try_send_with_metadata<D: AsRef<[u8]>>(
+        &mut self,
+        metadata: &Metadata,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        let _claim = self.claim()?;
+
+        let key = self.dedup_key(metadata.id.as_deref(), data.as_ref());
+        if self.was_sent(key.as_deref()) {
+            log::debug!("skipping an element already sent to {:?}", self.base);
+            return Ok(());
+        }
+
+        let (metadata, encoded) = self.encode(metadata, data.as_ref())?;
+
+        let payload_len = encoded.as_ref().map_or(data.as_ref().len(), Vec::len);
+        self.check_item_size(payload_len as u64).map_err(io::Error::from)?;
+        let size = metadata.encoded_len() + 4 + payload_len as u64;
+        let data = self.check_quota(data, size)?;
+        let data = self.check_capacity(data, 1, size)?;
         let data = self.maybe_cap_off_and_move(data)?;
 
         // Write to the queue and flush:
-        let written = self.write(data.as_ref())?;
-        self.file.flush()?; // guarantees atomic operation. See `new`.
-        self.state.advance_position(written);
+        let payload = encoded.as_deref().unwrap_or(data.as_ref());
+        let written = if self.split_items && size > self.segment_size.get() {
+            self.write_chunks(&metadata, payload)?
+        } else {
+            let written = self.write(&metadata, payload)?;
+
+            #[cfg(feature = "failpoints")]
+            if let Err(err) = failpoints::check(&self.base, Failpoint::PartialWrite) {
+                let pending = self.file.buffer().to_vec();
+                return Err(self.write_partially(&pending, err).into());
+            }
+
+            self.file.flush()?; // guarantees atomic operation. See `new`.
+            self.state.advance_position(written);
+            written
+        };
+        self.maybe_sync(1)?;
+        self.keep_sent(key)?;
+        self.stats.items_sent += 1;
+        self.stats.bytes_written += written;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.item_sent(&self.base, data.as_ref().len() as u64);
+            metrics.bytes_written(&self.base, written);
+        }
 
         Ok(())
     }
@@ -334,23 +1981,58 @@ impl Sender {
     /// # Errors
     ///
     /// This function returns any underlying errors encountered while writing or
-    /// flushing the queue.
+    /// flushing the queue. If the queue is over its quota, this returns an
+    /// error wrapping [`crate::QuotaExceeded`] instead of waiting.
+    ///
+    pub async fn send<D: AsRef<[u8]>>(&mut self, data: D) -> io::Result<()> {
+        self.send_with_metadata(&Metadata::default(), data).await
+    }
+
+    /// Serializes a value in the wire format of this sender (see
+    /// [`SenderBuilder::wire_format`]) and sends it into the queue, as in
+    /// [`Sender::send`]. Receive it with
+    /// [`crate::queue::RecvGuard::deserialize`].
+    ///
+    /// # Errors
     ///
-    pub async fn send<D: AsRef<[u8]>>(&mut self, mut data: D) -> io::Result<()> {
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the value cannot be serialized, in which case nothing is sent. Also,
+    /// it returns the same errors as [`Sender::send`].
+    #[cfg(feature = "serde")]
+    pub async fn send_serialized<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> io::Result<()> {
+        let data = self.wire_format.serialize(value)?;
+        self.send(data).await
+    }
+
+    /// Sends some data with some metadata into the queue. See [`Sender::send`].
+    pub(crate) async fn send_with_metadata<D: AsRef<[u8]>>(
+        &mut self,
+        metadata: &Metadata,
+        mut data: D,
+    ) -> io::Result<()> {
         loop {
-            match self.try_send(data) {
+            self.claim_async().await?;
+
+            match self.try_send_with_metadata(metadata, data) {
                 Ok(()) => break Ok(()),
                 Err(TrySendError::Io(err)) => break Err(err),
+                Err(TrySendError::QuotaExceeded { base, .. }) => {
+                    break Err(QuotaExceeded { base }.into())
+                }
                 Err(TrySendError::QueueFull { item, .. }) => {
                     data = item; // the "unmove"!
-                    self.deletion_stream().await // prevents spinlock
+                    self.wait_for_receiver().await // prevents spinlock
                 }
             }
         }
     }
 
     /// Tries to send all the contents of an iterable into the queue. If the
-    /// queue is too big to insert (as set in `max_queue_size`), this returns
+    /// queue is too big to insert (as set in `max_queue_size` or
+    /// `max_queue_bytes`), this returns
     /// [`TrySendError::QueueFull`]. All is buffered to be sent atomically, in
     /// one flush operation. Since this operation is atomic, it does not create
     /// new segments during the iteration. Be mindful of that when using this
@@ -360,22 +2042,66 @@ impl Sender {
     ///
     /// This function returns any underlying errors encountered while writing or
     /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
-    /// queue is too big.
-    pub fn try_send_batch<I>(&mut self, it: I) -> Result<(), TrySendError<I>>
+    /// queue is too big for the whole batch and [`TrySendError::QuotaExceeded`]
+    /// if the queue is over its quota. The batch is collected (and encoded) to
+    /// be checked against the limits, so these give the items back in a `Vec`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "send_batch",
+            level = "debug",
+            skip_all,
+            fields(base = ?self.base)
+        )
+    )]
+    pub fn try_send_batch<I>(&mut self, it: I) -> Result<(), TrySendError<Vec<I::Item>>>
     where
         I: IntoIterator,
         I::Item: AsRef<[u8]>,
     {
-        let it = self.maybe_cap_off_and_move(it)?;
+        let _claim = self.claim()?;
 
-        let mut written = 0;
-        // Drain iterator into the buffer.
-        for item in it {
-            written += self.write(item.as_ref())?;
+        // Drain the iterator and encode everything, so that the whole batch
+        // can be checked and go down in as few syscalls as possible:
+        let mut items = it.into_iter().collect::<Vec<_>>();
+        let keys = self.skip_sent(&mut items);
+        let encoded = items
+            .iter()
+            .map(|item| {
+                let (metadata, payload) = self.encode(&Metadata::default(), item.as_ref())?;
+                let payload_len = payload.as_ref().map_or(item.as_ref().len(), Vec::len);
+                self.check_item_size(payload_len as u64)?;
+                Ok(EncodedItem::new(&metadata, item.as_ref(), payload))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let size = items
+            .iter()
+            .zip(&encoded)
+            .map(|(item, encoded)| encoded.len(item.as_ref()))
+            .sum();
+        let items = self.check_quota(items, size)?;
+        let items = self.check_capacity(items, encoded.len() as u64, size)?;
+        let items = self.maybe_cap_off_and_move(items)?;
+
+        let mut slices = Vec::with_capacity(2 * items.len());
+        for (item, encoded) in items.iter().zip(&encoded) {
+            encoded.push_slices(item.as_ref(), &mut slices);
         }
 
-        self.file.flush()?; // guarantees atomic operation. See `new`.
+        let written = self.write_vectored(&mut slices, items.len() as u64)?;
         self.state.advance_position(written);
+        self.maybe_sync(items.len() as u64)?;
+        self.keep_sent(keys)?;
+        self.stats.items_sent += items.len() as u64;
+        self.stats.bytes_written += written;
+
+        if let Some(metrics) = &self.metrics {
+            for item in &items {
+                metrics.item_sent(&self.base, item.as_ref().len() as u64);
+            }
+            metrics.bytes_written(&self.base, written);
+        }
 
         Ok(())
     }
@@ -391,19 +2117,575 @@ impl Sender {
     ///
     /// This function returns any underlying errors encountered while writing or
     /// flushing the queue.
-    pub async fn send_batch<I>(&mut self, mut it: I) -> io::Result<()>
+    pub async fn send_batch<I>(&mut self, it: I) -> io::Result<()>
     where
         I: IntoIterator,
         I::Item: AsRef<[u8]>,
     {
+        let mut items = it.into_iter().collect::<Vec<_>>();
+
         loop {
-            match self.try_send_batch(it) {
+            self.claim_async().await?;
+
+            match self.try_send_batch(items) {
                 Ok(()) => break Ok(()),
                 Err(TrySendError::Io(err)) => break Err(err),
+                Err(TrySendError::QuotaExceeded { base, .. }) => {
+                    break Err(QuotaExceeded { base }.into())
+                }
                 Err(TrySendError::QueueFull { item, .. }) => {
-                    it = item; // the "unmove"!
-                    self.deletion_stream().await // prevents spinlock
+                    items = item; // the "unmove"!
+                    self.wait_for_receiver().await // prevents spinlock
+                }
+            }
+        }
+    }
+
+    /// Writes the elements from an iterator one after the other, without
+    /// flushing in between, until the iterator is exhausted or the queue is
+    /// full or over its quota. The elements written are flushed at the end,
+    /// whatever the outcome. Returns the number of elements written along
+    /// with the outcome.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "send_all", level = "debug", skip_all, fields(base = ?self.base))
+    )]
+    fn try_send_some<I>(&mut self, items: &mut Peekable<I>) -> (u64, Result<(), TrySendError<()>>)
+    where
+        I: Iterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut written = 0;
+        let mut count = 0;
+        // (the lengths of the items, to be reported once they are flushed)
+        let mut sent = Vec::new();
+        // (the keys of the items, to be kept once they are flushed)
+        let mut keys = Vec::new();
+        let mut pending = HashSet::new();
+
+        let outcome = (|| {
+            let _claim = self.claim()?;
+
+            while let Some(item) = items.peek() {
+                let key = self.dedup_key(None, item.as_ref());
+                let is_pending = key.as_ref().is_some_and(|key| pending.contains(key));
+                if is_pending || self.was_sent(key.as_deref()) {
+                    log::debug!("skipping an element already sent to {:?}", self.base);
+                    items.next();
+                    continue;
+                }
+
+                let (metadata, encoded) = self.encode(&Metadata::default(), item.as_ref())?;
+
+                let payload = encoded.as_deref().unwrap_or(item.as_ref());
+                self.check_item_size(payload.len() as u64).map_err(io::Error::from)?;
+                let size = metadata.encoded_len() + 4 + payload.len() as u64;
+                self.check_quota((), size)?;
+                self.check_capacity((), 1, size)?;
+                self.maybe_cap_off_and_move(())?;
+
+                let item_written = self.write(&metadata, payload)?;
+                self.state.advance_position(item_written);
+                written += item_written;
+
+                if self.metrics.is_some() {
+                    sent.push(item.as_ref().len() as u64);
+                }
+                if let Some(key) = key {
+                    pending.insert(key.clone());
+                    keys.push(key);
+                }
+                count += 1;
+                items.next();
+            }
+
+            Ok(())
+        })();
+
+        let flushed = self
+            .file
+            .flush()
+            .and_then(|()| self.maybe_sync(count))
+            .and_then(|()| self.keep_sent(keys))
+            .map_err(TrySendError::Io);
+        self.stats.items_sent += count;
+        self.stats.bytes_written += written;
+
+        if let Some(metrics) = &self.metrics {
+            for len in sent {
+                metrics.item_sent(&self.base, len);
+            }
+            metrics.bytes_written(&self.base, written);
+        }
+
+        (count, flushed.and(outcome))
+    }
+
+    /// Sends all the elements from an iterator into the queue, in order,
+    /// returning how many were sent. Unlike [`Sender::send_batch`], this is
+    /// not atomic: the elements are written one after the other, creating
+    /// new segments as needed, and are only flushed once at the end (or when
+    /// the sender has to wait for the queue to have room). Use this to
+    /// bulk-load a queue. This function is `async` because the queue might
+    /// be full and so we need to `.await` the receiver to consume enough
+    /// segments to clear the queue.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing
+    /// or flushing the queue, in which case the elements before the one that
+    /// failed may have been sent. If the queue is over its quota, this
+    /// returns an error wrapping [`crate::QuotaExceeded`] instead of waiting.
+    pub async fn send_all<I>(&mut self, it: I) -> io::Result<u64>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut items = it.into_iter().peekable();
+        let mut sent = 0;
+
+        loop {
+            self.claim_async().await?;
+
+            let (count, outcome) = self.try_send_some(&mut items);
+            sent += count;
+
+            match outcome {
+                Ok(()) => break Ok(sent),
+                Err(TrySendError::Io(err)) => break Err(err),
+                Err(TrySendError::QuotaExceeded { base, .. }) => {
+                    break Err(QuotaExceeded { base }.into())
                 }
+                Err(TrySendError::QueueFull { .. }) => self.wait_for_receiver().await,
+            }
+        }
+    }
+
+    /// Sends all the elements from a stream into the queue, as in
+    /// [`Sender::send_all`], returning how many were sent. The elements that
+    /// are ready are written together and flushed before waiting for the
+    /// stream to yield more, so that they can be received meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the same errors as [`Sender::send_all`].
+    pub async fn send_stream<S>(&mut self, stream: S) -> io::Result<u64>
+    where
+        S: Stream,
+        S::Item: AsRef<[u8]>,
+    {
+        let mut chunks = Box::pin(stream.ready_chunks(SEND_STREAM_CHUNK));
+        let mut sent = 0;
+
+        while let Some(chunk) = chunks.next().await {
+            sent += self.send_all(chunk).await?;
+        }
+
+        Ok(sent)
+    }
+
+    /// Turns this sender into a [`SendSink`], a [`futures::Sink`] into the
+    /// queue. See [`SendSink`] for how flushing works.
+    pub fn into_sink(self) -> SendSink {
+        SendSink::new(self)
+    }
+
+    /// Turns this sender into a [`CoalescingSender`], a handle that can be
+    /// cloned and shared between many tasks sending concurrently, which
+    /// writes the elements they send in groups. See
+    /// [`SenderBuilder::coalesce_window`].
+    pub fn into_coalescing(self) -> CoalescingSender {
+        let window = self.coalesce_window;
+        CoalescingSender::new(self, window)
+    }
+
+    /// Tries to send some data into the queue that will only be delivered at
+    /// or after a given instant, in the same way as [`Sender::try_send`]. The
+    /// instant is stored with the item, with millisecond precision.
+    ///
+    /// The order of the queue is kept: the receiver _parks_ at an item that
+    /// is not due yet, and the items sent after it are only delivered after
+    /// it. Therefore, delays work best when they are uniform, as in retry
+    /// scheduling with a fixed backoff.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
+    /// queue is too big.
+    pub fn try_send_at<D: AsRef<[u8]>>(
+        &mut self,
+        instant: SystemTime,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        let metadata = Metadata {
+            not_before: Some(instant),
+            ..Metadata::default()
+        };
+        self.try_send_with_metadata(&metadata, data)
+    }
+
+    /// Sends some data into the queue that will only be delivered at or after
+    /// a given instant, in the same way as [`Sender::send`]. See
+    /// [`Sender::try_send_at`] for how delays work.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue.
+    pub async fn send_at<D: AsRef<[u8]>>(
+        &mut self,
+        instant: SystemTime,
+        data: D,
+    ) -> io::Result<()> {
+        let metadata = Metadata {
+            not_before: Some(instant),
+            ..Metadata::default()
+        };
+        self.send_with_metadata(&metadata, data).await
+    }
+
+    /// Tries to send some data into the queue that will only be delivered
+    /// after a delay from now. See [`Sender::try_send_at`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
+    /// queue is too big.
+    pub fn try_send_after<D: AsRef<[u8]>>(
+        &mut self,
+        delay: Duration,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        self.try_send_at(SystemTime::now() + delay, data)
+    }
+
+    /// Sends some data into the queue that will only be delivered after a
+    /// delay from now. See [`Sender::try_send_at`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue.
+    pub async fn send_after<D: AsRef<[u8]>>(&mut self, delay: Duration, data: D) -> io::Result<()> {
+        self.send_at(SystemTime::now() + delay, data).await
+    }
+
+    /// Tries to send some data into the queue that expires after a given
+    /// time-to-live, in the same way as [`Sender::try_send`]. The receiver
+    /// silently discards the elements that have expired when it gets to them.
+    /// The expiration instant is stored with the item, with millisecond
+    /// precision.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
+    /// queue is too big.
+    pub fn try_send_with_ttl<D: AsRef<[u8]>>(
+        &mut self,
+        ttl: Duration,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        let metadata = Metadata {
+            expires_at: Some(SystemTime::now() + ttl),
+            ..Metadata::default()
+        };
+        self.try_send_with_metadata(&metadata, data)
+    }
+
+    /// Sends some data into the queue that expires after a given time-to-live,
+    /// in the same way as [`Sender::send`]. See [`Sender::try_send_with_ttl`]
+    /// for how expiration works.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue.
+    pub async fn send_with_ttl<D: AsRef<[u8]>>(
+        &mut self,
+        ttl: Duration,
+        data: D,
+    ) -> io::Result<()> {
+        let metadata = Metadata {
+            expires_at: Some(SystemTime::now() + ttl),
+            ..Metadata::default()
+        };
+        self.send_with_metadata(&metadata, data).await
+    }
+
+    /// Tries to send some data into the queue with an id, in the same way as
+    /// [`Sender::try_send`]. Receivers set to deduplicate (see
+    /// [`ReceiverBuilder::dedup_window`](crate::ReceiverBuilder::dedup_window))
+    /// skip the elements whose id they have already seen, so that a producer
+    /// can retry a send without the element being received twice. The id is
+    /// stored with the item and can be at most 255 bytes long.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the id is too long, in which case nothing is sent. Also, it returns
+    /// the same errors as [`Sender::try_send`].
+    pub fn try_send_with_id<I: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        id: I,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        let metadata = Metadata {
+            id: Some(checked_id(id.as_ref())?),
+            ..Metadata::default()
+        };
+        self.try_send_with_metadata(&metadata, data)
+    }
+
+    /// Sends some data into the queue with an id, in the same way as
+    /// [`Sender::send`]. See [`Sender::try_send_with_id`] for how ids work.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the id is too long, in which case nothing is sent. Also, it returns
+    /// the same errors as [`Sender::send`].
+    pub async fn send_with_id<I: AsRef<[u8]>, D: AsRef<[u8]>>(
+        &mut self,
+        id: I,
+        data: D,
+    ) -> io::Result<()> {
+        let metadata = Metadata {
+            id: Some(checked_id(id.as_ref())?),
+            ..Metadata::default()
+        };
+        self.send_with_metadata(&metadata, data).await
+    }
+
+    /// Tries to send some data into the queue with headers, in the same way
+    /// as [`Sender::try_send`]. Headers are names with values (e.g., a content
+    /// type, a trace id or a routing hint) that are stored with the item,
+    /// next to the payload, so that the receiver gets them with
+    /// [`RecvGuard::headers`](crate::queue::RecvGuard::headers) without an
+    /// envelope of its own inside the payload. Names can be at most 255 bytes
+    /// long and cannot be repeated. Be aware that headers are neither
+    /// compressed nor encrypted, nor transformed by the codecs of the sender.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if a name is too long or repeated, or if a value is too long (that is,
+    /// longer than 65279 bytes), in which case nothing is sent. Also, it
+    /// returns the same errors as [`Sender::try_send`].
+    pub fn try_send_with_headers<D: AsRef<[u8]>>(
+        &mut self,
+        headers: &[(&str, &[u8])],
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        let metadata = Metadata {
+            headers: checked_headers(headers)?,
+            ..Metadata::default()
+        };
+        self.try_send_with_metadata(&metadata, data)
+    }
+
+    /// Sends some data into the queue with headers, in the same way as
+    /// [`Sender::send`]. See [`Sender::try_send_with_headers`] for how headers
+    /// work.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if a name is too long or repeated, or if a value is too long, in which
+    /// case nothing is sent. Also, it returns the same errors as
+    /// [`Sender::send`].
+    pub async fn send_with_headers<D: AsRef<[u8]>>(
+        &mut self,
+        headers: &[(&str, &[u8])],
+        data: D,
+    ) -> io::Result<()> {
+        let metadata = Metadata {
+            headers: checked_headers(headers)?,
+            ..Metadata::default()
+        };
+        self.send_with_metadata(&metadata, data).await
+    }
+
+    /// The configuration of this sender, for the queues nested in this queue
+    /// (the priority lanes and the topics).
+    fn nested_builder(&self) -> SenderBuilder {
+        SenderBuilder {
+            segment_size: self.segment_size,
+            max_queue_size: self.max_queue_size,
+            max_queue_bytes: self.max_queue_bytes,
+            max_queue_items: self.max_queue_items,
+            max_segments: self.max_segments,
+            max_total_bytes: self.max_total_bytes,
+            retention: self.retention,
+            durability: self.durability,
+            coalesce_window: self.coalesce_window,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            #[cfg(feature = "serde")]
+            wire_format: self.wire_format,
+            codecs: self.codecs.clone(),
+            key_provider: self.key_provider.clone(),
+            checksum: self.checksum,
+            timestamp: self.timestamp,
+            on_segment_closed: self.on_segment_closed.clone(),
+            metrics: self.metrics.clone(),
+            shared: self.shared,
+            lease: self.lease,
+            storage: None,
+            dedup_window: self.dedup_window,
+            dedup_max_age: self.dedup_max_age,
+            #[cfg(unix)]
+            permissions: self.permissions,
+            in_process: self.in_process,
+            split_items: self.split_items,
+            max_item_size: self.max_item_size,
+        }
+    }
+
+    /// Gets the sender for a priority lane of this queue, opening the lane
+    /// with the same configuration as this sender if necessary.
+    fn lane(&mut self, priority: u8) -> io::Result<&mut Sender> {
+        if !self.lanes.contains_key(&priority) {
+            if self.custom_storage {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "priority lanes need the default storage",
+                ));
+            }
+
+            log::trace!("opening priority lane {} of {:?}", priority, self.base);
+            let lane = self
+                .nested_builder()
+                .open(lane_dirname(&self.base, priority))?;
+            self.lanes.insert(priority, lane);
+        }
+
+        Ok(self.lanes.get_mut(&priority).expect("lane was just opened"))
+    }
+
+    /// Tries to send some data into the queue with a given priority, in the
+    /// same way as [`Sender::try_send`]. Items of a higher priority are
+    /// delivered first by [`Receiver::recv_prioritized`](crate::Receiver::recv_prioritized).
+    /// The priority `0` is the queue itself, so `try_send` is the same as
+    /// sending with priority `0`.
+    ///
+    /// Each priority is kept in a _lane_, a nested queue in the folder
+    /// `priority-{priority}`. The `max_queue_size` is enforced separately for
+    /// every lane.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the queue has a storage of its own (see [`SenderBuilder::storage`]).
+    /// Also, it returns any underlying errors encountered while opening the
+    /// lane or while writing or flushing the queue, and
+    /// [`TrySendError::QueueFull`] if the lane is too big.
+    pub fn try_send_with_priority<D: AsRef<[u8]>>(
+        &mut self,
+        priority: u8,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        if priority == 0 {
+            self.try_send(data)
+        } else {
+            self.lane(priority)?.try_send(data)
+        }
+    }
+
+    /// Sends some data into the queue with a given priority, in the same way
+    /// as [`Sender::send`]. See [`Sender::try_send_with_priority`] for how
+    /// priorities work.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the queue has a storage of its own (see [`SenderBuilder::storage`]).
+    /// Also, it returns any underlying errors encountered while opening the
+    /// lane or while writing or flushing the queue.
+    pub async fn send_with_priority<D: AsRef<[u8]>>(
+        &mut self,
+        priority: u8,
+        data: D,
+    ) -> io::Result<()> {
+        if priority == 0 {
+            self.send(data).await
+        } else {
+            self.lane(priority)?.send(data).await
+        }
+    }
+
+    /// Gets the sender for a topic of this queue, opening the topic with the
+    /// same configuration as this sender if necessary.
+    fn topic(&mut self, topic: &str) -> io::Result<&mut Sender> {
+        if !self.topics.contains_key(topic) {
+            if self.custom_storage {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "topics need the default storage",
+                ));
+            }
+
+            check_topic(topic)?;
+            log::trace!("opening topic {:?} of {:?}", topic, self.base);
+            let sender = self
+                .nested_builder()
+                .open(topic_dirname(&self.base, topic))?;
+            self.topics.insert(topic.to_owned(), sender);
+        }
+
+        Ok(self.topics.get_mut(topic).expect("topic was just opened"))
+    }
+
+    /// Tries to send some data to a topic of the queue, in the same way as
+    /// [`Sender::try_send`]. Only the receivers subscribed to the topic get
+    /// the items sent to it (see
+    /// [`Receiver::subscribe`](crate::Receiver::subscribe)), which makes
+    /// many streams of items share one queue folder.
+    ///
+    /// Each topic is kept in a nested queue in the folder `topic-{topic}`,
+    /// with the same configuration as this sender, which is created the first
+    /// time something is sent to it. Topic names can only have ASCII letters,
+    /// digits, `-` and `_`, and are at most 64 bytes long. The
+    /// `max_queue_size` is enforced separately for every topic. See also
+    /// [`crate::queue::topics`] and [`crate::queue::remove_topic`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the topic name is not valid or if the queue has a storage of its own
+    /// (see [`SenderBuilder::storage`]). Also, it returns any underlying errors
+    /// encountered while opening the topic or while writing or flushing the
+    /// queue, and [`TrySendError::QueueFull`] if the topic is too big.
+    pub fn try_send_to<D: AsRef<[u8]>>(
+        &mut self,
+        topic: &str,
+        data: D,
+    ) -> Result<(), TrySendError<D>> {
+        self.topic(topic)?.try_send(data)
+    }
+
+    /// Sends some data to a topic of the queue, in the same way as
+    /// [`Sender::send`]. See [`Sender::try_send_to`] for how topics work.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the topic name is not valid or if the queue has a storage of its own
+    /// (see [`SenderBuilder::storage`]). Also, it returns any underlying errors
+    /// encountered while opening the topic or while writing or flushing the
+    /// queue.
+    pub async fn send_to<D: AsRef<[u8]>>(&mut self, topic: &str, data: D) -> io::Result<()> {
+        self.topic(topic)?.send(data).await
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        if self.durability != Durability::Never && self.unsynced > 0 {
+            if let Err(err) = self.sync_segment() {
+                log::error!("unable to sync sender on drop: {}", err);
             }
         }
     }
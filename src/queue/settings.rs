@@ -0,0 +1,331 @@
+//! The settings a queue is created with, which both ends of the queue honor.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::*;
+use std::io::{self, Write};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "serde")]
+use crate::codec::WireFormat;
+#[cfg(feature = "compression")]
+use crate::compression::Compression;
+use crate::sharing;
+
+/// The name of the file recording the settings of the queue.
+pub(crate) fn settings_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("settings")
+}
+
+/// The settings of a queue that the sender and the receiver have to agree on.
+/// The first sender to open a queue records its settings in the folder of the
+/// queue, and every sender and receiver opening the queue later uses the
+/// recorded ones instead of its own, logging a warning if they differ.
+///
+/// The file has one `name = value` line per setting, so that it can be
+/// edited by hand (e.g., to grow the segments of an existing queue).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct QueueSettings {
+    pub(crate) segment_size: NonZeroU64,
+    pub(crate) checksum: bool,
+    /// The largest element the senders accept, if there is a limit. Queues
+    /// without a limit do not record one.
+    pub(crate) max_item_size: Option<NonZeroU64>,
+    #[cfg(feature = "compression")]
+    pub(crate) compression: Option<Compression>,
+    /// The format of the serialized values, if the queue records one. Queues
+    /// created without the `serde` feature do not.
+    #[cfg(feature = "serde")]
+    pub(crate) wire_format: Option<WireFormat>,
+}
+
+#[cfg(feature = "compression")]
+fn compression_name(compression: Option<Compression>) -> &'static str {
+    match compression {
+        None => "none",
+        Some(Compression::Lz4) => "lz4",
+    }
+}
+
+#[cfg(feature = "compression")]
+fn parse_compression(name: &str) -> Option<Option<Compression>> {
+    match name {
+        "none" => Some(None),
+        "lz4" => Some(Some(Compression::Lz4)),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "serde")]
+fn wire_format_name(wire_format: WireFormat) -> &'static str {
+    match wire_format {
+        WireFormat::Json => "json",
+        #[cfg(feature = "bincode")]
+        WireFormat::Bincode => "bincode",
+        #[cfg(feature = "cbor")]
+        WireFormat::Cbor => "cbor",
+    }
+}
+
+#[cfg(feature = "serde")]
+fn parse_wire_format(name: &str) -> Option<WireFormat> {
+    match name {
+        "json" => Some(WireFormat::Json),
+        #[cfg(feature = "bincode")]
+        "bincode" => Some(WireFormat::Bincode),
+        #[cfg(feature = "cbor")]
+        "cbor" => Some(WireFormat::Cbor),
+        _ => None,
+    }
+}
+
+/// Uses a recorded setting instead of the given one, warning if they differ.
+fn adopt<T: PartialEq + Debug>(base: &Path, name: &str, recorded: T, given: T) -> T {
+    if recorded != given {
+        log::warn!(
+            "queue {:?} was created with {} {:?}, not {:?}; using {:?}",
+            base,
+            name,
+            recorded,
+            given,
+            recorded
+        );
+    }
+
+    recorded
+}
+
+impl QueueSettings {
+    /// Encodes the settings as the contents of the settings file.
+    fn encode(&self) -> String {
+        let mut encoded = format!(
+            "segment_size = {}\nchecksum = {}\n",
+            self.segment_size, self.checksum
+        );
+
+        if let Some(max_item_size) = self.max_item_size {
+            encoded.push_str(&format!("max_item_size = {}\n", max_item_size));
+        }
+
+        #[cfg(feature = "compression")]
+        encoded.push_str(&format!(
+            "compression = {}\n",
+            compression_name(self.compression)
+        ));
+
+        #[cfg(feature = "serde")]
+        if let Some(wire_format) = self.wire_format {
+            encoded.push_str(&format!(
+                "wire_format = {}\n",
+                wire_format_name(wire_format)
+            ));
+        }
+
+        encoded
+    }
+
+    /// Reads the settings recorded for a queue, taking the settings that are
+    /// not recorded from `given`. Returns `None` if the queue records no
+    /// settings. Unknown settings are ignored, so that older versions can
+    /// open queues created by newer ones.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+    /// if a setting cannot be parsed or needs a feature that is not enabled.
+    pub(crate) fn read(base: &Path, given: &QueueSettings) -> io::Result<Option<QueueSettings>> {
+        let path = settings_filename(base);
+        let contents = match read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let invalid = |problem: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse `{:?}` settings file: {}", path, problem),
+            )
+        };
+
+        let mut recorded = BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("no `=` in line `{}`", line)))?;
+            recorded.insert(name.trim(), value.trim());
+        }
+
+        let mut settings = *given;
+        let bad_value = |name: &str, value: &str| invalid(format!("bad {} `{}`", name, value));
+
+        if let Some(&value) = recorded.get("segment_size") {
+            settings.segment_size = value
+                .parse()
+                .map_err(|_| bad_value("segment_size", value))?;
+        }
+
+        if let Some(&value) = recorded.get("checksum") {
+            settings.checksum = value.parse().map_err(|_| bad_value("checksum", value))?;
+        }
+
+        if let Some(&value) = recorded.get("max_item_size") {
+            settings.max_item_size = Some(
+                value
+                    .parse()
+                    .map_err(|_| bad_value("max_item_size", value))?,
+            );
+        }
+
+        #[cfg(feature = "compression")]
+        if let Some(&value) = recorded.get("compression") {
+            settings.compression =
+                parse_compression(value).ok_or_else(|| bad_value("compression", value))?;
+        }
+
+        #[cfg(not(feature = "compression"))]
+        if recorded
+            .get("compression")
+            .is_some_and(|&value| value != "none")
+        {
+            log::warn!(
+                "queue {:?} is compressed, but the `compression` feature is not enabled",
+                base
+            );
+        }
+
+        #[cfg(feature = "serde")]
+        if let Some(&value) = recorded.get("wire_format") {
+            settings.wire_format =
+                Some(parse_wire_format(value).ok_or_else(|| bad_value("wire_format", value))?);
+        }
+
+        Ok(Some(settings))
+    }
+
+    /// Records the settings for a queue. The file is replaced atomically, so
+    /// that a concurrent reader sees either no settings or all of them.
+    fn write(&self, base: &Path) -> io::Result<()> {
+        let path = settings_filename(base);
+        let temp = path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        sharing::created(&temp)?;
+        file.write_all(self.encode().as_bytes())?;
+        file.sync_all()?;
+        rename(temp, path)
+    }
+
+    /// The settings a sender opening a queue uses: the recorded ones, if the
+    /// queue records any, or else the given ones, which get recorded. The
+    /// sender lock has to be held, so that only one sender records settings.
+    pub(crate) fn for_sender(base: &Path, given: QueueSettings) -> io::Result<QueueSettings> {
+        let recorded = match QueueSettings::read(base, &given)? {
+            Some(recorded) => recorded,
+            None => {
+                log::trace!("recording the settings of queue {:?}", base);
+                given.write(base)?;
+                return Ok(given);
+            }
+        };
+
+        Ok(QueueSettings {
+            segment_size: adopt(
+                base,
+                "segment_size",
+                recorded.segment_size,
+                given.segment_size,
+            ),
+            checksum: adopt(base, "checksum", recorded.checksum, given.checksum),
+            max_item_size: adopt(
+                base,
+                "max_item_size",
+                recorded.max_item_size,
+                given.max_item_size,
+            ),
+            #[cfg(feature = "compression")]
+            compression: adopt(base, "compression", recorded.compression, given.compression),
+            #[cfg(feature = "serde")]
+            wire_format: adopt(base, "wire_format", recorded.wire_format, given.wire_format),
+        })
+    }
+
+    /// The wire format a receiver opening a queue uses: the recorded one, if
+    /// the queue records one, or else the given one.
+    #[cfg(feature = "serde")]
+    pub(crate) fn wire_format_for_receiver(
+        base: &Path,
+        given: WireFormat,
+    ) -> io::Result<WireFormat> {
+        let defaults = QueueSettings {
+            wire_format: Some(given),
+            ..QueueSettings::unrecorded()
+        };
+
+        let recorded = QueueSettings::read(base, &defaults)?
+            .and_then(|recorded| recorded.wire_format)
+            .unwrap_or(given);
+
+        Ok(adopt(base, "wire_format", recorded, given))
+    }
+
+    /// The largest element a receiver opening a queue can expect, if the
+    /// queue records a limit. Anything longer is corruption.
+    pub(crate) fn max_item_size_for_receiver(base: &Path) -> io::Result<Option<NonZeroU64>> {
+        let recorded = QueueSettings::read(base, &QueueSettings::unrecorded())?;
+        Ok(recorded.and_then(|recorded| recorded.max_item_size))
+    }
+
+    /// Placeholder settings for a receiver to read the recorded ones over.
+    fn unrecorded() -> QueueSettings {
+        QueueSettings {
+            segment_size: NonZeroU64::MIN,
+            checksum: false,
+            max_item_size: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "serde")]
+            wire_format: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let base = Path::new("data/settings-roundtrip");
+        create_dir_all(base).unwrap();
+
+        let given = QueueSettings {
+            segment_size: NonZeroU64::new(1024).unwrap(),
+            checksum: true,
+            max_item_size: NonZeroU64::new(512),
+            #[cfg(feature = "compression")]
+            compression: Some(Compression::Lz4),
+            #[cfg(feature = "serde")]
+            wire_format: Some(WireFormat::Json),
+        };
+        assert_eq!(QueueSettings::read(base, &given).unwrap(), None);
+        assert_eq!(QueueSettings::for_sender(base, given).unwrap(), given);
+
+        // The recorded settings win:
+        let mut other = given;
+        other.segment_size = NonZeroU64::new(2048).unwrap();
+        other.checksum = false;
+        assert_eq!(QueueSettings::for_sender(base, other).unwrap(), given);
+
+        // Unknown settings are ignored, but bad values are not:
+        let path = settings_filename(base);
+        write(&path, "segment_size = 4096\nfrom_the_future = yes\n").unwrap();
+        let read = QueueSettings::read(base, &given).unwrap().unwrap();
+        assert_eq!(read.segment_size.get(), 4096);
+        assert_eq!(read.checksum, given.checksum);
+
+        write(&path, "checksum = maybe\n").unwrap();
+        let err = QueueSettings::read(base, &given).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,78 @@
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::Sender;
+
+/// A [`Sink`] into a queue, created by [`Sender::into_sink`]. Use this to
+/// `forward` a stream straight into the queue with the combinators of
+/// [`futures::StreamExt`].
+///
+/// Only one element is sent at a time. Sending waits for the queue to have
+/// room, just as [`Sender::send`] does. Every send is flushed to the segment
+/// file, so flushing the sink (or closing it) just waits for the send in
+/// progress to be done. There is no other send state to be persisted.
+pub struct SendSink {
+    /// The sender, while no element is being sent.
+    sender: Option<Sender>,
+    /// The send in progress, which owns the sender until it is done.
+    next: Option<BoxFuture<'static, (Sender, io::Result<()>)>>,
+}
+
+impl SendSink {
+    pub(crate) fn new(sender: Sender) -> SendSink {
+        SendSink {
+            sender: Some(sender),
+            next: None,
+        }
+    }
+
+    /// Drives the send in progress (if any) to completion.
+    fn poll_send(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(next) = self.next.as_mut() {
+            let (sender, outcome) = futures::ready!(next.poll_unpin(context));
+            self.next = None;
+            self.sender = Some(sender);
+
+            Poll::Ready(outcome)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for SendSink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(context)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> io::Result<()> {
+        let this = self.get_mut();
+        let mut sender = this
+            .sender
+            .take()
+            .expect("start_send called without poll_ready");
+
+        this.next = Some(
+            async move {
+                let outcome = sender.send(item).await;
+                (sender, outcome)
+            }
+            .boxed(),
+        );
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(context)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(context)
+    }
+}
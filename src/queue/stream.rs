@@ -0,0 +1,70 @@
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::Receiver;
+
+/// A [`Stream`] of the elements of a queue, created by
+/// [`Receiver::into_stream`]. Use this to compose the queue with the
+/// combinators of [`futures::StreamExt`] instead of writing the
+/// `loop { receiver.recv().await }` yourself.
+///
+/// Since a [`crate::queue::RecvGuard`] borrows the receiver, a stream cannot
+/// yield guards. Instead, _every element is committed as it is yielded_, just
+/// as with [`crate::queue::RecvGuard::try_into_inner`]. If the stream is
+/// dropped while an element is being received, the reception is rolled back.
+/// Use the [`Receiver`] directly if you need to commit only after processing
+/// the element.
+pub struct RecvStream {
+    /// The receiver, while no element is being received.
+    receiver: Option<Receiver>,
+    /// The reception in progress, which owns the receiver until it is done.
+    next: Option<BoxFuture<'static, (Receiver, io::Result<Vec<u8>>)>>,
+}
+
+impl RecvStream {
+    pub(crate) fn new(receiver: Receiver) -> RecvStream {
+        RecvStream {
+            receiver: Some(receiver),
+            next: None,
+        }
+    }
+}
+
+impl Stream for RecvStream {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // Start receiving the next element, if not started yet:
+        if this.next.is_none() {
+            let mut receiver = this
+                .receiver
+                .take()
+                .expect("receiver is returned after every reception");
+
+            this.next = Some(
+                async move {
+                    let outcome = match receiver.recv().await {
+                        Ok(guard) => guard.try_into_inner(),
+                        Err(err) => Err(err),
+                    };
+
+                    (receiver, outcome)
+                }
+                .boxed(),
+            );
+        }
+
+        let next = this.next.as_mut().expect("reception was just started");
+        let (receiver, outcome) = futures::ready!(next.poll_unpin(context));
+        this.next = None;
+        this.receiver = Some(receiver);
+
+        // The queue never ends:
+        Poll::Ready(Some(outcome))
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs::*;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{is_valid_name, try_clear};
+
+/// The prefix of the folders of the topics in the queue folder.
+const TOPIC_PREFIX: &str = "topic-";
+
+/// The name of the folder of a topic in the queue folder. Each topic is a
+/// queue of its own, nested in the queue, like the priority lanes.
+pub(crate) fn topic_dirname<P: AsRef<Path>>(base: P, topic: &str) -> PathBuf {
+    base.as_ref().join(format!("{}{}", TOPIC_PREFIX, topic))
+}
+
+/// Checks that a name can be used for a topic. See
+/// [`Sender::try_send_to`](crate::Sender::try_send_to).
+pub(crate) fn check_topic(topic: &str) -> io::Result<()> {
+    if is_valid_name(topic) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid topic name", topic),
+        ))
+    }
+}
+
+/// Lists the topics of a queue, in alphabetical order. A topic exists from
+/// the first time something is sent to it or a receiver subscribes to it until
+/// it is removed with [`remove_topic`]. See
+/// [`Sender::try_send_to`](crate::Sender::try_send_to).
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while listing the
+/// queue folder. A queue that does not exist has no topics.
+pub fn topics<P: AsRef<Path>>(base: P) -> io::Result<Vec<String>> {
+    let read_dir = match read_dir(base.as_ref()) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let mut topics = vec![];
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+        let topic = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(TOPIC_PREFIX));
+
+        if let Some(topic) = topic.filter(|&topic| is_valid_name(topic)) {
+            if dir_entry.file_type()?.is_dir() {
+                topics.push(topic.to_owned());
+            }
+        }
+    }
+    topics.sort_unstable();
+
+    Ok(topics)
+}
+
+/// Removes a topic from a queue, with everything that was sent to it, in the
+/// same way as [`try_clear`]. Removing a topic that does not exist does
+/// nothing.
+///
+/// # Errors
+///
+/// This function returns an error if the topic is in use for sending or for
+/// receiving, which is indicated by its lock files. Also, it returns any
+/// underlying errors encountered while removing the folder of the topic.
+pub fn remove_topic<P: AsRef<Path>>(base: P, topic: &str) -> io::Result<()> {
+    let dirname = topic_dirname(base, topic);
+    if !dirname.exists() {
+        return Ok(());
+    }
+
+    try_clear(dirname)
+}
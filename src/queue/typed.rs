@@ -0,0 +1,210 @@
+//! Queue handles for a single message type, on top of the serde codec.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{read_to_string, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::error::TryRecvError;
+use crate::sharing;
+
+use super::{Receiver, RecvGuard, Sender};
+
+/// The name of the file recording the type of the messages in the queue.
+fn message_type_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("message-type")
+}
+
+/// Records the type of the messages in the queue, if there is none yet, or
+/// checks it against the recorded one.
+fn check_message_type<T: ?Sized>(base: &Path) -> io::Result<()> {
+    let type_name = std::any::type_name::<T>();
+    let path = message_type_filename(base);
+
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => {
+            sharing::created(&path)?;
+            return writeln!(file, "{}", type_name);
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(err),
+    }
+
+    let recorded = read_to_string(&path)?;
+    let recorded = recorded.trim();
+
+    // An empty file is still being written by the handle that created it.
+    if recorded.is_empty() || recorded == type_name {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "queue `{:?}` holds messages of type `{}`, not `{}`; remove `{:?}` if \
+                the type was just renamed",
+                base, recorded, type_name, path
+            ),
+        ))
+    }
+}
+
+/// A [`Sender`] of values of a single type `T`, serialized in the wire format
+/// of the sender (see [`crate::SenderBuilder::wire_format`]).
+///
+/// The queue records the name of `T` the first time a typed handle opens it,
+/// so that opening it later with a [`TypedSender`] or a [`TypedReceiver`] of
+/// another type fails, instead of the receiver failing to deserialize the
+/// messages. Untyped handles neither record nor check anything.
+pub struct TypedSender<T: ?Sized> {
+    sender: Sender,
+    _type: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize + ?Sized> TypedSender<T> {
+    /// Opens a queue for sending values of type `T`. See [`Sender::open`].
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Sender::open`], this function returns an error
+    /// of kind [`io::ErrorKind::InvalidInput`] if the queue holds messages of
+    /// another type.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<TypedSender<T>> {
+        TypedSender::new(Sender::open(base)?)
+    }
+
+    /// Makes a sender of values of type `T` out of a sender, e.g., one opened
+    /// with a [`crate::SenderBuilder`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the queue holds messages of another type.
+    pub fn new(sender: Sender) -> io::Result<TypedSender<T>> {
+        check_message_type::<T>(sender.base())?;
+
+        Ok(TypedSender {
+            sender,
+            _type: PhantomData,
+        })
+    }
+
+    /// Sends a value into the queue. See [`Sender::send_serialized`].
+    pub async fn send(&mut self, value: &T) -> io::Result<()> {
+        self.sender.send_serialized(value).await
+    }
+
+    /// Unwraps the underlying sender.
+    pub fn into_inner(self) -> Sender {
+        self.sender
+    }
+}
+
+/// A [`Receiver`] of values of a single type `T`, deserialized in the wire
+/// format of the receiver (see [`crate::ReceiverBuilder::wire_format`]). See
+/// [`TypedSender`] for how the type of the queue is checked.
+pub struct TypedReceiver<T> {
+    receiver: Receiver,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedReceiver<T> {
+    /// Opens a queue for receiving values of type `T`. See [`Receiver::open`].
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Receiver::open`], this function returns an
+    /// error of kind [`io::ErrorKind::InvalidInput`] if the queue holds
+    /// messages of another type.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<TypedReceiver<T>> {
+        TypedReceiver::new(Receiver::open(base)?)
+    }
+
+    /// Makes a receiver of values of type `T` out of a receiver, e.g., one
+    /// opened with a [`crate::ReceiverBuilder`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidInput`]
+    /// if the queue holds messages of another type.
+    pub fn new(receiver: Receiver) -> io::Result<TypedReceiver<T>> {
+        check_message_type::<T>(receiver.base())?;
+
+        Ok(TypedReceiver {
+            receiver,
+            _type: PhantomData,
+        })
+    }
+
+    /// Retrieves a value from the queue. See [`Receiver::recv`].
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Receiver::recv`], this function returns an
+    /// error of kind [`io::ErrorKind::InvalidData`] if the element cannot be
+    /// deserialized. The element is then rolled back: use
+    /// [`TypedReceiver::get_mut`] to get rid of it.
+    pub async fn recv(&mut self) -> io::Result<TypedRecvGuard<'_, T>> {
+        TypedRecvGuard::new(self.receiver.recv().await?)
+    }
+
+    /// Tries to retrieve a value from the queue, without waiting. See
+    /// [`Receiver::try_recv`] and [`TypedReceiver::recv`].
+    pub fn try_recv(&mut self) -> Result<TypedRecvGuard<'_, T>, TryRecvError> {
+        Ok(TypedRecvGuard::new(self.receiver.try_recv()?)?)
+    }
+
+    /// The underlying receiver, e.g., to skip an element that cannot be
+    /// deserialized.
+    pub fn get_mut(&mut self) -> &mut Receiver {
+        &mut self.receiver
+    }
+
+    /// Unwraps the underlying receiver.
+    pub fn into_inner(self) -> Receiver {
+        self.receiver
+    }
+}
+
+/// A value received with a [`TypedReceiver`], along with the [`RecvGuard`]
+/// of the element it was deserialized from. As with the guard, the element is
+/// rolled back unless the value is committed.
+pub struct TypedRecvGuard<'a, T> {
+    guard: RecvGuard<'a, Vec<u8>>,
+    value: T,
+}
+
+impl<'a, T: DeserializeOwned> TypedRecvGuard<'a, T> {
+    fn new(guard: RecvGuard<'a, Vec<u8>>) -> io::Result<TypedRecvGuard<'a, T>> {
+        let value = guard.deserialize()?;
+        Ok(TypedRecvGuard { guard, value })
+    }
+}
+
+impl<'a, T> TypedRecvGuard<'a, T> {
+    /// Commits the transaction and returns the value. See
+    /// [`RecvGuard::try_into_inner`].
+    pub fn try_into_inner(self) -> io::Result<T> {
+        self.guard.commit()?;
+        Ok(self.value)
+    }
+
+    /// Commits the changes to the queue. See [`RecvGuard::commit`].
+    pub fn commit(self) -> io::Result<()> {
+        self.guard.commit()
+    }
+
+    /// Rolls the changes to the queue back. See [`RecvGuard::rollback`].
+    pub fn rollback(self) -> io::Result<()> {
+        self.guard.rollback()
+    }
+}
+
+impl<'a, T> Deref for TypedRecvGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
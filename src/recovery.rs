@@ -6,8 +6,8 @@
 //! different use cases:
 //!
 //! 1. Recover with replay (the standard): we can reconstruct a _lower bound_
-//! of the actual state of the queue during the crash, which consists of the
-//! _maximum_ of the following two positions:
+//!    of the actual state of the queue during the crash, which consists of the
+//!    _maximum_ of the following two positions:
 //!     * the bottom of the smallest segment still present in the directory.
 //!     * the position indicated in the metadata file.
 //!
@@ -16,28 +16,48 @@
 //! whatsoever.
 //!
 //! 2. Recover with loss: we can also reconstruct an _upper bound_ for the
-//! actual state of the queue: the bottom of the second smallest segment in
-//! the queue. In this case, the smallest segment is simply erased and the
-//! receiver caries on as if nothing has happened. If replays are intollerable,
-//! but some data loss is, this might be the right alternative for you. You can
-//! limit data loss by constraining the segment size, configuring this option on
-//! [`crate::SenderBuilder`].
+//!    actual state of the queue: the bottom of the second smallest segment in
+//!    the queue. In this case, the smallest segment is simply erased and the
+//!    receiver caries on as if nothing has happened. If replays are intollerable,
+//!    but some data loss is, this might be the right alternative for you. You can
+//!    limit data loss by constraining the segment size, configuring this option on
+//!    [`crate::SenderBuilder`].
+//!
+//! To find out whether a queue needs recovery in the first place, see
+//! [`verify_queue`].
 
 use std::fs::*;
-use std::io;
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use sysinfo::*;
 
-use super::queue::{recv_lock_filename, send_lock_filename};
+use super::error::{MalformedLock, TrySendError};
+use super::header::Header;
+use super::metadata::{Metadata, HEADER_METADATA};
+use super::queue::{
+    list_segments, recv_lock_filename, send_lock_filename, verify_segment, Sender, HEADER_EOF,
+};
+use super::sharing;
 use super::state::{QueueState, QueueStatePersistence};
-use super::sync::{FileGuard, UNIQUE_PROCESS_TOKEN};
+use super::sync::{FileGuard, OS_LOCK_MARKER, UNIQUE_PROCESS_TOKEN};
 
 /// Unlocks a `.lock` file if the owning process does not exist anymore. This
 /// function does nothing if the file does not exist.
 ///
-/// # Panics
+/// The owner is alive if it still holds its OS lock on the file, which the OS
+/// releases when the process dies. Lock files written by older versions of
+/// `yaque` (or on filesystems without OS locks) are checked by the PID of the
+/// owner instead. Since PIDs are recycled, the start time of the owner recorded
+/// in the file is also checked, so that a new process with the same PID is not
+/// mistaken for the owner.
+///
+/// # Errors
 ///
-/// This function panics if it cannot parse the lockfile.
+/// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+/// wrapping a [`MalformedLock`] if it cannot parse the lock file, so that you
+/// can decide what to do with it (e.g., [`force_unlock`] it).
 pub fn unlock<P: AsRef<Path>>(lock_filename: P) -> io::Result<()> {
     let contents = match read_to_string(&lock_filename) {
         Ok(contents) => contents,
@@ -45,84 +65,180 @@ pub fn unlock<P: AsRef<Path>>(lock_filename: P) -> io::Result<()> {
         Err(err) => return Err(err),
     };
 
-    let owner_pid = contents
-        .split("pid=")
-        .collect::<Vec<_>>()
-        .get(1)
-        .map(|token| {
-            token
-                .chars()
-                .take_while(|ch| ch.is_digit(10))
-                .collect::<String>()
-                .parse::<sysinfo::Pid>()
-        })
-        .expect("failed to parse recv lock file: no pid")
-        .expect("failed to parse recv lock file: bad pid");
+    let malformed = |field| -> io::Error {
+        MalformedLock {
+            path: lock_filename.as_ref().to_owned(),
+            field,
+        }
+        .into()
+    };
 
-    let owner_token = contents
-        .split("token=")
-        .collect::<Vec<_>>()
-        .get(1)
-        .map(|token| {
-            token
-                .chars()
-                .take_while(|ch| ch.is_digit(10))
-                .collect::<String>()
-                .parse::<u64>()
-        })
-        .expect("failed to parse recv lock file: no token")
-        .expect("failed to parse recv lock file: bad token");
+    let owner_pid = lock_field::<sysinfo::Pid>(&contents, "pid")
+        .flatten()
+        .ok_or_else(|| malformed("pid"))?;
+    let owner_token = lock_field::<u64>(&contents, "token")
+        .flatten()
+        .ok_or_else(|| malformed("token"))?;
 
-    let system = System::new_with_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::new()));
+    // Locks written by older versions or without the `recovery` feature have
+    // no start time:
+    let owner_start_time = lock_field::<u64>(&contents, "start")
+        .map(|start_time| start_time.ok_or_else(|| malformed("start time")))
+        .transpose()?;
+
+    let owner_is_alive = if contents.lines().any(|line| line == OS_LOCK_MARKER) {
+        is_os_locked(lock_filename.as_ref())?
+    } else {
+        let system = System::new_with_specifics(
+            RefreshKind::new().with_processes(ProcessRefreshKind::new()),
+        );
+
+        // The owner may be dead and its PID recycled (start times are in
+        // seconds, so allow for some rounding):
+        system
+            .process(owner_pid)
+            .map(|process| {
+                owner_start_time
+                    .map(|start_time| process.start_time().abs_diff(start_time) <= 1)
+                    .unwrap_or(true)
+            })
+            .unwrap_or(false)
+    };
 
     // Maybe somebody else is holding the lock:
-    let process_exists_and_is_not_me =
-        owner_pid.as_u32() != std::process::id() && system.process(owner_pid).is_some();
+    let process_exists_and_is_not_me = owner_pid.as_u32() != std::process::id() && owner_is_alive;
     // I am holding the lock:
     let lock_is_the_same_and_is_me =
         owner_pid.as_u32() == std::process::id() && owner_token == *UNIQUE_PROCESS_TOKEN;
 
     if process_exists_and_is_not_me {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
+        Err(io::Error::other(
             format!(
                 "another process, of id {}, is still locking `{:?}`",
                 owner_pid,
                 lock_filename.as_ref()
             ),
-        ));
+        ))
     } else if lock_is_the_same_and_is_me {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
+        Err(io::Error::other(
             format!(
                 "current process is still locking `{:?}`",
                 lock_filename.as_ref()
             ),
-        ));
+        ))
     } else {
-        remove_file(lock_filename)?;
+        sharing::remove_file(lock_filename)?;
         Ok(())
     }
 }
 
-/// Unlocks a queue in a given directory for sending. This function returns an
-/// error of kind [`io::ErrorKind::Other`] when the process listed in the
-/// lockfile still exists.
+/// Parses a numeric field of a lock file. Returns `None` if the field is not
+/// there and `Some(None)` if it cannot be parsed.
+fn lock_field<T: FromStr>(contents: &str, name: &str) -> Option<Option<T>> {
+    contents
+        .split(&format!("{}=", name))
+        .nth(1)
+        .map(|token| {
+            token
+                .chars()
+                .take_while(|ch| ch.is_ascii_digit())
+                .collect::<String>()
+                .parse::<T>()
+                .ok()
+        })
+}
+
+/// Whether some process still holds an OS lock on a file.
+fn is_os_locked(path: &Path) -> io::Result<bool> {
+    match sharing::open(path)?.try_lock() {
+        Ok(()) => Ok(false),
+        Err(TryLockError::WouldBlock) => Ok(true),
+        Err(TryLockError::Error(err)) => Err(err),
+    }
+}
+
+/// An explicit override of the liveness check of a lock, for
+/// [`force_unlock`]. It records why the lock was forced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForceToken {
+    reason: String,
+}
+
+impl ForceToken {
+    /// Creates a token to force a lock, giving the reason why.
+    pub fn new<R: Into<String>>(reason: R) -> ForceToken {
+        ForceToken {
+            reason: reason.into(),
+        }
+    }
+}
+
+/// The name of the file recording the history of a lock file: the same name,
+/// suffixed by `.history`.
+fn history_filename(lock_filename: &Path) -> PathBuf {
+    let mut filename = lock_filename.as_os_str().to_owned();
+    filename.push(".history");
+    PathBuf::from(filename)
+}
+
+/// Unlocks a `.lock` file no matter whether the owning process is alive or
+/// not, e.g., when you know that the owner is a zombie on another host. This
+/// function does nothing if the file does not exist.
 ///
-/// # Panics
+/// For auditability, a line is appended to the history of the lock (a file
+/// with the same name, suffixed by `.history`) telling when the lock was
+/// forced, by which process, why and what was in the lock file.
 ///
-/// This function panics if it cannot parse the lockfile.
+/// Be careful! If the owner is actually alive, two processes will think they
+/// hold the lock, which will corrupt the queue.
+pub fn force_unlock<P: AsRef<Path>>(lock_filename: P, token: ForceToken) -> io::Result<()> {
+    let contents = match read_to_string(&lock_filename) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    let forced_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut history = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_filename(lock_filename.as_ref()))?;
+    writeln!(
+        history,
+        "forced_at={} pid={} token={} reason={:?} owner={:?}",
+        forced_at,
+        std::process::id(),
+        *UNIQUE_PROCESS_TOKEN,
+        token.reason,
+        contents.trim_end()
+    )?;
+    history.flush()?;
+
+    log::warn!(
+        "forcing `{:?}` to unlock: {}",
+        lock_filename.as_ref(),
+        token.reason
+    );
+
+    match remove_file(lock_filename) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Unlocks a queue in a given directory for sending. This function returns an
+/// error of kind [`io::ErrorKind::Other`] when the process listed in the
+/// lockfile still exists. See [`unlock`].
 pub fn unlock_for_sending<P: AsRef<Path>>(base: P) -> io::Result<()> {
     unlock(send_lock_filename(base.as_ref()))
 }
 
 /// Unlocks a queue in a given directory for receiving. This function returns
 /// an error of kind [`io::ErrorKind::Other`] when the process listed in the
-/// lockfile still exists.
-///
-/// # Panics
-///
-/// This function panics if it cannot parse the lockfile.
+/// lockfile still exists. See [`unlock`].
 pub fn unlock_for_receiving<P: AsRef<Path>>(base: P) -> io::Result<()> {
     unlock(recv_lock_filename(base.as_ref()))
 }
@@ -130,10 +246,16 @@ pub fn unlock_for_receiving<P: AsRef<Path>>(base: P) -> io::Result<()> {
 /// Unlocks a queue in a given directory for both sending and receiving. This
 /// function is the combination of [`unlock_for_sending`] and
 /// [`unlock_for_receiving`].
-///
-/// # Panics
-///
-/// This function panics if it cannot parse either of the lock files.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "unlock_queue",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
 pub fn unlock_queue<P: AsRef<Path>>(base: P) -> io::Result<()> {
     unlock_for_sending(base.as_ref())?;
     unlock_for_receiving(base.as_ref())?;
@@ -150,21 +272,32 @@ pub fn unlock_queue<P: AsRef<Path>>(base: P) -> io::Result<()> {
 /// It is important to note two things:
 /// 1. The data in the current segment will be lost.
 /// 2. You don't need to use this function when replays are acceptable, since
-/// the existing metadata file is already a lower bound of the actual state of
-/// the queue.
+///    the existing metadata file is already a lower bound of the actual state of
+///    the queue.
 ///
 /// You should *not* use this function if you suppose that your data was corrupted.
+/// If the receiver metadata was lost, see [`guess_recv_metadata_with_strategy`].
 ///
 /// # Panics
 ///
 /// This function panics if there is a file in the queue folder with extension
 /// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "guess_recv_metadata",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
 pub fn guess_recv_metadata<P: AsRef<Path>>(base: P) -> io::Result<()> {
     // Lock for receiving:
     let lock = FileGuard::try_lock(recv_lock_filename(base.as_ref()))?;
 
     // Find smallest segment:
-    let mut min_segment = std::u64::MAX;
+    let mut min_segment = u64::MAX;
     for maybe_entry in read_dir(base.as_ref())? {
         let path = maybe_entry?.path();
         if path.extension().map(|ext| ext == "q").unwrap_or(false) {
@@ -208,8 +341,8 @@ pub fn guess_recv_metadata<P: AsRef<Path>>(base: P) -> io::Result<()> {
 /// It is important to note two things:
 /// 1. The data in the current segment will be lost.
 /// 2. You don't need to use this function when replays are acceptable, since
-/// the existing metadata file is already a lower bound of the actual state of
-/// the queue.
+///    the existing metadata file is already a lower bound of the actual state of
+///    the queue.
 ///
 /// You should use this function if you suppose that your data was corrupted.
 ///
@@ -217,12 +350,22 @@ pub fn guess_recv_metadata<P: AsRef<Path>>(base: P) -> io::Result<()> {
 ///
 /// This function panics if there is a file in the queue folder with extension
 /// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "guess_recv_metadata_with_loss",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
 pub fn guess_recv_metadata_with_loss<P: AsRef<Path>>(base: P) -> io::Result<()> {
     // Lock for receiving:
     let lock = FileGuard::try_lock(recv_lock_filename(base.as_ref()))?;
 
     // Find smallest segment:
-    let mut min_segment = std::u64::MAX;
+    let mut min_segment = u64::MAX;
     for maybe_entry in read_dir(base.as_ref())? {
         let path = maybe_entry?.path();
         if path.extension().map(|ext| ext == "q").unwrap_or(false) {
@@ -259,12 +402,235 @@ pub fn guess_recv_metadata_with_loss<P: AsRef<Path>>(base: P) -> io::Result<()>
     Ok(())
 }
 
+/// Truncates the newest segment of a queue at the end of its last valid
+/// element. This removes what is left of an element that was being written
+/// when the sender crashed (e.g., on a power loss), which would otherwise make
+/// the receiver fail forever. Since the senders find their position from
+/// the length of the newest segment, this also fixes their state. If the state
+/// of the receiver points past the cut, it is moved back to it. Returns the
+/// number of bytes removed.
+///
+/// This function does nothing if the newest segment is fine. It returns an
+/// error of kind [`io::ErrorKind::Other`] if the queue is in use either for
+/// sending or receiving.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "truncate_corrupt_tail",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
+pub fn truncate_corrupt_tail<P: AsRef<Path>>(base: P) -> io::Result<u64> {
+    let in_use = || io::Error::other(format!("queue `{:?}` is in use", base.as_ref()));
+    let send_lock = FileGuard::try_lock(send_lock_filename(base.as_ref()))?.ok_or_else(in_use)?;
+    let recv_lock = FileGuard::try_lock(recv_lock_filename(base.as_ref()))?.ok_or_else(in_use)?;
+
+    // The newest segment is the one being written to:
+    let state = QueueState::for_send_metadata(base.as_ref())?;
+    let filename = base.as_ref().join(format!("{}.q", state.segment));
+    let contents = match read(&filename) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let walked = walk_segment(state.segment, &contents, &mut QueueReport::default());
+    if !walked.is_broken {
+        return Ok(0);
+    }
+
+    // The start of the broken element:
+    let cut = *walked.starts.last().expect("broken element has a start");
+    OpenOptions::new()
+        .write(true)
+        .open(&filename)?
+        .set_len(cut)?;
+
+    let mut persistence = QueueStatePersistence::new();
+    let recv_state = persistence.open(base.as_ref())?;
+    if recv_state.segment == state.segment && recv_state.position > cut {
+        persistence.save(&QueueState {
+            segment: state.segment,
+            position: cut,
+        })?;
+    }
+
+    drop((send_lock, recv_lock));
+
+    Ok(contents.len() as u64 - cut)
+}
+
+/// Where to put the receiver when its state is lost. See
+/// [`guess_recv_metadata_with_strategy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvStrategy {
+    /// Restart from the bottom of the oldest segment present in the directory.
+    /// Elements may be replayed, but none is lost.
+    Oldest,
+    /// Jump to where the senders are, dropping the backlog. Nothing is
+    /// replayed, but all the elements that were not received yet are lost.
+    SendPosition,
+}
+
+/// Guesses the receive metadata for a given queue using a given strategy,
+/// ignoring the existing receiver metadata. Use this function when the
+/// metadata file was lost or cannot be trusted. With
+/// [`RecvStrategy::SendPosition`], the segments before the position of the
+/// senders are removed.
+///
+/// This function returns an error of kind [`io::ErrorKind::Other`] if the
+/// queue is in use for receiving.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "guess_recv_metadata_with_strategy",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
+pub fn guess_recv_metadata_with_strategy<P: AsRef<Path>>(
+    base: P,
+    strategy: RecvStrategy,
+) -> io::Result<()> {
+    // Lock for receiving:
+    let lock = FileGuard::try_lock(recv_lock_filename(base.as_ref()))?
+        .ok_or_else(|| io::Error::other(format!("queue `{:?}` is in use", base.as_ref())))?;
+
+    // Generate new queue state:
+    let queue_state = match strategy {
+        RecvStrategy::Oldest => QueueState {
+            segment: list_segments(base.as_ref())?.first().copied().unwrap_or(0),
+            ..QueueState::default()
+        },
+        RecvStrategy::SendPosition => {
+            let send_state = QueueState::for_send_metadata(base.as_ref())?;
+
+            // Drop the backlog:
+            for segment in list_segments(base.as_ref())? {
+                if segment < send_state.segment {
+                    remove_file(base.as_ref().join(format!("{}.q", segment)))?;
+                }
+            }
+
+            send_state
+        }
+    };
+
+    // And save:
+    let mut persistence = QueueStatePersistence::new();
+    let _ = persistence.open(base.as_ref())?;
+    persistence.save(&queue_state)?;
+
+    // Drop lock for receiving:
+    drop(lock);
+
+    Ok(())
+}
+
+/// The result of [`salvage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SalvageReport {
+    /// The number of elements copied to the new queue.
+    pub items: u64,
+    /// The number of bytes that could not be salvaged.
+    pub lost_bytes: u64,
+}
+
+/// Salvages what can still be read from a damaged queue at `src`, sending it
+/// into a fresh queue at `dst`. Use this as a last resort, e.g., after disk
+/// errors. Every element that can be decoded (and that matches its checksum,
+/// if it has one) is sent with its metadata, from the state saved by the
+/// receiver on. The rest of a segment after a corrupted header cannot be read
+/// and is lost. The queue at `src` is left as it was.
+///
+/// Since the receiver saves its state from time to time, some of the salvaged
+/// elements may have already been received. Priority lanes are queues of their
+/// own and are not salvaged.
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while reading the
+/// segments or while sending into the new queue, including when the queue at
+/// `dst` is in use for sending.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "salvage",
+        level = "info",
+        skip_all,
+        fields(src = ?src.as_ref(), dst = ?dst.as_ref()),
+        err
+    )
+)]
+pub fn salvage<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<SalvageReport> {
+    let recv_state = QueueStatePersistence::new().open(src.as_ref())?;
+    let mut sender = Sender::open(dst.as_ref())?;
+    let mut report = SalvageReport::default();
+
+    for segment in list_segments(src.as_ref())? {
+        if segment < recv_state.segment {
+            continue;
+        }
+
+        let contents = read(src.as_ref().join(format!("{}.q", segment)))?;
+        let mut position = 0;
+
+        while position < contents.len() && !contents[position..].starts_with(&HEADER_EOF) {
+            match read_element(&contents, position) {
+                Ok((len, Ok(metadata), data)) if metadata.matches_checksum(data) => {
+                    let was_received =
+                        segment == recv_state.segment && (position as u64) < recv_state.position;
+
+                    if !was_received {
+                        sender
+                            .try_send_with_metadata(&metadata, data)
+                            .map_err(TrySendError::unwrap_io)?;
+                        report.items += 1;
+                    }
+
+                    position += len;
+                }
+                Ok((len, _, _)) => {
+                    report.lost_bytes += len as u64;
+                    position += len;
+                }
+                Err(_) => {
+                    report.lost_bytes += (contents.len() - position) as u64;
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
 /// Recovers a queue using the "with replay" strategy.
 ///
 /// It applies the following operations, in this order:
 /// * Unlocks both the sender and receiver side of the queue.
 /// * Guesses the position of the receiver using [`guess_recv_metadata`] (this
-/// is just the existing state of the receiver most of the time).
+///   is just the existing state of the receiver most of the time).
 ///
 /// This means that some of the data may be replayed, since the receiver
 /// metadata is rarely touched (and it is *always* a lower bound of where the
@@ -277,8 +643,17 @@ pub fn guess_recv_metadata_with_loss<P: AsRef<Path>>(base: P) -> io::Result<()>
 /// # Panics
 ///
 /// This function panics if there is a file in the queue folder with extension
-/// `.q` whose name is not an integer, such as `foo.q` or if the lockfiles for
-/// either sending or receiving cannot be parsed.
+/// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "recover",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
 pub fn recover<P: AsRef<Path>>(base: P) -> io::Result<()> {
     unlock_queue(base.as_ref())?;
     guess_recv_metadata(base.as_ref())?;
@@ -291,7 +666,7 @@ pub fn recover<P: AsRef<Path>>(base: P) -> io::Result<()> {
 /// It applies the following operations, in this order:
 /// * Unlocks both the sender and receiver side of the queue.
 /// * Guesses the position of the receiver using [`guess_recv_metadata_with_loss`]
-/// (this truncates the bottom segment of the queue, resulting in data loss).
+///   (this truncates the bottom segment of the queue, resulting in data loss).
 ///
 /// This means that some of the data may be lost, since the bottom segment is
 /// erased. If data loss is not acceptable, see [`recover`].
@@ -301,8 +676,17 @@ pub fn recover<P: AsRef<Path>>(base: P) -> io::Result<()> {
 /// # Panics
 ///
 /// This function panics if there is a file in the queue folder with extension
-/// `.q` whose name is not an integer, such as `foo.q` or if the lockfiles for
-/// either sending or receiving cannot be parsed.
+/// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "recover_with_loss",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
 pub fn recover_with_loss<P: AsRef<Path>>(base: P) -> io::Result<()> {
     unlock_queue(base.as_ref())?;
     // this has to be first because it messes with the directory structure, invalidating a possible
@@ -312,6 +696,262 @@ pub fn recover_with_loss<P: AsRef<Path>>(base: P) -> io::Result<()> {
     Ok(())
 }
 
+/// A problem found in a queue by [`verify_queue`]. Positions are in bytes from
+/// the start of the segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// A segment is missing between two existing segments.
+    MissingSegment { segment: u64 },
+    /// The header of an element is corrupted. The rest of the segment cannot
+    /// be read.
+    BadHeader { segment: u64, position: u64 },
+    /// The metadata of an element is malformed.
+    BadMetadata { segment: u64, position: u64 },
+    /// An element does not match its checksum. See
+    /// [`crate::SenderBuilder::checksum`].
+    BadChecksum { segment: u64, position: u64 },
+    /// The segment ends in the middle of an element. This is expected in the
+    /// last segment while a sender is writing to it, but it otherwise means
+    /// that a sender crashed in the middle of a write.
+    Truncated { segment: u64, position: u64 },
+    /// A segment which is not the last one does not end with an EOF header.
+    MissingEof { segment: u64 },
+    /// The footer of a segment does not match the segment. See
+    /// [`crate::queue::SegmentFooter`].
+    BadFooter { segment: u64 },
+    /// The state saved by the receiver does not point to the start of an
+    /// element or is ahead of what the senders have written.
+    BadRecvState { segment: u64, position: u64 },
+}
+
+/// The result of [`verify_queue`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct QueueReport {
+    /// The number of segments in the queue folder.
+    pub segments: u64,
+    /// The number of elements in the segments, including the ones that were
+    /// already received, but whose segments were not removed yet.
+    pub items: u64,
+    /// The problems found, in the order of the queue.
+    pub problems: Vec<Problem>,
+}
+
+impl QueueReport {
+    /// Whether no problems were found.
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Verifies a queue in a given directory, reading all its segments: the
+/// headers, the metadata and the checksums of the elements, and the footers of
+/// the segments. The state saved by the receiver is also checked against the
+/// segments and the state of the senders.
+///
+/// This function only reads the queue and does not lock it. If the queue is
+/// in use, problems may be reported on the parts that are being written to.
+/// Priority lanes are queues of their own and are not verified.
+///
+/// # Errors
+///
+/// This function returns an error if the queue folder cannot be read. Problems
+/// with the contents of the queue are reported in the [`QueueReport`].
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        name = "verify_queue",
+        level = "info",
+        skip_all,
+        fields(base = ?base.as_ref()),
+        err
+    )
+)]
+pub fn verify_queue<P: AsRef<Path>>(base: P) -> io::Result<QueueReport> {
+    let mut report = QueueReport::default();
+
+    let segments = list_segments(base.as_ref())?;
+    report.segments = segments.len() as u64;
+
+    let recv_state = QueueStatePersistence::new().open(base.as_ref())?;
+    let send_state = QueueState::for_send_metadata(base.as_ref())?;
+    let mut recv_state_is_valid = segments
+        .first()
+        .map(|&first| recv_state.segment < first)
+        .unwrap_or(true);
+
+    for (i, &segment) in segments.iter().enumerate() {
+        let is_last = i + 1 == segments.len();
+
+        if let Some(&next) = segments.get(i + 1) {
+            for missing in segment + 1..next {
+                report
+                    .problems
+                    .push(Problem::MissingSegment { segment: missing });
+            }
+        }
+
+        let contents = read(base.as_ref().join(format!("{}.q", segment)))?;
+        let walked = walk_segment(segment, &contents, &mut report);
+
+        if recv_state.segment == segment {
+            recv_state_is_valid = walked.starts.contains(&recv_state.position);
+        }
+
+        match walked.eof {
+            Some(eof) => match verify_segment(base.as_ref(), segment) {
+                Ok(Some(footer)) if footer.items == walked.items => {}
+                // Segments closed by older versions have no footer:
+                Ok(None) if eof + 4 == contents.len() as u64 => {}
+                _ => report.problems.push(Problem::BadFooter { segment }),
+            },
+            None if !is_last && !walked.is_broken => {
+                report.problems.push(Problem::MissingEof { segment })
+            }
+            None => {}
+        }
+    }
+
+    if !recv_state_is_valid || recv_state > send_state {
+        report.problems.push(Problem::BadRecvState {
+            segment: recv_state.segment,
+            position: recv_state.position,
+        });
+    }
+
+    Ok(report)
+}
+
+/// What was found walking the elements of a segment.
+#[derive(Default)]
+struct WalkedSegment {
+    /// The number of elements in the segment.
+    items: u64,
+    /// The positions where a receiver may be: the start of each element and
+    /// the end of the elements. If the walk was cut short, the last one is the
+    /// start of the broken element.
+    starts: Vec<u64>,
+    /// The position of the EOF header, if any.
+    eof: Option<u64>,
+    /// Whether the walk was cut short by a problem.
+    is_broken: bool,
+}
+
+/// Walks the elements of a segment in memory, reporting the problems found.
+fn walk_segment(segment: u64, contents: &[u8], report: &mut QueueReport) -> WalkedSegment {
+    let mut walked = WalkedSegment::default();
+    let mut position = 0;
+
+    while position < contents.len() {
+        if contents[position..].starts_with(&HEADER_EOF) {
+            walked.eof = Some(position as u64);
+            break;
+        }
+
+        walked.starts.push(position as u64);
+        let at = position as u64;
+
+        match read_element(contents, position) {
+            Ok((len, metadata, data)) => {
+                match metadata {
+                    Ok(metadata) if !metadata.matches_checksum(data) => {
+                        report.problems.push(Problem::BadChecksum {
+                            segment,
+                            position: at,
+                        })
+                    }
+                    Ok(_) => {}
+                    Err(_) => report.problems.push(Problem::BadMetadata {
+                        segment,
+                        position: at,
+                    }),
+                }
+
+                position += len;
+                walked.items += 1;
+            }
+            Err(BrokenElement::Header) => {
+                report.problems.push(Problem::BadHeader {
+                    segment,
+                    position: at,
+                });
+                walked.is_broken = true;
+                break;
+            }
+            Err(BrokenElement::Truncated) => {
+                report.problems.push(Problem::Truncated {
+                    segment,
+                    position: at,
+                });
+                walked.is_broken = true;
+                break;
+            }
+        }
+    }
+
+    // The receiver may also be done with all elements:
+    if !walked.is_broken {
+        walked.starts.push(position as u64);
+    }
+
+    report.items += walked.items;
+
+    walked
+}
+
+/// Why an element could not be read.
+enum BrokenElement {
+    /// One of its headers is corrupted.
+    Header,
+    /// The segment ends before the element does.
+    Truncated,
+}
+
+/// Reads an element from a segment in memory, returning the number of bytes it
+/// takes, its metadata and its payload.
+fn read_element(
+    contents: &[u8],
+    mut position: usize,
+) -> Result<(usize, io::Result<Metadata>, &[u8]), BrokenElement> {
+    let start = position;
+    let take = |position: usize, len: usize| {
+        contents
+            .get(position..position + len)
+            .ok_or(BrokenElement::Truncated)
+    };
+    let take_header = |position: usize| {
+        let mut header = [0; 4];
+        header.copy_from_slice(take(position, 4)?);
+        Ok(header)
+    };
+    let decode = |header: [u8; 4]| {
+        Header::try_decode(header)
+            .map(|header| header.len() as usize)
+            .ok_or(BrokenElement::Header)
+    };
+
+    let mut header = take_header(position)?;
+    let mut metadata = Ok(Metadata::default());
+    position += 4;
+
+    if header == HEADER_METADATA {
+        let metadata_len = decode(take_header(position)?)?;
+        metadata = Metadata::decode(take(position + 4, metadata_len)?);
+        position += 4 + metadata_len;
+        header = take_header(position)?;
+        position += 4;
+    }
+
+    let len = decode(header)?;
+    let data = take(position, len)?;
+
+    Ok((position + len - start, metadata, data))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -334,6 +974,89 @@ mod tests {
         remove_file("data/test-unlock.lock").unwrap();
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_unlock_recycled_pid() {
+        // The parent process is certainly alive:
+        let parent = std::os::unix::process::parent_id();
+        let start_time = crate::sync::process_start_time(parent).unwrap();
+
+        write(
+            "data/test-unlock-recycled-pid.lock",
+            format!("pid={}\ntoken=0\nstart={}\n", parent, start_time),
+        )
+        .unwrap();
+        assert!(unlock("data/test-unlock-recycled-pid.lock").is_err());
+
+        // A process with the same PID, but which started at another time:
+        write(
+            "data/test-unlock-recycled-pid.lock",
+            format!("pid={}\ntoken=0\nstart={}\n", parent, start_time - 3600),
+        )
+        .unwrap();
+        unlock("data/test-unlock-recycled-pid.lock").unwrap();
+        assert!(!Path::new("data/test-unlock-recycled-pid.lock").exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unlock_os_lock() {
+        // The parent process is certainly alive...
+        let contents = format!(
+            "pid={}\ntoken=0\n{}\n",
+            std::os::unix::process::parent_id(),
+            OS_LOCK_MARKER
+        );
+        write("data/test-unlock-os-lock.lock", &contents).unwrap();
+
+        // ... but it only holds the lock while it holds the OS lock:
+        let file = File::open("data/test-unlock-os-lock.lock").unwrap();
+        file.lock_shared().unwrap();
+        assert!(unlock("data/test-unlock-os-lock.lock").is_err());
+
+        drop(file);
+        unlock("data/test-unlock-os-lock.lock").unwrap();
+        assert!(!Path::new("data/test-unlock-os-lock.lock").exists());
+    }
+
+    #[test]
+    fn test_force_unlock() {
+        let guard = FileGuard::try_lock("data/test-force-unlock.lock").unwrap();
+        std::mem::forget(guard);
+
+        assert!(unlock("data/test-force-unlock.lock").is_err());
+        force_unlock(
+            "data/test-force-unlock.lock",
+            ForceToken::new("owner is a zombie"),
+        )
+        .unwrap();
+        assert!(!Path::new("data/test-force-unlock.lock").exists());
+
+        let history = read_to_string("data/test-force-unlock.lock.history").unwrap();
+        assert_eq!(history.lines().count(), 1);
+        assert!(history.contains(r#"reason="owner is a zombie""#));
+        assert!(history.contains(&format!("pid={}", std::process::id())));
+
+        // Nothing to force:
+        force_unlock("data/test-force-unlock.lock", ForceToken::new("again")).unwrap();
+    }
+
+    #[test]
+    fn test_unlock_malformed() {
+        write("data/test-unlock-malformed.lock", "pid=1\ntoken=").unwrap();
+
+        let err = unlock("data/test-unlock-malformed.lock").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let malformed = err
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<MalformedLock>())
+            .expect("not a malformed lock");
+        assert_eq!(malformed.field, "token");
+
+        // The lock file is left for the caller to deal with:
+        assert!(Path::new("data/test-unlock-malformed.lock").exists());
+    }
+
     #[test]
     fn test_unlock_inexistent() {
         unlock("data/inexistent-lock.lock").unwrap();
@@ -362,4 +1085,180 @@ mod tests {
     fn test_recover_inexistent() {
         recover("data/recover-inexistent").unwrap();
     }
+
+    #[test]
+    fn test_truncate_corrupt_tail() {
+        let mut sender = crate::Sender::open("data/truncate-corrupt-tail").unwrap();
+        sender.try_send(b"hello").unwrap();
+        sender.try_send(b"world").unwrap();
+
+        // A power loss in the middle of a write:
+        let filename = "data/truncate-corrupt-tail/0.q";
+        let mut segment = read(filename).unwrap();
+        segment.extend_from_slice(&Header::new(10).encode());
+        segment.extend_from_slice(b"012");
+        write(filename, &segment).unwrap();
+        drop(sender);
+
+        // Cannot be truncated while in use:
+        let receiver = crate::Receiver::open("data/truncate-corrupt-tail").unwrap();
+        assert!(truncate_corrupt_tail("data/truncate-corrupt-tail").is_err());
+        drop(receiver);
+
+        assert_eq!(
+            truncate_corrupt_tail("data/truncate-corrupt-tail").unwrap(),
+            7
+        );
+        assert_eq!(
+            truncate_corrupt_tail("data/truncate-corrupt-tail").unwrap(),
+            0
+        );
+
+        let (mut sender, mut receiver) = crate::channel("data/truncate-corrupt-tail").unwrap();
+        sender.try_send(b"again").unwrap();
+
+        let batch = receiver.try_recv_batch(3).ok().unwrap();
+        assert_eq!(
+            &*batch,
+            &[b"hello".to_vec(), b"world".to_vec(), b"again".to_vec()]
+        );
+        batch.commit().unwrap();
+    }
+
+    #[test]
+    fn test_guess_recv_metadata_with_strategy() {
+        // One element per segment:
+        let mut sender = crate::SenderBuilder::new()
+            .segment_size(1)
+            .open("data/guess-recv-metadata")
+            .unwrap();
+        for i in 0..4u8 {
+            sender.try_send([i]).unwrap();
+        }
+
+        let mut receiver = crate::Receiver::open("data/guess-recv-metadata").unwrap();
+        receiver.try_recv_batch(2).ok().unwrap().commit().unwrap();
+        drop(receiver);
+
+        // The state of the receiver is lost (the first segment is gone):
+        remove_file("data/guess-recv-metadata/recv-metadata").ok();
+        guess_recv_metadata_with_strategy("data/guess-recv-metadata", RecvStrategy::Oldest)
+            .unwrap();
+
+        let mut receiver = crate::Receiver::open("data/guess-recv-metadata").unwrap();
+        assert_eq!(&*receiver.try_recv().ok().unwrap(), &[1]);
+        drop(receiver);
+
+        guess_recv_metadata_with_strategy("data/guess-recv-metadata", RecvStrategy::SendPosition)
+            .unwrap();
+        assert!(!Path::new("data/guess-recv-metadata/2.q").exists());
+
+        let mut receiver = crate::Receiver::open("data/guess-recv-metadata").unwrap();
+        assert!(receiver.try_recv().is_err());
+        sender.try_send([4]).unwrap();
+        assert_eq!(&*receiver.try_recv().ok().unwrap(), &[4]);
+    }
+
+    #[test]
+    fn test_salvage() {
+        // Each element takes 29 bytes with its checksum:
+        let mut sender = crate::SenderBuilder::new()
+            .segment_size(64)
+            .checksum(true)
+            .open("data/salvage-src")
+            .unwrap();
+        for i in 0..6u8 {
+            sender.try_send([i; 10]).unwrap();
+        }
+        drop(sender);
+
+        // Rot a bit of the payload of the second element and the header of
+        // the third, the last one of the first segment:
+        let filename = "data/salvage-src/0.q";
+        let mut segment = read(filename).unwrap();
+        segment[29 + 20] ^= 1;
+        segment[58 + 1] ^= 1;
+        write(filename, &segment).unwrap();
+
+        let report = salvage("data/salvage-src", "data/salvage-dst").unwrap();
+        assert_eq!(
+            report,
+            SalvageReport {
+                items: 4,
+                lost_bytes: 29 + segment.len() as u64 - 58,
+            }
+        );
+
+        let mut receiver = crate::Receiver::open("data/salvage-dst").unwrap();
+        let batch = receiver.try_recv_batch(4).ok().unwrap();
+        assert_eq!(
+            &*batch,
+            &[vec![0; 10], vec![3; 10], vec![4; 10], vec![5; 10]]
+        );
+        batch.commit().unwrap();
+    }
+
+    #[test]
+    fn test_verify_queue() {
+        // Each element takes 29 bytes with its checksum, one per segment:
+        let mut sender = crate::SenderBuilder::new()
+            .segment_size(16)
+            .checksum(true)
+            .open("data/verify-queue")
+            .unwrap();
+        let mut receiver = crate::Receiver::open("data/verify-queue").unwrap();
+
+        for _ in 0..3 {
+            sender.try_send(b"0123456789").unwrap();
+        }
+        receiver.try_recv().ok().unwrap().commit().unwrap();
+        receiver.save().unwrap();
+        drop((sender, receiver));
+
+        let report = verify_queue("data/verify-queue").unwrap();
+        assert!(report.is_healthy(), "{:?}", report.problems);
+        assert_eq!((report.segments, report.items), (3, 3));
+
+        // Rot a bit of the payload in the second segment...
+        let filename = "data/verify-queue/1.q";
+        let mut segment = read(filename).unwrap();
+        segment[20] ^= 1;
+        write(filename, &segment).unwrap();
+
+        // ... cut the last segment short...
+        let filename = "data/verify-queue/2.q";
+        let segment = read(filename).unwrap();
+        write(filename, &segment[..10]).unwrap();
+
+        // ... and mess up the state of the receiver:
+        let mut persistence = QueueStatePersistence::new();
+        persistence.open("data/verify-queue").unwrap();
+        persistence
+            .save(&QueueState {
+                segment: 0,
+                position: 5,
+            })
+            .unwrap();
+
+        let report = verify_queue("data/verify-queue").unwrap();
+        assert_eq!((report.segments, report.items), (3, 2));
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::BadChecksum {
+                    segment: 1,
+                    position: 0
+                },
+                Problem::BadFooter { segment: 1 },
+                Problem::Truncated {
+                    segment: 2,
+                    position: 0
+                },
+                Problem::BadRecvState {
+                    segment: 0,
+                    position: 5
+                },
+            ]
+        );
+    }
 }
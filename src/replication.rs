@@ -0,0 +1,360 @@
+//! Replication of a queue to a queue on another machine, over TCP. This
+//! module is only available with the `replication` feature.
+//!
+//! The _source_ takes the elements of a local queue with a [`Receiver`] and
+//! ships them with a [`Replicator`] to the _replica_, which sends them into a
+//! queue of its own with [`serve_replica`]. To keep receiving locally while
+//! replicating, replicate with a receiver of a consumer group of its own (see
+//! [`crate::ReceiverBuilder::consumer_group`]).
+//! ```rust,no_run
+//! use std::net::TcpListener;
+//! use yaque::replication::{self, Replicator};
+//! use yaque::{ReceiverBuilder, Sender};
+//!
+//! // On the replica:
+//! std::thread::spawn(|| {
+//!     let listener = TcpListener::bind("0.0.0.0:7878").unwrap();
+//!     let mut sender = Sender::open("data/my-replica").unwrap();
+//!     replication::serve(&listener, &mut sender).unwrap();
+//! });
+//!
+//! // On the source:
+//! let mut receiver = ReceiverBuilder::new()
+//!     .consumer_group("replication")
+//!     .open("data/my-queue")
+//!     .unwrap();
+//! let mut replicator = Replicator::connect("replica:7878").unwrap();
+//! replicator.replicate(&mut receiver).unwrap();
+//! ```
+//!
+//! The protocol is simple, with every integer in big endian:
+//!
+//! 1. Once connected, the replica sends the magic `YQR1`, followed by a `u8`
+//!    that is `1` if anything was replicated to it before and `0` otherwise,
+//!    and by the offset of the last element replicated (or zeros).
+//! 2. The source sends every element as its offset, a `u32` length and the
+//!    element itself.
+//! 3. The replica acknowledges every element, once it is in its queue, by
+//!    sending its offset back. The source only commits an element once it is
+//!    acknowledged.
+//!
+//! Offsets are positions in the source queue (a `u64` segment and a `u64`
+//! position), which only go forward. The replica saves the offset of the last
+//! element replicated in its queue folder, so that a source that reconnects,
+//! e.g., after a network failure, skips what the replica already has.
+//! However, if the replica crashes after sending an element into its queue,
+//! but before saving the offset, the element is replicated again: elements
+//! are replicated _at least once_. Only the elements themselves are
+//! replicated, as received by the source, not their metadata (such as the
+//! headers).
+//!
+//! Like [`crate::blocking`], everything here blocks the current thread, so do
+//! not use it from inside an asynchronous context.
+
+use futures::executor::block_on;
+use std::convert::TryFrom;
+use std::fs::*;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+use crate::error::TryRecvError;
+use crate::queue::{Receiver, RecvGuard, Sender};
+use crate::sharing;
+use crate::state::QueueState;
+
+/// The first bytes sent by a replica.
+const MAGIC: [u8; 4] = *b"YQR1";
+
+/// The name of the file with the offset of the last element replicated,
+/// inside the folder of a replica.
+fn offset_filename(base: &Path) -> PathBuf {
+    base.join("replica-offset")
+}
+
+/// Loads the offset of the last element replicated to a replica, if any.
+fn load_offset(base: &Path) -> io::Result<Option<QueueState>> {
+    match File::open(offset_filename(base)) {
+        Ok(file) => read_offset(&mut BufReader::new(file)).map(Some),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Saves the offset of the last element replicated to a replica. The offset
+/// is written to a temporary file that then replaces the old one.
+fn save_offset(base: &Path, offset: QueueState) -> io::Result<()> {
+    let path = offset_filename(base);
+    let tmp_path = path.with_extension("tmp");
+    write(&tmp_path, encode_offset(offset))?;
+
+    sharing::rename(tmp_path, path)
+}
+
+fn encode_offset(offset: QueueState) -> [u8; 16] {
+    let mut encoded = [0; 16];
+    encoded[..8].copy_from_slice(&offset.segment.to_be_bytes());
+    encoded[8..].copy_from_slice(&offset.position.to_be_bytes());
+    encoded
+}
+
+fn read_offset<R: Read>(reader: &mut R) -> io::Result<QueueState> {
+    let mut encoded = [0; 16];
+    reader.read_exact(&mut encoded)?;
+
+    let mut u64_buffer = [0; 8];
+    u64_buffer.copy_from_slice(&encoded[..8]);
+    let segment = u64::from_be_bytes(u64_buffer);
+    u64_buffer.copy_from_slice(&encoded[8..]);
+    let position = u64::from_be_bytes(u64_buffer);
+
+    Ok(QueueState { segment, position })
+}
+
+/// The source side of a replication: ships the elements of a local queue to
+/// a replica. See the [module documentation](self).
+pub struct Replicator {
+    stream: TcpStream,
+    /// The offset of the last element the replica has, if any.
+    replicated: Option<QueueState>,
+}
+
+impl Replicator {
+    /// Connects to a replica served by [`serve_replica`] and learns where it
+    /// is.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind [`io::ErrorKind::InvalidData`]
+    /// if the other end is not a replica. Also, it returns any underlying
+    /// errors encountered while connecting.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Replicator> {
+        Replicator::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Replicates over a connection already established with a replica. See
+    /// [`Replicator::connect`].
+    pub fn from_stream(mut stream: TcpStream) -> io::Result<Replicator> {
+        let mut handshake = [0; 5];
+        stream.read_exact(&mut handshake)?;
+
+        if handshake[..4] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the other end is not a yaque replica",
+            ));
+        }
+
+        let offset = read_offset(&mut stream)?;
+        let replicated = if handshake[4] == 1 {
+            Some(offset)
+        } else {
+            None
+        };
+        log::debug!(
+            "connected to replica, which replicated up to {:?}",
+            replicated
+        );
+
+        Ok(Replicator { stream, replicated })
+    }
+
+    /// The offset of the last element the replica has, if any.
+    pub fn replicated(&self) -> Option<QueueState> {
+        self.replicated
+    }
+
+    /// Replicates the elements of the queue as they arrive, forever. This only
+    /// returns on an error, after which the replication can be resumed with a
+    /// new connection.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while
+    /// receiving or shipping the elements. The element being shipped is then
+    /// rolled back.
+    pub fn replicate(&mut self, receiver: &mut Receiver) -> io::Result<()> {
+        loop {
+            let guard = block_on(receiver.recv())?;
+            self.ship(guard)?;
+        }
+    }
+
+    /// Replicates the elements waiting in the queue, until it is empty, and
+    /// returns how many elements were shipped. Elements the replica already
+    /// has are not shipped again, but they are received nonetheless.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while
+    /// receiving or shipping the elements. The element being shipped is then
+    /// rolled back.
+    pub fn replicate_available(&mut self, receiver: &mut Receiver) -> io::Result<u64> {
+        let mut shipped = 0;
+
+        loop {
+            match receiver.try_recv() {
+                Ok(guard) => shipped += self.ship(guard)? as u64,
+                Err(TryRecvError::QueueEmpty) => return Ok(shipped),
+                Err(TryRecvError::Io(err)) => return Err(err),
+            }
+        }
+    }
+
+    /// Ships an element to the replica, unless it has it already, and commits
+    /// it once it is acknowledged. Returns whether the element was shipped.
+    fn ship(&mut self, guard: RecvGuard<'_, Vec<u8>>) -> io::Result<bool> {
+        let offset = guard.offset();
+
+        if self
+            .replicated
+            .is_some_and(|replicated| offset <= replicated)
+        {
+            log::trace!("replica already has element at {:?}", offset);
+            guard.commit()?;
+            return Ok(false);
+        }
+
+        let len = u32::try_from(guard.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "element too big to replicate")
+        })?;
+        let mut frame = Vec::with_capacity(20 + guard.len());
+        frame.extend_from_slice(&encode_offset(offset));
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.extend_from_slice(&guard);
+        self.stream.write_all(&frame)?;
+
+        let acknowledged = read_offset(&mut self.stream)?;
+        if acknowledged != offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "replica acknowledged {:?} instead of {:?}",
+                    acknowledged, offset
+                ),
+            ));
+        }
+
+        self.replicated = Some(offset);
+        guard.commit()?;
+
+        Ok(true)
+    }
+}
+
+/// Serves a source on a connection, sending everything it replicates into a
+/// queue, until the source disconnects. See the [module
+/// documentation](self).
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while reading from
+/// the connection, while sending into the queue or while saving the offset.
+pub fn serve_replica(stream: TcpStream, sender: &mut Sender) -> io::Result<()> {
+    let base = sender.base().to_owned();
+    let mut replicated = load_offset(&base)?;
+    log::debug!("serving replica {:?} from {:?}", base, replicated);
+
+    let mut writer = stream.try_clone()?;
+    let mut handshake = MAGIC.to_vec();
+    handshake.push(replicated.is_some() as u8);
+    handshake.extend_from_slice(&encode_offset(replicated.unwrap_or_default()));
+    writer.write_all(&handshake)?;
+
+    let mut reader = BufReader::new(stream);
+    loop {
+        // The source disconnecting between elements is the normal end:
+        if reader.fill_buf()?.is_empty() {
+            return Ok(());
+        }
+
+        let offset = read_offset(&mut reader)?;
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        reader.read_exact(&mut data)?;
+
+        if replicated.is_none_or(|replicated| offset > replicated) {
+            block_on(sender.send(&data))?;
+            save_offset(&base, offset)?;
+            replicated = Some(offset);
+        }
+
+        writer.write_all(&encode_offset(offset))?;
+    }
+}
+
+/// Serves the sources connecting to a listener, one at a time, sending
+/// everything they replicate into a queue. Errors on a connection are logged
+/// and the next connection is served. This only returns if accepting a
+/// connection fails.
+///
+/// # Errors
+///
+/// This function returns any underlying errors encountered while accepting a
+/// connection.
+pub fn serve(listener: &TcpListener, sender: &mut Sender) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr().ok();
+
+        if let Err(err) = serve_replica(stream, sender) {
+            log::error!("replication from {:?} failed: {}", peer, err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replica(base: &'static str) -> (std::net::SocketAddr, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut sender = Sender::open(base).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            serve_replica(stream, &mut sender).unwrap();
+        });
+
+        (addr, handle)
+    }
+
+    #[test]
+    fn replicate_and_resume() {
+        let (mut sender, mut receiver) = crate::channel("data/replication-source").unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+
+        let (addr, handle) = replica("data/replication-replica");
+        let mut replicator = Replicator::connect(addr).unwrap();
+        assert_eq!(replicator.replicated(), None);
+        assert_eq!(replicator.replicate_available(&mut receiver).unwrap(), 2);
+        drop(replicator);
+        handle.join().unwrap();
+
+        // The replica tells where it is, so that nothing is shipped twice,
+        // even if the source starts over:
+        sender.try_send(b"third").unwrap();
+        let guard = receiver.try_recv().ok().unwrap();
+        let offset = guard.offset();
+        guard.rollback().unwrap();
+
+        let (addr, handle) = replica("data/replication-replica");
+        let mut replicator = Replicator::connect(addr).unwrap();
+        assert!(replicator.replicated().unwrap() < offset);
+        assert_eq!(replicator.replicate_available(&mut receiver).unwrap(), 1);
+        drop(replicator);
+        handle.join().unwrap();
+
+        let mut replica = Receiver::open("data/replication-replica").unwrap();
+        for expected in [&b"first"[..], b"second", b"third"] {
+            let guard = replica.try_recv().ok().unwrap();
+            assert_eq!(&*guard, expected);
+            guard.commit().unwrap();
+        }
+        assert!(replica.try_recv().is_err());
+    }
+}
@@ -0,0 +1,413 @@
+//! The files holding the segments of a queue. With the `io-uring` feature on
+//! Linux, the reads and writes of senders and receivers go through an
+//! [io_uring](https://man7.org/linux/man-pages/man7/io_uring.7.html) owned by
+//! each segment file. Elsewhere, or if the kernel does not support io_uring
+//! (e.g., because it is disabled by a seccomp profile), the plain blocking
+//! syscalls are used.
+//!
+//! Each operation is still waited for before returning, so that the
+//! semantics of the queue do not change: an element is written to the segment
+//! when the send returns.
+//!
+//! With the `mmap` feature, receivers can also read the segments through a
+//! memory map instead (see [`crate::ReceiverBuilder::mmap`]).
+
+use std::fs::*;
+use std::io::{self, IoSlice, Read, Seek, Write};
+use std::path::Path;
+
+use crate::sharing;
+
+/// The most slices written in one go, which is the `IOV_MAX` of most
+/// platforms.
+const MAX_SLICES: usize = 1024;
+
+/// A segment file, opened either for appending or for reading.
+pub(crate) struct SegmentFile {
+    file: File,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    ring: Option<uring::Ring>,
+    #[cfg(feature = "mmap")]
+    mapped: Option<mapped::Mapped>,
+}
+
+impl SegmentFile {
+    fn new(file: File) -> SegmentFile {
+        SegmentFile {
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            ring: uring::Ring::new(),
+            #[cfg(feature = "mmap")]
+            mapped: None,
+            file,
+        }
+    }
+
+    /// Opens a segment for appending, creating it if it does not exist.
+    pub(crate) fn append(path: &Path) -> io::Result<SegmentFile> {
+        let file = match sharing::open_options()
+            .create_new(true)
+            .append(true)
+            .open(path)
+        {
+            Ok(file) => {
+                sharing::created(path)?;
+                file
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                sharing::open_options().append(true).open(path)?
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(SegmentFile::new(file))
+    }
+
+    /// Wraps a segment file opened for reading, to be read through a memory
+    /// map if `mmap` is set (and the `mmap` feature is enabled).
+    #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+    pub(crate) fn reading(file: File, mmap: bool) -> SegmentFile {
+        #[allow(unused_mut)]
+        let mut segment_file = SegmentFile::new(file);
+
+        #[cfg(feature = "mmap")]
+        if mmap {
+            segment_file.mapped = Some(mapped::Mapped::default());
+        }
+
+        segment_file
+    }
+
+    /// Syncs the data written to the segment to the disk.
+    pub(crate) fn sync_data(&mut self) -> io::Result<()> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.sync_data(&self.file);
+        }
+
+        self.file.sync_data()
+    }
+}
+
+impl Write for SegmentFile {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.write(&self.file, buffer);
+        }
+
+        self.file.write(buffer)
+    }
+
+    fn write_vectored(&mut self, buffers: &[IoSlice<'_>]) -> io::Result<usize> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.write_vectored(&self.file, buffers);
+        }
+
+        self.file.write_vectored(buffers)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Read for SegmentFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &mut self.mapped {
+            return mapped.read(&self.file, buffer);
+        }
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = &mut self.ring {
+            return ring.read(&self.file, buffer);
+        }
+
+        self.file.read(buffer)
+    }
+}
+
+impl Seek for SegmentFile {
+    // (the ring reads and writes at the position of the file)
+    fn seek(&mut self, seek: io::SeekFrom) -> io::Result<u64> {
+        #[cfg(feature = "mmap")]
+        if let Some(mapped) = &mut self.mapped {
+            return mapped.seek(&self.file, seek);
+        }
+
+        self.file.seek(seek)
+    }
+}
+
+/// Writes all the slices with as few calls to [`Write::write_vectored`] as
+/// possible. The slices must not be empty.
+pub(crate) fn write_all_vectored<W: Write>(
+    writer: &mut W,
+    mut slices: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    while !slices.is_empty() {
+        let chunk = usize::min(slices.len(), MAX_SLICES);
+
+        match writer.write_vectored(&slices[..chunk]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring {
+    use io_uring::{opcode, squeue, types, IoUring};
+    use std::fs::File;
+    use std::io::{self, IoSlice};
+    use std::os::unix::io::AsRawFd;
+
+    /// The number of entries in each ring. Only one operation is in flight at
+    /// a time.
+    const RING_ENTRIES: u32 = 2;
+
+    /// The offset that makes reads and writes use (and update) the position
+    /// of the file, like `read(2)` and `write(2)`.
+    const CURRENT_POSITION: u64 = u64::MAX;
+
+    /// An io_uring doing the I/O of a segment file, one operation at a time.
+    pub(super) struct Ring {
+        ring: IoUring,
+    }
+
+    impl Ring {
+        /// Sets up a ring, if the kernel supports everything needed.
+        pub(super) fn new() -> Option<Ring> {
+            match IoUring::new(RING_ENTRIES) {
+                Ok(ring) if ring.params().is_feature_rw_cur_pos() => Some(Ring { ring }),
+                Ok(_) => {
+                    log::debug!("io_uring cannot use the file position. Using syscalls");
+                    None
+                }
+                Err(err) => {
+                    log::debug!("io_uring not available ({}). Using syscalls", err);
+                    None
+                }
+            }
+        }
+
+        /// Submits an operation and waits for its outcome.
+        ///
+        /// # Safety
+        ///
+        /// The buffers referenced by the operation must live at least until
+        /// this function returns.
+        unsafe fn run(&mut self, entry: squeue::Entry) -> io::Result<usize> {
+            self.ring
+                .submission()
+                .push(&entry)
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+
+            loop {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => break,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            let completion = self
+                .ring
+                .completion()
+                .next()
+                .expect("waited for one completion");
+
+            match completion.result() {
+                result if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+                result => Ok(result as usize),
+            }
+        }
+
+        pub(super) fn write(&mut self, file: &File, buffer: &[u8]) -> io::Result<usize> {
+            let entry = opcode::Write::new(
+                types::Fd(file.as_raw_fd()),
+                buffer.as_ptr(),
+                buffer.len().min(u32::MAX as usize) as u32,
+            )
+            .offset(CURRENT_POSITION)
+            .build();
+
+            // Safety: the operation is waited for while `buffer` is borrowed.
+            unsafe { self.run(entry) }
+        }
+
+        pub(super) fn write_vectored(
+            &mut self,
+            file: &File,
+            buffers: &[IoSlice<'_>],
+        ) -> io::Result<usize> {
+            // (`IoSlice` is guaranteed to be ABI-compatible with `iovec`)
+            let entry = opcode::Writev::new(
+                types::Fd(file.as_raw_fd()),
+                buffers.as_ptr().cast(),
+                buffers.len().min(u32::MAX as usize) as u32,
+            )
+            .offset(CURRENT_POSITION)
+            .build();
+
+            // Safety: the operation is waited for while `buffers` is borrowed.
+            unsafe { self.run(entry) }
+        }
+
+        pub(super) fn read(&mut self, file: &File, buffer: &mut [u8]) -> io::Result<usize> {
+            let entry = opcode::Read::new(
+                types::Fd(file.as_raw_fd()),
+                buffer.as_mut_ptr(),
+                buffer.len().min(u32::MAX as usize) as u32,
+            )
+            .offset(CURRENT_POSITION)
+            .build();
+
+            // Safety: the operation is waited for while `buffer` is borrowed.
+            unsafe { self.run(entry) }
+        }
+
+        pub(super) fn sync_data(&mut self, file: &File) -> io::Result<()> {
+            let entry = opcode::Fsync::new(types::Fd(file.as_raw_fd()))
+                .flags(types::FsyncFlags::DATASYNC)
+                .build();
+
+            // Safety: the operation references no buffers.
+            unsafe { self.run(entry) }.map(|_| ())
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+mod mapped {
+    use memmap2::Mmap;
+    use std::fs::File;
+    use std::io;
+
+    /// Reads a segment file through a memory map, which is remapped whenever
+    /// the reader gets to its end and the file has grown.
+    #[derive(Default)]
+    pub(super) struct Mapped {
+        /// The map, unless the file was empty the last it was mapped.
+        map: Option<Mmap>,
+        /// The position of the reader in the file.
+        position: u64,
+    }
+
+    impl Mapped {
+        fn mapped_len(&self) -> u64 {
+            self.map.as_ref().map(|map| map.len() as u64).unwrap_or(0)
+        }
+
+        pub(super) fn read(&mut self, file: &File, buffer: &mut [u8]) -> io::Result<usize> {
+            if self.position >= self.mapped_len() && file.metadata()?.len() > self.mapped_len() {
+                // Safety: segments are only appended to while they are being
+                // read. They are only truncated by the recovery functions,
+                // which need the receiver lock.
+                self.map = Some(unsafe { Mmap::map(file)? });
+            }
+
+            let map = match &self.map {
+                Some(map) if self.position < map.len() as u64 => map,
+                _ => return Ok(0),
+            };
+
+            let available = &map[self.position as usize..];
+            let len = usize::min(available.len(), buffer.len());
+            buffer[..len].copy_from_slice(&available[..len]);
+            self.position += len as u64;
+
+            Ok(len)
+        }
+
+        pub(super) fn seek(&mut self, file: &File, seek: io::SeekFrom) -> io::Result<u64> {
+            let position = match seek {
+                io::SeekFrom::Start(position) => Some(position),
+                io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+                io::SeekFrom::End(offset) => file.metadata()?.len().checked_add_signed(offset),
+            };
+
+            self.position = position.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+            })?;
+
+            Ok(self.position)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn append_and_read_segment_file() {
+        create_dir_all("data/segment-file").unwrap();
+        let path = Path::new("data/segment-file/0.q");
+        let _ = remove_file(path);
+
+        let mut file = SegmentFile::append(path).unwrap();
+        file.write_all(b"hello, ").unwrap();
+        file.write_all(b"world").unwrap();
+        file.sync_data().unwrap();
+
+        for mmap in [false, true] {
+            let mut file = SegmentFile::reading(File::open(path).unwrap(), mmap);
+            file.seek(io::SeekFrom::Start(7)).unwrap();
+            let mut read = String::new();
+            file.read_to_string(&mut read).unwrap();
+            assert_eq!(read, "world");
+        }
+    }
+
+    #[test]
+    fn write_many_slices() {
+        create_dir_all("data/segment-file-slices").unwrap();
+        let path = Path::new("data/segment-file-slices/0.q");
+        let _ = remove_file(path);
+
+        let items = (0..3000u32).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        let mut slices = items
+            .iter()
+            .map(|item| IoSlice::new(item))
+            .collect::<Vec<_>>();
+
+        let mut file = SegmentFile::append(path).unwrap();
+        write_all_vectored(&mut file, &mut slices).unwrap();
+
+        assert_eq!(read(path).unwrap(), items.concat());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn read_growing_mapped_segment_file() {
+        create_dir_all("data/mapped-segment-file").unwrap();
+        let path = Path::new("data/mapped-segment-file/0.q");
+        let _ = remove_file(path);
+
+        let mut writer = SegmentFile::append(path).unwrap();
+        let mut reader = SegmentFile::reading(File::open(path).unwrap(), true);
+        let mut buffer = [0; 5];
+
+        // Nothing there yet:
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+
+        writer.write_all(b"hello").unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+
+        writer.write_all(b"world").unwrap();
+        reader.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"world");
+
+        reader.seek(io::SeekFrom::Current(-3)).unwrap();
+        reader.read_exact(&mut buffer[..3]).unwrap();
+        assert_eq!(&buffer[..3], b"rld");
+        assert!(reader.seek(io::SeekFrom::Current(-11)).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! How the files of the queue are shared between the handles that have them
+//! open, which is where Windows and Unix differ the most.
+//!
+//! On Unix, a file can be removed or renamed while other handles have it
+//! open: they keep reading the old file. On Windows, this only works if every
+//! handle to the file was opened with `FILE_SHARE_DELETE`, and even then the
+//! file lingers as "delete pending" until the last handle is closed, during
+//! which it cannot be opened or created again. Handles from other programs
+//! (antivirus, indexers, backup tools) often do not share deletion at all.
+//!
+//! Therefore, the queue opens its files sharing everything and retries,
+//! for a short while, the operations that fail because of a handle it does
+//! not control. On Unix, nothing is ever retried.
+//!
+//! On Unix, the files of a queue can also be shared between users, by giving
+//! them the same [`FilePermissions`] whoever creates them.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(windows)]
+use std::time::Duration;
+
+#[cfg(unix)]
+use lazy_static::lazy_static;
+#[cfg(unix)]
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::sync::Mutex;
+
+#[cfg(unix)]
+lazy_static! {
+    /// The permissions of the files created in each queue folder from now on.
+    /// See [`set_permissions`].
+    static ref PERMISSIONS: Mutex<HashMap<PathBuf, FilePermissions>> = Mutex::default();
+}
+
+/// How many times an operation failing because of another handle to the same
+/// file is retried (waiting a bit longer each time) before giving up.
+#[cfg(windows)]
+const SHARING_RETRIES: u32 = 10;
+
+/// How long to wait before the first retry. See [`SHARING_RETRIES`].
+#[cfg(windows)]
+const SHARING_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// New [`OpenOptions`] for a file of the queue, letting other handles read,
+/// write, rename and remove it while it is open.
+pub(crate) fn open_options() -> OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = OpenOptions::new();
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        const FILE_SHARE_READ: u32 = 0x1;
+        const FILE_SHARE_WRITE: u32 = 0x2;
+        const FILE_SHARE_DELETE: u32 = 0x4;
+
+        options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+    }
+
+    options
+}
+
+/// Opens a file of the queue for reading. See [`open_options`].
+pub(crate) fn open<P: AsRef<Path>>(path: P) -> io::Result<fs::File> {
+    open_options().read(true).open(path)
+}
+
+/// Removes a file, even if another handle has it open.
+pub(crate) fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    retry_sharing_violations(|| fs::remove_file(path.as_ref()))
+}
+
+/// Renames a file (replacing `to`, if it exists), even if another handle has
+/// either of them open.
+pub(crate) fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    retry_sharing_violations(|| fs::rename(from.as_ref(), to.as_ref()))
+}
+
+/// Runs a filesystem operation, retrying it while it fails because another
+/// handle to the same file is in the way.
+#[cfg_attr(not(windows), allow(unused_mut))]
+pub(crate) fn retry_sharing_violations<T, F>(mut operation: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    #[cfg(windows)]
+    {
+        let mut delay = SHARING_RETRY_DELAY;
+
+        for _ in 0..SHARING_RETRIES {
+            match operation() {
+                Err(err) if is_sharing_violation(&err) => {
+                    log::debug!("sharing violation (retrying in {:?}): {}", delay, err);
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    operation()
+}
+
+/// The mode bits (and, optionally, the owner and the group) of the files and
+/// folders of a queue, for a sender and a receiver running as different users
+/// to share a queue (see [`crate::SenderBuilder::permissions`] and
+/// [`crate::ReceiverBuilder::permissions`]). Only on Unix.
+///
+/// The mode is set on every file the queue creates, whatever the umask of
+/// the process, and on the folders with the execute bits added wherever the
+/// read bits are set. Changing the owner usually needs special privileges,
+/// but a user can always change the group of a file it owns to any group it
+/// belongs to.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilePermissions {
+    mode: u32,
+    owner: Option<u32>,
+    group: Option<u32>,
+}
+
+#[cfg(unix)]
+impl FilePermissions {
+    /// Sets the mode bits of the files to `mode` (e.g., `0o660` for the owner
+    /// and the group to read and write), without changing the owner or the
+    /// group.
+    pub fn new(mode: u32) -> FilePermissions {
+        FilePermissions {
+            mode: mode & 0o7777,
+            owner: None,
+            group: None,
+        }
+    }
+
+    /// Also sets the owner of the files to the user with id `uid`.
+    pub fn owner(mut self, uid: u32) -> FilePermissions {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// Also sets the group of the files to the group with id `gid`.
+    pub fn group(mut self, gid: u32) -> FilePermissions {
+        self.group = Some(gid);
+        self
+    }
+
+    /// The mode of the folders: that of the files, with the execute bits added
+    /// wherever the read bits are set.
+    fn dir_mode(&self) -> u32 {
+        self.mode | ((self.mode & 0o444) >> 2)
+    }
+
+    /// Applies the permissions to a file or folder.
+    fn apply(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = if is_dir { self.dir_mode() } else { self.mode };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+
+        if self.owner.is_some() || self.group.is_some() {
+            std::os::unix::fs::chown(path, self.owner, self.group)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets the permissions of the files and folders created inside a queue
+/// folder from now on, by this process, and applies them to the folder
+/// itself.
+#[cfg(unix)]
+pub(crate) fn set_permissions(base: &Path, permissions: FilePermissions) -> io::Result<()> {
+    PERMISSIONS
+        .lock()
+        .expect("permissions lock poisoned")
+        .insert(base.to_owned(), permissions);
+
+    created(base)
+}
+
+/// Applies the permissions set for the queue folder it is in (see
+/// [`set_permissions`]), if any, to a file or a folder just created (or just
+/// opened, if it may have been created). Only the owner of a file can change
+/// it, so the files created by another user are left as they are.
+pub(crate) fn created(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let permissions = {
+            let permissions = PERMISSIONS.lock().expect("permissions lock poisoned");
+            path.ancestors()
+                .find_map(|base| permissions.get(base).copied())
+        };
+
+        if let Some(permissions) = permissions {
+            match permissions.apply(path, fs::metadata(path)?.is_dir()) {
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    log::debug!("leaving the permissions of {:?} alone: {}", path, err);
+                }
+                outcome => return outcome,
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Whether an error comes from another handle to the same file being in the
+/// way, which goes away once that handle is closed.
+#[cfg(windows)]
+fn is_sharing_violation(err: &io::Error) -> bool {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    // (files pending deletion are "access denied" to everyone)
+    matches!(
+        err.raw_os_error(),
+        Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn remove_open_file() {
+        let path = "data/remove-open-file";
+        let _ = fs::remove_file(path);
+
+        let mut file = open_options().create(true).append(true).open(path).unwrap();
+        let reader = open(path).unwrap();
+
+        remove_file(path).unwrap();
+
+        // Both handles still work:
+        file.write_all(b"abc").unwrap();
+        assert_eq!(reader.metadata().unwrap().len(), 3);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn retry_sharing_violation() {
+        let mut attempts = 0;
+        let outcome = retry_sharing_violations(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::from_raw_os_error(32))
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(outcome.unwrap(), 3);
+    }
+}
@@ -1,12 +1,16 @@
 //! Structures for managing the state of a queue.
 
 use std::cmp::{Ordering, PartialOrd};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::*;
 use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::sharing;
 
 /// The internal state of one side of the queue.
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct QueueState {
     /// The number of the actual segment.
     pub segment: u64,
@@ -15,15 +19,6 @@ pub struct QueueState {
     pub position: u64,
 }
 
-impl Default for QueueState {
-    fn default() -> QueueState {
-        QueueState {
-            segment: 0,
-            position: 0,
-        }
-    }
-}
-
 impl PartialOrd for QueueState {
     fn partial_cmp(&self, other: &QueueState) -> Option<Ordering> {
         if self.segment > other.segment {
@@ -82,7 +77,6 @@ impl QueueState {
                     let queue_state = QueueState {
                         segment: max_segment,
                         position,
-                        ..QueueState::default()
                     };
 
                     Ok(queue_state)
@@ -128,12 +122,25 @@ fn recv_persistence_filename<P: AsRef<Path>>(base: P) -> PathBuf {
     base.as_ref().join("recv-metadata")
 }
 
+/// Whether the receiver of a queue ever saved its state.
+pub(crate) fn recv_persistence_exists<P: AsRef<Path>>(base: P) -> bool {
+    recv_persistence_filename(base).exists()
+}
+
 impl QueueStatePersistence {
     /// Creates a new file persistence.
     pub fn new() -> QueueStatePersistence {
         QueueStatePersistence::default()
     }
 
+    /// Creates a new file persistence for a queue, to save the state without
+    /// opening it first.
+    pub(crate) fn at<P: AsRef<Path>>(base: P) -> QueueStatePersistence {
+        QueueStatePersistence {
+            path: Some(recv_persistence_filename(base)),
+        }
+    }
+
     pub fn open<P: AsRef<Path>>(&mut self, base: P) -> io::Result<QueueState> {
         let path = recv_persistence_filename(base);
         self.path = Some(path.clone());
@@ -157,18 +164,420 @@ impl QueueStatePersistence {
         }
     }
 
-    /// Saves the queue state.
+    /// Saves the queue state. The state is written to a temporary file that
+    /// then replaces the old one, so that senders reading the state (e.g., to
+    /// check the capacity of the queue) never see it half-written.
     pub fn save(&mut self, queue_state: &QueueState) -> io::Result<()> {
-        let mut file = BufWriter::new(File::create(
-            self.path
-                .as_ref()
-                .expect("save should be called *after* open"),
-        )?);
+        let path = self
+            .path
+            .as_ref()
+            .expect("save should be called *after* open");
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        sharing::created(&tmp_path)?;
 
         file.write_all(&queue_state.segment.to_be_bytes())?;
         file.write_all(&queue_state.position.to_be_bytes())?;
         file.flush()?;
+        drop(file);
+
+        sharing::rename(tmp_path, path)
+    }
+}
+
+/// The number of times the element at a given position of the queue was
+/// delivered by a receiver without being committed, and when it may be
+/// delivered again.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Deliveries {
+    /// The position of the element.
+    pub at: QueueState,
+    /// The number of times the element was delivered.
+    pub count: u64,
+    /// The element must not be delivered again before this instant. See
+    /// [`crate::queue::RecvGuard::nack_after`].
+    pub not_before: Option<SystemTime>,
+}
+
+/// The name of the deliveries file inside the queue folder.
+fn deliveries_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("recv-deliveries")
+}
+
+impl Deliveries {
+    /// Loads the deliveries of a queue. There were no deliveries if the file
+    /// does not exist.
+    pub fn load<P: AsRef<Path>>(base: P) -> io::Result<Deliveries> {
+        let mut u64_buffer = [0; 8];
+
+        match File::open(deliveries_filename(base)) {
+            Ok(mut file) => {
+                let mut read_u64 = move || -> io::Result<_> {
+                    file.read_exact(&mut u64_buffer)?;
+                    Ok(u64::from_be_bytes(u64_buffer))
+                };
+
+                let at = QueueState {
+                    segment: read_u64()?,
+                    position: read_u64()?,
+                };
+                let count = read_u64()?;
+
+                // (older files have no delay, which is also written as zero)
+                let not_before = match read_u64() {
+                    Ok(0) => None,
+                    Ok(millis) => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+                    Err(err) => return Err(err),
+                };
+
+                Ok(Deliveries {
+                    at,
+                    count,
+                    not_before,
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Deliveries::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Saves the deliveries of a queue. The deliveries are written to a
+    /// temporary file that then replaces the old one.
+    pub fn save<P: AsRef<Path>>(&self, base: P) -> io::Result<()> {
+        let path = deliveries_filename(base);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        sharing::created(&tmp_path)?;
+
+        file.write_all(&self.at.segment.to_be_bytes())?;
+        file.write_all(&self.at.position.to_be_bytes())?;
+        file.write_all(&self.count.to_be_bytes())?;
+        let not_before = self
+            .not_before
+            .map(|not_before| not_before.duration_since(UNIX_EPOCH).unwrap_or_default())
+            .map(|since_epoch| since_epoch.as_millis() as u64)
+            .unwrap_or(0);
+        file.write_all(&not_before.to_be_bytes())?;
+        file.flush()?;
+        drop(file);
+
+        sharing::rename(tmp_path, path)
+    }
+}
+
+/// The ids of the last elements delivered by a receiver, with the position of
+/// the element each id was first seen at. See
+/// [`crate::ReceiverBuilder::dedup_window`].
+#[derive(Debug, Clone, Default)]
+pub struct SeenIds {
+    /// The number of ids kept.
+    window: usize,
+    /// The ids kept, from the oldest to the newest.
+    order: VecDeque<Vec<u8>>,
+    /// Where each id kept was first seen.
+    positions: HashMap<Vec<u8>, QueueState>,
+}
+
+/// The name of the seen ids file inside the queue folder.
+fn seen_ids_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("recv-seen-ids")
+}
+
+impl SeenIds {
+    /// Loads the ids seen in a queue, keeping the last `window` of them. No
+    /// ids were seen if the file does not exist.
+    pub fn load<P: AsRef<Path>>(base: P, window: usize) -> io::Result<SeenIds> {
+        let mut seen = SeenIds {
+            window,
+            ..SeenIds::default()
+        };
+
+        let encoded = match read(seen_ids_filename(base)) {
+            Ok(encoded) => encoded,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(seen),
+            Err(err) => return Err(err),
+        };
+
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed seen ids");
+        let mut encoded = &encoded[..];
+
+        while !encoded.is_empty() {
+            let mut u64_buffer = [0; 8];
+            let mut read_u64 = |encoded: &mut &[u8]| -> io::Result<_> {
+                encoded
+                    .read_exact(&mut u64_buffer)
+                    .map_err(|_| malformed())?;
+                Ok(u64::from_be_bytes(u64_buffer))
+            };
+
+            let at = QueueState {
+                segment: read_u64(&mut encoded)?,
+                position: read_u64(&mut encoded)?,
+            };
+            let (&len, rest) = encoded.split_first().ok_or_else(malformed)?;
+            let id = rest.get(..len as usize).ok_or_else(malformed)?;
+            encoded = &rest[len as usize..];
+
+            seen.is_duplicate(id, at);
+        }
+
+        Ok(seen)
+    }
+
+    /// Saves the ids seen in a queue. The ids are written to a temporary file
+    /// that then replaces the old one.
+    pub fn save<P: AsRef<Path>>(&self, base: P) -> io::Result<()> {
+        let path = seen_ids_filename(base);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        sharing::created(&tmp_path)?;
+
+        for id in &self.order {
+            let at = self.positions[id];
+            file.write_all(&at.segment.to_be_bytes())?;
+            file.write_all(&at.position.to_be_bytes())?;
+            file.write_all(&[id.len() as u8])?;
+            file.write_all(id)?;
+        }
+
+        file.flush()?;
+        drop(file);
+
+        sharing::rename(tmp_path, path)
+    }
+
+    /// Whether an id was already seen at another position than the element
+    /// at `at`. Otherwise, the id is kept as seen at `at`, forgetting the
+    /// oldest id if the window is full.
+    pub fn is_duplicate(&mut self, id: &[u8], at: QueueState) -> bool {
+        if let Some(&seen_at) = self.positions.get(id) {
+            return seen_at != at;
+        }
+
+        self.order.push_back(id.to_vec());
+        self.positions.insert(id.to_vec(), at);
+
+        while self.order.len() > self.window {
+            let oldest = self.order.pop_front().expect("window is not empty");
+            self.positions.remove(&oldest);
+        }
+
+        false
+    }
+
+    /// Forgets the ids first seen at or after `from`. Returns whether any id
+    /// was forgotten.
+    pub fn forget_from(&mut self, from: QueueState) -> bool {
+        let positions = &mut self.positions;
+        let len = self.order.len();
+        self.order.retain(|id| {
+            let keep = positions[id] < from;
+            if !keep {
+                positions.remove(id);
+            }
+            keep
+        });
+
+        self.order.len() != len
+    }
+}
+
+/// The keys of the last elements sent by a sender, with when each was sent.
+/// The key of an element is its id, if it has one, or else a hash of its
+/// contents. See [`crate::SenderBuilder::dedup_window`].
+///
+/// Every key sent is appended to a file, which is compacted when it opens
+/// and whenever it grows well past the window.
+#[derive(Debug)]
+pub struct SentKeys {
+    /// The number of keys kept.
+    window: usize,
+    /// How long keys are kept, if not forever.
+    max_age: Option<Duration>,
+    /// The keys kept, from the oldest to the newest, with when they were sent.
+    order: VecDeque<(Vec<u8>, SystemTime)>,
+    /// The keys kept.
+    keys: HashSet<Vec<u8>>,
+    /// The number of keys in the file.
+    logged: usize,
+    /// The file the keys are appended to, once compacted.
+    file: Option<BufWriter<File>>,
+}
+
+/// The name of the sent keys file inside the queue folder.
+fn sent_keys_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("send-dedup")
+}
+
+/// Where a key of a content hash starts. See [`SentKeys::key_of`].
+const KEY_HASH: u8 = 0;
+/// Where a key of an id starts. See [`SentKeys::key_of`].
+const KEY_ID: u8 = 1;
+
+/// Hashes the contents of an element with FNV-1a, which, unlike the hashers in
+/// the standard library, is stable, so that the hashes can be saved.
+fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl SentKeys {
+    /// Opens the keys sent to a queue, keeping the last `window` of them, sent
+    /// at most `max_age` ago, if given. No keys were sent if the file does
+    /// not exist.
+    pub fn open<P: AsRef<Path>>(
+        base: P,
+        window: usize,
+        max_age: Option<Duration>,
+    ) -> io::Result<SentKeys> {
+        let path = sent_keys_filename(base);
+        let mut order = VecDeque::new();
+
+        let encoded = match read(&path) {
+            Ok(encoded) => encoded,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed sent keys");
+        let mut encoded = &encoded[..];
+
+        while !encoded.is_empty() {
+            let mut millis = [0; 8];
+            encoded.read_exact(&mut millis).map_err(|_| malformed())?;
+            let sent_at = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis));
+
+            // (the key starts with its kind and its length)
+            let len = *encoded.get(1).ok_or_else(malformed)? as usize;
+            let key = encoded.get(..2 + len).ok_or_else(malformed)?;
+            encoded = &encoded[2 + len..];
+
+            order.push_back((key.to_vec(), sent_at));
+        }
+
+        let mut sent = SentKeys {
+            window,
+            max_age,
+            keys: order.iter().map(|(key, _)| key.clone()).collect(),
+            order,
+            logged: 0,
+            file: None,
+        };
+        sent.compact(&path)?;
+
+        Ok(sent)
+    }
+
+    /// The key of an element with an id, if it has one, and some contents.
+    pub fn key_of(id: Option<&[u8]>, data: &[u8]) -> Vec<u8> {
+        match id {
+            Some(id) => [&[KEY_ID, id.len() as u8][..], id].concat(),
+            None => [&[KEY_HASH, 8][..], &content_hash(data).to_be_bytes()].concat(),
+        }
+    }
+
+    /// Forgets the keys that are out of the window.
+    fn forget_old(&mut self) {
+        let now = SystemTime::now();
+
+        while let Some((key, sent_at)) = self.order.front() {
+            let is_old = self.max_age.is_some_and(|max_age| {
+                now.duration_since(*sent_at).unwrap_or_default() > max_age
+            });
+
+            if self.order.len() <= self.window && !is_old {
+                break;
+            }
+
+            self.keys.remove(key);
+            self.order.pop_front();
+        }
+    }
+
+    /// Rewrites the file with only the keys kept. The keys are written to a
+    /// temporary file that then replaces the old one.
+    fn compact(&mut self, path: &Path) -> io::Result<()> {
+        self.forget_old();
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        sharing::created(&tmp_path)?;
+
+        for (key, sent_at) in &self.order {
+            write_key(&mut file, key, *sent_at)?;
+        }
+
+        file.flush()?;
+        drop(file);
+        sharing::rename(tmp_path, path)?;
+
+        self.logged = self.order.len();
+        self.file = Some(BufWriter::new(
+            OpenOptions::new().append(true).open(path)?,
+        ));
+
+        Ok(())
+    }
+
+    /// Whether an element with this key was sent within the window.
+    pub fn was_sent(&mut self, key: &[u8]) -> bool {
+        self.forget_old();
+        self.keys.contains(key)
+    }
+
+    /// Keeps that an element with this key was just sent, forgetting the
+    /// oldest key if the window is full. The key is only saved for sure once
+    /// [`SentKeys::flush`] is called.
+    pub fn insert<P: AsRef<Path>>(&mut self, base: P, key: Vec<u8>) -> io::Result<()> {
+        let sent_at = SystemTime::now();
+
+        if self.keys.insert(key.clone()) {
+            self.order.push_back((key, sent_at));
+        } else if let Some(at) = self.order.iter().position(|(kept, _)| *kept == key) {
+            // (it was sent again anyway)
+            let (key, _) = self.order.remove(at).expect("position exists");
+            self.order.push_back((key, sent_at));
+        }
+
+        let (key, _) = self.order.back().expect("key was just kept");
+        let file = self.file.as_mut().expect("file opened when compacted");
+        write_key(file, key, sent_at)?;
+        self.logged += 1;
+
+        self.forget_old();
+
+        // Do not let the file grow forever:
+        if self.logged > 2 * self.window {
+            self.compact(&sent_keys_filename(base))?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the keys kept since the last flush.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map_or(Ok(()), Write::flush)
+    }
+
+    /// Saves the keys kept since the last flush and syncs them to the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+            file.get_ref().sync_data()?;
+        }
 
         Ok(())
     }
 }
+
+/// Writes a key sent at some time to a sent keys file.
+fn write_key<W: Write>(file: &mut W, key: &[u8], sent_at: SystemTime) -> io::Result<()> {
+    let millis = sent_at
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0);
+    file.write_all(&millis.to_be_bytes())?;
+    file.write_all(key)
+}
@@ -0,0 +1,392 @@
+//! Where the segments and the state of a queue are kept. See [`Storage`].
+
+use std::fs::*;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::queue::{list_segments, segment_filename};
+use crate::segment_file::SegmentFile;
+use crate::sharing;
+use crate::state::QueueStatePersistence;
+use crate::sync::open_new;
+
+pub use crate::state::QueueState;
+
+/// A segment opened for appending, as returned by [`Storage::append`].
+pub trait SegmentAppend: Write + Send {
+    /// Makes sure that everything written so far survives a crash of the
+    /// machine (e.g., with `fsync`).
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+/// A segment opened for reading, as returned by [`Storage::read`]. Reading
+/// past the end returns `Ok(0)`, but a later read has to return what was
+/// appended in the meantime, like a file does.
+pub trait SegmentRead: Read + Seek + Send {}
+
+impl<R: Read + Seek + Send> SegmentRead for R {}
+
+/// Where the segments of a queue and the state of its receiver are kept, so
+/// that senders and receivers can work on something else than files (e.g.,
+/// object storage or a block device). See
+/// [`SenderBuilder::storage`](crate::SenderBuilder::storage) and
+/// [`ReceiverBuilder::storage`](crate::ReceiverBuilder::storage). By default,
+/// everything is in files in the queue folder (see [`FileStorage`]).
+///
+/// This only covers the data path. The queue folder still holds the locks, the
+/// version of the queue, the delivery counts of the receiver and the ids it has
+/// seen, and every function working on the folder (such as
+/// [`crate::queue::queue_info`], the `recovery` module or [`crate::backup`])
+/// still reads the files. Priority lanes and topics are nested queues in
+/// folders of their own, so senders and receivers with a storage of their own
+/// refuse to open them. Receivers poll storages without files to watch, every
+/// `poll_interval` (see
+/// [`ReceiverBuilder::poll_interval`](crate::ReceiverBuilder::poll_interval)).
+pub trait Storage: Send + Sync {
+    /// The segments in the storage, in any order.
+    fn segments(&self) -> io::Result<Vec<u64>>;
+
+    /// The length of a segment, or `None` if the segment does not exist.
+    fn segment_len(&self, segment: u64) -> io::Result<Option<u64>>;
+
+    /// Opens a segment for appending, creating it if it does not exist yet.
+    fn append(&self, segment: u64) -> io::Result<Box<dyn SegmentAppend>>;
+
+    /// Opens a segment for reading, from the start. A segment that does not
+    /// exist yet reads as empty, until it is created and appended to.
+    fn read(&self, segment: u64) -> io::Result<Box<dyn SegmentRead>>;
+
+    /// Opens a segment for reading, from the start, without receiving from it
+    /// (e.g., to walk the queue), or returns `None` if the segment does not
+    /// exist. By default, the same as [`Storage::read`], for the segments that
+    /// exist.
+    fn peek(&self, segment: u64) -> io::Result<Option<Box<dyn SegmentRead>>> {
+        match self.segment_len(segment)? {
+            Some(_) => self.read(segment).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes a segment. Deleting a segment that does not exist is not an
+    /// error.
+    fn delete(&self, segment: u64) -> io::Result<()>;
+
+    /// Loads the state of the receiver, as last persisted, or the start of
+    /// the queue if it was never persisted.
+    fn load_state(&self) -> io::Result<QueueState>;
+
+    /// Persists the state of the receiver. Senders may load the state at any
+    /// time, so it has to be replaced atomically.
+    fn persist_state(&self, state: &QueueState) -> io::Result<()>;
+
+    /// The file to watch for changes to a segment, if there is one. Receivers
+    /// poll segments without a file to watch.
+    fn watch_path(&self, segment: u64) -> Option<PathBuf> {
+        let _ = segment;
+        None
+    }
+}
+
+/// The default [`Storage`]: segments and state in files in the queue folder.
+pub struct FileStorage {
+    base: PathBuf,
+    state_base: PathBuf,
+    mmap: bool,
+}
+
+impl FileStorage {
+    /// Keeps the queue in files in a folder, which has to exist.
+    pub fn new<P: AsRef<Path>>(base: P) -> FileStorage {
+        FileStorage {
+            base: base.as_ref().to_owned(),
+            state_base: base.as_ref().to_owned(),
+            mmap: false,
+        }
+    }
+
+    /// Keeps the state of the receiver in another folder, which has to exist.
+    /// See [`ReceiverBuilder::consumer_group`](crate::ReceiverBuilder::consumer_group).
+    pub(crate) fn state_base(mut self, state_base: PathBuf) -> FileStorage {
+        self.state_base = state_base;
+        self
+    }
+
+    /// Reads the segments through a memory map. See
+    /// [`ReceiverBuilder::mmap`](crate::ReceiverBuilder::mmap).
+    pub(crate) fn mmap(mut self, mmap: bool) -> FileStorage {
+        self.mmap = mmap;
+        self
+    }
+}
+
+impl Storage for FileStorage {
+    fn segments(&self) -> io::Result<Vec<u64>> {
+        match list_segments(&self.base) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            outcome => outcome,
+        }
+    }
+
+    fn segment_len(&self, segment: u64) -> io::Result<Option<u64>> {
+        match metadata(segment_filename(&self.base, segment)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn append(&self, segment: u64) -> io::Result<Box<dyn SegmentAppend>> {
+        Ok(Box::new(SegmentFile::append(&segment_filename(
+            &self.base, segment,
+        ))?))
+    }
+
+    fn read(&self, segment: u64) -> io::Result<Box<dyn SegmentRead>> {
+        let file = open_new(segment_filename(&self.base, segment))?;
+        Ok(Box::new(SegmentFile::reading(file, self.mmap)))
+    }
+
+    fn peek(&self, segment: u64) -> io::Result<Option<Box<dyn SegmentRead>>> {
+        match sharing::open(segment_filename(&self.base, segment)) {
+            Ok(file) => Ok(Some(Box::new(file))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn delete(&self, segment: u64) -> io::Result<()> {
+        match sharing::remove_file(segment_filename(&self.base, segment)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            outcome => outcome,
+        }
+    }
+
+    fn load_state(&self) -> io::Result<QueueState> {
+        QueueStatePersistence::new().open(&self.state_base)
+    }
+
+    fn persist_state(&self, state: &QueueState) -> io::Result<()> {
+        QueueStatePersistence::at(&self.state_base).save(state)
+    }
+
+    fn watch_path(&self, segment: u64) -> Option<PathBuf> {
+        Some(segment_filename(&self.base, segment))
+    }
+}
+
+/// A [`Storage`] in two tiers: the segments and the state in files in the
+/// queue folder, as with [`FileStorage`], except for the segments that were
+/// spilled to a secondary folder (e.g., on a slow disk or a mounted bucket)
+/// with [`TieredStorage::spill`], to make room on the local volume for a deep
+/// backlog. Spilled segments are moved back to the queue folder when the
+/// receiver gets to them. The sender and the receiver of the queue have to
+/// use the same tiers.
+/// ```rust
+/// use std::sync::Arc;
+/// use yaque::storage::TieredStorage;
+/// use yaque::{ReceiverBuilder, SenderBuilder};
+///
+/// # std::fs::create_dir_all("data/my-tiered-queue").unwrap();
+/// let storage = Arc::new(TieredStorage::new("data/my-tiered-queue", "data/my-cold-tier"));
+/// let mut sender = SenderBuilder::new()
+///     .storage(storage.clone())
+///     .open("data/my-tiered-queue")
+///     .unwrap();
+/// let mut receiver = ReceiverBuilder::new()
+///     .storage(storage.clone())
+///     .open("data/my-tiered-queue")
+///     .unwrap();
+///
+/// // Every now and then, keep only the next 4 segments to read locally:
+/// storage.spill(4).unwrap();
+/// ```
+pub struct TieredStorage {
+    hot: FileStorage,
+    cold: PathBuf,
+}
+
+impl TieredStorage {
+    /// Keeps the queue in files in a folder, which has to exist, spilling
+    /// segments to another folder, which is created when needed.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(base: P, cold: Q) -> TieredStorage {
+        TieredStorage {
+            hot: FileStorage::new(base),
+            cold: cold.as_ref().to_owned(),
+        }
+    }
+
+    /// Spills the segments waiting to be received to the secondary folder,
+    /// except for the `keep_hot` segments the receiver reads next (as of the
+    /// state it saved last) and the segment the sender is writing to. The
+    /// segment the receiver is at is always kept, even if `keep_hot` is `0`.
+    /// Returns how many segments were spilled.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while moving
+    /// the segments. The segments moved before the error stay spilled.
+    pub fn spill(&self, keep_hot: u64) -> io::Result<u64> {
+        let state = self.load_state()?;
+        let mut hot = list_segments(&self.hot.base)?;
+        // (the newest segment is the one the sender is writing to)
+        hot.pop();
+        let mut spilled = 0;
+
+        for segment in hot {
+            if segment < state.segment + keep_hot.max(1) {
+                continue;
+            }
+
+            create_dir_all(&self.cold)?;
+            let from = segment_filename(&self.hot.base, segment);
+            let to = segment_filename(&self.cold, segment);
+            log::debug!("spilling segment {} from {:?} to {:?}", segment, from, to);
+
+            // (the receiver may have consumed the segment in the meantime)
+            if move_file(&from, &to)? {
+                spilled += 1;
+            }
+        }
+
+        Ok(spilled)
+    }
+
+    /// The segments in the secondary folder.
+    fn cold_segments(&self) -> io::Result<Vec<u64>> {
+        match list_segments(&self.cold) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            outcome => outcome,
+        }
+    }
+}
+
+/// Moves a file to another folder, maybe on another device, returning whether
+/// it existed. The file is copied next to its destination and then renamed,
+/// so that it never appears incomplete.
+fn move_file(from: &Path, to: &Path) -> io::Result<bool> {
+    let tmp = to.with_extension("tmp");
+    match copy(from, &tmp) {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    }
+
+    if !from.exists() {
+        // (consumed while it was being copied)
+        sharing::remove_file(&tmp)?;
+        return Ok(false);
+    }
+
+    sharing::rename(&tmp, to)?;
+    sharing::remove_file(from)?;
+
+    Ok(true)
+}
+
+impl Storage for TieredStorage {
+    fn segments(&self) -> io::Result<Vec<u64>> {
+        let mut segments = self.hot.segments()?;
+        segments.extend(self.cold_segments()?);
+        segments.sort_unstable();
+        segments.dedup();
+
+        Ok(segments)
+    }
+
+    fn segment_len(&self, segment: u64) -> io::Result<Option<u64>> {
+        match self.hot.segment_len(segment)? {
+            Some(len) => Ok(Some(len)),
+            None => FileStorage::new(&self.cold).segment_len(segment),
+        }
+    }
+
+    fn append(&self, segment: u64) -> io::Result<Box<dyn SegmentAppend>> {
+        self.hot.append(segment)
+    }
+
+    fn read(&self, segment: u64) -> io::Result<Box<dyn SegmentRead>> {
+        let hot = segment_filename(&self.hot.base, segment);
+        let cold = segment_filename(&self.cold, segment);
+
+        if !hot.exists() && move_file(&cold, &hot)? {
+            log::debug!("fetched segment {} back from {:?}", segment, self.cold);
+        }
+
+        self.hot.read(segment)
+    }
+
+    fn peek(&self, segment: u64) -> io::Result<Option<Box<dyn SegmentRead>>> {
+        // (walking the queue leaves spilled segments where they are)
+        match self.hot.peek(segment)? {
+            Some(file) => Ok(Some(file)),
+            None => FileStorage::new(&self.cold).peek(segment),
+        }
+    }
+
+    fn delete(&self, segment: u64) -> io::Result<()> {
+        self.hot.delete(segment)?;
+        FileStorage::new(&self.cold).delete(segment)
+    }
+
+    fn load_state(&self) -> io::Result<QueueState> {
+        self.hot.load_state()
+    }
+
+    fn persist_state(&self, state: &QueueState) -> io::Result<()> {
+        self.hot.persist_state(state)
+    }
+
+    fn watch_path(&self, segment: u64) -> Option<PathBuf> {
+        self.hot.watch_path(segment)
+    }
+}
+
+impl SegmentAppend for SegmentFile {
+    fn sync_data(&mut self) -> io::Result<()> {
+        SegmentFile::sync_data(self)
+    }
+}
+
+/// The top of the queue, where the sender appends: the end of the newest
+/// segment.
+pub(crate) fn top(storage: &dyn Storage) -> io::Result<QueueState> {
+    match storage.segments()?.into_iter().max() {
+        Some(segment) => Ok(QueueState {
+            segment,
+            position: storage.segment_len(segment)?.unwrap_or(0),
+        }),
+        None => Ok(QueueState::default()),
+    }
+}
+
+/// The first segment from a given segment on, which is the segment itself,
+/// unless it was deleted and newer segments exist.
+pub(crate) fn surviving_segment(storage: &dyn Storage, segment: u64) -> io::Result<u64> {
+    if storage.segment_len(segment)?.is_some() {
+        return Ok(segment);
+    }
+
+    Ok(storage
+        .segments()?
+        .into_iter()
+        .filter(|&found| found > segment)
+        .min()
+        .unwrap_or(segment))
+}
+
+/// The segments in the storage, from the oldest to the newest, with their
+/// lengths.
+pub(crate) fn segment_lens(storage: &dyn Storage) -> io::Result<Vec<(u64, u64)>> {
+    let mut segments = storage.segments()?;
+    segments.sort_unstable();
+
+    let mut lens = Vec::with_capacity(segments.len());
+    for segment in segments {
+        // (segments may be deleted in the meantime)
+        if let Some(len) = storage.segment_len(segment)? {
+            lens.push((segment, len));
+        }
+    }
+
+    Ok(lens)
+}
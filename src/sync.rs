@@ -1,30 +1,92 @@
 //! Synchronization structures based on the filesystem.
 
+use futures::future::{self, Either};
 use lazy_static::lazy_static;
 use notify::RecommendedWatcher;
 use rand::Rng;
+use std::collections::HashMap;
 use std::fs::*;
 use std::future::Future;
 use std::io::{self, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime};
 
-use crate::watcher::{file_removal_watcher, file_watcher, removal_watcher};
+use crate::sharing;
+use crate::storage::{SegmentRead, Storage};
+use crate::timer::Delay;
+use crate::watcher::{change_watcher, creation_watcher, file_removal_watcher, file_watcher};
 
 lazy_static! {
     /// A unique token to differentiate between processes wich might have the
     /// same PID, but are otherwise differente instances.
     pub(crate) static ref UNIQUE_PROCESS_TOKEN: u64 = rand::thread_rng().gen();
+
+    /// The paths of the locks held in this process without a lock file, with
+    /// their markers. See [`FileGuard::try_lock_in_process`].
+    static ref IN_PROCESS_LOCKS: Mutex<HashMap<PathBuf, Option<&'static str>>> = Mutex::default();
+}
+
+#[cfg(all(feature = "recovery", not(target_os = "wasi")))]
+lazy_static! {
+    /// The start time of this process, in seconds since the UNIX epoch. This
+    /// differentiates between processes which got the same PID one after the
+    /// other, since PIDs are recycled.
+    pub(crate) static ref PROCESS_START_TIME: Option<u64> = process_start_time(std::process::id());
+}
+
+/// Gets the start time of a process, in seconds since the UNIX epoch, if the
+/// process exists.
+#[cfg(all(feature = "recovery", not(target_os = "wasi")))]
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+    use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_process_specifics(pid, ProcessRefreshKind::new());
+    system.process(pid).map(|process| process.start_time())
+}
+
+/// The prefix of the line in a lock file with the duration of its lease, in
+/// milliseconds. See [`FileGuard::try_lock_leased`].
+const LEASE_PREFIX: &str = "lease=";
+
+/// How often a [`TailFollower`] checks a file that cannot be watched for
+/// more data, unless set otherwise.
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often [`FileGuard::lock_in_process`] checks if the lock was released.
+const IN_PROCESS_RECHECK: Duration = Duration::from_millis(1);
+
+/// The line in a lock file telling that its owner holds an OS lock on it. See
+/// [`FileGuard`].
+pub(crate) const OS_LOCK_MARKER: &str = "os-lock=shared";
+
+/// The id of this process. WASI has no notion of processes (and asking for
+/// the id panics there), so this is always 0 on WASI, where the unique
+/// process token is what tells the instances apart.
+pub(crate) fn process_id() -> u32 {
+    #[cfg(not(target_os = "wasi"))]
+    return std::process::id();
+
+    #[cfg(target_os = "wasi")]
+    return 0;
 }
 
 pub fn render_lock() -> String {
-    format!(
-        "pid={}\ntoken={}",
-        std::process::id(),
-        *UNIQUE_PROCESS_TOKEN
-    )
+    let rendered = format!("pid={}\ntoken={}", process_id(), *UNIQUE_PROCESS_TOKEN);
+
+    // The start time is only known with the `recovery` feature, which is the
+    // only one to read it:
+    #[cfg(all(feature = "recovery", not(target_os = "wasi")))]
+    if let Some(start_time) = *PROCESS_START_TIME {
+        return format!("{}\nstart={}", rendered, start_time);
+    }
+
+    rendered
 }
 
 /// A lock using the atomicity of [`OpenOptions::create_new`].
@@ -33,14 +95,43 @@ pub fn render_lock() -> String {
 /// it into the trash. It is not the most guaranteed for of atomicity, but it
 /// is one standard way of providing a persistent locking mechanism between
 /// processes.
+///
+/// The owner also holds a shared OS lock on the file (`flock` or
+/// `LockFileEx`), which the OS releases when the process dies, even if it
+/// crashes. This is how `recovery::unlock` tells stale locks apart.
+///
+/// Locks can also be held with a _lease_, which the owner renews from time to
+/// time. See [`FileGuard::try_lock_leased`].
+///
+/// Within a single process, locks can also be held without any lock file. See
+/// [`FileGuard::try_lock_in_process`].
 pub struct FileGuard {
     path: PathBuf,
     ignore: bool,
+    abandoned: bool,
+    /// The lock file, unless the lock is held in-process.
+    file: Option<File>,
+    heartbeat: Option<Heartbeat>,
 }
 
 impl Drop for FileGuard {
     fn drop(&mut self) {
-        if let Err(err) = remove_file(&self.path) {
+        // (nobody else can break an in-process lock, so it is never abandoned)
+        if self.file.is_none() {
+            IN_PROCESS_LOCKS
+                .lock()
+                .expect("in-process locks poisoned")
+                .remove(&self.path);
+            log::trace!("in-process guard on `{:?}` dropped", self.path);
+            return;
+        }
+
+        if self.abandoned {
+            log::trace!("file guard on `{:?}` abandoned", self.path);
+            return;
+        }
+
+        if let Err(err) = sharing::remove_file(&self.path) {
             if !self.ignore {
                 log::error!("unable to drop file lock: {}", err);
                 return;
@@ -57,18 +148,192 @@ impl FileGuard {
         self.ignore = true;
     }
 
+    /// Stops renewing the lease of the lock, if it has one. The lease then
+    /// expires one lease from now, unless the lock is released before.
+    pub(crate) fn stop_renewing(&mut self) -> io::Result<()> {
+        if let (Some(_), Some(file)) = (self.heartbeat.take(), &self.file) {
+            file.set_modified(SystemTime::now())?;
+        }
+
+        Ok(())
+    }
+
+    /// Gives up the lock without removing the lock file, because its lease
+    /// has expired and someone else may hold the lock by now. An expired lock
+    /// left behind is broken by the next process trying to lock.
+    pub(crate) fn abandon(mut self) {
+        self.abandoned = true;
+    }
+
     /// Tries to lock using a certain path in the disk. If the file exists, i.e.
     /// the lock is locked, returns `Ok(None)`.
     pub fn try_lock<P: AsRef<Path>>(path: P) -> io::Result<Option<FileGuard>> {
-        match OpenOptions::new().write(true).create_new(true).open(&path) {
+        FileGuard::try_lock_leased(path, None)
+    }
+
+    /// Tries to lock using a certain path in the disk, with an optional lease.
+    /// If the file exists, i.e. the lock is locked, returns `Ok(None)`.
+    ///
+    /// The owner of a leased lock renews the lease every third of its duration
+    /// by touching the lock file, from a background thread. If the lease is
+    /// not renewed in time (e.g., because the owner died on another host), any
+    /// process trying to lock breaks the lock. Unlike PIDs, this works across
+    /// hosts, as long as their clocks agree to within a fraction of the lease.
+    /// Breaking a lock is best effort: in a tight race, two processes breaking
+    /// the same lock may both get it.
+    pub fn try_lock_leased<P: AsRef<Path>>(
+        path: P,
+        lease: Option<Duration>,
+    ) -> io::Result<Option<FileGuard>> {
+        FileGuard::try_lock_file(path, lease, None)
+    }
+
+    /// Tries to lock like [`FileGuard::try_lock_leased`], writing a marker
+    /// line to the lock file that others can look for with
+    /// [`FileGuard::is_marked`].
+    pub(crate) fn try_lock_marked<P: AsRef<Path>>(
+        path: P,
+        lease: Option<Duration>,
+        marker: &'static str,
+    ) -> io::Result<Option<FileGuard>> {
+        FileGuard::try_lock_file(path, lease, Some(marker))
+    }
+
+    /// Tries to lock with a lock file, with an optional lease and marker.
+    fn try_lock_file<P: AsRef<Path>>(
+        path: P,
+        lease: Option<Duration>,
+        marker: Option<&'static str>,
+    ) -> io::Result<Option<FileGuard>> {
+        // (locks held in-process have no file)
+        if is_locked_in_process(path.as_ref()) {
+            return Ok(None);
+        }
+
+        // (on Windows, the file of a lock just released may linger for a bit)
+        let created = sharing::retry_sharing_violations(|| {
+            sharing::open_options()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+        });
+
+        match created {
             Ok(mut file) => {
-                writeln!(file, "{}", render_lock())?;
+                sharing::created(path.as_ref())?;
+
+                let mut rendered = render_lock();
+                if let Some(lease) = lease {
+                    rendered = format!("{}\n{}{}", rendered, LEASE_PREFIX, lease.as_millis());
+                }
+                if let Some(marker) = marker {
+                    rendered = format!("{}\n{}", rendered, marker);
+                }
+                writeln!(file, "{}\n{}", rendered, OS_LOCK_MARKER)?;
+
+                // Without the OS lock, the owner can only be found by its PID:
+                if let Err(err) = file.try_lock_shared() {
+                    log::debug!("unable to hold OS lock on `{:?}`: {}", path.as_ref(), err);
+                    file.set_len(0)?;
+                    file.seek(io::SeekFrom::Start(0))?;
+                    writeln!(file, "{}", rendered)?;
+                }
+
+                let heartbeat = if let Some(lease) = lease {
+                    Some(Heartbeat::start(file.try_clone()?, lease)?)
+                } else {
+                    None
+                };
+
                 Ok(Some(FileGuard {
                     path: path.as_ref().to_path_buf(),
                     ignore: false,
+                    abandoned: false,
+                    file: Some(file),
+                    heartbeat,
                 }))
             }
-            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                if break_expired_lease(path.as_ref())? {
+                    log::debug!("broke expired lease on `{:?}`", path.as_ref());
+                    FileGuard::try_lock_file(path, lease, marker)
+                } else {
+                    Ok(None)
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Tries to lock a certain path within this process only, without
+    /// creating any lock file. If the path is locked in this process (either
+    /// way), returns `None`. Lock files on the path, even stale ones left by
+    /// other processes, are ignored: only use this for queues that no other
+    /// process opens.
+    pub(crate) fn try_lock_in_process<P: AsRef<Path>>(path: P) -> Option<FileGuard> {
+        FileGuard::lock_in_process_with(path, None)
+    }
+
+    /// Tries to lock like [`FileGuard::try_lock_in_process`], with a marker
+    /// that others can look for with [`FileGuard::is_marked`].
+    pub(crate) fn try_lock_in_process_marked<P: AsRef<Path>>(
+        path: P,
+        marker: &'static str,
+    ) -> Option<FileGuard> {
+        FileGuard::lock_in_process_with(path, Some(marker))
+    }
+
+    /// Tries to lock within this process only, with an optional marker.
+    fn lock_in_process_with<P: AsRef<Path>>(
+        path: P,
+        marker: Option<&'static str>,
+    ) -> Option<FileGuard> {
+        let mut locks = IN_PROCESS_LOCKS.lock().expect("in-process locks poisoned");
+
+        if locks.contains_key(path.as_ref()) {
+            return None;
+        }
+
+        locks.insert(path.as_ref().to_path_buf(), marker);
+
+        Some(FileGuard {
+            path: path.as_ref().to_path_buf(),
+            ignore: false,
+            abandoned: false,
+            file: None,
+            heartbeat: None,
+        })
+    }
+
+    /// Awaits for the lock on a certain path to be unlocked within this
+    /// process and locks it, without creating any lock file. See
+    /// [`FileGuard::try_lock_in_process`].
+    pub(crate) async fn lock_in_process<P: AsRef<Path>>(path: P) -> FileGuard {
+        loop {
+            if let Some(file_guard) = FileGuard::try_lock_in_process(path.as_ref()) {
+                break file_guard;
+            }
+
+            Delay::new(IN_PROCESS_RECHECK).await;
+        }
+    }
+
+    /// Whether a certain path is locked, in this process or with a lock file,
+    /// with a given marker. See [`FileGuard::try_lock_marked`].
+    pub(crate) fn is_marked<P: AsRef<Path>>(path: P, marker: &str) -> io::Result<bool> {
+        let in_process = IN_PROCESS_LOCKS
+            .lock()
+            .expect("in-process locks poisoned")
+            .get(path.as_ref())
+            .copied();
+
+        if let Some(in_process) = in_process {
+            return Ok(in_process == Some(marker));
+        }
+
+        match read_to_string(path) {
+            Ok(contents) => Ok(contents.lines().any(|line| line == marker)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
             Err(err) => Err(err),
         }
     }
@@ -76,19 +341,156 @@ impl FileGuard {
     /// Awaits for the lock in a certain disk path to be unlocked and locks it
     /// when possible.
     pub async fn lock<P: AsRef<Path>>(path: P) -> io::Result<FileGuard> {
+        FileGuard::lock_leased(path, None).await
+    }
+
+    /// Awaits for the lock in a certain disk path to be unlocked (or for its
+    /// lease to expire) and locks it with an optional lease when possible. See
+    /// [`FileGuard::try_lock_leased`].
+    pub async fn lock_leased<P: AsRef<Path>>(
+        path: P,
+        lease: Option<Duration>,
+    ) -> io::Result<FileGuard> {
         // Set up waker:
         let waker = Arc::new(Mutex::new(None));
 
-        // Set up watcher:
-        let _watcher = file_removal_watcher(path.as_ref(), waker.clone());
+        // Set up watcher (or else, check the lock every so often):
+        let watcher = file_removal_watcher(path.as_ref(), waker.clone());
+        let poll_interval = match watcher {
+            Ok(_) => None,
+            Err(err) => {
+                log::debug!("unable to watch `{:?}`, polling: {}", path.as_ref(), err);
+                Some(FALLBACK_POLL_INTERVAL)
+            }
+        };
 
-        Lock { path, waker }.await
+        loop {
+            let lock = Lock {
+                path: path.as_ref(),
+                lease,
+                waker: waker.clone(),
+            };
+
+            // Leases of dead owners expire without the lock file being removed:
+            let expiry =
+                time_to_expiry(path.as_ref())?.map(|expiry| expiry + Duration::from_millis(10));
+            let recheck = match (expiry, poll_interval) {
+                (Some(expiry), Some(interval)) => Some(expiry.min(interval)),
+                (expiry, interval) => expiry.or(interval),
+            };
+
+            match recheck {
+                Some(recheck) => {
+                    if let Either::Left((locked, _)) =
+                        future::select(lock, Delay::new(recheck)).await
+                    {
+                        break locked;
+                    }
+                }
+                None => break lock.await,
+            }
+        }
     }
 }
 
-/// Future for the internals of [`FileGuard::lock`].
+/// Whether a path is locked within this process, without a lock file. See
+/// [`FileGuard::try_lock_in_process`].
+fn is_locked_in_process(path: &Path) -> bool {
+    IN_PROCESS_LOCKS
+        .lock()
+        .expect("in-process locks poisoned")
+        .contains_key(path)
+}
+
+/// Renews the lease of a lock until dropped.
+struct Heartbeat {
+    _stop: mpsc::Sender<()>,
+}
+
+impl Heartbeat {
+    /// Starts renewing the lease of a lock file. This fails if no thread can
+    /// be spawned to do so (e.g., on WASI).
+    fn start(file: File, lease: Duration) -> io::Result<Heartbeat> {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        std::thread::Builder::new()
+            .name("yaque lease heartbeat".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(lease / 3) {
+                    if let Err(err) = file.set_modified(SystemTime::now()) {
+                        log::error!("unable to renew lease of lock: {}", err);
+                    }
+                }
+            })?;
+
+        Ok(Heartbeat { _stop: stop })
+    }
+}
+
+/// Gets the lease of a lock file, if it has one.
+fn read_lease(path: &Path) -> io::Result<Option<Duration>> {
+    let contents = match read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    Ok(contents
+        .lines()
+        .find_map(|line| line.strip_prefix(LEASE_PREFIX))
+        .and_then(|millis| millis.parse::<u64>().ok())
+        .map(Duration::from_millis))
+}
+
+/// Gets how long until the lease of a lock file expires, if the lock file has
+/// a lease.
+fn time_to_expiry(path: &Path) -> io::Result<Option<Duration>> {
+    let lease = if let Some(lease) = read_lease(path)? {
+        lease
+    } else {
+        return Ok(None);
+    };
+
+    let renewed = match metadata(path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    // (the clock of the owner may be ahead)
+    let elapsed = renewed.elapsed().unwrap_or_default();
+    Ok(Some(lease.saturating_sub(elapsed)))
+}
+
+/// Removes a lock file if its lease has expired. Returns whether the lock was
+/// broken, by this process or by another one.
+fn break_expired_lease(path: &Path) -> io::Result<bool> {
+    if time_to_expiry(path)? != Some(Duration::ZERO) {
+        return Ok(false);
+    }
+
+    // Only one process gets to move the lock file away:
+    let broken = path.with_extension(format!("broken-{}", *UNIQUE_PROCESS_TOKEN));
+    match sharing::rename(path, &broken) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(err) => return Err(err),
+    }
+
+    // But another process may have broken the lease and locked in the meantime:
+    if time_to_expiry(&broken)? == Some(Duration::ZERO) {
+        sharing::remove_file(&broken)?;
+        Ok(true)
+    } else {
+        sharing::rename(&broken, path)?;
+        Ok(false)
+    }
+}
+
+/// Future for the internals of [`FileGuard::lock_leased`].
 struct Lock<P: AsRef<Path>> {
     path: P,
+    lease: Option<Duration>,
     waker: Arc<Mutex<Option<Waker>>>,
 }
 
@@ -99,7 +501,7 @@ impl<P: AsRef<Path>> Future for Lock<P> {
         let mut lock = self.waker.lock().expect("waker mutex poisoned");
         *lock = Some(context.waker().clone());
 
-        match FileGuard::try_lock(self.path.as_ref()) {
+        match FileGuard::try_lock_leased(self.path.as_ref(), self.lease) {
             Ok(Some(file_guard)) => Poll::Ready(Ok(file_guard)),
             Ok(None) => Poll::Pending,
             Err(err) => Poll::Ready(Err(err)),
@@ -107,55 +509,96 @@ impl<P: AsRef<Path>> Future for Lock<P> {
     }
 }
 
-fn open_new<P: AsRef<Path>>(path: P) -> io::Result<File> {
+pub(crate) fn open_new<P: AsRef<Path>>(path: P) -> io::Result<File> {
     // "Touch" the file and then open it to ensure its existence:
     // Any errors here are OK.
-    let maybe_new = OpenOptions::new().create_new(true).append(true).open(&path);
+    let maybe_new = sharing::open_options()
+        .create_new(true)
+        .append(true)
+        .open(&path);
 
     if maybe_new.is_ok() {
         log::debug!("file `{:?}` didn't exist. Created new", path.as_ref());
+        sharing::created(path.as_ref())?;
     }
 
-    File::open(&path)
+    sharing::open(&path)
 }
 
-/// Follows a file asynchronously. The file needs not to even to exist.
+/// Follows a segment asynchronously. The segment needs not to even to exist.
 pub struct TailFollower {
-    file: io::BufReader<File>,
+    file: io::BufReader<Box<dyn SegmentRead>>,
     read_and_unused: usize,
-    _watcher: RecommendedWatcher,
+    /// The watcher of the file, unless the file cannot be watched.
+    _watcher: Option<RecommendedWatcher>,
     waker: Arc<Mutex<Option<Waker>>>,
+    /// How often to check the file for more data while waiting, if at all.
+    poll_interval: Option<Duration>,
+    /// The timer to the next check of the file (lazy inited!).
+    recheck: Option<Delay>,
 }
 
 impl TailFollower {
-    /// Creates a new following file.
-    fn new(path: &Path, file: File) -> TailFollower
-    {
+    /// Creates a new following segment, reading it through a buffer of
+    /// `buffer_size` bytes. The segment is checked for more data whenever its
+    /// file (if any) changes and every `poll_interval`, if set.
+    fn new(
+        path: Option<&Path>,
+        file: Box<dyn SegmentRead>,
+        buffer_size: usize,
+        mut poll_interval: Option<Duration>,
+    ) -> TailFollower {
         // Set up waker:
         let waker = Arc::new(Mutex::new(None));
 
-        // Set up watcher:
-        let watcher = file_watcher(path, waker.clone());
+        // Set up watcher (or poll, if there are no events to watch):
+        let watcher = match path.map(|path| (path, file_watcher(path, waker.clone()))) {
+            Some((_, Ok(watcher))) => Some(watcher),
+            Some((path, Err(err))) => {
+                log::warn!("cannot watch {:?} ({}). Polling instead", path, err);
+                poll_interval = poll_interval.or(Some(FALLBACK_POLL_INTERVAL));
+                None
+            }
+            None => {
+                poll_interval = poll_interval.or(Some(FALLBACK_POLL_INTERVAL));
+                None
+            }
+        };
 
         TailFollower {
-            file: io::BufReader::new(file),
+            file: io::BufReader::with_capacity(buffer_size, file),
             read_and_unused: 0,
             _watcher: watcher,
             waker,
+            poll_interval,
+            recheck: None,
         }
     }
 
-    /// Tries to open a file for reading, creating it, if necessary. This is
-    /// not atomic: someone might sneak in just in the right moment and delete
-    /// the file before we open it for reading. To prevent this, use a lockfile.
-    pub fn open(path: &Path) -> io::Result<TailFollower>
-    {
-        let file = open_new(&path)?;
-
-        Ok(TailFollower::new(path, file))
+    /// Tries to open a segment for reading, creating it, if necessary. This
+    /// is not atomic: someone might sneak in just in the right moment and
+    /// delete the segment before we open it for reading. To prevent this, use
+    /// a lockfile.
+    pub fn open(
+        storage: &dyn Storage,
+        segment: u64,
+        buffer_size: usize,
+        poll_interval: Option<Duration>,
+    ) -> io::Result<TailFollower> {
+        let file = storage.read(segment)?;
+
+        Ok(TailFollower::new(
+            storage.watch_path(segment).as_deref(),
+            file,
+            buffer_size,
+            poll_interval,
+        ))
     }
 
+    /// Seeks the underlying file. This forgets any bytes read by an
+    /// incomplete [`TailFollower::read_exact`].
     pub fn seek(&mut self, seek: io::SeekFrom) -> io::Result<()> {
+        self.read_and_unused = 0;
         self.file.seek(seek).map(|_| ())
     }
 
@@ -186,6 +629,8 @@ impl TailFollower {
             buffer,
             waker: &self.waker,
             read_and_unused: &mut self.read_and_unused,
+            poll_interval: self.poll_interval,
+            recheck: &mut self.recheck,
             was_polled: false,
         }
     }
@@ -215,10 +660,12 @@ impl TailFollower {
 
 /// The future returned by `TailFollower::read_exact`.
 pub struct ReadExact<'a> {
-    file: &'a mut io::BufReader<File>,
+    file: &'a mut io::BufReader<Box<dyn SegmentRead>>,
     buffer: &'a mut [u8],
     waker: &'a Mutex<Option<Waker>>,
     read_and_unused: &'a mut usize,
+    poll_interval: Option<Duration>,
+    recheck: &'a mut Option<Delay>,
     was_polled: bool,
 }
 
@@ -270,13 +717,44 @@ impl<'a> Future for ReadExact<'a> {
             *lock = Some(context.waker().clone());
 
             // Now, you will have to recheck (TOCTOU!)
-            self.read_until_you_drain()
+            let outcome = self.read_until_you_drain();
+            drop(lock);
+
+            if outcome.is_pending() {
+                self.schedule_recheck(context);
+            } else {
+                *self.recheck = None;
+            }
+
+            outcome
         } else {
+            *self.recheck = None;
             outcome
         }
     }
 }
 
+impl<'a> ReadExact<'a> {
+    /// Makes sure that the future is woken after the poll interval, if set,
+    /// even if the file watcher misses the changes to the file.
+    fn schedule_recheck(&mut self, context: &mut Context<'_>) {
+        if let Some(poll_interval) = self.poll_interval {
+            loop {
+                let recheck = self
+                    .recheck
+                    .get_or_insert_with(|| Delay::new(poll_interval));
+
+                if Pin::new(recheck).poll(context).is_pending() {
+                    break;
+                }
+
+                // It was time to check: start over.
+                *self.recheck = None;
+            }
+        }
+    }
+}
+
 impl<'a> Drop for ReadExact<'a> {
     fn drop(&mut self) {
         if !self.was_polled {
@@ -285,31 +763,92 @@ impl<'a> Drop for ReadExact<'a> {
     }
 }
 
-/// A future that resolves every time that a file is deleted in a directory. This
-/// future can be polled over and over again to make a stream of deletions.
-pub struct DeletionEvent {
+/// A future that resolves once anything is created, modified or removed in a
+/// directory after the first poll, such as the receiver saving its state or
+/// removing a segment. This future can be polled over and over again to make a
+/// stream of changes.
+///
+/// If the directory cannot be watched, this never resolves. Always wait on it
+/// with a timeout.
+pub struct ChangeEvent {
     waker: Arc<Mutex<Option<Waker>>>,
-    _watcher: RecommendedWatcher,
+    is_waiting: bool,
+    _watcher: Option<RecommendedWatcher>,
 }
 
-impl Future for DeletionEvent {
+impl Future for ChangeEvent {
     type Output = ();
-    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut lock = this.waker.lock().expect("waker mutex poisoned");
+
+        // The watcher takes the waker when something changes (and then, wait
+        // for the next change):
+        if this.is_waiting && lock.is_none() {
+            this.is_waiting = false;
+            return Poll::Ready(());
+        }
+
         // Set the waker in the file watcher:
-        let mut lock = self.waker.lock().expect("waker mutex poisoned");
         *lock = Some(context.waker().clone());
+        this.is_waiting = true;
 
-        Poll::Ready(())
+        Poll::Pending
     }
 }
 
-impl DeletionEvent {
-    pub fn new(base: &Path) -> DeletionEvent {
+impl ChangeEvent {
+    pub fn new(base: &Path) -> ChangeEvent {
         let waker = Arc::new(Mutex::new(None));
-        let watcher = removal_watcher(base, Arc::clone(&waker));
+        let watcher = change_watcher(base, Arc::clone(&waker))
+            .map_err(|err| log::debug!("unable to watch `{:?}` for changes: {}", base, err))
+            .ok();
 
-        DeletionEvent {
+        ChangeEvent {
             waker,
+            is_waiting: false,
+            _watcher: watcher,
+        }
+    }
+}
+
+/// A future that resolves once anything is created in a folder after the
+/// first poll. If the folder cannot be watched, this never resolves.
+pub struct CreationEvent {
+    waker: Arc<Mutex<Option<Waker>>>,
+    is_waiting: bool,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl Future for CreationEvent {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut lock = this.waker.lock().expect("waker mutex poisoned");
+
+        // The watcher takes the waker when something is created:
+        if this.is_waiting && lock.is_none() {
+            return Poll::Ready(());
+        }
+
+        // Set the waker in the file watcher:
+        *lock = Some(context.waker().clone());
+        this.is_waiting = true;
+
+        Poll::Pending
+    }
+}
+
+impl CreationEvent {
+    pub fn new(base: &Path) -> CreationEvent {
+        let waker = Arc::new(Mutex::new(None));
+        let watcher = creation_watcher(base, Arc::clone(&waker))
+            .map_err(|err| log::debug!("unable to watch `{:?}` for creations: {}", base, err))
+            .ok();
+
+        CreationEvent {
+            waker,
+            is_waiting: false,
             _watcher: watcher,
         }
     }
@@ -318,18 +857,20 @@ impl DeletionEvent {
 /// A simpler, sync alternative to [`TailFollower`] used in the implementation
 /// of [`crate::QueueIter`].
 pub struct SyncFollower {
-    file: io::BufReader<File>,
+    file: io::BufReader<Box<dyn SegmentRead>>,
 }
 
 impl SyncFollower {
-    /// Tries to open a file for reading, creating it, if necessary. This is
-    /// not atomic: someone might sneak in just in the right moment and delete
-    /// the file before we open it for reading. To prevent this, use a lockfile.
-    pub fn open<P>(path: P) -> io::Result<SyncFollower>
-    where
-        P: 'static + AsRef<Path> + Send + Sync,
-    {
-        let file = io::BufReader::new(open_new(&path)?);
+    /// Tries to open a segment for reading without receiving from it (see
+    /// [`Storage::peek`]), creating it, if necessary. This is not atomic:
+    /// someone might sneak in just in the right moment and delete the segment
+    /// before we open it for reading. To prevent this, use a lockfile.
+    pub fn open(storage: &dyn Storage, segment: u64) -> io::Result<SyncFollower> {
+        let file = match storage.peek(segment)? {
+            Some(file) => file,
+            None => storage.read(segment)?,
+        };
+        let file = io::BufReader::new(file);
 
         Ok(SyncFollower { file })
     }
@@ -342,3 +883,34 @@ impl SyncFollower {
         self.file.read_exact(buffer)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::FileStorage;
+
+    #[test]
+    fn poll_unwatched_file() {
+        create_dir_all("data/poll-unwatched-file").unwrap();
+        let path = Path::new("data/poll-unwatched-file/0.q");
+        let _ = remove_file(path);
+
+        let storage = FileStorage::new("data/poll-unwatched-file");
+        let mut tail_follower =
+            TailFollower::open(&storage, 0, 8, Some(Duration::from_millis(10))).unwrap();
+        // Events are all missed:
+        tail_follower._watcher = None;
+
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            file.write_all(b"hello").unwrap();
+        });
+
+        let mut buffer = [0; 5];
+        futures::executor::block_on(tail_follower.read_exact(&mut buffer)).unwrap();
+        assert_eq!(&buffer, b"hello");
+
+        writer.join().unwrap();
+    }
+}
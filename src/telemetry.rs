@@ -0,0 +1,194 @@
+//! Hooks to feed telemetry systems with what happens in a queue. See
+//! [`QueueMetrics`] and, with the `metrics` feature, [`MetricsExporter`].
+
+use std::path::Path;
+use std::sync::Arc;
+
+#[cfg(feature = "metrics")]
+use crate::queue::queue_disk_usage;
+
+/// Callbacks for what senders and receivers do, so that queues can be
+/// monitored by any telemetry system. Register an implementation with
+/// [`crate::SenderBuilder::metrics`] and [`crate::ReceiverBuilder::metrics`].
+///
+/// Every callback gets the folder of the queue it is about, since the same
+/// implementation is also used for the priority lanes and the dead-letter
+/// queue of the sender or the receiver it was registered with. All callbacks
+/// do nothing by default. They are called synchronously while sending and
+/// receiving, so they should be cheap (e.g., updating some atomic counters).
+pub trait QueueMetrics: Send + Sync {
+    /// An element of `len` bytes (as it was given to the sender) was sent.
+    fn item_sent(&self, base: &Path, len: u64) {
+        let _ = (base, len);
+    }
+
+    /// An element of `len` bytes was received and its transaction was
+    /// committed. Elements that are rolled back are not counted.
+    fn item_received(&self, base: &Path, len: u64) {
+        let _ = (base, len);
+    }
+
+    /// A transaction with `items` elements was rolled back, so that they will
+    /// be received again.
+    fn rolled_back(&self, base: &Path, items: u64) {
+        let _ = (base, items);
+    }
+
+    /// The sender flushed `bytes` bytes to the current segment, including
+    /// headers, metadata and the footers of closed segments.
+    fn bytes_written(&self, base: &Path, bytes: u64) {
+        let _ = (base, bytes);
+    }
+
+    /// The sender closed the current segment and moved on to `segment`.
+    fn segment_rotated(&self, base: &Path, segment: u64) {
+        let _ = (base, segment);
+    }
+
+    /// The receiver saved its state, at `position` in `segment`.
+    fn state_saved(&self, base: &Path, segment: u64, position: u64) {
+        let _ = (base, segment, position);
+    }
+}
+
+/// The callbacks senders and receivers report to by default: none, or a
+/// [`MetricsExporter`] with the `metrics` feature.
+pub(crate) fn default_metrics() -> Option<Arc<dyn QueueMetrics>> {
+    #[cfg(feature = "metrics")]
+    {
+        Some(Arc::new(MetricsExporter))
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    {
+        None
+    }
+}
+
+/// Reports what happens in every queue of the process to the [`metrics`]
+/// crate, enabled by the `metrics` feature. With the feature, this is the
+/// default of [`crate::SenderBuilder::metrics`] and
+/// [`crate::ReceiverBuilder::metrics`], so installing a recorder (e.g., the
+/// Prometheus exporter of `metrics-exporter-prometheus`) is all it takes.
+///
+/// All metrics have a `queue` label with the folder of the queue:
+///
+/// * `yaque_items_sent_total` and `yaque_items_received_total` (counters)
+///   count the elements sent and received. Their rates are the send and
+///   receive rates.
+/// * `yaque_bytes_sent_total` and `yaque_bytes_received_total` (counters)
+///   count the bytes of these elements.
+/// * `yaque_bytes_written_total` (counter) counts the bytes written to the
+///   segments.
+/// * `yaque_segments_rotated_total` (counter) counts the segments closed.
+/// * `yaque_rollbacks_total` (counter) counts the transactions rolled back.
+/// * `yaque_queue_depth` (gauge) is the number of elements sent minus the
+///   number of elements received by this process. It matches the number of
+///   elements in the queue only if the queue started empty and both sides are
+///   in this process.
+/// * `yaque_bytes_pending` (gauge) is the size of the segments waiting to be
+///   received, updated when the receiver saves its state. See
+///   [`crate::queue::DiskUsage::live`].
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsExporter;
+
+#[cfg(feature = "metrics")]
+impl MetricsExporter {
+    /// The value of the `queue` label.
+    fn label(base: &Path) -> String {
+        base.to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl QueueMetrics for MetricsExporter {
+    fn item_sent(&self, base: &Path, len: u64) {
+        let queue = MetricsExporter::label(base);
+        ::metrics::counter!("yaque_items_sent_total", "queue" => queue.clone()).increment(1);
+        ::metrics::counter!("yaque_bytes_sent_total", "queue" => queue.clone()).increment(len);
+        ::metrics::gauge!("yaque_queue_depth", "queue" => queue).increment(1.0);
+    }
+
+    fn item_received(&self, base: &Path, len: u64) {
+        let queue = MetricsExporter::label(base);
+        ::metrics::counter!("yaque_items_received_total", "queue" => queue.clone()).increment(1);
+        ::metrics::counter!("yaque_bytes_received_total", "queue" => queue.clone()).increment(len);
+        ::metrics::gauge!("yaque_queue_depth", "queue" => queue).decrement(1.0);
+    }
+
+    fn rolled_back(&self, base: &Path, _items: u64) {
+        let queue = MetricsExporter::label(base);
+        ::metrics::counter!("yaque_rollbacks_total", "queue" => queue).increment(1);
+    }
+
+    fn bytes_written(&self, base: &Path, bytes: u64) {
+        let queue = MetricsExporter::label(base);
+        ::metrics::counter!("yaque_bytes_written_total", "queue" => queue).increment(bytes);
+    }
+
+    fn segment_rotated(&self, base: &Path, _segment: u64) {
+        let queue = MetricsExporter::label(base);
+        ::metrics::counter!("yaque_segments_rotated_total", "queue" => queue).increment(1);
+    }
+
+    fn state_saved(&self, base: &Path, _segment: u64, _position: u64) {
+        match queue_disk_usage(base) {
+            Ok(usage) => {
+                let queue = MetricsExporter::label(base);
+                ::metrics::gauge!("yaque_bytes_pending", "queue" => queue).set(usage.live as f64);
+            }
+            Err(err) => log::warn!("could not get the disk usage of {:?}: {}", base, err),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use super::*;
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    #[test]
+    fn export_metrics() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        ::metrics::with_local_recorder(&recorder, || {
+            let base = Path::new("data/export-metrics");
+            MetricsExporter.item_sent(base, 10);
+            MetricsExporter.item_sent(base, 5);
+            MetricsExporter.item_received(base, 10);
+            MetricsExporter.rolled_back(base, 1);
+        });
+
+        let mut values = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _unit, _description, value)| (key.key().name().to_owned(), value))
+            .collect::<Vec<_>>();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(
+            values,
+            [
+                (
+                    "yaque_bytes_received_total".to_owned(),
+                    DebugValue::Counter(10)
+                ),
+                ("yaque_bytes_sent_total".to_owned(), DebugValue::Counter(15)),
+                (
+                    "yaque_items_received_total".to_owned(),
+                    DebugValue::Counter(1)
+                ),
+                ("yaque_items_sent_total".to_owned(), DebugValue::Counter(2)),
+                (
+                    "yaque_queue_depth".to_owned(),
+                    DebugValue::Gauge(1.0.into())
+                ),
+                ("yaque_rollbacks_total".to_owned(), DebugValue::Counter(1)),
+            ]
+        );
+    }
+}
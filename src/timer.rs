@@ -0,0 +1,146 @@
+//! The timers behind the waits of the queue, such as parking at elements that
+//! are not due yet or checking a full queue again after a while. These work
+//! under any async runtime, or none at all: by default, they come from
+//! `futures-timer`, which keeps its own timer thread. On WASI, which has no
+//! threads, waiting blocks instead, a little at a time.
+//!
+//! With the `tokio`, `async-std` or `smol` feature, the timers of that
+//! runtime are used instead, in this order of precedence if more than one is
+//! enabled. Since tokio timers only work within a tokio runtime (which must
+//! have its timers enabled), the default timers are still used outside of one
+//! (e.g., in the [`crate::blocking`] mirrors).
+//!
+//! The queue never spawns tasks. The only background work it does (renewing
+//! the lease of a lock) runs on a thread of its own.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A timer of whichever implementation is used.
+type BoxedDelay = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A future that completes once a given time has elapsed.
+pub(crate) struct Delay {
+    inner: BoxedDelay,
+}
+
+impl Delay {
+    /// Creates a future that completes after `duration`.
+    pub(crate) fn new(duration: Duration) -> Delay {
+        Delay {
+            inner: runtime_delay(duration).unwrap_or_else(|| default_delay(duration)),
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        self.inner.as_mut().poll(context)
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn default_delay(duration: Duration) -> BoxedDelay {
+    Box::pin(futures_timer::Delay::new(duration))
+}
+
+#[cfg(target_os = "wasi")]
+fn default_delay(duration: Duration) -> BoxedDelay {
+    Box::pin(wasi::Sleep::new(duration))
+}
+
+#[cfg(target_os = "wasi")]
+mod wasi {
+    use super::*;
+    use std::time::Instant;
+
+    /// How long a [`Sleep`] blocks at most before yielding to other tasks.
+    const TICK: Duration = Duration::from_millis(1);
+
+    /// A timer without a timer thread. Each poll blocks for a tick and then
+    /// yields, so that the other tasks of the executor still get to run.
+    pub(super) struct Sleep {
+        deadline: Instant,
+    }
+
+    impl Sleep {
+        pub(super) fn new(duration: Duration) -> Sleep {
+            Sleep {
+                deadline: Instant::now() + duration,
+            }
+        }
+    }
+
+    impl Future for Sleep {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Poll::Ready(());
+            }
+
+            std::thread::sleep(remaining.min(TICK));
+            context.waker().wake_by_ref();
+
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+fn runtime_delay(duration: Duration) -> Option<BoxedDelay> {
+    // (`tokio::time::sleep` panics outside of a runtime)
+    tokio::runtime::Handle::try_current()
+        .ok()
+        .map(|_| Box::pin(tokio::time::sleep(duration)) as BoxedDelay)
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+fn runtime_delay(duration: Duration) -> Option<BoxedDelay> {
+    Some(Box::pin(async_std::task::sleep(duration)))
+}
+
+#[cfg(all(feature = "smol", not(any(feature = "tokio", feature = "async-std"))))]
+fn runtime_delay(duration: Duration) -> Option<BoxedDelay> {
+    let timer = async_io::Timer::after(duration);
+    Some(Box::pin(async move {
+        timer.await;
+    }))
+}
+
+#[cfg(not(any(feature = "tokio", feature = "async-std", feature = "smol")))]
+fn runtime_delay(_duration: Duration) -> Option<BoxedDelay> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn delay_elapses() {
+        let started_at = Instant::now();
+        futures::executor::block_on(Delay::new(Duration::from_millis(20)));
+        assert!(started_at.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn delay_elapses_in_tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        let started_at = Instant::now();
+        runtime.block_on(Delay::new(Duration::from_millis(20)));
+        assert!(started_at.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,83 @@
+//! Pluggable transformations of the items on the data path. See [`Codec`].
+
+use std::io;
+use std::sync::Arc;
+
+/// Transforms the items on their way into the queue and back, e.g., to add
+/// an envelope, or a compression or an encryption of your own. See
+/// [`crate::SenderBuilder::codec`] and [`crate::ReceiverBuilder::codec`].
+///
+/// Codecs are stacked: the sender encodes each item with its codecs in the
+/// order they were added, and the receiver decodes it in the reverse order.
+/// All of that happens before the built-in compression and encryption (if
+/// any), which are the outermost layers. Errors returned by the codecs are
+/// returned by the sender or by the receiver as they are.
+///
+/// Unlike the built-in transformations, codecs are not recorded in the queue:
+/// the sender and the receiver have to be set up with the same stack of
+/// codecs.
+pub trait Codec: Send + Sync {
+    /// Encodes an item as given to the sender.
+    fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Decodes an item encoded with [`Codec::encode`].
+    fn decode(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Encodes an item with a stack of codecs. Returns `None` if there are no
+/// codecs, i.e., if the encoded item is the item itself.
+pub(crate) fn encode(codecs: &[Arc<dyn Codec>], data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut encoded: Option<Vec<u8>> = None;
+
+    for codec in codecs {
+        encoded = Some(codec.encode(encoded.as_deref().unwrap_or(data))?);
+    }
+
+    Ok(encoded)
+}
+
+/// Decodes an item with a stack of codecs, undoing [`encode`].
+pub(crate) fn decode(codecs: &[Arc<dyn Codec>], mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+    for codec in codecs.iter().rev() {
+        data = codec.decode(&data)?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Prefix(u8);
+
+    impl Codec for Prefix {
+        fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut encoded = vec![self.0];
+            encoded.extend_from_slice(data);
+            Ok(encoded)
+        }
+
+        fn decode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            match data.split_first() {
+                Some((&prefix, rest)) if prefix == self.0 => Ok(rest.to_vec()),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad prefix")),
+            }
+        }
+    }
+
+    #[test]
+    fn stack() {
+        let codecs: Vec<Arc<dyn Codec>> = vec![Arc::new(Prefix(1)), Arc::new(Prefix(2))];
+
+        assert_eq!(encode(&[], b"abc").unwrap(), None);
+
+        let encoded = encode(&codecs, b"abc").unwrap().unwrap();
+        assert_eq!(encoded, b"\x02\x01abc");
+        assert_eq!(decode(&codecs, encoded).unwrap(), b"abc");
+
+        // Decoding in the wrong order fails:
+        let reversed = [codecs[1].clone(), codecs[0].clone()];
+        assert!(decode(&reversed, b"\x02\x01abc".to_vec()).is_err());
+    }
+}
@@ -2,10 +2,13 @@
 //!
 
 use semver::{Version, VersionReq};
-use std::io;
-use std::path::Path;
+use std::fs::*;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 use crate::mutex::Mutex;
+use crate::sharing;
+use crate::queue::{compaction_marker_filename, tombstone_filename};
 
 // fn get_version_for_queue<P: AsRef<Path>>(base: P) -> io::Result<Version> {
 //     let version_file_contents = read_to_string(base.as_ref().join("yaque-version"))?;
@@ -60,11 +63,92 @@ use crate::mutex::Mutex;
 //     }
 // }
 
+/// The version of the on-disk format of the queues written by this version of
+/// `yaque`. Each queue records the format it was created with in its folder.
+/// Queues created before the format was recorded are of format `1`.
+///
+/// The formats so far are:
+/// * `1`: the original format, with elements, metadata and EOF headers.
+/// * `2`: closed segments end with a [`crate::queue::SegmentFooter`].
+///
+/// Older formats are upgraded in place by [`crate::queue::migrate_queue`].
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The oldest format that can still be opened without migrating it first.
+const MIN_FORMAT_VERSION: u32 = 1;
+
+/// The name of the file recording the format of the queue.
+fn format_filename(base: &Path) -> PathBuf {
+    base.join("format")
+}
+
+/// Reads the format of the queue. Queues without a format file are of format
+/// `1`.
+pub(crate) fn read_format_version(base: &Path) -> io::Result<u32> {
+    let contents = match read_to_string(format_filename(base)) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(1),
+        Err(err) => return Err(err),
+    };
+
+    contents.trim().parse().map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "failed to parse `{:?}` format file: {}; contents were `{}`",
+                format_filename(base),
+                err,
+                contents
+            ),
+        )
+    })
+}
+
+/// Records the format of the queue. The file is replaced atomically, so that
+/// a concurrent reader sees either the old format or the new one.
+pub(crate) fn write_format_version(base: &Path, format: u32) -> io::Result<()> {
+    let temp = base.join("format.tmp");
+    let mut file = File::create(&temp)?;
+    sharing::created(&temp)?;
+    writeln!(file, "{}", format)?;
+    file.sync_all()?;
+    rename(temp, format_filename(base))
+}
+
 /// Gets the version of the queue, or sets it if there is not one and then checks if the version is
 /// compatible with the current loaded version of `yaque`. It uses a mutex to implement atomicity
 /// (yes, we have had some race conditions during testing), but, for the sake of API compatibility
 /// in [`crate::Sender::open`] and [`crate::Receiver::open`], it performs a spinlock, instead of `.await`ing.
+///
+/// This also records the format of new queues and fails with an error of kind
+/// [`io::ErrorKind::InvalidData`] if the format of the queue is too new for
+/// this version of `yaque` or too old to be opened without migrating it. If
+/// the queue was moved with [`crate::queue::move_queue`], it fails with an
+/// error of kind [`io::ErrorKind::NotFound`]. It also fails if a compaction
+/// of the queue was interrupted (see [`crate::queue::compact_queue`]).
 pub fn check_queue_version<P: AsRef<Path>>(base: P) -> io::Result<()> {
+    match read_to_string(tombstone_filename(base.as_ref())) {
+        Ok(moved_to) => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "queue `{:?}` was moved to `{}`",
+                    base.as_ref(),
+                    moved_to.trim()
+                ),
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    if compaction_marker_filename(base.as_ref()).exists() {
+        return Err(io::Error::other(format!(
+            "queue `{:?}` has an interrupted compaction; call `compact_queue` to finish it",
+            base.as_ref()
+        )));
+    }
+
     let mutex = Mutex::open(base.as_ref().join("version"))?;
 
     // Spin lock but it should be fine...
@@ -81,6 +165,11 @@ pub fn check_queue_version<P: AsRef<Path>>(base: P) -> io::Result<()> {
 
     if str_contents.is_empty() {
         lock.write(format!("{}\n", env!("CARGO_PKG_VERSION")).as_bytes())?;
+
+        // Queues restored with `backup::import` already know their format:
+        if !format_filename(base.as_ref()).exists() {
+            write_format_version(base.as_ref(), FORMAT_VERSION)?;
+        }
     } else {
         let version = match str_contents.trim().parse::<Version>() {
             Ok(version) => version,
@@ -108,6 +197,30 @@ pub fn check_queue_version<P: AsRef<Path>>(base: P) -> io::Result<()> {
                 requirement
             );
         }
+
+        let format = read_format_version(base.as_ref())?;
+
+        if format > FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "queue `{:?}` is of format {}, but you have yaque version {}, which supports formats up to {}",
+                    base.as_ref(),
+                    format,
+                    env!("CARGO_PKG_VERSION"),
+                    FORMAT_VERSION
+                ),
+            ));
+        } else if format < MIN_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "queue `{:?}` is of format {}, which must be upgraded with `migrate_queue` first",
+                    base.as_ref(),
+                    format
+                ),
+            ));
+        }
     }
 
     Ok(())
@@ -2,11 +2,27 @@
 //! the `inotify` API.
 
 use notify::event::{Event, EventKind, ModifyKind};
-use notify::{RecommendedWatcher, Watcher};
+use notify::{EventHandler, RecommendedWatcher, Watcher};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::task::Waker;
 
+/// Creates the recommended watcher of the platform.
+#[cfg(not(target_os = "wasi"))]
+fn recommended_watcher<F: EventHandler>(event_handler: F) -> notify::Result<RecommendedWatcher> {
+    notify::recommended_watcher(event_handler)
+}
+
+/// Creates the recommended watcher of the platform. WASI has neither events
+/// nor the threads to poll for them (the poll watcher of `notify` would just
+/// never deliver anything), so there is none.
+#[cfg(target_os = "wasi")]
+fn recommended_watcher<F: EventHandler>(_event_handler: F) -> notify::Result<RecommendedWatcher> {
+    Err(notify::Error::generic(
+        "filesystem events are not supported on WASI",
+    ))
+}
+
 // /// Watches for the creation of a given future file.
 // fn file_creation_watcher<P>(path: P, waker: Arc<Mutex<Option<Waker>>>) -> RecommendedWatcher
 // where
@@ -43,108 +59,118 @@ use std::task::Waker;
 //     watcher
 // }
 
-/// Watches for the removal of a given future file.
+/// Watches for the removal of a given future file. This fails if the platform
+/// (or the filesystem) cannot deliver events.
 pub(crate) fn file_removal_watcher<P>(
     path: P,
     waker: Arc<Mutex<Option<Waker>>>,
-) -> RecommendedWatcher
+) -> notify::Result<RecommendedWatcher>
 where
     P: AsRef<Path>,
 {
     // Set up watcher:
-    let mut watcher =
-        notify::recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
-            match maybe_event.expect("received error from watcher") {
-                // When any modification in the file happens
-                Event {
-                    kind: EventKind::Remove(_),
-                    ..
-                } => {
-                    waker
-                        .lock()
-                        .expect("waker poisoned")
-                        .take()
-                        .map(|waker: Waker| waker.wake());
-                }
-                _ => {}
+    let mut watcher = recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
+        // When the file is removed
+        if let Event {
+            kind: EventKind::Remove(_),
+            ..
+        } = maybe_event.expect("received error from watcher")
+        {
+            if let Some(waker) = waker.lock().expect("waker poisoned").take() {
+                waker.wake();
             }
-        })
-        .expect("could not create watcher");
+        }
+    })?;
 
     // Put watcher to run:
-    watcher
-        .watch(
-            path.as_ref().parent().expect("file must have parent"),
-            notify::RecursiveMode::NonRecursive,
-        )
-        .expect("could not start watching file");
-
-    watcher
+    watcher.watch(
+        path.as_ref().parent().expect("file must have parent"),
+        notify::RecursiveMode::NonRecursive,
+    )?;
+
+    Ok(watcher)
 }
 
-/// Watches *any* removal in a given path.
-pub(crate) fn removal_watcher(path: &Path, waker: Arc<Mutex<Option<Waker>>>) -> RecommendedWatcher
-{
+/// Watches *any* creation, modification or removal in a given path. This
+/// fails if the platform (or the filesystem) cannot deliver events.
+pub(crate) fn change_watcher(
+    path: &Path,
+    waker: Arc<Mutex<Option<Waker>>>,
+) -> notify::Result<RecommendedWatcher> {
     // Set up watcher:
-    let mut watcher =
-        notify::recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
-            match maybe_event.expect("received error from watcher") {
-                Event {
-                    kind: EventKind::Remove(_),
-                    ..
-                } => {
-                    waker
-                        .lock()
-                        .expect("waker poisoned")
-                        .take()
-                        .map(|waker: Waker| waker.wake());
-                }
-                _ => {}
+    let mut watcher = recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
+        if let Event {
+            kind: EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_),
+            ..
+        } = maybe_event.expect("received error from watcher")
+        {
+            if let Some(waker) = waker.lock().expect("waker poisoned").take() {
+                waker.wake();
             }
-        })
-        .expect("could not create watcher");
+        }
+    })?;
 
     // Put watcher to run:
-    watcher
-        .watch(path, notify::RecursiveMode::NonRecursive)
-        .expect("could not start watching file");
+    watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
 
-    watcher
+    Ok(watcher)
 }
 
-/// Watches a file for changes in its content.
-pub(crate) fn file_watcher(path: &Path, waker: Arc<Mutex<Option<Waker>>>) -> RecommendedWatcher
-{
+/// Watches *any* creation in a given path. This fails if the platform (or the
+/// filesystem) cannot deliver events.
+pub(crate) fn creation_watcher(
+    path: &Path,
+    waker: Arc<Mutex<Option<Waker>>>,
+) -> notify::Result<RecommendedWatcher> {
     // Set up watcher:
-    let mut watcher =
-        notify::recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
-            match maybe_event.expect("received error from watcher") {
-                // When any modification in the file happens
-                Event {
-                    kind: EventKind::Modify(ModifyKind::Data(_)),
-                    ..
-                } => {
-                    waker
-                        .lock()
-                        .expect("waker poisoned")
-                        .take()
-                        .map(|waker: Waker| waker.wake());
-                }
-                Event {
-                    kind: EventKind::Remove(_),
-                    ..
-                } => {
-                    log::debug!("file being watched was removed");
+    let mut watcher = recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
+        if let Event {
+            kind: EventKind::Create(_),
+            ..
+        } = maybe_event.expect("received error from watcher")
+        {
+            if let Some(waker) = waker.lock().expect("waker poisoned").take() {
+                waker.wake();
+            }
+        }
+    })?;
+
+    // Put watcher to run:
+    watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
+
+    Ok(watcher)
+}
+
+/// Watches a file for changes in its content. This fails if the platform (or
+/// the filesystem) cannot deliver events.
+pub(crate) fn file_watcher(
+    path: &Path,
+    waker: Arc<Mutex<Option<Waker>>>,
+) -> notify::Result<RecommendedWatcher> {
+    // Set up watcher:
+    let mut watcher = recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
+        match maybe_event.expect("received error from watcher") {
+            // When any modification in the file happens
+            Event {
+                kind: EventKind::Modify(ModifyKind::Data(_)),
+                ..
+            } => {
+                if let Some(waker) = waker.lock().expect("waker poisoned").take() {
+                    waker.wake();
                 }
-                _ => {}
             }
-        })
-        .expect("could not create watcher");
+            Event {
+                kind: EventKind::Remove(_),
+                ..
+            } => {
+                log::debug!("file being watched was removed");
+            }
+            _ => {}
+        }
+    })?;
 
     // Put watcher to run:
-    watcher
-        .watch(path, notify::RecursiveMode::NonRecursive)
-        .expect("could not start watching file");
+    watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
 
-    watcher
+    Ok(watcher)
 }
@@ -0,0 +1,92 @@
+//! The queue removing, renaming and relocking files that other handles still
+//! have open, which is fine on Unix but not on Windows, unless done right.
+//! These run on every platform, but are meant for the Windows CI.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use yaque::queue::RetentionPolicy;
+use yaque::{Receiver, Sender, SenderBuilder, TryRecvError};
+
+fn segment(base: &str, segment: u64) -> std::path::PathBuf {
+    Path::new(base).join(format!("{}.q", segment))
+}
+
+#[test]
+fn relock_right_after_release() {
+    let base = "data/file-sharing-relock";
+    let _ = fs::remove_dir_all(base);
+
+    // The lock file of the last sender may still be pending deletion:
+    for i in 0..50u8 {
+        let mut sender = Sender::open(base).unwrap();
+        sender.try_send([i; 4]).unwrap();
+    }
+
+    let mut receiver = Receiver::open(base).unwrap();
+    for i in 0..50u8 {
+        let guard = receiver.try_recv().ok().unwrap();
+        assert_eq!(&*guard, &[i; 4]);
+        guard.commit().unwrap();
+    }
+}
+
+#[test]
+fn receive_segment_open_elsewhere() {
+    let base = "data/file-sharing-receive";
+    let _ = fs::remove_dir_all(base);
+
+    // Two elements per segment:
+    let mut sender = SenderBuilder::new().segment_size(16).open(base).unwrap();
+    for i in 0..6u8 {
+        sender.try_send([i; 10]).unwrap();
+    }
+
+    // (e.g., a backup tool or an indexer)
+    let mut other = File::open(segment(base, 0)).unwrap();
+
+    let mut receiver = Receiver::open(base).unwrap();
+    for i in 0..6u8 {
+        let guard = receiver.try_recv().ok().unwrap();
+        assert_eq!(&*guard, &[i; 10]);
+        guard.commit().unwrap();
+    }
+
+    assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+
+    // The other handle still reads the removed segment:
+    let mut contents = Vec::new();
+    other.read_to_end(&mut contents).unwrap();
+    assert!(!contents.is_empty());
+}
+
+#[test]
+fn drop_segment_open_by_receiver() {
+    let base = "data/file-sharing-drop-oldest";
+    let _ = fs::remove_dir_all(base);
+
+    // Two elements (of 14 bytes) per segment (of 48 bytes, with the EOF and
+    // the footer):
+    let mut sender = SenderBuilder::new()
+        .segment_size(16)
+        .retention(RetentionPolicy::DropOldest { max_bytes: 96 })
+        .open(base)
+        .unwrap();
+    sender.try_send([0; 10]).unwrap();
+
+    // The receiver follows the first segment...
+    let mut receiver = Receiver::open(base).unwrap();
+    receiver.try_recv().ok().unwrap().commit().unwrap();
+
+    // ... while the sender drops it:
+    for i in 1..10u8 {
+        sender.try_send([i; 10]).unwrap();
+    }
+
+    drop(receiver);
+    let mut receiver = Receiver::open(base).unwrap();
+    let guard = receiver.try_recv().ok().unwrap();
+    assert!(guard[0] >= 4);
+    guard.commit().unwrap();
+}
@@ -0,0 +1,67 @@
+//! The queue within a single thread, waiting on a receiver and on a lock in
+//! the same task that unblocks them, which is all there is on WASI (where the
+//! queue falls back to polling, since there are no filesystem events). These
+//! run on every platform, but are meant for `wasm32-wasip1` too, e.g.:
+//! ```text
+//! CARGO_TARGET_WASM32_WASIP1_RUNNER="wasmtime --dir=." \
+//!     cargo test --target wasm32-wasip1 --test wasi
+//! ```
+
+use std::fs;
+
+use yaque::mutex::Mutex;
+use yaque::{channel, TryRecvError};
+
+#[test]
+fn send_and_receive() {
+    let base = "data/wasi-send-and-receive";
+    let _ = fs::remove_dir_all(base);
+    let (mut sender, mut receiver) = channel(base).unwrap();
+
+    futures::executor::block_on(async {
+        for i in 0..10u8 {
+            sender.send([i; 10]).await.unwrap();
+        }
+
+        for i in 0..10u8 {
+            let guard = receiver.recv().await.unwrap();
+            assert_eq!(&*guard, &[i; 10]);
+            guard.commit().unwrap();
+        }
+    });
+
+    assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+}
+
+#[test]
+fn receive_before_send() {
+    let base = "data/wasi-receive-before-send";
+    let _ = fs::remove_dir_all(base);
+    let (mut sender, mut receiver) = channel(base).unwrap();
+
+    futures::executor::block_on(async {
+        let (received, sent) = futures::join!(
+            async {
+                let guard = receiver.recv().await.unwrap();
+                let data = guard.to_vec();
+                guard.commit().unwrap();
+                data
+            },
+            sender.send(b"late"),
+        );
+
+        sent.unwrap();
+        assert_eq!(received, b"late");
+    });
+}
+
+#[test]
+fn lock_after_release() {
+    let mutex = Mutex::open("data/wasi-lock-after-release").unwrap();
+    let guard = futures::executor::block_on(mutex.lock()).unwrap();
+
+    futures::executor::block_on(async {
+        let (locked, ()) = futures::join!(mutex.lock(), async move { drop(guard) });
+        locked.unwrap();
+    });
+}